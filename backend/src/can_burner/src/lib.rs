@@ -7,25 +7,50 @@ use ic_e8s::c::E8s;
 use ic_ledger_types::{AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferArg;
+use shared::access::{
+    GetAccessLogRequest, GetAccessLogResponse, GrantRoleRequest, GrantRoleResponse,
+    RevokeRoleRequest, RevokeRoleResponse, Role,
+};
 use shared::burner::api::{
-    ClaimRewardRequest, ClaimRewardResponse, GetBurnersRequest, GetBurnersResponse,
-    GetKamikazesRequest, GetKamikazesResponse, GetTotalsResponse, MigrateMsqAccountRequest,
-    MigrateMsqAccountResponse, StakeRequest, StakeResponse, VerifyDecideIdRequest,
-    VerifyDecideIdResponse, WithdrawRequest, WithdrawResponse,
+    AcceptAccountTransferRequest, AcceptAccountTransferResponse, CancelAccountTransferRequest,
+    CancelAccountTransferResponse, ClaimRewardRequest, ClaimRewardResponse,
+    GetAccountHistoryRequest, GetAccountHistoryResponse, GetAccountTransferProposalRequest,
+    GetAccountTransferProposalResponse, GetAccountTransfersRequest, GetAccountTransfersResponse,
+    GetBurnersRequest, GetBurnersResponse, GetEmissionProjectionRequest,
+    GetEmissionProjectionResponse, GetKamikazesRequest, GetKamikazesResponse,
+    GetRedistributionsRequest, GetRedistributionsResponse, GetRoundHistoryRequest,
+    GetRoundHistoryResponse, GetSharesSnapshotChunkRequest, GetSharesSnapshotChunkResponse,
+    GetSharesSnapshotsRequest, GetSharesSnapshotsResponse, GetSpikesRequest, GetSpikesResponse,
+    GetTotalsCertifiedResponse, GetTotalsResponse, MigrateMsqAccountRequest,
+    MigrateMsqAccountResponse, ProposeAccountTransferRequest, ProposeAccountTransferResponse,
+    RequestSharesSnapshotResponse, SetEmissionScheduleRequest, SetEmissionScheduleResponse,
+    SetSharesSnapshotIntervalRequest, StakeRequest, StakeResponse, VerifyDecideIdRequest,
+    VerifyDecideIdResponse, VerifyKamikazeRoundRequest, VerifyKamikazeRoundResponse,
+    VerifyLotteryRoundRequest, VerifyLotteryRoundResponse, WithdrawRequest, WithdrawResponse,
 };
 use shared::burner::types::{
-    BURNER_DEV_FEE_SUBACCOUNT, BURNER_REDISTRIBUTION_SUBACCOUNT, BURNER_SPIKE_SUBACCOUNT,
+    StakeKind, BURNER_DEV_FEE_SUBACCOUNT, BURNER_FEATURES, BURNER_REDISTRIBUTION_SUBACCOUNT,
+    BURNER_SPIKE_SUBACCOUNT, FEATURE_STAKE, FEATURE_STAKE_KAMIKAZE,
 };
-use shared::icrc1::ICRC1CanisterClient;
-use shared::{ENV_VARS, ICP_FEE, MIN_ICP_STAKE_E8S_U64};
+use shared::certification::get_data_certificate;
+use shared::feature_flags::{FeatureFlag, SetFeatureFlagRequest, SetFeatureFlagResponse};
+use shared::icrc1::{ICRC1Api, ICRC1CanisterClient};
+use shared::metrics::{ecs_to_f64, serve_metrics, HttpRequest, HttpResponse};
+use shared::outbox::{GetOutboxTransfersRequest, GetOutboxTransfersResponse};
+use shared::timers::{self, GetTimersStatusResponse, RestartTimerRequest, RestartTimerResponse};
+use shared::{ENV_VARS, ICP_FEE};
 use utils::{
-    assert_caller_is_dev, assert_running, set_cycles_icp_exchange_rate_timer,
-    set_icp_redistribution_timer, set_init_seed_one_timer, set_spike_timer,
-    stake_callers_icp_for_redistribution, STATE, STOPPED_FOR_UPDATE,
+    assert_caller_has_role, assert_feature_enabled, assert_running, certify_totals,
+    claim_reward_burn, restart_job, set_cycles_icp_exchange_rate_timer,
+    set_icp_redistribution_timer, set_init_seed_one_timer, set_outbox_timer, set_spike_timer,
+    stake_callers_icp, CERTIFIED_TOTALS, STATE, STOPPED_FOR_UPDATE,
 };
 
 mod utils;
 
+#[cfg(test)]
+mod simulator;
+
 #[update]
 async fn mint(pid: Principal, qty: E8s) {
     assert_running();
@@ -84,132 +109,80 @@ async fn withdraw(req: WithdrawRequest) -> WithdrawResponse {
 
 #[update]
 async fn stake(req: StakeRequest) -> StakeResponse {
-    panic!("The mining is temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_STAKE);
 
     assert_running();
 
-    if req.qty_e8s_u64 < MIN_ICP_STAKE_E8S_U64 {
-        panic!("At least 0.5 ICP is required to participate");
-    }
-
-    stake_callers_icp_for_redistribution(req.qty_e8s_u64)
-        .await
-        .expect("Unable to stake ICP");
-
-    let staked_icps_e12s = E8s::from(req.qty_e8s_u64)
-        .to_dynamic()
-        .to_decimals(12)
-        .to_const::<12>();
-
-    STATE.with_borrow_mut(|s| {
-        let info = s.get_info();
-        let cycles_rate = info.get_icp_to_cycles_exchange_rate();
-
-        let shares_minted = staked_icps_e12s * cycles_rate;
-
-        s.mint_share(shares_minted.clone(), caller());
-    });
-
-    StakeResponse {}
+    stake_callers_icp(req, StakeKind::Common).await
 }
 
 #[update]
 async fn stake_kamikaze(req: StakeRequest) -> StakeResponse {
-    panic!("The mining is temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_STAKE_KAMIKAZE);
 
     assert_running();
 
-    if req.qty_e8s_u64 < MIN_ICP_STAKE_E8S_U64 {
-        panic!("At least 0.5 ICP is required to participate");
-    }
-
-    stake_callers_icp_for_redistribution(req.qty_e8s_u64)
-        .await
-        .expect("Unable to stake ICP");
-
-    let staked_icps_e12s = E8s::from(req.qty_e8s_u64)
-        .to_dynamic()
-        .to_decimals(12)
-        .to_const::<12>();
-
-    STATE.with_borrow_mut(|s| {
-        let info = s.get_info();
-        let cycles_rate = info.get_icp_to_cycles_exchange_rate();
-
-        let shares_minted = staked_icps_e12s * cycles_rate;
+    let tier = req.kamikaze_tier.unwrap_or_default();
 
-        s.mint_kamikaze_share(shares_minted.clone(), caller(), time());
-    });
-
-    StakeResponse {}
+    stake_callers_icp(req, StakeKind::Kamikaze(tier)).await
 }
 
 #[update]
 async fn claim_reward(req: ClaimRewardRequest) -> ClaimRewardResponse {
     assert_running();
 
-    let c = caller();
+    let burn_token_can = ICRC1CanisterClient::new(ENV_VARS.burn_token_canister_id);
+    let result = claim_reward_burn(&burn_token_can, caller(), req, time()).await;
 
-    let result = if let Some(unclaimed) = STATE.with_borrow_mut(|s| s.claim_reward(c)) {
-        let burn_token_can = ICRC1CanisterClient::new(ENV_VARS.burn_token_canister_id);
-
-        let res = burn_token_can
-            .icrc1_transfer(TransferArg {
-                to: Account {
-                    owner: req.to,
-                    subaccount: None,
-                },
-                amount: Nat(unclaimed.clone().val),
-                from_subaccount: None,
-                fee: None,
-                created_at_time: None,
-                memo: None,
-            })
-            .await
-            .map_err(|e| format!("{:?}", e))
-            .map(|(r,)| r.map_err(|e| format!("{:?}", e)));
-
-        match res {
-            Ok(r) => match r {
-                Ok(idx) => Ok(idx),
-                Err(e) => {
-                    STATE.with_borrow_mut(|s| s.revert_claim_reward(c, unclaimed));
-                    Err(e)
-                }
-            },
-            Err(e) => {
-                STATE.with_borrow_mut(|s| s.revert_claim_reward(c, unclaimed));
-                Err(e)
-            }
-        }
-    } else {
-        Err(format!("Not unclaimed reward found!"))
-    };
+    certify_totals();
 
     ClaimRewardResponse { result }
 }
 
 #[update]
-fn verify_decide_id(_req: VerifyDecideIdRequest) -> VerifyDecideIdResponse {
-    /* STATE
-    .with_borrow_mut(|s| s.verify_decide_id(&req.jwt, caller(), time()))
-    .expect("Unable to verify Decide ID"); */
+fn verify_decide_id(req: VerifyDecideIdRequest) -> VerifyDecideIdResponse {
+    let result = STATE.with_borrow_mut(|s| s.verify_decide_id(&req.jwt, caller(), time()));
 
-    VerifyDecideIdResponse {}
+    VerifyDecideIdResponse { result }
 }
 
 #[update]
 fn migrate_msq_account(req: MigrateMsqAccountRequest) -> MigrateMsqAccountResponse {
     STATE
-        .with_borrow_mut(|s| s.migrate_burner_account(&caller(), req.to))
+        .with_borrow_mut(|s| s.migrate_burner_account(&caller(), req.to, time()))
         .expect("Unable to migrate MSQ account");
 
     MigrateMsqAccountResponse {}
 }
 
+#[update]
+fn propose_account_transfer(req: ProposeAccountTransferRequest) -> ProposeAccountTransferResponse {
+    assert_running();
+
+    let result = STATE.with_borrow_mut(|s| s.propose_account_transfer(caller(), req.to, time()));
+
+    ProposeAccountTransferResponse { result }
+}
+
+#[update]
+fn accept_account_transfer(req: AcceptAccountTransferRequest) -> AcceptAccountTransferResponse {
+    assert_running();
+
+    let result = STATE.with_borrow_mut(|s| s.accept_account_transfer(caller(), req.from, time()));
+
+    AcceptAccountTransferResponse { result }
+}
+
+#[update]
+fn cancel_account_transfer(req: CancelAccountTransferRequest) -> CancelAccountTransferResponse {
+    let result = STATE.with_borrow_mut(|s| s.cancel_account_transfer(caller(), req.from, time()));
+
+    CancelAccountTransferResponse { result }
+}
+
 #[update]
 fn enable_lottery() {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Operator);
 
     STATE.with_borrow_mut(|s| {
         let mut info = s.get_info();
@@ -220,7 +193,7 @@ fn enable_lottery() {
 
 #[update]
 fn disable_lottery() {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Operator);
 
     STATE.with_borrow_mut(|s| {
         let mut info = s.get_info();
@@ -231,7 +204,7 @@ fn disable_lottery() {
 
 #[update]
 fn enable_kamikaze_pool() {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Operator);
 
     STATE.with_borrow_mut(|s| {
         let mut info = s.get_info();
@@ -242,7 +215,7 @@ fn enable_kamikaze_pool() {
 
 #[update]
 fn disable_kamikaze_pool() {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Operator);
 
     STATE.with_borrow_mut(|s| {
         let mut info = s.get_info();
@@ -251,9 +224,44 @@ fn disable_kamikaze_pool() {
     });
 }
 
+#[update]
+fn set_emission_schedule(req: SetEmissionScheduleRequest) -> SetEmissionScheduleResponse {
+    assert_caller_has_role(Role::Owner);
+
+    let result = STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.schedule_emission_schedule(req.schedule, req.activate_at_round)?;
+        s.set_info(info);
+
+        Ok(())
+    });
+
+    SetEmissionScheduleResponse { result }
+}
+
+#[update]
+fn request_shares_snapshot() -> RequestSharesSnapshotResponse {
+    assert_caller_has_role(Role::Operator);
+
+    let snapshot_id = STATE.with_borrow_mut(|s| s.request_shares_snapshot(time()));
+
+    RequestSharesSnapshotResponse { snapshot_id }
+}
+
+#[update]
+fn set_shares_snapshot_interval(req: SetSharesSnapshotIntervalRequest) {
+    assert_caller_has_role(Role::Operator);
+
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+        info.shares_snapshot_interval_rounds = req.interval_rounds;
+        s.set_info(info);
+    });
+}
+
 #[update]
 async fn withdraw_dev_fee_icp(qty: u64, account_id: AccountIdentifier) -> Result<u64, String> {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Owner);
 
     let arg = TransferArgs {
         from_subaccount: Some(Subaccount(BURNER_DEV_FEE_SUBACCOUNT)),
@@ -310,11 +318,87 @@ fn get_kamikazes(req: GetKamikazesRequest) -> GetKamikazesResponse {
     STATE.with_borrow(|s| s.get_kamikazes(req))
 }
 
+#[query]
+fn get_account_history(req: GetAccountHistoryRequest) -> GetAccountHistoryResponse {
+    STATE.with_borrow(|s| s.get_account_history(req))
+}
+
+#[query]
+fn get_account_daily_pos_rounds(req: GetAccountHistoryRequest) -> GetAccountHistoryResponse {
+    STATE.with_borrow(|s| s.get_account_daily_pos_rounds(req))
+}
+
+#[query]
+fn get_round_history(req: GetRoundHistoryRequest) -> GetRoundHistoryResponse {
+    STATE.with_borrow(|s| s.get_round_history(req))
+}
+
+#[query]
+fn get_emission_projection(req: GetEmissionProjectionRequest) -> GetEmissionProjectionResponse {
+    STATE.with_borrow(|s| s.get_emission_projection(req))
+}
+
+#[query]
+fn verify_kamikaze_round(req: VerifyKamikazeRoundRequest) -> VerifyKamikazeRoundResponse {
+    STATE.with_borrow(|s| s.verify_kamikaze_round(req))
+}
+
+#[query]
+fn verify_lottery_round(req: VerifyLotteryRoundRequest) -> VerifyLotteryRoundResponse {
+    STATE.with_borrow(|s| s.verify_lottery_round(req))
+}
+
+#[query]
+fn get_redistributions(req: GetRedistributionsRequest) -> GetRedistributionsResponse {
+    STATE.with_borrow(|s| s.get_redistributions(req))
+}
+
+#[query]
+fn get_outbox_transfers(req: GetOutboxTransfersRequest) -> GetOutboxTransfersResponse {
+    STATE.with_borrow(|s| s.outbox.get_transfers(req))
+}
+
+#[query]
+fn get_spikes(req: GetSpikesRequest) -> GetSpikesResponse {
+    STATE.with_borrow(|s| s.get_spikes(req))
+}
+
+#[query]
+fn get_account_transfer_proposal(
+    req: GetAccountTransferProposalRequest,
+) -> GetAccountTransferProposalResponse {
+    STATE.with_borrow(|s| s.get_account_transfer_proposal(req))
+}
+
+#[query]
+fn get_account_transfers(req: GetAccountTransfersRequest) -> GetAccountTransfersResponse {
+    STATE.with_borrow(|s| s.get_account_transfers(req))
+}
+
+#[query]
+fn get_shares_snapshots(req: GetSharesSnapshotsRequest) -> GetSharesSnapshotsResponse {
+    STATE.with_borrow(|s| s.get_shares_snapshots(req))
+}
+
+#[query]
+fn get_shares_snapshot_chunk(req: GetSharesSnapshotChunkRequest) -> GetSharesSnapshotChunkResponse {
+    STATE.with_borrow(|s| s.get_shares_snapshot_chunk(req))
+}
+
 #[query]
 fn get_totals() -> GetTotalsResponse {
     STATE.with_borrow(|s| s.get_totals(&caller()))
 }
 
+#[query]
+fn get_totals_certified() -> GetTotalsCertifiedResponse {
+    CERTIFIED_TOTALS.with_borrow(|(totals, tree)| GetTotalsCertifiedResponse {
+        totals: totals.clone(),
+        certificate: get_data_certificate(),
+        witness: tree.witness(),
+    })
+}
+
 #[query]
 fn subaccount_of(id: Principal) -> Subaccount {
     Subaccount::from(id)
@@ -322,43 +406,158 @@ fn subaccount_of(id: Principal) -> Subaccount {
 
 #[init]
 fn init_hook() {
-    STOPPED_FOR_UPDATE.with_borrow_mut(|(dev, _)| *dev = caller());
+    STATE.with_borrow_mut(|s| {
+        s.access.bootstrap(caller(), time());
+        s.feature_flags.init_disabled(BURNER_FEATURES, time());
+    });
 
+    certify_totals();
     set_init_seed_one_timer();
     set_cycles_icp_exchange_rate_timer();
     set_icp_redistribution_timer();
+    set_outbox_timer();
     set_spike_timer();
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
-    STOPPED_FOR_UPDATE.with_borrow_mut(|(dev, _)| *dev = caller());
+    STATE.with_borrow_mut(|s| {
+        // the first upgrade after the roles were introduced makes the upgrader the owner
+        s.access.bootstrap(caller(), time());
+        s.feature_flags.init_disabled(BURNER_FEATURES, time());
+        s.migrate_lottery_participants();
+        s.migrate_round_seeds();
+    });
+    certify_totals();
 
     set_cycles_icp_exchange_rate_timer();
     set_icp_redistribution_timer();
+    set_outbox_timer();
     set_spike_timer();
 }
 
 #[update]
-fn stop() {
-    assert_caller_is_dev();
+fn set_feature_flag(req: SetFeatureFlagRequest) -> SetFeatureFlagResponse {
+    assert_caller_has_role(Role::Operator);
 
-    STOPPED_FOR_UPDATE.with_borrow_mut(|(_dev, is_stopped)| {
-        if !*is_stopped {
-            *is_stopped = true;
-        }
+    let result = STATE.with_borrow_mut(|s| s.feature_flags.set_feature_flag(req, caller(), time()));
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_feature_flags() -> Vec<(String, FeatureFlag)> {
+    STATE.with_borrow(|s| s.feature_flags.get_feature_flags(time()))
+}
+
+#[query]
+fn get_timers_status() -> GetTimersStatusResponse {
+    timers::get_timers_status(time())
+}
+
+#[update]
+fn restart_timer(req: RestartTimerRequest) -> RestartTimerResponse {
+    assert_caller_has_role(Role::Operator);
+
+    RestartTimerResponse {
+        result: restart_job(&req.job),
+    }
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    serve_metrics(req, |m| {
+        STATE.with_borrow(|s| {
+            let info = s.get_info();
+
+            m.gauge(
+                "burner_shares_count",
+                "The number of accounts in the shares map",
+                s.shares.len() as f64,
+            );
+            m.gauge(
+                "burner_kamikaze_shares_count",
+                "The number of accounts in the kamikaze pool",
+                s.kamikaze_shares.len() as f64,
+            );
+            m.gauge(
+                "burner_lottery_participants_count",
+                "The number of accounts participating in the lottery",
+                s.lottery_participants.by_index.len() as f64,
+            );
+            m.gauge(
+                "burner_outbox_pending_count",
+                "The number of outgoing transfers which are not yet delivered",
+                s.outbox.pending.len() as f64,
+            );
+            m.gauge(
+                "burner_current_pos_round",
+                "The index of the current PoS round",
+                info.current_pos_round as f64,
+            );
+            m.gauge(
+                "burner_total_shares_supply",
+                "The total supply of the pool shares",
+                ecs_to_f64(&info.total_shares_supply),
+            );
+            m.gauge(
+                "burner_total_tcycles_burned",
+                "The total amount of burned cycles in TCycles",
+                ecs_to_f64(&info.total_tcycles_burned),
+            );
+            m.gauge(
+                "burner_total_burn_token_minted",
+                "The total amount of minted BURN tokens",
+                ecs_to_f64(&info.total_burn_token_minted),
+            );
+            m.gauge(
+                "burner_current_burn_token_reward",
+                "The amount of BURN tokens minted each PoS round",
+                ecs_to_f64(&info.current_burn_token_reward),
+            );
+        })
     })
 }
 
+#[update]
+fn grant_role(req: GrantRoleRequest) -> GrantRoleResponse {
+    let result =
+        STATE.with_borrow_mut(|s| s.access.grant_role(caller(), req.pid, req.role, time()));
+
+    GrantRoleResponse { result }
+}
+
+#[update]
+fn revoke_role(req: RevokeRoleRequest) -> RevokeRoleResponse {
+    let result = STATE.with_borrow_mut(|s| s.access.revoke_role(caller(), req.pid, time()));
+
+    RevokeRoleResponse { result }
+}
+
+#[query]
+fn get_roles() -> Vec<(Principal, Role)> {
+    STATE.with_borrow(|s| s.access.get_roles())
+}
+
+#[query]
+fn get_access_log(req: GetAccessLogRequest) -> GetAccessLogResponse {
+    assert_caller_has_role(Role::Auditor);
+
+    STATE.with_borrow(|s| s.access.get_access_log(req))
+}
+
+#[update]
+fn stop() {
+    assert_caller_has_role(Role::Operator);
+
+    STOPPED_FOR_UPDATE.with_borrow_mut(|is_stopped| *is_stopped = true)
+}
+
 #[update]
 fn resume() {
-    assert_caller_is_dev();
+    assert_caller_has_role(Role::Operator);
 
-    STOPPED_FOR_UPDATE.with_borrow_mut(|(_dev, is_stopped)| {
-        if *is_stopped {
-            *is_stopped = false;
-        }
-    })
+    STOPPED_FOR_UPDATE.with_borrow_mut(|is_stopped| *is_stopped = false)
 }
 
 export_candid!();
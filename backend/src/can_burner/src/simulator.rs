@@ -0,0 +1,489 @@
+//! A deterministic multi-round simulation of the burner economy.
+//!
+//! Synthetic stakers stake, enter the kamikaze pool and claim their rewards, while the rounds are
+//! driven through `kamikaze_round_batch`, `kamikaze_harakiri_batch` and `pos_round_batch` in the same
+//! order the timers of `kamikaze_and_pos` call them. Everything runs against the thread-local `STATE`
+//! of the canister on an in-memory `DefaultMemoryImpl` and against fake ledgers, so the same seed
+//! always produces the same report.
+//!
+//! To get the CSV reports of a long run:
+//! `cargo test -p burner simulate_burner_economy -- --ignored --nocapture`
+
+use std::{collections::BTreeMap, fmt::Write, thread};
+
+use candid::{Nat, Principal};
+use futures::executor::block_on;
+use ic_e8s::c::E8s;
+use icrc_ledger_types::icrc1::account::Account;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use shared::{
+    burner::{
+        api::{ClaimRewardRequest, StakeRequest},
+        types::{KamikazeTier, StakeKind, TCycles, TimestampNs, POS_ACCOUNTS_PER_BATCH},
+    },
+    fakes::FakeICRC1Ledger,
+    metrics::nat_to_f64,
+    ICP_FEE, MIN_ICP_STAKE_E8S_U64, ONE_DAY_NS,
+};
+
+use crate::utils::{claim_reward_burn, stake_icp, update_share_fee, STATE};
+
+const START_TIME_NS: TimestampNs = 1_700_000_000_000_000_000;
+const ONE_YEAR_NS: u64 = ONE_DAY_NS * 365;
+const STAKER_BALANCE_E8S: u64 = 100_000_000_000_000;
+const BURN_FEE_E8S: u64 = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    pub seed: u64,
+    pub rounds: u64,
+    pub common_stakers: u32,
+    pub kamikaze_stakers: u32,
+    // overrides the delay the state is initialized with, longer rounds let the kamikaze positions expire sooner
+    pub round_delay_ns: u64,
+    // the chances of each staker to act during a round
+    pub stake_probability: f64,
+    pub claim_probability: f64,
+    pub max_stake_e8s: u64,
+    // the rewards are in BURN and the stakes are in ICP, so the APR needs a price to compare them
+    pub burn_price_icp: f64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 42,
+            rounds: 5000,
+            common_stakers: 50,
+            kamikaze_stakers: 20,
+            round_delay_ns: ONE_DAY_NS / 24 / 4,
+            stake_probability: 0.02,
+            claim_probability: 0.01,
+            max_stake_e8s: 10_0000_0000,
+            burn_price_icp: 0.0001,
+        }
+    }
+}
+
+pub struct SimulationReport {
+    pub rounds_csv: String,
+    pub accounts_csv: String,
+    // the rounds after which total_shares_supply didn't match the sum of the shares
+    pub invariant_violations: Vec<u64>,
+    pub total_reward_emitted: E8s,
+    pub total_tcycles_burned: TCycles,
+}
+
+#[derive(Default)]
+struct StakerStats {
+    kamikaze_tier: Option<KamikazeTier>,
+    icp_staked_e8s: u64,
+    burn_claimed: E8s,
+    first_stake_at: Option<TimestampNs>,
+}
+
+/// Runs the simulation in a separate thread, so it always starts with a fresh state
+pub fn simulate(config: SimulationConfig) -> SimulationReport {
+    thread::spawn(move || run(config))
+        .join()
+        .expect("The simulation has panicked")
+}
+
+fn run(config: SimulationConfig) -> SimulationReport {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let burner_id = Principal::from_slice(&[1]);
+
+    init_state(&mut rng, &config);
+
+    let stakers = create_stakers(&config);
+    let icp_ledger = FakeICRC1Ledger::new(burner_id, ICP_FEE);
+
+    for pid in stakers.keys() {
+        let account = Account::from(*pid);
+
+        icp_ledger.set_balance(account, STAKER_BALANCE_E8S);
+        icp_ledger.approve(account, Account::from(burner_id), STAKER_BALANCE_E8S);
+    }
+
+    // the burner is the minting account of the BURN token
+    let mut burn_ledger = FakeICRC1Ledger::new(burner_id, BURN_FEE_E8S);
+    burn_ledger.minting_account = Account::from(burner_id);
+
+    let mut sim = Simulation {
+        config,
+        rng,
+        burner_id,
+        icp_ledger,
+        burn_ledger,
+        stakers,
+        now: START_TIME_NS,
+        rounds_csv: String::from(ROUNDS_CSV_HEADER),
+        invariant_violations: Vec::new(),
+        total_reward_emitted: E8s::zero(),
+        total_tcycles_burned: TCycles::zero(),
+    };
+
+    for _ in 0..sim.config.rounds {
+        sim.act();
+        sim.round();
+    }
+
+    let accounts_csv = sim.accounts_csv();
+
+    SimulationReport {
+        rounds_csv: sim.rounds_csv,
+        accounts_csv,
+        invariant_violations: sim.invariant_violations,
+        total_reward_emitted: sim.total_reward_emitted,
+        total_tcycles_burned: sim.total_tcycles_burned,
+    }
+}
+
+fn init_state(rng: &mut StdRng, config: &SimulationConfig) {
+    let mut seed = vec![0u8; 32];
+    rng.fill(seed.as_mut_slice());
+
+    STATE.with_borrow_mut(|s| {
+        s.init(seed);
+
+        let mut info = s.get_info();
+        info.pos_round_delay_ns = config.round_delay_ns;
+
+        if config.kamikaze_stakers > 0 {
+            info.enable_kamikaze_pool();
+        }
+
+        s.set_info(info);
+    });
+}
+
+fn create_stakers(config: &SimulationConfig) -> BTreeMap<Principal, StakerStats> {
+    let tiers = [
+        KamikazeTier::OneDay,
+        KamikazeTier::ThreeDays,
+        KamikazeTier::SevenDays,
+    ];
+
+    (0..config.common_stakers + config.kamikaze_stakers)
+        .map(|i| {
+            // the kamikaze stakers keep the same tier all the time, otherwise their stakes get rejected
+            let kamikaze_tier = i
+                .checked_sub(config.common_stakers)
+                .map(|k| tiers[k as usize % tiers.len()]);
+
+            let stats = StakerStats {
+                kamikaze_tier,
+                ..Default::default()
+            };
+
+            (staker_id(i), stats)
+        })
+        .collect()
+}
+
+fn staker_id(idx: u32) -> Principal {
+    let mut bytes = vec![0xff];
+    bytes.extend(idx.to_be_bytes());
+
+    Principal::from_slice(&bytes)
+}
+
+const ROUNDS_CSV_HEADER: &str = "round,ended_at_ns,reward_emitted_e8s,tcycles_burned_e12s,share_fee_e12s,participants,kamikaze_winner,lottery_winner,total_shares_supply_e12s,sum_of_shares_e12s,shares_invariant_holds,kamikaze_total_shares_e12s,sum_of_kamikaze_shares_e12s,kamikaze_positions,active_burners,total_burn_token_minted_e8s\n";
+
+struct Simulation {
+    config: SimulationConfig,
+    rng: StdRng,
+    burner_id: Principal,
+    icp_ledger: FakeICRC1Ledger,
+    burn_ledger: FakeICRC1Ledger,
+    stakers: BTreeMap<Principal, StakerStats>,
+    now: TimestampNs,
+    rounds_csv: String,
+    invariant_violations: Vec<u64>,
+    total_reward_emitted: E8s,
+    total_tcycles_burned: TCycles,
+}
+
+impl Simulation {
+    // each staker decides to stake and to claim independently, in the order of their ids
+    fn act(&mut self) {
+        let pids: Vec<_> = self.stakers.keys().copied().collect();
+
+        for pid in pids {
+            if self.rng.gen_bool(self.config.stake_probability) {
+                self.stake(pid);
+            }
+
+            if self.rng.gen_bool(self.config.claim_probability) {
+                self.claim(pid);
+            }
+        }
+    }
+
+    fn stake(&mut self, pid: Principal) {
+        let qty_e8s_u64 = self
+            .rng
+            .gen_range(MIN_ICP_STAKE_E8S_U64..=self.config.max_stake_e8s);
+
+        let stats = self.stakers.get_mut(&pid).unwrap();
+
+        let kind = match stats.kamikaze_tier {
+            Some(tier) => StakeKind::Kamikaze(tier),
+            None => StakeKind::Common,
+        };
+
+        let req = StakeRequest {
+            qty_e8s_u64,
+            created_at_time: self.now,
+            kamikaze_tier: stats.kamikaze_tier,
+        };
+
+        block_on(stake_icp(
+            &self.icp_ledger,
+            pid,
+            self.burner_id,
+            &req,
+            kind,
+            self.now,
+        ))
+        .expect("Unable to stake");
+
+        stats.icp_staked_e8s += qty_e8s_u64;
+        stats.first_stake_at.get_or_insert(self.now);
+    }
+
+    fn claim(&mut self, pid: Principal) {
+        let unclaimed = STATE
+            .with_borrow(|s| s.shares.get(&pid))
+            .map(|(_, unclaimed)| unclaimed)
+            .unwrap_or_default();
+
+        if unclaimed == E8s::zero() {
+            return;
+        }
+
+        let req = ClaimRewardRequest {
+            to: Account::from(pid),
+            qty: None,
+            memo: None,
+        };
+
+        block_on(claim_reward_burn(&self.burn_ledger, pid, req, self.now))
+            .expect("Unable to claim");
+
+        self.stakers.get_mut(&pid).unwrap().burn_claimed += unclaimed;
+    }
+
+    // the same sequence of calls the timers make, see `kamikaze_and_pos`, `kamikaze`, `harakiri` and `pos`
+    fn round(&mut self) {
+        update_share_fee();
+
+        let (kamikaze_pool_enabled, lottery_enabled) = STATE.with_borrow(|s| {
+            let info = s.get_info();
+            (info.is_kamikaze_pool_enabled(), info.is_lottery_enabled())
+        });
+
+        let split_reward_in_half = if kamikaze_pool_enabled {
+            self.kamikaze()
+        } else if lottery_enabled {
+            STATE.with_borrow_mut(|s| s.lottery_round(self.now))
+        } else {
+            false
+        };
+
+        let round = STATE.with_borrow(|s| s.get_info().current_pos_round);
+
+        while !STATE.with_borrow_mut(|s| {
+            s.pos_round_batch(split_reward_in_half, POS_ACCOUNTS_PER_BATCH, self.now)
+        }) {}
+
+        self.record_round(round);
+
+        self.now += STATE.with_borrow(|s| s.get_info().pos_round_delay_ns);
+    }
+
+    // returns true if the kamikaze pool has taken half of the block
+    fn kamikaze(&self) -> bool {
+        loop {
+            match STATE
+                .with_borrow_mut(|s| s.kamikaze_round_batch(POS_ACCOUNTS_PER_BATCH, self.now))
+            {
+                Some(true) => continue,
+                Some(false) => break,
+                None => return false,
+            }
+        }
+
+        while STATE.with_borrow_mut(|s| s.kamikaze_harakiri_batch(self.now, POS_ACCOUNTS_PER_BATCH))
+        {
+        }
+
+        true
+    }
+
+    fn record_round(&mut self, round: u64) {
+        STATE.with_borrow(|s| {
+            let info = s.get_info();
+
+            let sum_of_shares = s
+                .shares
+                .iter()
+                .fold(TCycles::zero(), |acc, (_, (share, _))| acc + share);
+            let sum_of_kamikaze_shares = s
+                .kamikaze_shares
+                .iter()
+                .fold(TCycles::zero(), |acc, (_, position)| acc + position.share);
+
+            let holds = info.total_shares_supply == sum_of_shares;
+            if !holds {
+                self.invariant_violations.push(round);
+            }
+
+            // an empty pool doesn't complete the round, so there might be no summary
+            let summary = s.round_history.get(&round).unwrap_or_default();
+
+            self.total_reward_emitted += &summary.reward_emitted;
+            self.total_tcycles_burned += &summary.tcycles_burned;
+
+            writeln!(
+                self.rounds_csv,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                round,
+                summary.ended_at,
+                summary.reward_emitted.val,
+                summary.tcycles_burned.val,
+                summary.share_fee.val,
+                summary.participants,
+                summary
+                    .kamikaze_winner
+                    .map(|it| it.to_text())
+                    .unwrap_or_default(),
+                summary
+                    .lottery_winner
+                    .map(|it| it.to_text())
+                    .unwrap_or_default(),
+                info.total_shares_supply.val,
+                sum_of_shares.val,
+                holds,
+                info.kamikaze_pool_total_shares.unwrap_or_default().val,
+                sum_of_kamikaze_shares.val,
+                s.kamikaze_shares.len(),
+                s.get_active_burners_count(),
+                info.total_burn_token_minted.val,
+            )
+            .unwrap();
+        });
+    }
+
+    fn accounts_csv(&self) -> String {
+        let mut csv = String::from(
+            "account,pool,icp_staked_e8s,burn_claimed_e8s,burn_unclaimed_e8s,shares_left_e12s,apr_percent\n",
+        );
+
+        for (pid, stats) in &self.stakers {
+            let (share, unclaimed) = STATE.with_borrow(|s| s.shares.get(pid)).unwrap_or_default();
+
+            let pool = match &stats.kamikaze_tier {
+                Some(tier) => format!("kamikaze_{:?}", tier),
+                None => String::from("common"),
+            };
+
+            writeln!(
+                csv,
+                "{},{},{},{},{},{},{:.4}",
+                pid.to_text(),
+                pool,
+                stats.icp_staked_e8s,
+                stats.burn_claimed.val,
+                unclaimed.val,
+                share.val,
+                self.apr_percent(stats, &unclaimed),
+            )
+            .unwrap();
+        }
+
+        csv
+    }
+
+    // the value of all the earned BURN relative to the staked ICP, annualized over the time since the first stake
+    fn apr_percent(&self, stats: &StakerStats, unclaimed: &E8s) -> f64 {
+        let Some(first_stake_at) = stats.first_stake_at else {
+            return 0.0;
+        };
+
+        let elapsed_ns = self.now - first_stake_at;
+        if elapsed_ns == 0 {
+            return 0.0;
+        }
+
+        let earned_burn = nat_to_f64(&Nat(stats.burn_claimed.val.clone() + &unclaimed.val), 8);
+        let staked_icp = stats.icp_staked_e8s as f64 / 1_0000_0000.0;
+
+        earned_burn * self.config.burn_price_icp / staked_icp * ONE_YEAR_NS as f64
+            / elapsed_ns as f64
+            * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use ic_e8s::c::E8s;
+    use shared::{burner::types::TCycles, ONE_HOUR_NS};
+
+    use super::{simulate, SimulationConfig};
+
+    fn short_config() -> SimulationConfig {
+        SimulationConfig {
+            rounds: 200,
+            common_stakers: 10,
+            kamikaze_stakers: 5,
+            // long enough for the one day kamikaze positions to expire
+            round_delay_ns: ONE_HOUR_NS / 2,
+            stake_probability: 0.05,
+            claim_probability: 0.05,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shares_supply_matches_the_sum_of_shares() {
+        let report = simulate(short_config());
+
+        assert!(
+            report.invariant_violations.is_empty(),
+            "The invariant is broken after rounds {:?}",
+            report.invariant_violations
+        );
+        assert!(report.total_reward_emitted > E8s::zero());
+        assert!(report.total_tcycles_burned > TCycles::zero());
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        let first = simulate(short_config());
+        let second = simulate(short_config());
+
+        assert_eq!(first.rounds_csv, second.rounds_csv);
+        assert_eq!(first.accounts_csv, second.accounts_csv);
+    }
+
+    #[test]
+    #[ignore]
+    fn simulate_burner_economy() {
+        let report = simulate(SimulationConfig::default());
+
+        let dir = std::env::temp_dir();
+        let rounds_path = dir.join("burner_simulation_rounds.csv");
+        let accounts_path = dir.join("burner_simulation_accounts.csv");
+
+        fs::write(&rounds_path, &report.rounds_csv).unwrap();
+        fs::write(&accounts_path, &report.accounts_csv).unwrap();
+
+        println!("Rounds: {}", rounds_path.display());
+        println!("Accounts: {}", accounts_path.display());
+
+        assert!(report.invariant_violations.is_empty());
+    }
+}
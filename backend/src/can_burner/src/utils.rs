@@ -1,38 +1,53 @@
 use std::{cell::RefCell, time::Duration};
 
-use candid::Principal;
+use candid::{Nat, Principal};
 use ic_cdk::{
-    api::{
-        management_canister::main::raw_rand,
-        time,
-    },
+    api::{management_canister::main::raw_rand, time},
     caller, id, spawn,
 };
 use ic_cdk_timers::set_timer;
 
 use ic_ledger_types::{
-    transfer, AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs,
+    transfer, AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Timestamp, Tokens,
+    TransferArgs, TransferError,
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
     Cell, DefaultMemoryImpl, StableBTreeMap,
 };
+use icrc_ledger_types::{
+    icrc1::{account::Account, transfer::TransferArg},
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
 use shared::{
+    access::{AccessControl, Role},
     burner::{
-        state::BurnerState,
+        api::{
+            CertifiedBurnerTotals, ClaimRewardRequest, StakeReceipt, StakeRequest, StakeResponse,
+        },
+        state::{BurnerState, LotteryParticipants},
         types::{
-            BurnerStateInfo, BURNER_DEV_FEE_SUBACCOUNT, BURNER_REDISTRIBUTION_SUBACCOUNT,
-            BURNER_SPIKE_SUBACCOUNT, ICPSWAP_PRICE_UPDATE_INTERVAL_NS,
-            ICP_REDISTRIBUTION_INTERVAL_NS, POS_ACCOUNTS_PER_BATCH, REDISTRIBUTION_DEV_SHARE_E8S,
-            REDISTRIBUTION_FURNACE_SHARE_E8S, REDISTRIBUTION_SPIKE_SHARE_E8S,
+            BurnerStateInfo, SpikeEvent, SpikeStatus, StakeKind, TCycles, TimestampNs,
+            BURNER_REDISTRIBUTION_SUBACCOUNT, BURNER_SPIKE_SUBACCOUNT,
+            ICPSWAP_PRICE_UPDATE_INTERVAL_NS, ICP_REDISTRIBUTION_INTERVAL_NS,
+            POS_ACCOUNTS_PER_BATCH, REFERENCE_ICP_TO_CYCLES_EXCHANGE_RATE,
+            SHARES_SNAPSHOT_ENTRIES_PER_BATCH, SHARE_FEE_TARGET_POSITION_LIFESPAN_ROUNDS,
+            SPIKE_MAX_TRANSFER_ATTEMPTS, SPIKE_RETRY_INTERVAL_NS, SPIKING_INTERVAL_NS,
+            TCYCLE_POS_ROUND_BASE_FEE,
         },
     },
-    cmc::CMCClient,
-    ENV_VARS, ICP_FEE,
+    certification::CertifiedTotals,
+    cmc::{CMCApi, CMCClient, NotifyTopUpError, NotifyTopUpRequest},
+    feature_flags::FeatureFlags,
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    outbox::{
+        send_outbox_transfer, Outbox, OutboxSendError, OUTBOX_PROCESSING_INTERVAL_NS,
+        OUTBOX_TRANSFERS_PER_BATCH,
+    },
+    timers::{note_job_failed, note_job_finished, prepare_job_restart, schedule_job},
+    ENV_VARS, ICP_FEE, MEMO_TOP_UP_CANISTER, MIN_ICP_STAKE_E8S_U64,
 };
 
-use crate::subaccount_of;
-
 thread_local! {
     pub static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -49,6 +64,14 @@ thread_local! {
             eligible_for_lottery: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3)))
             ),
+            lottery_participants: LotteryParticipants {
+                index_of: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(15)))
+                ),
+                by_index: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(16)))
+                ),
+            },
             lottery_rounds_won: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4)))
             ),
@@ -59,52 +82,157 @@ thread_local! {
             kamikaze_rounds_won: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(6)))
             ),
+
+            stakes: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(7)))
+            ),
+            account_history: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(8)))
+            ),
+            round_history: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(9)))
+            ),
+            kamikaze_round_proofs: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(10)))
+            ),
+
+            outbox: Outbox {
+                transfers: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(11)))
+                ),
+                pending: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(12)))
+                ),
+            },
+            redistributions: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(13)))
+            ),
+            spikes: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(14)))
+            ),
+
+            account_transfer_proposals: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(17)))
+            ),
+            account_transfers: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(18)))
+            ),
+
+            shares_snapshots: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(19)))
+            ),
+            shares_snapshot_entries: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(20)))
+            ),
+            shares_snapshot_originals: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(24)))
+            ),
+            decide_id_transferred: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(25)))
+            ),
+            account_daily_pos_rounds: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(26)))
+            ),
+            lottery_round_proofs: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(27)))
+            ),
+
+            access: AccessControl {
+                roles: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(21)))
+                ),
+                log: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(22)))
+                ),
+            },
+            feature_flags: FeatureFlags {
+                flags: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(23)))
+                ),
+            },
         }
     )
 }
 
-// temporarily disabled
-/* pub fn lottery_and_pos() {
+pub const JOB_POS_ROUND: &str = "kamikaze_and_pos";
+pub const JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE: &str = "fetch_cycles_icp_exchange_rate";
+pub const JOB_REDISTRIBUTE_ICPS: &str = "redistribute_icps";
+pub const JOB_PROCESS_OUTBOX: &str = "process_outbox";
+pub const JOB_PRODUCE_CHART_SPIKE: &str = "produce_chart_spike";
+
+pub fn kamikaze_and_pos() {
     // if the canister is stopped for an upgrade - don't run any rounds and reschedule the next block in case the canister resumes.
     if is_stopped() {
         let delay = STATE.with_borrow(|s| s.get_info().pos_round_delay_ns);
-        set_timer(Duration::from_nanos(delay), lottery_and_pos);
+        note_job_finished(JOB_POS_ROUND);
+        schedule_job(JOB_POS_ROUND, Duration::from_nanos(delay), kamikaze_and_pos);
 
         return;
     }
 
-    let lottery_enabled = STATE.with_borrow(|s| s.get_info().is_lottery_enabled());
-
-    if lottery_enabled {
-        let lottery_round_complete = STATE.with_borrow_mut(|s| s.lottery_round());
-
-        set_timer(Duration::from_nanos(0), move || pos(lottery_round_complete));
-    } else {
-        pos(false);
-    }
-} */
-
-pub fn kamikaze_and_pos() {
-    // if the canister is stopped for an upgrade - don't run any rounds and reschedule the next block in case the canister resumes.
-    if is_stopped() {
-        let delay = STATE.with_borrow(|s| s.get_info().pos_round_delay_ns);
-        set_timer(Duration::from_nanos(delay), kamikaze_and_pos);
+    // a snapshot requested between the rounds
+    if STATE.with_borrow(|s| s.get_info().shares_snapshot_in_progress.is_some()) {
+        shares_snapshot(true);
 
         return;
     }
 
-    let kamikaze_pool_enabled = STATE.with_borrow(|s| s.get_info().is_kamikaze_pool_enabled());
+    update_share_fee();
 
+    let (kamikaze_pool_enabled, lottery_enabled) = STATE.with_borrow(|s| {
+        let info = s.get_info();
+        (info.is_kamikaze_pool_enabled(), info.is_lottery_enabled())
+    });
+
+    // both take half of the block, so only one of them can run each round
     if kamikaze_pool_enabled {
         kamikaze();
+    } else if lottery_enabled {
+        let lottery_round_complete = STATE.with_borrow_mut(|s| s.lottery_round(time()));
+
+        set_timer(Duration::from_nanos(0), move || pos(lottery_round_complete));
     } else {
         pos(false);
     }
 }
 
+pub fn update_share_fee() {
+    STATE.with_borrow_mut(|s| {
+        let mut info = s.get_info();
+
+        let fee = calculate_share_fee(
+            &info.total_shares_supply,
+            s.get_active_burners_count(),
+            &info.get_icp_to_cycles_exchange_rate(),
+        );
+        info.set_current_fee(fee);
+
+        s.set_info(info);
+    });
+}
+
+// the base fee is kept worth the same amount of ICP, but once the pool gets big enough, the fee grows
+// so an average position burns out in about SHARE_FEE_TARGET_POSITION_LIFESPAN_ROUNDS rounds;
+// the result is clamped to the min/max bounds by the state
+pub fn calculate_share_fee(
+    total_shares_supply: &TCycles,
+    active_burners: u64,
+    icp_to_cycles_exchange_rate: &TCycles,
+) -> TCycles {
+    let base_fee = TCycles::from(TCYCLE_POS_ROUND_BASE_FEE) * icp_to_cycles_exchange_rate
+        / TCycles::from(REFERENCE_ICP_TO_CYCLES_EXCHANGE_RATE);
+
+    let rounds_to_burn_out = TCycles::new(
+        TCycles::base() * (active_burners.max(1) * SHARE_FEE_TARGET_POSITION_LIFESPAN_ROUNDS),
+    );
+    let supply_fee = total_shares_supply / rounds_to_burn_out;
+
+    base_fee.max(supply_fee)
+}
+
 pub fn kamikaze() {
     let kamikaze_round_result =
-        STATE.with_borrow_mut(|s| s.kamikaze_round_batch(POS_ACCOUNTS_PER_BATCH));
+        STATE.with_borrow_mut(|s| s.kamikaze_round_batch(POS_ACCOUNTS_PER_BATCH, time()));
 
     match kamikaze_round_result {
         Some(should_reschedule) => {
@@ -132,17 +260,62 @@ pub fn harakiri() {
 }
 
 pub fn pos(split_reward_in_half: bool) {
-    let round_complete =
-        STATE.with_borrow_mut(|s| s.pos_round_batch(split_reward_in_half, POS_ACCOUNTS_PER_BATCH));
+    let round_complete = STATE.with_borrow_mut(|s| {
+        s.pos_round_batch(split_reward_in_half, POS_ACCOUNTS_PER_BATCH, time())
+    });
+
+    certify_totals();
 
     if round_complete {
-        let delay = STATE.with_borrow(|s| s.get_info().pos_round_delay_ns);
-        set_timer(Duration::from_nanos(delay), kamikaze_and_pos);
+        shares_snapshot(false);
     } else {
         set_timer(Duration::from_nanos(0), move || pos(split_reward_in_half));
     };
 }
 
+// the snapshot is built before the next round starts, so all of its batches see the same round boundary
+pub fn shares_snapshot(start_round_next: bool) {
+    let has_more_work =
+        STATE.with_borrow_mut(|s| s.shares_snapshot_batch(SHARES_SNAPSHOT_ENTRIES_PER_BATCH));
+
+    if has_more_work {
+        set_timer(Duration::from_nanos(0), move || {
+            shares_snapshot(start_round_next)
+        });
+
+        return;
+    }
+
+    certify_totals();
+
+    if start_round_next {
+        kamikaze_and_pos();
+    } else {
+        let delay = STATE.with_borrow(|s| s.get_info().pos_round_delay_ns);
+        note_job_finished(JOB_POS_ROUND);
+        schedule_job(JOB_POS_ROUND, Duration::from_nanos(delay), kamikaze_and_pos);
+    }
+}
+
+// only stuck jobs can be restarted, see `prepare_job_restart`
+pub fn restart_job(job: &str) -> Result<(), String> {
+    let restart: fn() = match job {
+        JOB_POS_ROUND => || {
+            schedule_job(JOB_POS_ROUND, Duration::from_nanos(0), kamikaze_and_pos);
+        },
+        JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE => set_cycles_icp_exchange_rate_timer,
+        JOB_REDISTRIBUTE_ICPS => set_icp_redistribution_timer,
+        JOB_PROCESS_OUTBOX => set_outbox_timer,
+        JOB_PRODUCE_CHART_SPIKE => set_spike_timer,
+        _ => return Err(format!("Unknown job {}", job)),
+    };
+
+    prepare_job_restart(job, time())?;
+    restart();
+
+    Ok(())
+}
+
 pub fn set_init_seed_one_timer() {
     set_timer(Duration::from_nanos(0), init_seed);
 }
@@ -156,7 +329,11 @@ fn init_seed() {
 }
 
 pub fn set_cycles_icp_exchange_rate_timer() {
-    set_timer(Duration::from_nanos(0), fetch_cycles_icp_exchange_rate);
+    schedule_job(
+        JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE,
+        Duration::from_nanos(0),
+        fetch_cycles_icp_exchange_rate,
+    );
 }
 
 fn fetch_cycles_icp_exchange_rate() {
@@ -164,16 +341,27 @@ fn fetch_cycles_icp_exchange_rate() {
         let cmc = CMCClient(ENV_VARS.cycles_minting_canister_id);
         let call_result = cmc.get_icp_xdr_conversion_rate().await;
 
-        if let Ok(response) = call_result {
-            STATE.with_borrow_mut(|s| {
-                let mut info = s.get_info();
-                info.update_icp_to_cycles_exchange_rate(response.0.data);
+        match call_result {
+            Ok(response) => {
+                STATE.with_borrow_mut(|s| {
+                    let mut info = s.get_info();
+                    info.update_icp_to_cycles_exchange_rate(response.0.data);
+
+                    s.set_info(info);
+                });
 
-                s.set_info(info);
-            });
+                note_job_finished(JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE);
+            }
+            Err((c, m)) => {
+                note_job_failed(
+                    JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE,
+                    format!("{:?}: {}", c, m),
+                );
+            }
         }
 
-        set_timer(
+        schedule_job(
+            JOB_FETCH_CYCLES_ICP_EXCHANGE_RATE,
             Duration::from_nanos(ICPSWAP_PRICE_UPDATE_INTERVAL_NS),
             fetch_cycles_icp_exchange_rate,
         );
@@ -181,106 +369,143 @@ fn fetch_cycles_icp_exchange_rate() {
 }
 
 pub fn set_icp_redistribution_timer() {
-    set_timer(Duration::from_nanos(0), redistribute_icps);
+    schedule_job(
+        JOB_REDISTRIBUTE_ICPS,
+        Duration::from_nanos(0),
+        redistribute_icps,
+    );
 }
 
 fn redistribute_icps() {
     spawn(async {
-        let this_canister_id = id();
-        let redistribution_subaccount = Subaccount(BURNER_REDISTRIBUTION_SUBACCOUNT);
-        let redistribution_account_id =
-            AccountIdentifier::new(&this_canister_id, &redistribution_subaccount);
-
-        let account_balance_args = AccountBalanceArgs {
-            account: redistribution_account_id,
-        };
+        let icp = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
 
-        // fetching how much ICPs were collected during this time
-        let balance_call_result =
-            ic_ledger_types::account_balance(ENV_VARS.icp_token_canister_id, account_balance_args)
-                .await;
+        match redistribute_collected_icps(&icp, id(), time()).await {
+            Ok(redistribution_id) => {
+                if redistribution_id.is_some() {
+                    set_timer(Duration::from_nanos(0), process_outbox);
+                }
 
-        if let Ok(balance) = balance_call_result {
-            let balance_e8s = balance.e8s();
-            let one_e8s = 1_0000_0000;
-
-            // if more than 1 ICP is collected
-            if balance_e8s > one_e8s {
-                let qty_to_furnace = balance_e8s * REDISTRIBUTION_FURNACE_SHARE_E8S / one_e8s;
-                let qty_to_spike = balance_e8s * REDISTRIBUTION_SPIKE_SHARE_E8S / one_e8s;
-                let qty_to_dev = balance_e8s * REDISTRIBUTION_DEV_SHARE_E8S / one_e8s;
-
-                // send half to the Furnace (Bonfire) canister
-                let furnace_account_id =
-                    AccountIdentifier::new(&ENV_VARS.furnace_canister_id, &Subaccount([0u8; 32]));
-
-                let _ = ic_ledger_types::transfer(
-                    ENV_VARS.icp_token_canister_id,
-                    TransferArgs {
-                        from_subaccount: Some(redistribution_subaccount),
-                        to: furnace_account_id,
-                        amount: Tokens::from_e8s(qty_to_furnace - ICP_FEE),
-                        memo: Memo(1),
-                        fee: Tokens::from_e8s(ICP_FEE),
-                        created_at_time: None,
-                    },
-                )
-                .await;
-
-                // send another half to a special subaccount of this canister, that will eventually burn them
-                let spike_account_id =
-                    AccountIdentifier::new(&this_canister_id, &Subaccount(BURNER_SPIKE_SUBACCOUNT));
-
-                let _ = ic_ledger_types::transfer(
-                    ENV_VARS.icp_token_canister_id,
-                    TransferArgs {
-                        from_subaccount: Some(redistribution_subaccount),
-                        to: spike_account_id,
-                        amount: Tokens::from_e8s(qty_to_spike - ICP_FEE),
-                        memo: Memo(1),
-                        fee: Tokens::from_e8s(ICP_FEE),
-                        created_at_time: None,
-                    },
-                )
-                .await;
-
-                // send a little bit to the subaccount, where the devs can withdraw them
-                let dev_account_id = AccountIdentifier::new(
-                    &this_canister_id,
-                    &Subaccount(BURNER_DEV_FEE_SUBACCOUNT),
-                );
-
-                let _ = ic_ledger_types::transfer(
-                    ENV_VARS.icp_token_canister_id,
-                    TransferArgs {
-                        from_subaccount: Some(redistribution_subaccount),
-                        to: dev_account_id,
-                        amount: Tokens::from_e8s(qty_to_dev - ICP_FEE),
-                        memo: Memo(1),
-                        fee: Tokens::from_e8s(ICP_FEE),
-                        created_at_time: None,
-                    },
-                )
-                .await;
+                note_job_finished(JOB_REDISTRIBUTE_ICPS);
+            }
+            Err(e) => {
+                note_job_failed(JOB_REDISTRIBUTE_ICPS, e);
             }
         }
 
-        set_timer(
+        schedule_job(
+            JOB_REDISTRIBUTE_ICPS,
             Duration::from_nanos(ICP_REDISTRIBUTION_INTERVAL_NS),
             redistribute_icps,
         );
     });
 }
 
+/// Splits the ICPs collected on the redistribution subaccount of `this_canister_id` and enqueues
+/// the transfers into the outbox. Returns the id of the redistribution, if there was enough to split.
+pub async fn redistribute_collected_icps(
+    icp: &impl ICRC1Api,
+    this_canister_id: Principal,
+    now: TimestampNs,
+) -> Result<Option<u64>, String> {
+    // fetching how much ICPs were collected during this time
+    let (balance,) = icp
+        .icrc1_balance_of(Account {
+            owner: this_canister_id,
+            subaccount: Some(BURNER_REDISTRIBUTION_SUBACCOUNT),
+        })
+        .await
+        .map_err(|(c, m)| format!("{:?}: {}", c, m))?;
+
+    let balance_e8s: u64 = balance.0.try_into().unwrap_or(u64::MAX);
+
+    // half goes to the Furnace (Bonfire) canister, another half - to a special subaccount of this canister,
+    // that will eventually burn them, and a little bit - to the subaccount, where the devs can withdraw them
+    let redistribution_id = STATE.with_borrow_mut(|s| {
+        s.redistribute_icps(
+            balance_e8s,
+            ENV_VARS.icp_token_canister_id,
+            this_canister_id,
+            ENV_VARS.furnace_canister_id,
+            now,
+        )
+    });
+
+    Ok(redistribution_id)
+}
+
+pub fn set_outbox_timer() {
+    schedule_job(
+        JOB_PROCESS_OUTBOX,
+        Duration::from_nanos(0),
+        process_outbox_periodically,
+    );
+}
+
+fn process_outbox_periodically() {
+    // each transfer tracks its own attempts and errors
+    process_outbox();
+    note_job_finished(JOB_PROCESS_OUTBOX);
+
+    schedule_job(
+        JOB_PROCESS_OUTBOX,
+        Duration::from_nanos(OUTBOX_PROCESSING_INTERVAL_NS),
+        process_outbox_periodically,
+    );
+}
+
+fn process_outbox() {
+    let due = STATE.with_borrow_mut(|s| s.outbox.take_due(time(), OUTBOX_TRANSFERS_PER_BATCH));
+
+    if due.is_empty() {
+        return;
+    }
+
+    spawn(async move {
+        for (id, transfer) in due {
+            let ledger = ICRC1CanisterClient::new(transfer.ledger);
+            let result = send_outbox_transfer(&ledger, &transfer).await;
+
+            STATE.with_borrow_mut(|s| s.outbox.complete(id, result));
+        }
+    });
+}
+
 pub fn set_spike_timer() {
-    set_timer(Duration::from_nanos(0), try_producing_a_chart_spike);
+    schedule_job(
+        JOB_PRODUCE_CHART_SPIKE,
+        Duration::from_nanos(0),
+        try_producing_a_chart_spike,
+    );
 }
 
 fn try_producing_a_chart_spike() {
     spawn(async {
-        let this_canister_id = id();
-        let spike_subaccount = Subaccount(BURNER_SPIKE_SUBACCOUNT);
-        let spike_account_id = AccountIdentifier::new(&this_canister_id, &spike_subaccount);
+        // the errors are recorded in the spike event itself
+        let in_progress = produce_a_chart_spike().await;
+        note_job_finished(JOB_PRODUCE_CHART_SPIKE);
+
+        // the timer is set again after each upgrade, and the spike in progress is resumed from the state
+        let delay = if in_progress {
+            SPIKE_RETRY_INTERVAL_NS
+        } else {
+            SPIKING_INTERVAL_NS
+        };
+
+        schedule_job(
+            JOB_PRODUCE_CHART_SPIKE,
+            Duration::from_nanos(delay),
+            try_producing_a_chart_spike,
+        );
+    });
+}
+
+// returns true, if the spike is still in progress and should be retried soon
+async fn produce_a_chart_spike() -> bool {
+    let spike = if let Some(in_progress) = STATE.with_borrow(|s| s.get_spike_in_progress()) {
+        in_progress
+    } else {
+        let spike_account_id = AccountIdentifier::new(&id(), &Subaccount(BURNER_SPIKE_SUBACCOUNT));
 
         let account_balance_args = AccountBalanceArgs {
             account: spike_account_id,
@@ -291,67 +516,269 @@ fn try_producing_a_chart_spike() {
             ic_ledger_types::account_balance(ENV_VARS.icp_token_canister_id, account_balance_args)
                 .await;
 
-        if let Ok(balance) = balance_call_result {
-            if balance.e8s() > 0 {
-                deposit_cycles(balance.e8s()).await;
-            }
+        let Ok(balance) = balance_call_result else {
+            return false;
+        };
+
+        match STATE.with_borrow_mut(|s| s.begin_spike(balance.e8s(), time())) {
+            Some(new_spike) => new_spike,
+            None => return false,
         }
-    });
-}
+    };
 
-pub async fn stake_callers_icp_for_redistribution(qty_e8s_u64: u64) -> Result<(), String> {
-    let caller_subaccount = subaccount_of(caller());
-    let canister_id = id();
-    let redistribution_subaccount = Subaccount(BURNER_REDISTRIBUTION_SUBACCOUNT);
+    let (spike_id, spike) = spike;
 
-    let transfer_args = TransferArgs {
-        from_subaccount: Some(caller_subaccount),
-        to: AccountIdentifier::new(&canister_id, &redistribution_subaccount),
-        amount: Tokens::from_e8s(qty_e8s_u64),
-        memo: Memo(0),
-        fee: Tokens::from_e8s(ICP_FEE),
-        created_at_time: None,
+    let block_index = match spike.status {
+        SpikeStatus::Transferring => {
+            if spike.attempts >= SPIKE_MAX_TRANSFER_ATTEMPTS {
+                let reason = spike.last_error.unwrap_or_default();
+                STATE.with_borrow_mut(|s| s.fail_spike(spike_id, reason, time()));
+
+                return false;
+            }
+
+            STATE.with_borrow_mut(|s| s.note_spike_attempt(spike_id));
+
+            match transfer_spike_icp_to_cmc(&spike).await {
+                Ok(block_index) => {
+                    STATE.with_borrow_mut(|s| s.note_spike_transferred(spike_id, block_index));
+                    block_index
+                }
+                Err(OutboxSendError::Retryable(e)) => {
+                    STATE.with_borrow_mut(|s| s.note_spike_error(spike_id, e));
+                    return true;
+                }
+                Err(OutboxSendError::Fatal(e)) => {
+                    STATE.with_borrow_mut(|s| s.fail_spike(spike_id, e, time()));
+                    return false;
+                }
+            }
+        }
+        SpikeStatus::Notifying { block_index } => block_index,
+        _ => return false,
     };
 
-    transfer(ENV_VARS.icp_token_canister_id, transfer_args)
-        .await
-        .map_err(|(code, msg)| format!("{:?} {}", code, msg))?
-        .map_err(|e| format!("{}", e))
-        .map(|_| ())
+    let cmc = CMCClient(ENV_VARS.cycles_minting_canister_id);
+
+    match notify_cmc_top_up(&cmc, block_index, id()).await {
+        Ok(cycles_minted) => {
+            STATE.with_borrow_mut(|s| s.complete_spike(spike_id, cycles_minted, time()));
+            false
+        }
+        Err(OutboxSendError::Retryable(e)) => {
+            STATE.with_borrow_mut(|s| s.note_spike_error(spike_id, e));
+            true
+        }
+        Err(OutboxSendError::Fatal(e)) => {
+            STATE.with_borrow_mut(|s| s.fail_spike(spike_id, e, time()));
+            false
+        }
+    }
 }
 
-async fn deposit_cycles(qty_e8s_u64: u64) {
+async fn transfer_spike_icp_to_cmc(spike: &SpikeEvent) -> Result<u64, OutboxSendError> {
     let transfer_args = TransferArgs {
         from_subaccount: Some(Subaccount(BURNER_SPIKE_SUBACCOUNT)),
-        to: AccountIdentifier::from_hex(
-            "913512894829707b183043705a46cd355096b3ccf00e89936314d4e834518221",
-        )
-        .unwrap(),
-        amount: Tokens::from_e8s(qty_e8s_u64 - ICP_FEE),
+        to: AccountIdentifier::new(
+            &ENV_VARS.cycles_minting_canister_id,
+            &Subaccount::from(id()),
+        ),
+        amount: Tokens::from_e8s(spike.qty_e8s - ICP_FEE),
 
-        memo: Memo(0),
+        memo: Memo(MEMO_TOP_UP_CANISTER),
         fee: Tokens::from_e8s(ICP_FEE),
-        created_at_time: None,
+        // the same for each attempt, so the ledger deduplicates the retries
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: spike.started_at,
+        }),
     };
 
-    transfer(ENV_VARS.icp_token_canister_id, transfer_args)
-        .await
-        .expect("Unable to call canister")
-        .expect("Unable to transfer");
+    match transfer(ENV_VARS.icp_token_canister_id, transfer_args).await {
+        Ok(Ok(block_index)) => Ok(block_index),
+        // a previous attempt went through, but its response was lost
+        Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+        Ok(Err(e @ TransferError::TxCreatedInFuture)) => {
+            Err(OutboxSendError::Retryable(e.to_string()))
+        }
+        Ok(Err(e)) => Err(OutboxSendError::Fatal(e.to_string())),
+        Err((c, m)) => Err(OutboxSendError::Retryable(format!("{:?}: {}", c, m))),
+    }
+}
+
+async fn notify_cmc_top_up(
+    cmc: &impl CMCApi,
+    block_index: u64,
+    this_canister_id: Principal,
+) -> Result<Nat, OutboxSendError> {
+    let notify_args = NotifyTopUpRequest {
+        block_index,
+        canister_id: this_canister_id,
+    };
+
+    // notifying about the same block is idempotent, so it is safe to retry
+    match cmc.notify_top_up(notify_args).await {
+        Ok((Ok(cycles_minted),)) => Ok(cycles_minted),
+        Ok((Err(e @ NotifyTopUpError::Processing),))
+        | Ok((Err(e @ NotifyTopUpError::Other { .. }),)) => {
+            Err(OutboxSendError::Retryable(format!("{:?}", e)))
+        }
+        // refunded ICP gets back to the spike subaccount and is used by the next spike
+        Ok((Err(e),)) => Err(OutboxSendError::Fatal(format!("{:?}", e))),
+        Err((c, m)) => Err(OutboxSendError::Retryable(format!("{:?}: {}", c, m))),
+    }
+}
+
+pub async fn stake_callers_icp(req: StakeRequest, kind: StakeKind) -> StakeResponse {
+    if req.qty_e8s_u64 < MIN_ICP_STAKE_E8S_U64 {
+        return StakeResponse {
+            result: Err(String::from("At least 0.5 ICP is required to participate")),
+        };
+    }
+
+    let icp_can = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+    let result = stake_icp(&icp_can, caller(), id(), &req, kind, time()).await;
+
+    certify_totals();
+
+    StakeResponse { result }
+}
+
+/// Moves the staked ICP from the caller to the redistribution subaccount of `this_canister_id` and
+/// mints the shares. The stake is reverted, if the transfer fails.
+pub async fn stake_icp(
+    icp_can: &impl ICRC1Api,
+    caller: Principal,
+    this_canister_id: Principal,
+    req: &StakeRequest,
+    kind: StakeKind,
+    now: TimestampNs,
+) -> Result<StakeReceipt, String> {
+    let created_at_time = req.created_at_time;
+
+    let processed = STATE.with_borrow_mut(|s| s.begin_stake(caller, req, kind, now))?;
+
+    // a retry of an already processed stake - nothing to do
+    if let Some(receipt) = processed {
+        return Ok(receipt);
+    }
+
+    match transfer_from_callers_icp_for_redistribution(icp_can, caller, this_canister_id, req).await
+    {
+        Ok(block_idx) => Ok(
+            STATE.with_borrow_mut(|s| s.complete_stake(caller, created_at_time, block_idx, now))
+        ),
+        Err(e) => {
+            STATE.with_borrow_mut(|s| s.revert_stake(caller, created_at_time));
+            Err(e)
+        }
+    }
+}
+
+async fn transfer_from_callers_icp_for_redistribution(
+    icp_can: &impl ICRC1Api,
+    caller: Principal,
+    this_canister_id: Principal,
+    req: &StakeRequest,
+) -> Result<Nat, String> {
+    let call_result = icp_can
+        .icrc2_transfer_from(TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: this_canister_id,
+                subaccount: Some(BURNER_REDISTRIBUTION_SUBACCOUNT),
+            },
+            amount: Nat::from(req.qty_e8s_u64),
+            fee: Some(Nat::from(ICP_FEE)),
+            memo: None,
+            created_at_time: Some(req.created_at_time),
+        })
+        .await;
+
+    match call_result {
+        Ok((Ok(block_idx),)) => Ok(block_idx),
+        // the transfer was made by a previous attempt, which never got to mint the shares
+        Ok((Err(TransferFromError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+        Ok((Err(e),)) => Err(e.to_string()),
+        Err((c, m)) => Err(format!("{:?}: {}", c, m)),
+    }
+}
+
+/// Mints the claimed BURN tokens to the provided account. The claim is reverted, if the transfer fails.
+pub async fn claim_reward_burn(
+    burn_token_can: &impl ICRC1Api,
+    caller: Principal,
+    req: ClaimRewardRequest,
+    now: TimestampNs,
+) -> Result<Nat, String> {
+    let unclaimed = STATE.with_borrow_mut(|s| s.claim_reward(caller, req.qty, now))?;
+
+    let call_result = burn_token_can
+        .icrc1_transfer(TransferArg {
+            to: req.to,
+            amount: Nat(unclaimed.clone().val),
+            from_subaccount: None,
+            fee: None,
+            created_at_time: None,
+            memo: req.memo,
+        })
+        .await;
+
+    let result = match call_result {
+        Ok((Ok(idx),)) => Ok(idx),
+        Ok((Err(e),)) => Err(format!("{:?}", e)),
+        Err(e) => Err(format!("{:?}", e)),
+    };
+
+    if result.is_err() {
+        STATE.with_borrow_mut(|s| s.revert_claim_reward(caller, unclaimed, now));
+    }
+
+    result
 }
 
 thread_local! {
-    pub static STOPPED_FOR_UPDATE: RefCell<(Principal, bool)> = RefCell::new((Principal::anonymous(), false));
+    pub static CERTIFIED_TOTALS: RefCell<(CertifiedBurnerTotals, CertifiedTotals)> = RefCell::default();
+}
+
+// should be called each time any of the certified counters changes
+pub fn certify_totals() {
+    let totals = STATE.with_borrow(|s| s.get_certified_totals());
+
+    CERTIFIED_TOTALS.with_borrow_mut(|(certified, tree)| {
+        tree.insert("total_share_supply", &totals.total_share_supply);
+        tree.insert("total_tcycles_burned", &totals.total_tcycles_burned);
+        tree.insert("total_burn_token_minted", &totals.total_burn_token_minted);
+        tree.insert("current_pos_round", &totals.current_pos_round);
+        tree.insert("shares_snapshot_roots", &totals.shares_snapshot_roots);
+        tree.certify();
+
+        *certified = totals;
+    });
+}
+
+thread_local! {
+    pub static STOPPED_FOR_UPDATE: RefCell<bool> = RefCell::default();
 }
 
 pub fn is_stopped() -> bool {
-    STOPPED_FOR_UPDATE.with_borrow(|(_, is_stopped)| *is_stopped)
+    STOPPED_FOR_UPDATE.with_borrow(|is_stopped| *is_stopped)
+}
+
+pub fn assert_caller_has_role(role: Role) {
+    STATE
+        .with_borrow(|s| s.access.assert_role(&caller(), role))
+        .expect("Access denied");
 }
 
-pub fn assert_caller_is_dev() {
-    let dev = STOPPED_FOR_UPDATE.with_borrow(|(dev, _)| *dev);
-    if caller() != dev {
-        panic!("Access denied");
+pub fn assert_feature_enabled(feature: &str) {
+    let result = STATE.with_borrow(|s| s.feature_flags.assert_enabled(feature, time()));
+
+    if let Err(reason) = result {
+        panic!("{}", reason);
     }
 }
 
@@ -360,3 +787,645 @@ pub fn assert_running() {
         panic!("The canister is stopped and is awaiting for an update");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use futures::executor::block_on;
+    use ic_cdk::api::call::RejectionCode;
+    use ic_e8s::c::E8s;
+    use icrc_ledger_types::icrc1::account::Account;
+    use shared::{
+        burner::{
+            api::{
+                ClaimRewardRequest, GetAccountHistoryRequest, StakeReceipt, StakeRequest,
+                VerifyLotteryRoundRequest,
+            },
+            types::{
+                AccountEventKind, KamikazeTier, SharesSnapshotEntry, StakeKind, TCycles,
+                BURNER_DEV_FEE_SUBACCOUNT, BURNER_REDISTRIBUTION_SUBACCOUNT,
+                BURNER_SPIKE_SUBACCOUNT, REDISTRIBUTION_DEV_SHARE_E8S,
+                REDISTRIBUTION_FURNACE_SHARE_E8S, REDISTRIBUTION_SPIKE_SHARE_E8S,
+                SHARES_SNAPSHOTS_TO_KEEP, SHARES_SNAPSHOT_CHUNK_SIZE, STAKE_DEDUP_WINDOW_NS,
+            },
+        },
+        cmc::NotifyTopUpError,
+        fakes::{FakeCMC, FakeICRC1Ledger},
+        merkle::merkle_root,
+        outbox::{send_outbox_transfer, OutboxSendError},
+        ICP_FEE, ONE_DAY_NS, ONE_MINUTE_NS,
+    };
+
+    use super::{
+        claim_reward_burn, notify_cmc_top_up, redistribute_collected_icps, stake_icp, ENV_VARS,
+        STATE,
+    };
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+    const STAKE_E8S: u64 = 1_0000_0000;
+    const INITIAL_BALANCE_E8S: u64 = 10_0000_0000;
+
+    fn burner_id() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn user() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn account(owner: Principal, subaccount: Option<[u8; 32]>) -> Account {
+        Account { owner, subaccount }
+    }
+
+    fn icp_ledger() -> FakeICRC1Ledger {
+        let ledger = FakeICRC1Ledger::new(burner_id(), ICP_FEE);
+        ledger.set_balance(account(user(), None), INITIAL_BALANCE_E8S);
+        ledger.approve(
+            account(user(), None),
+            account(burner_id(), None),
+            INITIAL_BALANCE_E8S,
+        );
+
+        ledger
+    }
+
+    fn stake(ledger: &FakeICRC1Ledger) -> Result<StakeReceipt, String> {
+        let req = StakeRequest {
+            qty_e8s_u64: STAKE_E8S,
+            created_at_time: NOW,
+            kamikaze_tier: None,
+        };
+
+        block_on(stake_icp(
+            ledger,
+            user(),
+            burner_id(),
+            &req,
+            StakeKind::Common,
+            NOW,
+        ))
+    }
+
+    #[test]
+    fn stake_moves_icp_and_mints_shares() {
+        let ledger = icp_ledger();
+        let receipt = stake(&ledger).unwrap();
+
+        assert_eq!(
+            ledger.balance(&account(
+                burner_id(),
+                Some(BURNER_REDISTRIBUTION_SUBACCOUNT)
+            )),
+            Nat::from(STAKE_E8S)
+        );
+        assert!(receipt.shares_minted > TCycles::zero());
+
+        let (share, _) = STATE.with_borrow(|s| s.shares.get(&user())).unwrap();
+        assert_eq!(share, receipt.shares_minted);
+    }
+
+    #[test]
+    fn stake_is_reverted_if_the_transfer_fails() {
+        let ledger = icp_ledger();
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::CanisterError, "The ledger is stopped");
+
+        assert!(stake(&ledger).is_err());
+        assert!(STATE.with_borrow(|s| s.shares.get(&user())).is_none());
+        assert_eq!(
+            ledger.balance(&account(user(), None)),
+            Nat::from(INITIAL_BALANCE_E8S)
+        );
+
+        // the stake is unlocked, so the same request can be retried
+        assert!(stake(&ledger).is_ok());
+        assert_eq!(ledger.blocks(), 1);
+    }
+
+    #[test]
+    fn stake_retried_after_a_lost_response_is_charged_once() {
+        let ledger = icp_ledger();
+        ledger.lose_next_response();
+
+        // the ledger has made the transfer, but the burner doesn't know about it
+        assert!(stake(&ledger).is_err());
+        assert!(STATE.with_borrow(|s| s.shares.get(&user())).is_none());
+
+        let receipt = stake(&ledger).unwrap();
+
+        assert_eq!(receipt.block_idx, Nat::from(0u64));
+        assert_eq!(ledger.blocks(), 1);
+        assert_eq!(
+            ledger.balance(&account(user(), None)),
+            Nat::from(INITIAL_BALANCE_E8S - STAKE_E8S - ICP_FEE)
+        );
+        assert!(STATE.with_borrow(|s| s.shares.get(&user())).is_some());
+    }
+
+    #[test]
+    fn stuck_stake_is_dropped_after_the_dedup_window() {
+        let ledger = icp_ledger();
+        let req = StakeRequest {
+            qty_e8s_u64: STAKE_E8S,
+            created_at_time: NOW,
+            kamikaze_tier: None,
+        };
+
+        // the callback of this stake has trapped, so it is never completed nor reverted
+        STATE.with_borrow_mut(|s| s.begin_stake(user(), &req, StakeKind::Common, NOW).unwrap());
+        assert_eq!(
+            stake(&ledger).unwrap_err(),
+            "The stake is already being processed"
+        );
+
+        let later = NOW + STAKE_DEDUP_WINDOW_NS + 1;
+        let retry = StakeRequest {
+            created_at_time: later,
+            ..req
+        };
+
+        assert!(block_on(stake_icp(
+            &ledger,
+            user(),
+            burner_id(),
+            &retry,
+            StakeKind::Common,
+            later,
+        ))
+        .is_ok());
+        assert!(STATE.with_borrow(|s| !s.stakes.contains_key(&(user(), NOW))));
+    }
+
+    #[test]
+    fn stake_rejected_before_the_transfer_returns_an_error() {
+        let ledger = icp_ledger();
+        let req = StakeRequest {
+            qty_e8s_u64: STAKE_E8S,
+            created_at_time: NOW - STAKE_DEDUP_WINDOW_NS - 1,
+            kamikaze_tier: None,
+        };
+
+        let result = block_on(stake_icp(
+            &ledger,
+            user(),
+            burner_id(),
+            &req,
+            StakeKind::Common,
+            NOW,
+        ));
+
+        assert_eq!(result.unwrap_err(), "The stake is too old");
+        assert_eq!(ledger.blocks(), 0);
+    }
+
+    #[test]
+    fn claim_is_reverted_if_the_transfer_fails() {
+        let reward = E8s::from(5_0000_0000u64);
+        STATE.with_borrow_mut(|s| s.shares.insert(user(), (TCycles::zero(), reward.clone())));
+
+        // the burner is the minting account of the BURN token
+        let mut ledger = FakeICRC1Ledger::new(burner_id(), 10_000);
+        ledger.minting_account = account(burner_id(), None);
+
+        let req = || ClaimRewardRequest {
+            to: account(user(), None),
+            qty: None,
+            memo: None,
+        };
+
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::SysTransient, "Out of cycles");
+
+        assert!(block_on(claim_reward_burn(&ledger, user(), req(), NOW)).is_err());
+
+        let (_, unclaimed) = STATE.with_borrow(|s| s.shares.get(&user())).unwrap();
+        assert_eq!(unclaimed, reward);
+        assert_eq!(
+            STATE.with_borrow(|s| s.get_info().total_burn_token_minted),
+            E8s::zero()
+        );
+
+        assert!(block_on(claim_reward_burn(&ledger, user(), req(), NOW)).is_ok());
+        assert_eq!(ledger.balance(&account(user(), None)), Nat(reward.val));
+    }
+
+    #[test]
+    fn shares_snapshot_is_built_in_batches() {
+        let accounts = SHARES_SNAPSHOT_CHUNK_SIZE * 2 + 10;
+
+        let entries: Vec<_> = (0..accounts)
+            .map(|i| SharesSnapshotEntry {
+                pid: Principal::from_slice(&i.to_be_bytes()),
+                share: TCycles::from(i + 1),
+                kamikaze_share: TCycles::zero(),
+            })
+            .collect();
+
+        STATE.with_borrow_mut(|s| {
+            for entry in &entries {
+                s.shares
+                    .insert(entry.pid, (entry.share.clone(), E8s::zero()));
+            }
+
+            // an account with only an unclaimed reward left is skipped
+            s.shares.insert(user(), (TCycles::zero(), E8s::one()));
+        });
+
+        let mut entries = entries;
+        entries.sort_by_key(|it| it.pid);
+
+        let chunk_hashes: Vec<_> = entries
+            .chunks(SHARES_SNAPSHOT_CHUNK_SIZE as usize)
+            .map(|chunk| merkle_root(&chunk.iter().map(|it| it.leaf_hash()).collect::<Vec<_>>()))
+            .collect();
+
+        for i in 0..SHARES_SNAPSHOTS_TO_KEEP + 1 {
+            let id = STATE.with_borrow_mut(|s| s.begin_shares_snapshot(i, NOW));
+            assert_eq!(id, i);
+
+            let mut batches = 0;
+            while STATE.with_borrow_mut(|s| s.shares_snapshot_batch(333)) {
+                batches += 1;
+            }
+            assert!(batches > 1);
+
+            let snapshot = STATE.with_borrow(|s| s.shares_snapshots.get(&id)).unwrap();
+            assert_eq!(snapshot.total_entries, accounts);
+            assert_eq!(snapshot.chunk_hashes, chunk_hashes);
+            assert_eq!(snapshot.merkle_root, merkle_root(&chunk_hashes));
+        }
+
+        // the oldest snapshot is gone together with its entries
+        STATE.with_borrow(|s| {
+            assert_eq!(s.shares_snapshots.len(), SHARES_SNAPSHOTS_TO_KEEP);
+            assert!(s.shares_snapshots.get(&0).is_none());
+            assert_eq!(
+                s.shares_snapshot_entries.len(),
+                SHARES_SNAPSHOTS_TO_KEEP * accounts
+            );
+            assert_eq!(
+                s.get_certified_totals().shares_snapshot_roots.len() as u64,
+                SHARES_SNAPSHOTS_TO_KEEP
+            );
+        });
+    }
+
+    #[test]
+    fn shares_snapshot_keeps_the_shares_of_its_boundary() {
+        let (a, b, c) = (
+            Principal::from_slice(&[10]),
+            Principal::from_slice(&[20]),
+            Principal::from_slice(&[30]),
+        );
+        let share = TCycles::from(1_000_000_000_000u64);
+
+        STATE.with_borrow_mut(|s| {
+            s.mint_share(share.clone(), a);
+            s.mint_share(share.clone(), b);
+        });
+
+        let id = STATE.with_borrow_mut(|s| s.begin_shares_snapshot(0, NOW));
+        assert!(STATE.with_borrow_mut(|s| s.shares_snapshot_batch(1)));
+
+        // `a` is already copied, when its share moves to `b`, which is not yet
+        STATE.with_borrow_mut(|s| {
+            s.propose_account_transfer(a, b, NOW).unwrap();
+            s.accept_account_transfer(b, a, NOW).unwrap();
+            s.mint_share(share.clone(), c);
+        });
+
+        while STATE.with_borrow_mut(|s| s.shares_snapshot_batch(1)) {}
+
+        STATE.with_borrow(|s| {
+            let snapshot = s.shares_snapshots.get(&id).unwrap();
+            let entries: Vec<_> = s
+                .shares_snapshot_entries
+                .range((id, 0)..(id + 1, 0))
+                .map(|(_, entry)| (entry.pid, entry.share))
+                .collect();
+
+            assert_eq!(entries, vec![(a, share.clone()), (b, share.clone())]);
+            assert_eq!(snapshot.total_share, &share + &share);
+            assert!(s.shares_snapshot_originals.is_empty());
+        });
+    }
+
+    #[test]
+    fn transferred_decide_id_verification_cant_be_used_again() {
+        let to = Principal::from_slice(&[3]);
+
+        STATE.with_borrow_mut(|s| {
+            s.mint_share(TCycles::from(1_000_000_000_000u64), user());
+            s.verified_via_decide_id.insert(user(), ());
+
+            s.propose_account_transfer(user(), to, NOW).unwrap();
+            s.accept_account_transfer(to, user(), NOW).unwrap();
+        });
+
+        STATE.with_borrow_mut(|s| {
+            assert!(s.verified_via_decide_id.contains_key(&to));
+            assert!(!s.verified_via_decide_id.contains_key(&user()));
+
+            // rejected before the credential is even looked at
+            let result = s.verify_decide_id("", user(), NOW);
+            assert!(result.unwrap_err().contains("transferred"));
+        });
+    }
+
+    #[test]
+    fn topped_up_kamikaze_positions_expire_without_an_underflow() {
+        let other_user = Principal::from_slice(&[3]);
+
+        STATE.with_borrow_mut(|s| {
+            // each top-up weight is truncated on its own
+            for _ in 0..3 {
+                s.mint_kamikaze_share(TCycles::from(1u64), user(), KamikazeTier::ThreeDays, NOW);
+                s.mint_kamikaze_share(
+                    TCycles::from(1u64),
+                    other_user,
+                    KamikazeTier::SevenDays,
+                    NOW,
+                );
+            }
+        });
+
+        let after_a_week = NOW + KamikazeTier::SevenDays.lifespan_ns();
+        while STATE.with_borrow_mut(|s| s.kamikaze_harakiri_batch(after_a_week, 10)) {}
+
+        STATE.with_borrow(|s| {
+            let info = s.get_info();
+
+            assert_eq!(s.kamikaze_shares.len(), 0);
+            assert_eq!(info.get_kamikaze_pool_total_weight(), TCycles::zero());
+            assert_eq!(info.kamikaze_pool_total_shares, Some(TCycles::zero()));
+        });
+    }
+
+    #[test]
+    fn kamikaze_round_seed_is_published_before_the_selection_and_never_replaced() {
+        STATE.with_borrow_mut(|s| {
+            s.init(vec![7; 32]);
+            s.mint_share(TCycles::from(1_000_000_000_000u64), burner_id());
+            s.mint_kamikaze_share(
+                TCycles::from(1_000_000_000_000u64),
+                user(),
+                KamikazeTier::default(),
+                NOW,
+            );
+        });
+
+        let published = STATE
+            .with_borrow(|s| s.kamikaze_round_proofs.get(&0))
+            .unwrap();
+        assert!(published.winner.is_none());
+
+        while STATE.with_borrow_mut(|s| s.kamikaze_round_batch(1, NOW)) == Some(true) {}
+
+        // running the selection again before the round closes doesn't change anything
+        assert_eq!(
+            STATE.with_borrow_mut(|s| s.kamikaze_round_batch(1, NOW)),
+            Some(false)
+        );
+
+        let selected = STATE
+            .with_borrow(|s| s.kamikaze_round_proofs.get(&0))
+            .unwrap();
+        assert_eq!(
+            selected.commitment.seed_hash,
+            published.commitment.seed_hash
+        );
+        assert_eq!(selected.winner, Some(user()));
+        assert!(selected.commitment.revealed_seed().is_none());
+
+        while !STATE.with_borrow_mut(|s| s.pos_round_batch(true, 1, NOW)) {}
+
+        STATE.with_borrow(|s| {
+            assert!(s.kamikaze_round_proofs.get(&0).unwrap().verify(0).is_valid);
+
+            let next = s.kamikaze_round_proofs.get(&1).unwrap();
+            assert!(next.winner.is_none());
+            assert!(next.commitment.revealed_seed().is_none());
+        });
+
+        // nobody can win a round without a published seed
+        STATE.with_borrow_mut(|s| {
+            s.kamikaze_round_proofs.remove(&1);
+            assert_eq!(s.kamikaze_round_batch(1, NOW), None);
+        });
+    }
+
+    #[test]
+    fn lottery_winner_is_selected_with_the_published_seed_and_can_be_verified() {
+        STATE.with_borrow_mut(|s| {
+            s.init(vec![7; 32]);
+
+            for pid in [burner_id(), user()] {
+                s.mint_share(TCycles::from(1_000_000_000_000u64), pid);
+                s.lottery_participants.insert(pid);
+            }
+        });
+
+        let published = STATE
+            .with_borrow(|s| s.lottery_round_proofs.get(&0))
+            .unwrap();
+        assert!(published.winner.is_none());
+
+        assert!(STATE.with_borrow_mut(|s| s.lottery_round(NOW)));
+
+        let selected = STATE
+            .with_borrow(|s| s.lottery_round_proofs.get(&0))
+            .unwrap();
+        assert_eq!(
+            selected.commitment.seed_hash,
+            published.commitment.seed_hash
+        );
+        assert_eq!(selected.participants, 2);
+        assert!(selected.winner.is_some());
+        assert!(!selected.verify(0).is_valid);
+
+        // running the lottery again before the round closes doesn't pick another winner
+        assert!(STATE.with_borrow_mut(|s| s.lottery_round(NOW)));
+        assert_eq!(
+            STATE.with_borrow(|s| s.lottery_round_proofs.get(&0).unwrap().winner),
+            selected.winner
+        );
+
+        while !STATE.with_borrow_mut(|s| s.pos_round_batch(true, 1, NOW)) {}
+
+        STATE.with_borrow(|s| {
+            let verification = s
+                .verify_lottery_round(VerifyLotteryRoundRequest { round: 0 })
+                .result
+                .unwrap();
+
+            assert!(verification.is_valid);
+            assert_eq!(verification.recomputed_winner_idx, selected.winner_idx);
+            assert!(s.lottery_round_proofs.get(&1).unwrap().winner.is_none());
+        });
+
+        // nobody can win a round without a published seed
+        STATE.with_borrow_mut(|s| {
+            s.lottery_round_proofs.remove(&1);
+            assert!(!s.lottery_round(NOW));
+        });
+    }
+
+    #[test]
+    fn pos_rounds_are_summed_up_per_day_apart_from_the_account_history() {
+        let round =
+            |now: u64| while !STATE.with_borrow_mut(|s| s.pos_round_batch(false, 10, now)) {};
+        let daily_pos_rounds = |start: Option<u64>, take: u32| {
+            STATE.with_borrow(|s| {
+                s.get_account_daily_pos_rounds(GetAccountHistoryRequest {
+                    pid: user(),
+                    start,
+                    take,
+                })
+                .entries
+            })
+        };
+
+        STATE.with_borrow_mut(|s| s.mint_share(TCycles::from(1_000_000_000_000_000u64), user()));
+
+        for i in 0..3 {
+            round(NOW + i * ONE_MINUTE_NS);
+        }
+
+        let entries = daily_pos_rounds(None, 10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, NOW / ONE_DAY_NS);
+        assert!(matches!(
+            entries[0].1.kind,
+            AccountEventKind::PosRound {
+                round: 0,
+                last_round: Some(2),
+                ..
+            }
+        ));
+        assert!(STATE.with_borrow(|s| s.account_history.is_empty()));
+
+        for day in 1..=3 {
+            round(NOW + day * ONE_DAY_NS);
+        }
+
+        assert_eq!(daily_pos_rounds(None, 10).len(), 4);
+
+        let page = daily_pos_rounds(Some(NOW / ONE_DAY_NS + 1), 1);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].0, NOW / ONE_DAY_NS + 2);
+    }
+
+    #[test]
+    fn share_below_the_fee_is_burned_with_its_reward_instead_of_diluting_the_pool() {
+        let dust_owner = Principal::from_slice(&[3]);
+        let fee = STATE.with_borrow(|s| s.get_info().get_current_fee());
+
+        let reward = E8s::from(1_0000_0000u64);
+
+        STATE.with_borrow_mut(|s| {
+            let mut info = s.get_info();
+            info.current_burn_token_reward = reward.clone();
+            s.set_info(info);
+
+            s.mint_share(&fee * TCycles::from(10u64), user());
+            s.mint_share(&fee / TCycles::two(), dust_owner);
+        });
+
+        while !STATE.with_borrow_mut(|s| s.pos_round_batch(false, 10, NOW)) {}
+
+        STATE.with_borrow(|s| {
+            let (user_share, user_reward) = s.shares.get(&user()).unwrap();
+            let (dust_share, dust_reward) = s.shares.get(&dust_owner).unwrap();
+
+            assert_eq!(dust_share, TCycles::zero());
+            assert!(dust_reward > E8s::zero());
+            assert_eq!(s.get_info().total_shares_supply, user_share);
+
+            // only the rounding is not distributed
+            let emitted = user_reward + dust_reward;
+            assert!(emitted <= reward && reward - emitted <= E8s::from(2u64));
+        });
+    }
+
+    #[test]
+    fn collected_icps_are_redistributed_through_the_outbox() {
+        let redistribution = account(burner_id(), Some(BURNER_REDISTRIBUTION_SUBACCOUNT));
+        let furnace = account(ENV_VARS.furnace_canister_id, None);
+        let spike = account(burner_id(), Some(BURNER_SPIKE_SUBACCOUNT));
+        let dev = account(burner_id(), Some(BURNER_DEV_FEE_SUBACCOUNT));
+
+        let ledger = FakeICRC1Ledger::new(burner_id(), ICP_FEE);
+        ledger.set_balance(redistribution, INITIAL_BALANCE_E8S);
+
+        // nothing is split, if the balance is unknown
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::SysTransient, "");
+        assert!(block_on(redistribute_collected_icps(&ledger, burner_id(), NOW)).is_err());
+        assert_eq!(STATE.with_borrow(|s| s.redistributions.len()), 0);
+
+        let redistribution_id =
+            block_on(redistribute_collected_icps(&ledger, burner_id(), NOW)).unwrap();
+        assert!(redistribution_id.is_some());
+
+        // the enqueued transfers are not split again
+        assert_eq!(
+            block_on(redistribute_collected_icps(&ledger, burner_id(), NOW)),
+            Ok(None)
+        );
+
+        let due = STATE.with_borrow_mut(|s| s.outbox.take_due(NOW, 10));
+        assert_eq!(due.len(), 3);
+
+        for (id, transfer) in due {
+            let result = block_on(send_outbox_transfer(&ledger, &transfer));
+            STATE.with_borrow_mut(|s| s.outbox.complete(id, result));
+        }
+
+        let share = |share_e8s: u64| {
+            Nat::from(
+                (INITIAL_BALANCE_E8S as u128 * share_e8s as u128 / 1_0000_0000) as u64 - ICP_FEE,
+            )
+        };
+
+        assert_eq!(
+            ledger.balance(&furnace),
+            share(REDISTRIBUTION_FURNACE_SHARE_E8S)
+        );
+        assert_eq!(
+            ledger.balance(&spike),
+            share(REDISTRIBUTION_SPIKE_SHARE_E8S)
+        );
+        assert_eq!(ledger.balance(&dev), share(REDISTRIBUTION_DEV_SHARE_E8S));
+        // the fees are paid out of the redistribution subaccount
+        assert_eq!(ledger.balance(&redistribution), Nat::from(0u64));
+    }
+
+    #[test]
+    fn spike_top_up_notification_is_retried_only_if_it_can_succeed() {
+        let cmc = FakeCMC::default();
+        cmc.set_notify_result(0, Ok(Nat::from(1_000_000u64)));
+        cmc.set_notify_result(1, Err(NotifyTopUpError::Processing));
+        cmc.set_notify_result(
+            2,
+            Err(NotifyTopUpError::Refunded {
+                reason: String::new(),
+                block_index: None,
+            }),
+        );
+
+        let notify = |block_index| block_on(notify_cmc_top_up(&cmc, block_index, burner_id()));
+
+        assert!(matches!(notify(0), Ok(cycles) if cycles == 1_000_000u64));
+        assert!(matches!(notify(1), Err(OutboxSendError::Retryable(_))));
+        assert!(matches!(notify(2), Err(OutboxSendError::Fatal(_))));
+
+        cmc.rejects
+            .reject_next_call(RejectionCode::CanisterError, "");
+        assert!(matches!(notify(0), Err(OutboxSendError::Retryable(_))));
+
+        assert_eq!(cmc.notified(), vec![0, 1, 2]);
+    }
+}
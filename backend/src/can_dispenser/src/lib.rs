@@ -14,35 +14,40 @@ use ic_ledger_types::{AccountIdentifier, Subaccount};
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 use shared::{
     burner::types::TCycles,
+    certification::get_data_certificate,
     dispenser::{
         api::{
             CancelDistributionRequest, CancelDistributionResponse, ClaimTokensRequest,
             ClaimTokensResponse, CreateDistributionRequest, CreateDistributionResponse,
             FurnaceTriggerDistributionRequest, FurnaceTriggerDistributionResponse,
-            GetDistributionsRequest, GetDistributionsResponse, InitArgs, WithdrawCanceledRequest,
-            WithdrawCanceledResponse, WithdrawUserTokensRequest, WithdrawUserTokensResponse,
+            GetDistributionsRequest, GetDistributionsResponse, GetInfoCertifiedResponse, InitArgs,
+            WithdrawCanceledRequest, WithdrawCanceledResponse, WithdrawUserTokensRequest,
+            WithdrawUserTokensResponse,
         },
         types::{
             CurrentDistributionInfo, DispenserInfoPub, Distribution, DistributionId,
-            DISPENSER_DEV_FEE_SUBACCOUNT, DISPENSER_DISTRIBUTION_SUBACCOUNT,
-            DISPENSER_ICP_FEE_SUBACCOUNT,
+            DISPENSER_DEV_FEE_SUBACCOUNT, DISPENSER_DISTRIBUTION_SUBACCOUNT, DISPENSER_FEATURES,
+            DISPENSER_ICP_FEE_SUBACCOUNT, FEATURE_CREATE_DISTRIBUTION,
         },
     },
-    icrc1::ICRC1CanisterClient,
+    feature_flags::{FeatureFlag, SetFeatureFlagRequest, SetFeatureFlagResponse},
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    metrics::{nat_to_f64, serve_metrics, HttpRequest, HttpResponse},
+    timers::{self, GetTimersStatusResponse, RestartTimerRequest, RestartTimerResponse},
     Guard, ENV_VARS, ICP_FEE,
 };
 use utils::{
-    charge_caller_distribution_creation_fee_icp, charge_caller_tokens, charge_dev_fee,
-    claim_caller_tokens, set_init_canister_one_timer, set_tick_timer,
-    set_transfer_dev_fee_to_furnace_timer, set_transform_icp_fee_to_cycles_timer, IS_STOPPED,
-    STATE, TIMERS,
+    assert_feature_enabled, certify_info, charge_caller_distribution_creation_fee_icp,
+    charge_caller_tokens, charge_dev_fee, claim_caller_tokens, restart_job,
+    set_init_canister_one_timer, set_tick_timer, set_transfer_dev_fee_to_furnace_timer,
+    set_transform_icp_fee_to_cycles_timer, CERTIFIED_INFO, IS_STOPPED, STATE, TIMERS,
 };
 
 pub mod utils;
 
 #[update]
 async fn create_distribution(mut req: CreateDistributionRequest) -> CreateDistributionResponse {
-    panic!("The dispensing is temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_CREATE_DISTRIBUTION);
 
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
@@ -171,6 +176,15 @@ fn get_info() -> DispenserInfoPub {
     STATE.with_borrow(|s| s.get_dispenser_info().to_pub())
 }
 
+#[query]
+fn get_info_certified() -> GetInfoCertifiedResponse {
+    CERTIFIED_INFO.with_borrow(|(info, tree)| GetInfoCertifiedResponse {
+        info: info.clone(),
+        certificate: get_data_certificate(),
+        witness: tree.witness(),
+    })
+}
+
 #[query]
 fn get_distribution(id: DistributionId) -> Option<Distribution> {
     STATE.with_borrow(|s| s.get_distribution(id))
@@ -322,6 +336,84 @@ fn stop() {
     });
 }
 
+#[query]
+fn get_timers_status() -> GetTimersStatusResponse {
+    timers::get_timers_status(time())
+}
+
+#[update]
+fn restart_timer(req: RestartTimerRequest) -> RestartTimerResponse {
+    if caller() != ENV_VARS.furnace_canister_id {
+        panic!("Access denied");
+    }
+
+    RestartTimerResponse {
+        result: restart_job(&req.job),
+    }
+}
+
+#[update]
+fn set_feature_flag(req: SetFeatureFlagRequest) -> SetFeatureFlagResponse {
+    if caller() != ENV_VARS.furnace_canister_id {
+        panic!("Access denied");
+    }
+
+    let result = STATE.with_borrow_mut(|s| s.feature_flags.set_feature_flag(req, caller(), time()));
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_feature_flags() -> Vec<(String, FeatureFlag)> {
+    STATE.with_borrow(|s| s.feature_flags.get_feature_flags(time()))
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    serve_metrics(req, |m| {
+        STATE.with_borrow(|s| {
+            let info = s.get_dispenser_info();
+
+            m.gauge(
+                "dispenser_unclaimed_tokens_count",
+                "The number of accounts with unclaimed tokens",
+                s.unclaimed_tokens.len() as f64,
+            );
+            m.gauge(
+                "dispenser_common_pool_members_count",
+                "The number of members of the common pool",
+                s.common_pool_members.len() as f64,
+            );
+            m.gauge(
+                "dispenser_active_distributions_count",
+                "The number of distributions in progress",
+                s.active_distributions.len() as f64,
+            );
+            m.gauge(
+                "dispenser_scheduled_distributions_count",
+                "The number of distributions waiting to start",
+                s.scheduled_distributions.len() as f64,
+            );
+            m.gauge(
+                "dispenser_cur_tick",
+                "The index of the current distribution tick",
+                info.cur_tick as f64,
+            );
+            m.gauge(
+                "dispenser_total_distributed",
+                "The total amount of tokens distributed",
+                nat_to_f64(&info.total_distributed, info.token_decimals as u32),
+            );
+        });
+
+        m.gauge(
+            "dispenser_is_stopped",
+            "1 if the dispenser is stopped",
+            utils::is_stopped() as u8 as f64,
+        );
+    })
+}
+
 #[update]
 fn resume() {
     if caller() != ENV_VARS.furnace_canister_id {
@@ -341,8 +433,11 @@ fn init_hook(args: InitArgs) {
         let mut info = s.get_dispenser_info();
         info.token_can_id = Some(args.token_can_id);
         s.set_dispenser_info(info);
+
+        s.feature_flags.init_disabled(DISPENSER_FEATURES, time());
     });
 
+    certify_info();
     set_init_canister_one_timer();
 
     set_transform_icp_fee_to_cycles_timer();
@@ -352,6 +447,9 @@ fn init_hook(args: InitArgs) {
 
 #[post_upgrade]
 fn post_upgrade_hook() {
+    STATE.with_borrow_mut(|s| s.feature_flags.init_disabled(DISPENSER_FEATURES, time()));
+
+    certify_info();
     set_transfer_dev_fee_to_furnace_timer();
 
     set_init_canister_one_timer();
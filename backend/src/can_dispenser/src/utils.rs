@@ -22,22 +22,27 @@ use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 use shared::{
     burner::{
         api::{GetBurnersRequest, GetKamikazesRequest},
-        client::BurnerClient,
+        client::{BurnerApi, BurnerClient},
         types::TCycles,
     },
-    cmc::{CMCClient, NotifyTopUpError, NotifyTopUpRequest},
+    certification::CertifiedTotals,
+    cmc::{CMCApi, CMCClient, NotifyTopUpError, NotifyTopUpRequest},
     dispenser::{
         state::DispenserState,
         types::{
-            CurrentDistributionInfo, DispenserInfo, DISPENSER_DEFAULT_TICK_DELAY_NS,
-            DISPENSER_DEV_FEE_SUBACCOUNT, DISPENSER_DISTRIBUTION_SUBACCOUNT, DISPENSER_ICP_FEE_E8S,
-            DISPENSER_ICP_FEE_SUBACCOUNT,
+            CertifiedDispenserInfo, CurrentDistributionInfo, DispenserInfo,
+            DISPENSER_DEFAULT_TICK_DELAY_NS, DISPENSER_DEV_FEE_SUBACCOUNT,
+            DISPENSER_DISTRIBUTION_SUBACCOUNT, DISPENSER_ICP_FEE_E8S, DISPENSER_ICP_FEE_SUBACCOUNT,
         },
     },
+    feature_flags::FeatureFlags,
     furnace::{
-        api::GetCurRoundPositionsRequest, client::FurnaceClient, types::FURNACE_DEV_FEE_SUBACCOUNT,
+        api::GetCurRoundPositionsRequest,
+        client::{FurnaceApi, FurnaceClient},
+        types::FURNACE_DEV_FEE_SUBACCOUNT,
     },
-    icrc1::ICRC1CanisterClient,
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    timers::{note_job_failed, note_job_finished, prepare_job_restart, schedule_job},
     ENV_VARS, ICP_FEE, MEMO_TOP_UP_CANISTER, ONE_DAY_NS, ONE_MINUTE_NS,
 };
 
@@ -80,18 +85,47 @@ thread_local! {
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(8))),
                 DispenserInfo::default()
             ).expect("Unable to create dispenser info cell"),
+
+            feature_flags: FeatureFlags {
+                flags: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(9))),
+                ),
+            },
         }
     );
 
     pub static TIMERS: RefCell<Vec<TimerId>> = RefCell::default();
 
     pub static IS_STOPPED: RefCell<bool> = RefCell::default();
+
+    pub static CERTIFIED_INFO: RefCell<(CertifiedDispenserInfo, CertifiedTotals)> = RefCell::default();
+}
+
+// should be called each time any of the certified counters changes
+pub fn certify_info() {
+    let info = STATE.with_borrow(|s| s.get_dispenser_info().to_certified());
+
+    CERTIFIED_INFO.with_borrow_mut(|(certified, tree)| {
+        tree.insert("total_distributed", &info.total_distributed);
+        tree.insert("cur_tick", &info.cur_tick);
+        tree.certify();
+
+        *certified = info;
+    });
 }
 
 pub fn is_stopped() -> bool {
     IS_STOPPED.with_borrow(|s| *s)
 }
 
+pub fn assert_feature_enabled(feature: &str) {
+    let result = STATE.with_borrow(|s| s.feature_flags.assert_enabled(feature, time()));
+
+    if let Err(reason) = result {
+        panic!("{}", reason);
+    }
+}
+
 pub fn set_init_canister_one_timer() {
     set_timer(Duration::from_nanos(0), init_canister);
 }
@@ -122,12 +156,32 @@ fn init_canister() {
     });
 }
 
+pub const JOB_TICK: &str = "tick";
+pub const JOB_TRANSFER_DEV_FEE_TO_FURNACE: &str = "transfer_dev_fee_to_furnace";
+pub const JOB_TRANSFORM_ICP_FEE_TO_CYCLES: &str = "transform_icp_fee_to_cycles";
+
+// only stuck jobs can be restarted, see `prepare_job_restart`
+pub fn restart_job(job: &str) -> Result<(), String> {
+    let restart: fn() = match job {
+        JOB_TICK => || set_tick_timer(true),
+        JOB_TRANSFER_DEV_FEE_TO_FURNACE => set_transfer_dev_fee_to_furnace_timer,
+        JOB_TRANSFORM_ICP_FEE_TO_CYCLES => set_transform_icp_fee_to_cycles_timer,
+        _ => return Err(format!("Unknown job {}", job)),
+    };
+
+    prepare_job_restart(job, time())?;
+    restart();
+
+    Ok(())
+}
+
 pub fn set_tick_timer(immediate: bool) {
     if is_stopped() {
         return;
     }
 
-    let id = set_timer(
+    let id = schedule_job(
+        JOB_TICK,
         Duration::from_nanos(if immediate {
             0
         } else {
@@ -156,7 +210,9 @@ fn tick_start() {
     });
 
     if should_reschedule {
-        set_timer(
+        note_job_finished(JOB_TICK);
+        schedule_job(
+            JOB_TICK,
             Duration::from_nanos(DISPENSER_DEFAULT_TICK_DELAY_NS),
             tick_start,
         );
@@ -221,6 +277,8 @@ fn try_complete_active_distributions() {
     print(format!("Completing the tick"));
 
     STATE.with_borrow_mut(|s| s.complete_tick(time()));
+    certify_info();
+    note_job_finished(JOB_TICK);
 
     // restart
     set_tick_timer(false);
@@ -398,7 +456,8 @@ pub fn set_transfer_dev_fee_to_furnace_timer() {
         return;
     }
 
-    let id = set_timer(
+    let id = schedule_job(
+        JOB_TRANSFER_DEV_FEE_TO_FURNACE,
         Duration::from_nanos(ONE_DAY_NS),
         transfer_dev_fee_to_furnace,
     );
@@ -418,24 +477,38 @@ fn transfer_dev_fee_to_furnace() {
 
         let balance_resp = token.icrc1_balance_of(from).await;
 
-        if let Ok((balance,)) = balance_resp {
-            let fee = STATE.with_borrow(|s| s.get_dispenser_info().token_fee);
+        let (balance,) = match balance_resp {
+            Ok(it) => it,
+            Err((c, m)) => {
+                note_job_failed(JOB_TRANSFER_DEV_FEE_TO_FURNACE, format!("{:?}: {}", c, m));
+                return;
+            }
+        };
 
-            let to = Account {
-                owner: ENV_VARS.furnace_canister_id,
-                subaccount: Some(FURNACE_DEV_FEE_SUBACCOUNT),
-            };
+        let fee = STATE.with_borrow(|s| s.get_dispenser_info().token_fee);
 
-            let _ = token
-                .icrc1_transfer(TransferArg {
-                    from_subaccount: from.subaccount,
-                    to,
-                    amount: balance - fee.clone(),
-                    fee: Some(fee),
-                    created_at_time: None,
-                    memo: None,
-                })
-                .await;
+        let to = Account {
+            owner: ENV_VARS.furnace_canister_id,
+            subaccount: Some(FURNACE_DEV_FEE_SUBACCOUNT),
+        };
+
+        let transfer_result = token
+            .icrc1_transfer(TransferArg {
+                from_subaccount: from.subaccount,
+                to,
+                amount: balance - fee.clone(),
+                fee: Some(fee),
+                created_at_time: None,
+                memo: None,
+            })
+            .await;
+
+        match transfer_result {
+            Ok((Ok(_),)) => note_job_finished(JOB_TRANSFER_DEV_FEE_TO_FURNACE),
+            Ok((Err(e),)) => note_job_failed(JOB_TRANSFER_DEV_FEE_TO_FURNACE, e.to_string()),
+            Err((c, m)) => {
+                note_job_failed(JOB_TRANSFER_DEV_FEE_TO_FURNACE, format!("{:?}: {}", c, m))
+            }
         }
     });
 
@@ -547,7 +620,8 @@ pub fn set_transform_icp_fee_to_cycles_timer() {
         return;
     }
 
-    let id = set_timer(
+    let id = schedule_job(
+        JOB_TRANSFORM_ICP_FEE_TO_CYCLES,
         Duration::from_nanos(ONE_DAY_NS),
         transform_icp_fee_to_cycles,
     );
@@ -570,11 +644,29 @@ fn transform_icp_fee_to_cycles() {
             ic_ledger_types::account_balance(ENV_VARS.icp_token_canister_id, account_balance_args)
                 .await;
 
-        if let Ok(balance) = balance_call_result {
-            if balance.e8s() > ICP_FEE {
-                deposit_cycles(balance.e8s()).await;
+        let balance = match balance_call_result {
+            Ok(it) => it,
+            Err((c, m)) => {
+                note_job_failed(JOB_TRANSFORM_ICP_FEE_TO_CYCLES, format!("{:?}: {}", c, m));
+                return;
+            }
+        };
+
+        if balance.e8s() > ICP_FEE {
+            match deposit_cycles(balance.e8s()).await {
+                Ok((Ok(_),)) => {}
+                Ok((Err(e),)) => {
+                    note_job_failed(JOB_TRANSFORM_ICP_FEE_TO_CYCLES, format!("{:?}", e));
+                    return;
+                }
+                Err((c, m)) => {
+                    note_job_failed(JOB_TRANSFORM_ICP_FEE_TO_CYCLES, format!("{:?}: {}", c, m));
+                    return;
+                }
             }
         }
+
+        note_job_finished(JOB_TRANSFORM_ICP_FEE_TO_CYCLES);
     });
 
     set_transform_icp_fee_to_cycles_timer();
@@ -18,72 +18,101 @@ use ic_e8s::{c::E8s, d::EDs};
 use ic_ledger_types::{AccountIdentifier, Subaccount};
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 use shared::{
+    access::{
+        GetAccessLogRequest, GetAccessLogResponse, GrantRoleRequest, GrantRoleResponse,
+        RevokeRoleRequest, RevokeRoleResponse, Role,
+    },
     burner::types::TCycles,
-    dispenser::{client::DispenserClient, types::DistributionStartCondition},
+    certification::get_data_certificate,
+    dispenser::{
+        client::{DispenserApi, DispenserClient},
+        types::DistributionStartCondition,
+    },
+    feature_flags::{FeatureFlag, SetFeatureFlagRequest, SetFeatureFlagResponse},
     furnace::{
         api::{
             AddSupportedTokenRequest, AddSupportedTokenResponse, ClaimRewardICPRequest,
             ClaimRewardICPResponse, CreateDistributionTriggerRequest,
             CreateDistributionTriggerResponse, DeployDispenserRequest, DeployDispenserResponse,
             GetCurRoundPositionsRequest, GetCurRoundPositionsResponse,
-            GetDistributionTriggersRequest, GetDistributionTriggersResponse, GetWinnersRequest,
-            GetWinnersResponse, PledgeRequest, PledgeResponse, Position,
-            RemoveSupportedTokenRequest, RemoveSupportedTokenResponse, VoteTokenXRequest,
-            VoteTokenXResponse, WithdrawRequest, WithdrawResponse,
+            GetDistributionTriggersRequest, GetDistributionTriggersResponse,
+            GetFurnaceInfoCertifiedResponse, GetWinnersRequest, GetWinnersResponse, PledgeRequest,
+            PledgeResponse, Position, RemoveSupportedTokenRequest, RemoveSupportedTokenResponse,
+            ResolvePrizePayoutRequest, ResolvePrizePayoutResponse, VerifyRaffleRoundRequest,
+            VerifyRaffleRoundResponse, VoteTokenXRequest, VoteTokenXResponse, WithdrawRequest,
+            WithdrawResponse,
         },
         types::{
-            FurnaceInfoPub, TokenX, TokenXVote, FURNACE_DEV_FEE_SUBACCOUNT,
+            FurnaceInfoPub, TokenX, TokenXVote, FEATURE_CREATE_DISTRIBUTION_TRIGGER,
+            FEATURE_DEPLOY_DISPENSER, FEATURE_PLEDGE, FEATURE_VOTE_TOKEN_X,
+            FURNACE_DEV_FEE_SUBACCOUNT, FURNACE_FEATURES,
             FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT, FURNACE_REDISTRIBUTION_SUBACCOUNT,
         },
     },
     icpswap::ICPSwapTokenInfo,
-    icrc1::ICRC1CanisterClient,
-    CanisterMode, Guard, ENV_VARS, ICP_FEE,
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    metrics::{ecs_to_f64, serve_metrics, HttpRequest, HttpResponse},
+    price_oracle::TokenPrice,
+    timers::{
+        self, note_job_started, GetTimersStatusResponse, RestartTimerRequest, RestartTimerResponse,
+    },
+    CanisterMode, Guard, ENV_VARS,
 };
 use utils::{
-    deploy_dispenser_for, deposit_cycles, is_stopped, process_pledge_triggers,
-    set_fetch_token_prices_timer, set_init_canister_one_timer, set_raffle_timer, start_the_raffle,
-    IS_STOPPED, NEXT_RAFFLE_TIMESTAMP, STATE,
+    assert_caller_has_role, assert_feature_enabled, begin_raffle, certify_furnace_info,
+    claim_prize_icp, deploy_dispenser_for, deposit_cycles, is_stopped, process_pledge_triggers,
+    refund_pledged_tokens, restart_job, resume_raffle, set_fetch_token_prices_timer,
+    set_init_canister_one_timer, set_prize_payouts_timer, set_raffle_timer,
+    transfer_pledged_tokens, CERTIFIED_FURNACE_INFO, IS_STOPPED, JOB_RAFFLE, STATE,
 };
 
 pub mod utils;
 
 #[update]
 async fn pledge(mut req: PledgeRequest) -> PledgeResponse {
-    panic!("The Bonfire is temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_PLEDGE);
 
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
     }
 
-    STATE.with_borrow(|s| {
+    // the value is fixed before the transfer, the prices can change while it is in progress
+    let (round, usd_value) = STATE.with_borrow(|s| {
         req.validate_and_escape(s, caller(), time())
             .expect("Invalid request");
+
+        let usd_value = s
+            .get_pledge_usd_value(&req.token_can_id, req.qty.clone())
+            .expect("Invalid request");
+
+        (s.get_furnace_info_ref().current_round, usd_value)
     });
 
     let token_can_id = req.token_can_id;
+    let qty = req.qty.clone();
 
     let token = ICRC1CanisterClient::new(token_can_id);
-    let caller_subaccount = Subaccount::from(caller()).0;
 
-    token
-        .icrc1_transfer(TransferArg {
-            from_subaccount: Some(caller_subaccount),
-            to: Account {
-                owner: id(),
-                subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
-            },
-            amount: req.qty.clone(),
-            fee: None,
-            created_at_time: None,
-            memo: None,
-        })
+    let block_index = transfer_pledged_tokens(&token, token_can_id, caller(), id(), qty.clone())
         .await
-        .expect("Unable to pledge")
-        .0
         .expect("Unable to pledge");
 
-    let response = STATE.with_borrow_mut(|s| s.pledge(req));
+    let response = match STATE.with_borrow_mut(|s| s.pledge(req, round, usd_value, block_index)) {
+        Ok(response) => response,
+        Err(e) => {
+            let refund_result =
+                refund_pledged_tokens(&token, token_can_id, caller(), id(), qty).await;
+
+            match refund_result {
+                Ok(block_idx) => panic!(
+                    "{}. The tokens were returned to your furnace subaccount at block {}",
+                    e, block_idx
+                ),
+                Err(refund_e) => panic!("{}. Unable to return the tokens: {}", e, refund_e),
+            }
+        }
+    };
+    certify_furnace_info();
 
     // TODO: maybe move it to a timer
     process_pledge_triggers(token_can_id);
@@ -124,7 +153,7 @@ async fn withdraw(mut req: WithdrawRequest) -> WithdrawResponse {
 
 #[update]
 fn vote_token_x(mut req: VoteTokenXRequest) -> VoteTokenXResponse {
-    panic!("The Bonfire is temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_VOTE_TOKEN_X);
 
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
@@ -139,61 +168,42 @@ fn vote_token_x(mut req: VoteTokenXRequest) -> VoteTokenXResponse {
 }
 
 #[update]
-async fn claim_reward_icp(mut req: ClaimRewardICPRequest) -> ClaimRewardICPResponse {
+async fn claim_reward_icp(req: ClaimRewardICPRequest) -> ClaimRewardICPResponse {
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
     }
 
-    let qty = STATE.with_borrow_mut(|s| {
-        req.validate_and_escape(s, caller(), time())
-            .expect("Invalid request");
-
-        // preventing re-entrancy
-        s.claim_reward(req)
-    });
-
     let icp = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+    let result = claim_prize_icp(&icp, caller(), req, time()).await;
 
-    let call_result = icp
-        .icrc1_transfer(TransferArg {
-            from_subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
-            to: req.to,
-            amount: Nat(qty.val) - Nat::from(ICP_FEE),
-            fee: Some(Nat::from(ICP_FEE)),
-            created_at_time: None,
-            memo: None,
-        })
-        .await;
+    ClaimRewardICPResponse { result }
+}
 
-    let result = match call_result {
-        Ok((Ok(block_idx),)) => Ok(block_idx),
-        Ok((Err(e),)) => Err(e.to_string()),
-        Err((c, m)) => Err(format!("{:?} {}", c, m)),
-    };
+#[update]
+fn resolve_prize_payout(req: ResolvePrizePayoutRequest) -> ResolvePrizePayoutResponse {
+    assert_caller_has_role(Role::Owner);
 
-    if result.is_err() {
-        STATE.with_borrow_mut(|s| s.revert_claim_reward(req));
-    }
+    let result = STATE.with_borrow_mut(|s| s.resolve_prize_payout(req, time()));
 
-    ClaimRewardICPResponse { result }
+    ResolvePrizePayoutResponse { result }
 }
 
 #[update]
 async fn deploy_dispenser(mut req: DeployDispenserRequest) -> DeployDispenserResponse {
-    panic!("The Airdrops are temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_DEPLOY_DISPENSER);
 
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
     }
 
-    let info = STATE.with_borrow(|s| {
+    let is_operator = STATE.with_borrow(|s| {
         req.validate_and_escape(s, caller(), time())
             .expect("Invalid request");
 
-        s.get_furnace_info()
+        s.access.has_role(&caller(), Role::Operator)
     });
 
-    if !info.is_dev(&caller()) {
+    if !is_operator {
         deposit_cycles(caller(), 9990_0000u64)
             .await
             .expect("Unable to collect the fee")
@@ -270,11 +280,25 @@ fn list_token_x_alternatives() -> Vec<(Principal, E8s)> {
     STATE.with_borrow(|s| s.list_token_x_alternatives())
 }
 
+#[query]
+fn verify_raffle_round(req: VerifyRaffleRoundRequest) -> VerifyRaffleRoundResponse {
+    STATE.with_borrow(|s| s.verify_raffle_round(req))
+}
+
 #[query]
 fn get_furnace_info() -> FurnaceInfoPub {
     STATE.with_borrow(|s| s.get_furnace_info().to_pub())
 }
 
+#[query]
+fn get_furnace_info_certified() -> GetFurnaceInfoCertifiedResponse {
+    CERTIFIED_FURNACE_INFO.with_borrow(|(info, tree)| GetFurnaceInfoCertifiedResponse {
+        info: info.clone(),
+        certificate: get_data_certificate(),
+        witness: tree.witness(),
+    })
+}
+
 #[query]
 fn get_winners(mut req: GetWinnersRequest) -> GetWinnersResponse {
     STATE.with_borrow(|s| {
@@ -364,21 +388,16 @@ fn remove_supported_token(mut req: RemoveSupportedTokenRequest) -> RemoveSupport
 
 #[update]
 fn update_dispenser_wasm(wasm: Vec<u8>) {
-    STATE.with_borrow_mut(|s| {
-        let info = s.get_furnace_info();
-        if !info.is_dev(&caller()) {
-            panic!("Access denied");
-        }
+    assert_caller_has_role(Role::Owner);
 
-        s.set_dispenser_wasm(wasm);
-    });
+    STATE.with_borrow_mut(|s| s.set_dispenser_wasm(wasm));
 }
 
 #[update]
 async fn create_distribution_trigger(
     mut req: CreateDistributionTriggerRequest,
 ) -> CreateDistributionTriggerResponse {
-    panic!("The Airdrops are temporarily stopped. Please, come back later");
+    assert_feature_enabled(FEATURE_CREATE_DISTRIBUTION_TRIGGER);
 
     if is_stopped() {
         panic!("The canister is stopped for an upgrade");
@@ -401,9 +420,9 @@ async fn create_distribution_trigger(
         .0
         .expect("Distribution not found");
 
-    let info = STATE.with_borrow(|s| s.get_furnace_info());
+    let is_operator = STATE.with_borrow(|s| s.access.has_role(&caller(), Role::Operator));
 
-    if distribution.owner != caller() && !info.is_dev(&caller()) {
+    if distribution.owner != caller() && !is_operator {
         panic!("Access denied");
     }
 
@@ -467,12 +486,12 @@ fn start_raffle() {
         panic!("The canister is stopped for an upgrade");
     }
 
-    let info = STATE.with_borrow(|s| s.get_furnace_info());
-    if !info.is_dev(&caller()) {
-        panic!("Access denied");
-    }
+    assert_caller_has_role(Role::Operator);
 
-    start_the_raffle();
+    // a raffle which is already in progress keeps its own run
+    if begin_raffle() {
+        note_job_started(JOB_RAFFLE);
+    }
 }
 
 #[update]
@@ -481,10 +500,7 @@ async fn upgrade_dispensers() {
         panic!("The canister is stopped for an upgrade");
     }
 
-    let info = STATE.with_borrow(|s| s.get_furnace_info());
-    if !info.is_dev(&caller()) {
-        panic!("Access denied");
-    }
+    assert_caller_has_role(Role::Owner);
 
     let dispensers: Vec<_> = STATE.with_borrow(|s| {
         s.token_dispensers
@@ -533,15 +549,21 @@ fn list_exchange_rates() -> Vec<(Principal, ICPSwapTokenInfo)> {
         let mut res = Vec::new();
 
         for (token_can_id, _) in s.supported_tokens.iter() {
-            let rate = s.token_exchange_rates.get(&token_can_id).unwrap();
-
-            res.push((token_can_id, rate));
+            if let Some(rate) = s.token_exchange_rates.get(&token_can_id) {
+                res.push((token_can_id, rate));
+            }
         }
 
         res
     })
 }
 
+// where each price comes from, including the rejected quotes
+#[query]
+fn list_token_prices() -> Vec<(Principal, TokenPrice)> {
+    STATE.with_borrow(|s| s.token_prices.iter().collect())
+}
+
 #[query]
 fn get_cycles_balance() -> TCycles {
     let balance = canister_balance128();
@@ -597,35 +619,176 @@ fn get_account_ids() -> BTreeMap<String, (AccountIdentifier, Account)> {
 
 #[query]
 fn next_raffle_timestamp() -> u64 {
-    NEXT_RAFFLE_TIMESTAMP.with_borrow(|s| *s)
+    STATE.with_borrow(|s| s.get_furnace_info_ref().get_next_round_at())
 }
 
 #[update]
-fn stop() {
-    let is_dev = STATE.with_borrow(|s| s.get_furnace_info().is_dev(&caller()));
-    if !is_dev {
-        panic!("Access denied");
+fn set_feature_flag(req: SetFeatureFlagRequest) -> SetFeatureFlagResponse {
+    assert_caller_has_role(Role::Operator);
+
+    let result = STATE.with_borrow_mut(|s| s.feature_flags.set_feature_flag(req, caller(), time()));
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_feature_flags() -> Vec<(String, FeatureFlag)> {
+    STATE.with_borrow(|s| s.feature_flags.get_feature_flags(time()))
+}
+
+// the furnace is the only one allowed to manage the dispensers
+#[update]
+async fn set_dispenser_feature_flag(
+    token_can_id: Principal,
+    req: SetFeatureFlagRequest,
+) -> SetFeatureFlagResponse {
+    assert_caller_has_role(Role::Operator);
+
+    let dispenser_id = STATE
+        .with_borrow(|s| s.token_dispensers.get(&token_can_id).flatten())
+        .expect("No dispenser for this token");
+
+    let result = match DispenserClient(dispenser_id).set_feature_flag(req).await {
+        Ok((response,)) => response.result,
+        Err((c, m)) => Err(format!("Unable to call the dispenser - {:?}: {}", c, m)),
+    };
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_timers_status() -> GetTimersStatusResponse {
+    timers::get_timers_status(time())
+}
+
+#[update]
+fn restart_timer(req: RestartTimerRequest) -> RestartTimerResponse {
+    assert_caller_has_role(Role::Operator);
+
+    RestartTimerResponse {
+        result: restart_job(&req.job),
     }
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    serve_metrics(req, |m| {
+        STATE.with_borrow(|s| {
+            let info = s.get_furnace_info();
+
+            m.gauge(
+                "furnace_cur_round_positions_count",
+                "The number of positions pledged in the current round",
+                s.cur_round_positions.len() as f64,
+            );
+            m.gauge(
+                "furnace_cur_round_burn_positions_count",
+                "The number of BURN positions pledged in the current round",
+                s.cur_round_burn_positions.len() as f64,
+            );
+            m.gauge(
+                "furnace_supported_tokens_count",
+                "The number of tokens which can be pledged",
+                s.supported_tokens.len() as f64,
+            );
+            m.gauge(
+                "furnace_winners_count",
+                "The number of completed raffle rounds in the winners history",
+                s.winners.len() as f64,
+            );
+            m.gauge(
+                "furnace_current_round",
+                "The index of the current raffle round",
+                info.current_round as f64,
+            );
+            m.gauge(
+                "furnace_is_looking_for_winners",
+                "1 if the raffle of the current round is in progress",
+                info.is_looking_for_winners as u8 as f64,
+            );
+            m.gauge(
+                "furnace_next_round_at_seconds",
+                "The deadline of the next raffle, it is overdue while the canister is stopped",
+                (info.get_next_round_at() / 1_000_000_000) as f64,
+            );
+            m.gauge(
+                "furnace_missed_rounds",
+                "The number of raffle deadlines which have passed while the canister was stopped",
+                info.missed_rounds.unwrap_or_default() as f64,
+            );
+            m.gauge(
+                "furnace_icp_won_total",
+                "The total amount of ICP won in all raffle rounds",
+                ecs_to_f64(&info.icp_won_total),
+            );
+            m.gauge(
+                "furnace_total_pledged_usd",
+                "The total USD value pledged in all rounds",
+                ecs_to_f64(&info.total_pledged_usd),
+            );
+            m.gauge(
+                "furnace_cur_round_pledged_usd",
+                "The USD value pledged in the current round",
+                ecs_to_f64(&info.cur_round_pledged_usd),
+            );
+
+            if let Some(raffle) = s.raffle_round_info.get() {
+                m.gauge(
+                    "furnace_raffle_prize_fund_icp",
+                    "The ICP prize fund of the raffle in progress",
+                    ecs_to_f64(&raffle.prize_fund_icp),
+                );
+            }
+        })
+    })
+}
+
+#[update]
+fn grant_role(req: GrantRoleRequest) -> GrantRoleResponse {
+    let result =
+        STATE.with_borrow_mut(|s| s.access.grant_role(caller(), req.pid, req.role, time()));
+
+    GrantRoleResponse { result }
+}
+
+#[update]
+fn revoke_role(req: RevokeRoleRequest) -> RevokeRoleResponse {
+    let result = STATE.with_borrow_mut(|s| s.access.revoke_role(caller(), req.pid, time()));
+
+    RevokeRoleResponse { result }
+}
+
+#[query]
+fn get_roles() -> Vec<(Principal, Role)> {
+    STATE.with_borrow(|s| s.access.get_roles())
+}
+
+#[query]
+fn get_access_log(req: GetAccessLogRequest) -> GetAccessLogResponse {
+    assert_caller_has_role(Role::Auditor);
+
+    STATE.with_borrow(|s| s.access.get_access_log(req))
+}
+
+#[update]
+fn stop() {
+    assert_caller_has_role(Role::Operator);
 
     IS_STOPPED.with_borrow_mut(|s| *s = true);
 }
 
 #[update]
 fn resume() {
-    let is_dev = STATE.with_borrow(|s| s.get_furnace_info().is_dev(&caller()));
-    if !is_dev {
-        panic!("Access denied");
-    }
+    assert_caller_has_role(Role::Operator);
 
     IS_STOPPED.with_borrow_mut(|s| *s = false);
+    resume_raffle();
+    set_prize_payouts_timer();
 }
 
 #[update]
 async fn burn_token(token_can_id: Principal, from_subaccount: Option<[u8; 32]>, qty: Nat) {
-    let is_dev = STATE.with_borrow(|s| s.get_furnace_info().is_dev(&caller()));
-    if !is_dev {
-        panic!("Access denied");
-    }
+    assert_caller_has_role(Role::Owner);
 
     let token = ICRC1CanisterClient::new(token_can_id);
     token
@@ -638,10 +801,7 @@ async fn burn_token(token_can_id: Principal, from_subaccount: Option<[u8; 32]>,
 
 #[update]
 async fn withdraw_dev_fee(token_can_id: Principal, fee: Nat) {
-    let is_dev = STATE.with_borrow(|s| s.get_furnace_info().is_dev(&caller()));
-    if !is_dev {
-        panic!("Access denied");
-    }
+    assert_caller_has_role(Role::Owner);
 
     let token = ICRC1CanisterClient::new(token_can_id);
     let balance = token
@@ -673,6 +833,10 @@ async fn withdraw_dev_fee(token_can_id: Principal, fee: Nat) {
 
 #[init]
 fn init_hook() {
+    STATE.with_borrow_mut(|s| {
+        s.access.bootstrap(caller(), time());
+        s.feature_flags.init_disabled(FURNACE_FEATURES, time());
+    });
     set_init_canister_one_timer(caller());
 
     STATE.with_borrow_mut(|s| {
@@ -698,14 +862,24 @@ fn init_hook() {
         }
     });
 
+    certify_furnace_info();
     set_fetch_token_prices_timer();
     set_raffle_timer();
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
+    STATE.with_borrow_mut(|s| {
+        // the first upgrade after the roles were introduced makes the previous dev the owner
+        let owner = s.get_furnace_info_ref().dev_pid.unwrap_or(caller());
+        s.access.bootstrap(owner, time());
+        s.feature_flags.init_disabled(FURNACE_FEATURES, time());
+    });
+
+    certify_furnace_info();
     set_fetch_token_prices_timer();
     set_raffle_timer();
+    set_prize_payouts_timer();
 }
 
 export_candid!();
@@ -1,7 +1,8 @@
-use std::{cell::RefCell, time::Duration};
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
 
 use candid::{encode_args, Nat, Principal};
 
+use futures::future::join_all;
 use ic_cdk::{
     api::{
         call::{CallResult, RejectionCode},
@@ -11,34 +12,50 @@ use ic_cdk::{
         },
         time,
     },
-    id, notify, print, spawn,
+    caller, id, notify, print, spawn,
 };
-use ic_cdk_timers::set_timer;
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
 use ic_e8s::{c::E8s, d::EDs};
 use ic_ledger_types::{transfer, AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
     Cell, DefaultMemoryImpl, StableBTreeMap,
 };
-use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
+use icrc_ledger_types::{
+    icrc1::{
+        account::Account,
+        transfer::{TransferArg, TransferError},
+    },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
 use shared::{
-    cmc::{CMCClient, NotifyTopUpError, NotifyTopUpRequest},
+    access::{AccessControl, Role},
+    burner::types::TimestampNs,
+    certification::CertifiedTotals,
+    cmc::{CMCApi, CMCClient, NotifyTopUpError, NotifyTopUpRequest},
     dispenser::{
         api::{CreateDistributionRequest, FurnaceTriggerDistributionRequest, InitArgs},
-        client::DispenserClient,
+        client::{DispenserApi, DispenserClient},
         types::{DistributionScheme, DistributionStartCondition},
     },
+    feature_flags::FeatureFlags,
     furnace::{
+        api::ClaimRewardICPRequest,
         state::FurnaceState,
         types::{
-            DistributionTriggerKind, FurnaceInfo, TokenX, FURNACE_DEV_FEE_SUBACCOUNT,
+            CertifiedFurnaceInfo, DistributionTriggerKind, FurnaceInfo, PrizePayoutOutcome,
+            RaffleStage, TokenX, FURNACE_DEV_FEE_SUBACCOUNT,
             FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT, FURNACE_REDISTRIBUTION_SUBACCOUNT,
+            PRIZE_PAYOUTS_BATCH_SIZE, PRIZE_PAYOUT_RETRY_DELAY_NS,
+            RAFFLE_ROUND_POSITIONS_BATCH_SIZE,
         },
     },
-    icpswap::ICPSwapClient,
-    icrc1::ICRC1CanisterClient,
-    utils::duration_until_next_sunday_15_00,
-    CanisterMode, ENV_VARS, ICP_FEE, MEMO_TOP_UP_CANISTER, ONE_MINUTE_NS,
+    icpswap::{ICPSwapApi, ICPSwapClient},
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    timers::{
+        note_job_error, note_job_failed, note_job_finished, prepare_job_restart, schedule_job,
+    },
+    CanisterMode, Guard, ENV_VARS, ICP_FEE, MEMO_TOP_UP_CANISTER, ONE_MINUTE_NS,
 };
 
 thread_local! {
@@ -62,6 +79,9 @@ thread_local! {
             token_exchange_rates: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(5))),
             ),
+            token_prices: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(18))),
+            ),
             supported_tokens: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(6))),
             ),
@@ -88,18 +108,81 @@ thread_local! {
             total_pledged_tokens: StableBTreeMap::init(
                 MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(13))),
             ),
+
+            raffle_round_proofs: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(14))),
+            ),
+            raffle_round_positions: StableBTreeMap::init(
+                MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(19))),
+            ),
+
+            access: AccessControl {
+                roles: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(15))),
+                ),
+                log: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(16))),
+                ),
+            },
+            feature_flags: FeatureFlags {
+                flags: StableBTreeMap::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(17))),
+                ),
+            },
         }
     );
 
     pub static IS_STOPPED: RefCell<bool> = RefCell::default();
 
-    pub static NEXT_RAFFLE_TIMESTAMP: RefCell<u64> = RefCell::default();
+    // set, when a raffle step is skipped because the canister is stopped, so `resume` can continue it
+    pub static RAFFLE_PAUSED: RefCell<bool> = RefCell::default();
+
+    pub static CERTIFIED_FURNACE_INFO: RefCell<(CertifiedFurnaceInfo, CertifiedTotals)> = RefCell::default();
+
+    // whether the token ledgers advertise ICRC-2, asked once per token and forgotten on upgrades
+    pub static ICRC2_SUPPORT: RefCell<BTreeMap<Principal, bool>> = RefCell::default();
+
+    // the pending timer of the payouts job, so scheduling it again never leaves two of them
+    pub static PRIZE_PAYOUTS_TIMER: RefCell<Option<TimerId>> = RefCell::default();
+}
+
+// should be called each time any of the certified counters changes
+pub fn certify_furnace_info() {
+    let info = STATE.with_borrow(|s| s.get_furnace_info_ref().to_certified());
+
+    CERTIFIED_FURNACE_INFO.with_borrow_mut(|(certified, tree)| {
+        tree.insert("current_round", &info.current_round);
+        tree.insert("icp_won_total", &info.icp_won_total);
+        tree.insert("total_pledged_usd", &info.total_pledged_usd);
+        tree.insert("cur_round_pledged_usd", &info.cur_round_pledged_usd);
+        tree.insert(
+            "cur_round_pledged_burn_usd",
+            &info.cur_round_pledged_burn_usd,
+        );
+        tree.certify();
+
+        *certified = info;
+    });
 }
 
 pub fn is_stopped() -> bool {
     IS_STOPPED.with_borrow(|s| *s)
 }
 
+pub fn assert_caller_has_role(role: Role) {
+    STATE
+        .with_borrow(|s| s.access.assert_role(&caller(), role))
+        .expect("Access denied");
+}
+
+pub fn assert_feature_enabled(feature: &str) {
+    let result = STATE.with_borrow(|s| s.feature_flags.assert_enabled(feature, time()));
+
+    if let Err(reason) = result {
+        panic!("{}", reason);
+    }
+}
+
 pub fn set_init_canister_one_timer(caller: Principal) {
     set_timer(Duration::from_nanos(0), move || init_canister(caller));
 }
@@ -112,12 +195,36 @@ fn init_canister(caller: Principal) {
     });
 }
 
+pub const JOB_FETCH_TOKEN_PRICES: &str = "fetch_token_prices";
+pub const JOB_RAFFLE: &str = "raffle";
+pub const JOB_PRIZE_PAYOUTS: &str = "prize_payouts";
+
+// only stuck jobs can be restarted, see `prepare_job_restart`
+pub fn restart_job(job: &str) -> Result<(), String> {
+    let restart: fn() = match job {
+        JOB_FETCH_TOKEN_PRICES => set_fetch_token_prices_timer,
+        // a stuck raffle continues from the stage it has reached
+        JOB_RAFFLE => set_raffle_timer,
+        JOB_PRIZE_PAYOUTS => set_prize_payouts_timer,
+        _ => return Err(format!("Unknown job {}", job)),
+    };
+
+    prepare_job_restart(job, time())?;
+    restart();
+
+    Ok(())
+}
+
 pub fn set_fetch_token_prices_timer() {
     if is_stopped() {
         return;
     }
 
-    set_timer(Duration::from_nanos(0), fetch_token_prices);
+    schedule_job(
+        JOB_FETCH_TOKEN_PRICES,
+        Duration::from_nanos(0),
+        fetch_token_prices,
+    );
 }
 
 fn fetch_token_prices() {
@@ -125,37 +232,138 @@ fn fetch_token_prices() {
         let should_mock = matches!(ENV_VARS.mode, CanisterMode::Dev);
         let icpswap = ICPSwapClient::new(None, should_mock);
 
-        let call_result = icpswap.get_all_tokens().await;
+        let tokens = STATE.with_borrow(|s| s.list_price_tracked_tokens());
+        let mut errors = Vec::new();
+
+        let token_list = match icpswap.get_all_tokens().await {
+            Ok(response) => response,
+            Err((c, m)) => {
+                errors.push(format!("ICPSwap token list - {:?}: {}", c, m));
+                Vec::new()
+            }
+        };
+
+        let pool_prices = match icpswap
+            .get_pool_prices(&tokens, ENV_VARS.icp_token_canister_id)
+            .await
+        {
+            Ok(response) => response,
+            Err((c, m)) => {
+                errors.push(format!("ICPSwap pools - {:?}: {}", c, m));
+                Vec::new()
+            }
+        };
 
-        if let Ok(response) = call_result {
-            STATE.with_borrow_mut(|s| s.update_token_exchange_rates(response));
+        STATE
+            .with_borrow_mut(|s| s.update_token_prices(&token_list, &pool_prices, &errors, time()));
+
+        // one of the sources is enough to keep the prices up to date
+        if errors.len() < 2 {
+            note_job_finished(JOB_FETCH_TOKEN_PRICES);
+        } else {
+            note_job_failed(JOB_FETCH_TOKEN_PRICES, errors.join("; "));
         }
 
-        set_timer(Duration::from_nanos(ONE_MINUTE_NS * 10), fetch_token_prices);
+        schedule_job(
+            JOB_FETCH_TOKEN_PRICES,
+            Duration::from_nanos(ONE_MINUTE_NS * 10),
+            fetch_token_prices,
+        );
     });
 }
 
+/// Arms the raffle timer according to the persisted state of the raffle. Safe to call after an
+/// upgrade - an interrupted raffle continues from the stage it has reached, an overdue one starts
+/// right away, see `FurnaceInfo::start_round`.
 pub fn set_raffle_timer() {
-    if is_stopped() {
-        return;
+    let now = time();
+
+    let (stage, delay_ns) = STATE.with_borrow_mut(|s| {
+        let mut info = s.get_furnace_info();
+        info.ensure_next_round_scheduled(now);
+
+        let delay_ns = info.get_timer_delay_before_next_round(now);
+        s.set_furnace_info(info);
+
+        (s.get_raffle_stage(), delay_ns)
+    });
+
+    let (step, delay_ns): (fn(), u64) = match stage {
+        None => (start_the_raffle, delay_ns),
+        Some(RaffleStage::MovingPrizeFund) => (handle_prize_fund_icp, 0),
+        Some(RaffleStage::FindingWinners) => (find_winners, 0),
+        Some(RaffleStage::RecordingPositions) => (record_raffle_positions, 0),
+        Some(RaffleStage::ElectingTokenX) => (select_next_token_x, 0),
+        Some(RaffleStage::TriggeringTokenXDistributions) => (process_next_token_x_triggers, 0),
+        Some(RaffleStage::Completing) => (complete_raffle, 0),
+    };
+
+    schedule_job(JOB_RAFFLE, Duration::from_nanos(delay_ns), step);
+}
+
+// the raffle doesn't advance while the canister is stopped, `resume` re-arms it from the persisted stage
+fn pause_raffle_if_stopped() -> bool {
+    if !is_stopped() {
+        return false;
     }
 
-    let now = time();
-    let duration = duration_until_next_sunday_15_00(now);
-    NEXT_RAFFLE_TIMESTAMP.with_borrow_mut(|s| *s = now + duration.as_nanos() as u64);
+    RAFFLE_PAUSED.with_borrow_mut(|p| *p = true);
+    // nothing runs until the canister resumes, so the job should not look stuck
+    note_job_finished(JOB_RAFFLE);
+
+    true
+}
+
+pub fn resume_raffle() {
+    let was_paused = RAFFLE_PAUSED.with_borrow_mut(std::mem::take);
 
-    set_timer(duration, start_the_raffle);
+    if was_paused {
+        set_raffle_timer();
+    }
 }
 
 pub fn start_the_raffle() {
-    STATE.with_borrow_mut(|s| s.set_looking_for_winners(true));
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
+    // a stale timer, the deadline has been moved by a raffle started manually and the new one is already armed
+    let is_due = STATE.with_borrow(|s| {
+        s.get_furnace_info_ref()
+            .get_timer_delay_before_next_round(time())
+            == 0
+    });
+
+    // the run has nothing to do, if the raffle is not started by it
+    if !is_due || !begin_raffle() {
+        note_job_finished(JOB_RAFFLE);
+    }
+}
+
+/// Starts the raffle regardless of its deadline, unless one is already in progress.
+/// Returns true if the raffle was started.
+pub fn begin_raffle() -> bool {
+    let started = STATE.with_borrow_mut(|s| s.start_raffle(time()));
+
+    if !started {
+        print("The raffle is already in progress");
+        return false;
+    }
+
+    certify_furnace_info();
 
     set_timer(Duration::from_nanos(ONE_MINUTE_NS), handle_prize_fund_icp);
 
     print("Starting the raffle");
+
+    true
 }
 
 fn handle_prize_fund_icp() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
     spawn(async {
         let icp = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
         let call_result = icp
@@ -165,27 +373,34 @@ fn handle_prize_fund_icp() {
             })
             .await;
 
-        if let Ok((balance,)) = call_result {
-            let balance_e8s = E8s::new(balance.0);
-            let prize_fund_cur_round = balance_e8s * E8s::from(8500_0000u64); // reserve 15% for the next round to keep the fund accumulating
+        let error = match call_result {
+            Ok((balance,)) => {
+                let balance_e8s = E8s::new(balance.0);
+                let prize_fund_cur_round = balance_e8s * E8s::from(8500_0000u64); // reserve 15% for the next round to keep the fund accumulating
 
-            print("Moving the prize fund");
+                print("Moving the prize fund");
 
-            let prize_fund_moved = move_prize_fund(icp, prize_fund_cur_round.clone()).await;
+                let prize_fund_moved = move_prize_fund(&icp, prize_fund_cur_round.clone()).await;
 
-            if prize_fund_moved {
-                STATE.with_borrow_mut(|s| {
-                    s.prepare_raffle(prize_fund_cur_round - E8s::from(10_000u64))
-                });
+                if prize_fund_moved {
+                    STATE.with_borrow_mut(|s| {
+                        s.prepare_raffle(prize_fund_cur_round - E8s::from(10_000u64))
+                    });
 
-                print("Raffle prepared");
+                    print("Raffle prepared");
 
-                set_timer(Duration::from_nanos(0), redistirbute_pledged_tokens);
-                set_timer(Duration::from_nanos(0), find_winners);
+                    set_timer(Duration::from_nanos(0), redistirbute_pledged_tokens);
+                    set_timer(Duration::from_nanos(0), find_winners);
 
-                return;
+                    return;
+                }
+
+                String::from("Unable to move the prize fund")
             }
-        }
+            Err((c, m)) => format!("{:?}: {}", c, m),
+        };
+
+        note_job_error(JOB_RAFFLE, error);
 
         set_timer(
             Duration::from_nanos(ONE_MINUTE_NS * 10),
@@ -196,53 +411,82 @@ fn handle_prize_fund_icp() {
 
 fn redistirbute_pledged_tokens() {
     spawn(async {
-        let token_x = STATE.with_borrow(|s| s.get_furnace_info().cur_token_x);
-        let burn = TokenX {
-            can_id: ENV_VARS.burn_token_canister_id,
-            fee: Nat::from(10_000u64),
-            decimals: 8,
-        };
+        let tokens = STATE.with_borrow(|s| s.get_furnace_info().get_active_tokens_x());
 
-        if token_x.can_id == burn.can_id {
-            redistribute_pledged_token(burn).await;
-        } else {
-            redistribute_pledged_token(burn).await;
-            redistribute_pledged_token(token_x).await;
+        for active_token in tokens {
+            redistribute_pledged_token(active_token.token).await;
         }
     });
 }
 
-// TODO: check all the responses and react to errors
 async fn redistribute_pledged_token(token_x_info: TokenX) {
     let token = ICRC1CanisterClient::new(token_x_info.can_id);
 
-    let balance_call_result = token
+    let token_dispenser = STATE
+        .with_borrow(|s| s.dispenser_of(&token_x_info.can_id))
+        .expect("Token dispenser was not scheduled for deployment")
+        .expect("Token dispenser was deployed badly");
+
+    let dispenser = DispenserClient(token_dispenser);
+    let can_id = token_x_info.can_id;
+
+    match redistribute_collected_token(
+        &token,
+        &dispenser,
+        token_dispenser,
+        token_x_info,
+        id(),
+        time(),
+    )
+    .await
+    {
+        Ok(()) => print(format!("Redistributed pledged token {}", can_id)),
+        Err(errors) => print(format!(
+            "Unable to redistribute pledged token {}: {:?}",
+            can_id, errors
+        )),
+    }
+}
+
+/// Splits the tokens collected on the redistribution subaccount of `this_canister_id` between
+/// a new distribution of the token's dispenser, the burn and the devs. A failed split doesn't stop
+/// the others - its tokens stay on the subaccount and are redistributed next time.
+pub async fn redistribute_collected_token(
+    token: &impl ICRC1Api,
+    dispenser: &impl DispenserApi,
+    dispenser_id: Principal,
+    token_x_info: TokenX,
+    this_canister_id: Principal,
+    now: TimestampNs,
+) -> Result<(), Vec<String>> {
+    let (balance,) = token
         .icrc1_balance_of(Account {
-            owner: id(),
+            owner: this_canister_id,
             subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
         })
-        .await;
+        .await
+        .map_err(|(c, m)| vec![format!("{:?} {}", c, m)])?;
 
-    if let Ok((balance,)) = balance_call_result {
-        let balance_eds = EDs::new(balance.0, token_x_info.decimals);
+    let balance_eds = EDs::new(balance.0, token_x_info.decimals);
 
-        let burn_share = &balance_eds * EDs::from((4750_0000u64, token_x_info.decimals));
-        let pool_share = &balance_eds * EDs::from((5000_0000u64, token_x_info.decimals));
-        let dev_fee_share = &balance_eds * EDs::from((0250_0000u64, token_x_info.decimals));
+    let burn_share = &balance_eds * EDs::from((4750_0000u64, token_x_info.decimals));
+    let pool_share = &balance_eds * EDs::from((5000_0000u64, token_x_info.decimals));
+    let dev_fee_share = &balance_eds * EDs::from((0250_0000u64, token_x_info.decimals));
 
-        let token_dispenser = STATE
-            .with_borrow(|s| s.dispenser_of(&token_x_info.can_id))
-            .expect("Token dispenser was not scheduled for deployment")
-            .expect("Token dispenser was deployed badly");
+    let mut errors = Vec::new();
 
-        let dispenser_qty = Nat(pool_share.val) - token_x_info.fee.clone();
+    // the fees are paid out of each split, so a split smaller than its fees stays on the subaccount
+    let pool_qty = Nat(pool_share.val);
 
-        let _ = token
+    if pool_qty > token_x_info.fee.clone() * Nat::from(2u64) {
+        let dispenser_qty = pool_qty - token_x_info.fee.clone();
+
+        let transfer_result = token
             .icrc1_transfer(TransferArg {
                 from_subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
                 to: Account {
-                    owner: token_dispenser,
-                    subaccount: Some(Subaccount::from(id()).0),
+                    owner: dispenser_id,
+                    subaccount: Some(Subaccount::from(this_canister_id).0),
                 },
                 amount: dispenser_qty.clone(),
                 fee: None,
@@ -251,33 +495,51 @@ async fn redistribute_pledged_token(token_x_info: TokenX) {
             })
             .await;
 
-        let dispenser = DispenserClient(token_dispenser);
-        let _ = dispenser
-            .create_distribution(CreateDistributionRequest {
-                qty: dispenser_qty - token_x_info.fee.clone(),
-                start_condition: DistributionStartCondition::AtTickDelay(0),
-                duration_ticks: 168, // ~1 week
-                name: format!("Bonfire Distribution #{}", time() / 1_000_000_000),
-                scheme: DistributionScheme::Linear,
-                hidden: false,
-                distribute_to_bonfire: false,
-            })
-            .await;
+        match transfer_result {
+            Ok((Ok(_),)) => {
+                let create_result = dispenser
+                    .create_distribution(CreateDistributionRequest {
+                        qty: dispenser_qty - token_x_info.fee.clone(),
+                        start_condition: DistributionStartCondition::AtTickDelay(0),
+                        duration_ticks: 168, // ~1 week
+                        name: format!("Bonfire Distribution #{}", now / 1_000_000_000),
+                        scheme: DistributionScheme::Linear,
+                        hidden: false,
+                        distribute_to_bonfire: false,
+                    })
+                    .await;
+
+                // the tokens are already on the dispenser, they are distributed with the next distribution
+                if let Err((c, m)) = create_result {
+                    errors.push(format!("{:?} {}", c, m));
+                }
+            }
+            Ok((Err(e),)) => errors.push(format!("{:?}", e)),
+            Err((c, m)) => errors.push(format!("{:?} {}", c, m)),
+        }
+    }
 
-        let _ = token
-            .icrc1_furnace_burn(
-                Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
-                Nat(burn_share.val.clone()),
-            )
-            .await;
+    let burn_result = token
+        .icrc1_furnace_burn(
+            Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+            Nat(burn_share.val.clone()),
+        )
+        .await;
 
-        STATE.with_borrow_mut(|s| s.note_burned_token(token_x_info.can_id, &burn_share));
+    match burn_result {
+        Ok((Ok(_),)) => {
+            STATE.with_borrow_mut(|s| s.note_burned_token(token_x_info.can_id, &burn_share))
+        }
+        Ok((Err(e),)) => errors.push(format!("{:?}", e)),
+        Err((c, m)) => errors.push(format!("{:?} {}", c, m)),
+    }
 
-        let _ = token
+    if Nat(dev_fee_share.val.clone()) > token_x_info.fee {
+        let dev_fee_result = token
             .icrc1_transfer(TransferArg {
                 from_subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
                 to: Account {
-                    owner: id(),
+                    owner: this_canister_id,
                     subaccount: Some(FURNACE_DEV_FEE_SUBACCOUNT),
                 },
                 amount: Nat(dev_fee_share.val) - token_x_info.fee,
@@ -287,15 +549,26 @@ async fn redistribute_pledged_token(token_x_info: TokenX) {
             })
             .await;
 
-        print(format!(
-            "Redistributed pledged token {}",
-            token_x_info.can_id
-        ));
+        match dev_fee_result {
+            Ok((Ok(_),)) => {}
+            Ok((Err(e),)) => errors.push(format!("{:?}", e)),
+            Err((c, m)) => errors.push(format!("{:?} {}", c, m)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
     }
 }
 
 // one position can win more than one prize in case there are less positions, than prizes
 pub fn find_winners() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
     print(format!("Looking for winners"));
 
     let should_reschedule = STATE.with_borrow_mut(|s| s.find_winners_batch(300));
@@ -305,26 +578,66 @@ pub fn find_winners() {
         return;
     }
 
-    set_timer(Duration::from_nanos(0), complete_raffle);
+    STATE.with_borrow_mut(|s| s.set_raffle_stage(RaffleStage::RecordingPositions));
+    set_timer(Duration::from_nanos(0), record_raffle_positions);
+}
+
+// keeps the positions of the round, so anyone can replay the winner selection later
+pub fn record_raffle_positions() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
+    print(format!("Recording raffle positions"));
+
+    let should_reschedule = STATE
+        .with_borrow_mut(|s| s.record_raffle_positions_batch(RAFFLE_ROUND_POSITIONS_BATCH_SIZE));
+
+    if should_reschedule {
+        set_timer(Duration::from_nanos(0), record_raffle_positions);
+        return;
+    }
+
+    STATE.with_borrow_mut(|s| s.set_raffle_stage(RaffleStage::ElectingTokenX));
+    set_timer(Duration::from_nanos(0), select_next_token_x);
 }
 
 pub fn select_next_token_x() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
     print(format!("Selecting next token x"));
 
-    let token_can_id = STATE.with_borrow_mut(|s| s.select_next_token_x());
+    let token_can_ids = STATE.with_borrow_mut(|s| {
+        let token_can_ids = s.select_next_token_x();
+        s.set_raffle_stage(RaffleStage::TriggeringTokenXDistributions);
+
+        token_can_ids
+    });
 
-    set_deploy_dispenser_timer(token_can_id);
+    for token_can_id in token_can_ids {
+        set_deploy_dispenser_timer(token_can_id);
+    }
     set_timer(Duration::from_nanos(0), process_next_token_x_triggers);
 }
 
 pub fn process_next_token_x_triggers() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
     print(format!("Processing endround triggers"));
 
     let (triggers_to_execute_opt, should_reschedule) = STATE.with_borrow_mut(|s| {
-        let token_x_can_id = s.get_furnace_info().cur_token_x.can_id;
-        let trigger_kind = DistributionTriggerKind::TokenXVotingWinner(token_x_can_id);
-
-        s.process_triggers_batch(50, trigger_kind)
+        let winners: Vec<_> = s
+            .get_furnace_info()
+            .get_elected_next_tokens_x()
+            .into_iter()
+            .map(DistributionTriggerKind::TokenXVotingWinner)
+            .collect();
+
+        s.process_triggers_batch(50, &winners)
     });
 
     if let Some(triggers_to_execute) = triggers_to_execute_opt {
@@ -349,6 +662,7 @@ pub fn process_next_token_x_triggers() {
     if should_reschedule {
         set_timer(Duration::from_nanos(0), process_next_token_x_triggers);
     } else {
+        STATE.with_borrow_mut(|s| s.set_raffle_stage(RaffleStage::Completing));
         set_timer(Duration::from_nanos(0), complete_raffle);
     }
 }
@@ -368,7 +682,7 @@ pub fn process_pledge_triggers(token_can_id: Principal) {
             threshold: reached,
         };
 
-        s.process_triggers_batch(50, trigger_kind)
+        s.process_triggers_batch(50, &[trigger_kind])
     });
 
     if let Some(triggers_to_execute) = triggers_to_execute_opt {
@@ -398,15 +712,110 @@ pub fn process_pledge_triggers(token_can_id: Principal) {
 }
 
 pub fn complete_raffle() {
+    if pause_raffle_if_stopped() {
+        return;
+    }
+
     print(format!("Completing the raffle"));
 
-    STATE.with_borrow_mut(|s| {
-        s.complete_raffle(time());
+    // also schedules the next round
+    STATE.with_borrow_mut(|s| s.complete_raffle(time()));
+
+    certify_furnace_info();
+    note_job_finished(JOB_RAFFLE);
+
+    set_raffle_timer();
+    set_prize_payouts_timer();
+}
+
+pub fn set_prize_payouts_timer() {
+    schedule_prize_payouts(Duration::from_nanos(0));
+}
+
+fn schedule_prize_payouts(delay: Duration) {
+    if is_stopped() || !STATE.with_borrow(|s| s.has_prize_payouts_left()) {
+        return;
+    }
+
+    let timer_id = schedule_job(JOB_PRIZE_PAYOUTS, delay, pay_out_prizes);
 
-        s.set_looking_for_winners(false);
+    if let Some(prev_timer_id) = PRIZE_PAYOUTS_TIMER.replace(Some(timer_id)) {
+        clear_timer(prev_timer_id);
+    }
+}
+
+fn pay_out_prizes() {
+    spawn(async {
+        let icp = ICRC1CanisterClient::new(ENV_VARS.icp_token_canister_id);
+        let (paid_out, errors) = pay_out_prizes_batch(&icp, time()).await;
+
+        if errors.is_empty() {
+            note_job_finished(JOB_PRIZE_PAYOUTS);
+        } else {
+            note_job_failed(JOB_PRIZE_PAYOUTS, errors.join("; "));
+        }
+
+        // a full batch means there are likely more prizes to pay out right away
+        let delay_ns = if paid_out == PRIZE_PAYOUTS_BATCH_SIZE {
+            0
+        } else {
+            PRIZE_PAYOUT_RETRY_DELAY_NS
+        };
+
+        schedule_prize_payouts(Duration::from_nanos(delay_ns));
     });
+}
+
+/// Pays out the next batch of the prizes concurrently. Returns the size of the batch and the errors.
+pub async fn pay_out_prizes_batch(icp: &impl ICRC1Api, now: TimestampNs) -> (usize, Vec<String>) {
+    let batch =
+        STATE.with_borrow_mut(|s| s.reserve_prize_payouts_batch(PRIZE_PAYOUTS_BATCH_SIZE, now));
+
+    let results = join_all(batch.iter().map(|task| {
+        icp.icrc1_transfer(TransferArg {
+            from_subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
+            to: Account {
+                owner: task.to,
+                subaccount: None,
+            },
+            amount: Nat(task.prize_icp.val.clone()) - Nat::from(ICP_FEE),
+            fee: Some(Nat::from(ICP_FEE)),
+            created_at_time: Some(task.created_at_time),
+            memo: None,
+        })
+    }))
+    .await;
+
+    let mut errors = Vec::new();
+
+    for (task, call_result) in batch.iter().zip(results) {
+        let outcome = match call_result {
+            Ok((Ok(block_idx),)) => PrizePayoutOutcome::Paid(block_idx),
+            // the previous attempt has made the transfer
+            Ok((Err(TransferError::Duplicate { duplicate_of }),)) => {
+                PrizePayoutOutcome::Paid(duplicate_of)
+            }
+            // out of the deduplication window, so there is no way to tell what happened to the previous attempts
+            Ok((Err(TransferError::TooOld),)) => {
+                PrizePayoutOutcome::Unknown(TransferError::TooOld.to_string())
+            }
+            Ok((Err(e),)) => PrizePayoutOutcome::Rejected(e.to_string()),
+            Err((c, m)) => PrizePayoutOutcome::Unknown(format!("{:?} {}", c, m)),
+        };
+
+        if let PrizePayoutOutcome::Rejected(e) | PrizePayoutOutcome::Unknown(e) = &outcome {
+            errors.push(format!(
+                "Prize {}/{} - {}",
+                task.winning_entry_timestamp_ns, task.winner_idx, e
+            ));
+        }
 
-    //set_raffle_timer();
+        STATE.with_borrow_mut(|s| s.complete_prize_payout(task, outcome));
+    }
+
+    STATE.with_borrow_mut(|s| s.update_prize_payout_cursor(now));
+
+    (batch.len(), errors)
 }
 
 pub fn set_deploy_dispenser_timer(token_can_id: Principal) {
@@ -478,7 +887,7 @@ fn install_dispenser_code(can_id: Principal, token_can_id: Principal) {
     });
 }
 
-async fn move_prize_fund(icp: ICRC1CanisterClient, prize_fund_cur_round: E8s) -> bool {
+async fn move_prize_fund(icp: &impl ICRC1Api, prize_fund_cur_round: E8s) -> bool {
     let res = icp
         .icrc1_transfer(TransferArg {
             from_subaccount: None,
@@ -499,6 +908,164 @@ async fn move_prize_fund(icp: ICRC1CanisterClient, prize_fund_cur_round: E8s) ->
     }
 }
 
+// whether the token supports ICRC-2, remembered per token until the furnace is upgraded
+async fn token_supports_icrc2(token: &impl ICRC1Api, token_can_id: Principal) -> bool {
+    if let Some(supported) = ICRC2_SUPPORT.with_borrow(|it| it.get(&token_can_id).copied()) {
+        return supported;
+    }
+
+    match token.supports_icrc2().await {
+        Ok(supported) => {
+            ICRC2_SUPPORT.with_borrow_mut(|it| it.insert(token_can_id, supported));
+
+            supported
+        }
+        // not remembered, so the next pledge asks again
+        Err(_) => false,
+    }
+}
+
+/// Moves the pledged tokens to the redistribution subaccount and returns the block index.
+///
+/// If the token supports ICRC-2, the tokens are pulled from the caller's account with `icrc2_transfer_from`.
+/// Otherwise, or if the caller has approved nothing, they are taken from the caller's subaccount on the
+/// furnace, where the caller has to transfer them beforehand.
+pub async fn transfer_pledged_tokens(
+    token: &impl ICRC1Api,
+    token_can_id: Principal,
+    caller: Principal,
+    this_canister_id: Principal,
+    qty: Nat,
+) -> Result<Nat, String> {
+    let to = Account {
+        owner: this_canister_id,
+        subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+    };
+
+    if token_supports_icrc2(token, token_can_id).await {
+        let call_result = token
+            .icrc2_transfer_from(TransferFromArgs {
+                spender_subaccount: None,
+                from: Account {
+                    owner: caller,
+                    subaccount: None,
+                },
+                to,
+                amount: qty.clone(),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            })
+            .await;
+
+        match call_result {
+            Ok((Ok(block_idx),)) => return Ok(block_idx),
+            // the caller has used the subaccount flow
+            Ok((Err(TransferFromError::InsufficientAllowance { allowance }),))
+                if allowance == 0u64 => {}
+            Ok((Err(e),)) => return Err(e.to_string()),
+            Err((c, m)) => return Err(format!("{:?} {}", c, m)),
+        }
+    }
+
+    let call_result = token
+        .icrc1_transfer(TransferArg {
+            from_subaccount: Some(Subaccount::from(caller).0),
+            to,
+            amount: qty,
+            fee: None,
+            created_at_time: None,
+            memo: None,
+        })
+        .await;
+
+    match call_result {
+        Ok((Ok(block_idx),)) => Ok(block_idx),
+        Ok((Err(e),)) => Err(e.to_string()),
+        Err((c, m)) => Err(format!("{:?} {}", c, m)),
+    }
+}
+
+/// Returns the pledged tokens, which were not counted, to the caller's subaccount on the furnace,
+/// from where they can be withdrawn or pledged again.
+pub async fn refund_pledged_tokens(
+    token: &impl ICRC1Api,
+    token_can_id: Principal,
+    caller: Principal,
+    this_canister_id: Principal,
+    qty: Nat,
+) -> Result<Nat, String> {
+    let fee = STATE
+        .with_borrow(|s| s.get_supported_token(&token_can_id))
+        .map(|it| it.fee)
+        .ok_or(String::from("Unsupported token"))?;
+
+    if qty <= fee {
+        return Err(String::from("The amount is too small to return"));
+    }
+
+    let call_result = token
+        .icrc1_transfer(TransferArg {
+            from_subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+            to: Account {
+                owner: this_canister_id,
+                subaccount: Some(Subaccount::from(caller).0),
+            },
+            amount: qty - fee.clone(),
+            fee: Some(fee),
+            created_at_time: None,
+            memo: None,
+        })
+        .await;
+
+    match call_result {
+        Ok((Ok(block_idx),)) => Ok(block_idx),
+        Ok((Err(e),)) => Err(e.to_string()),
+        Err((c, m)) => Err(format!("{:?} {}", c, m)),
+    }
+}
+
+/// Sends the prize of a raffle winner from the prize distribution subaccount.
+/// The prize is marked as unclaimed again, if the transfer fails.
+pub async fn claim_prize_icp(
+    icp: &impl ICRC1Api,
+    caller: Principal,
+    mut req: ClaimRewardICPRequest,
+    now: TimestampNs,
+) -> Result<Nat, String> {
+    let qty = STATE.with_borrow_mut(|s| {
+        req.validate_and_escape(s, caller, now)
+            .expect("Invalid request");
+
+        // preventing re-entrancy
+        s.claim_reward(req)
+    });
+
+    let call_result = icp
+        .icrc1_transfer(TransferArg {
+            from_subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
+            to: req.to,
+            amount: Nat(qty.val) - Nat::from(ICP_FEE),
+            fee: Some(Nat::from(ICP_FEE)),
+            created_at_time: None,
+            memo: None,
+        })
+        .await;
+
+    let result = match call_result {
+        Ok((Ok(block_idx),)) => Ok(block_idx),
+        Ok((Err(e),)) => Err(e.to_string()),
+        Err((c, m)) => Err(format!("{:?} {}", c, m)),
+    };
+
+    STATE.with_borrow_mut(|s| match &result {
+        Ok(block_idx) => s.complete_claim_reward(req, block_idx.clone()),
+        Err(e) => s.revert_claim_reward(req, e.clone()),
+    });
+
+    result
+}
+
 pub async fn deposit_cycles(
     caller: Principal,
     qty_e8s_u64: u64,
@@ -534,3 +1101,500 @@ pub async fn deposit_cycles(
 
     CallResult::Err((RejectionCode::Unknown, String::from("")))
 }
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use futures::executor::block_on;
+    use ic_cdk::api::call::RejectionCode;
+    use ic_e8s::c::E8s;
+    use ic_ledger_types::Subaccount;
+    use icrc_ledger_types::icrc1::account::Account;
+    use shared::{
+        fakes::{FakeDispenser, FakeICRC1Ledger},
+        furnace::{
+            api::{
+                ClaimRewardICPRequest, PledgeRequest, ResolvePrizePayoutRequest,
+                VerifyRaffleRoundRequest,
+            },
+            types::{
+                FurnaceWinner, FurnaceWinnerHistoryEntry, PrizePayout, PrizePayoutStatus,
+                RaffleRoundProof, TokenX, FURNACE_DEV_FEE_SUBACCOUNT,
+                FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT, FURNACE_REDISTRIBUTION_SUBACCOUNT,
+                MAX_PRIZE_PAYOUT_ATTEMPTS, PRIZE_PAYOUT_DEDUP_WINDOW_NS,
+                PRIZE_PAYOUT_RETRY_DELAY_NS, RAFFLE_ROUND_PROOFS_TO_KEEP,
+            },
+        },
+        Guard, ENV_VARS, ICP_FEE,
+    };
+
+    use super::{
+        claim_prize_icp, pay_out_prizes_batch, redistribute_collected_token, refund_pledged_tokens,
+        transfer_pledged_tokens, STATE,
+    };
+
+    const ROUND_TIMESTAMP: u64 = 1_700_000_000_000_000_000;
+    const PRIZE_E8S: u64 = 3_0000_0000;
+
+    fn furnace_id() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn winner() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn winner_account() -> Account {
+        Account {
+            owner: winner(),
+            subaccount: None,
+        }
+    }
+
+    fn claim_req() -> ClaimRewardICPRequest {
+        ClaimRewardICPRequest {
+            winning_entry_timestamp_ns: ROUND_TIMESTAMP,
+            winner_idx: 0,
+            to: winner_account(),
+        }
+    }
+
+    fn is_claimed() -> bool {
+        STATE.with_borrow(|s| s.winners.get(&ROUND_TIMESTAMP).unwrap().winners[0].claimed)
+    }
+
+    #[test]
+    fn prize_claim_is_reverted_if_the_transfer_fails() {
+        STATE.with_borrow_mut(|s| {
+            s.winners.insert(
+                ROUND_TIMESTAMP,
+                FurnaceWinnerHistoryEntry {
+                    timestamp: ROUND_TIMESTAMP,
+                    token_can_id: Principal::anonymous(),
+                    pledged_usd: E8s::zero(),
+                    round: 0,
+                    prize_fund_icp: E8s::from(PRIZE_E8S),
+                    winners: vec![FurnaceWinner {
+                        pid: winner(),
+                        prize_icp: E8s::from(PRIZE_E8S),
+                        claimed: false,
+                        share_normalized: E8s::one(),
+                        payout: None,
+                    }],
+                    tokens_x: None,
+                },
+            )
+        });
+
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+        ledger.set_balance(
+            Account {
+                owner: furnace_id(),
+                subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
+            },
+            PRIZE_E8S,
+        );
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::CanisterError, "The ledger is stopped");
+
+        let now = ROUND_TIMESTAMP + 1;
+
+        assert!(block_on(claim_prize_icp(&ledger, winner(), claim_req(), now)).is_err());
+        assert!(!is_claimed());
+
+        assert!(block_on(claim_prize_icp(&ledger, winner(), claim_req(), now)).is_ok());
+        assert!(is_claimed());
+        assert_eq!(
+            ledger.balance(&winner_account()),
+            Nat::from(PRIZE_E8S - ICP_FEE)
+        );
+    }
+
+    #[test]
+    fn pledge_pulls_approved_tokens_or_falls_back_to_the_subaccount() {
+        const PLEDGE_E8S: u64 = 1_0000_0000;
+
+        let token_can_id = Principal::from_slice(&[3]);
+        let redistribution = Account {
+            owner: furnace_id(),
+            subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+        };
+        let caller_subaccount = Account {
+            owner: furnace_id(),
+            subaccount: Some(Subaccount::from(winner()).0),
+        };
+
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+        ledger.set_balance(winner_account(), PLEDGE_E8S + ICP_FEE);
+        ledger.set_balance(caller_subaccount, PLEDGE_E8S + ICP_FEE);
+        ledger.approve(
+            winner_account(),
+            Account {
+                owner: furnace_id(),
+                subaccount: None,
+            },
+            PLEDGE_E8S + ICP_FEE,
+        );
+
+        let pledge = || {
+            block_on(transfer_pledged_tokens(
+                &ledger,
+                token_can_id,
+                winner(),
+                furnace_id(),
+                Nat::from(PLEDGE_E8S),
+            ))
+        };
+
+        // the approved tokens are pulled first
+        assert_eq!(pledge(), Ok(Nat::from(0u64)));
+        assert_eq!(ledger.balance(&winner_account()), Nat::from(0u64));
+
+        // nothing is approved anymore, so the subaccount is used
+        assert_eq!(pledge(), Ok(Nat::from(1u64)));
+        assert_eq!(ledger.balance(&caller_subaccount), Nat::from(0u64));
+        assert_eq!(ledger.balance(&redistribution), Nat::from(PLEDGE_E8S * 2));
+
+        assert!(pledge().is_err());
+    }
+
+    fn insert_prize_payout() {
+        STATE.with_borrow_mut(|s| {
+            s.winners.insert(
+                ROUND_TIMESTAMP,
+                FurnaceWinnerHistoryEntry {
+                    timestamp: ROUND_TIMESTAMP,
+                    token_can_id: Principal::anonymous(),
+                    pledged_usd: E8s::zero(),
+                    round: 0,
+                    prize_fund_icp: E8s::from(PRIZE_E8S),
+                    winners: vec![FurnaceWinner {
+                        pid: winner(),
+                        prize_icp: E8s::from(PRIZE_E8S),
+                        claimed: false,
+                        share_normalized: E8s::one(),
+                        payout: Some(PrizePayout::default()),
+                    }],
+                    tokens_x: None,
+                },
+            );
+
+            let mut info = s.get_furnace_info();
+            info.prize_payout_cursor = Some(ROUND_TIMESTAMP);
+            s.set_furnace_info(info);
+        });
+    }
+
+    #[test]
+    fn lost_prize_payout_is_retried_without_paying_twice() {
+        insert_prize_payout();
+
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+        ledger.set_balance(
+            Account {
+                owner: furnace_id(),
+                subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
+            },
+            PRIZE_E8S * 2,
+        );
+        ledger.lose_next_response();
+
+        let now = ROUND_TIMESTAMP + 1;
+        let (paid_out, errors) = block_on(pay_out_prizes_batch(&ledger, now));
+        assert_eq!((paid_out, errors.len()), (1, 1));
+
+        // the transfer could have been made, so the prize can't be claimed manually
+        STATE.with_borrow(|s| {
+            assert!(claim_req().validate_and_escape(s, winner(), now).is_err());
+        });
+
+        // too early to retry
+        assert_eq!(block_on(pay_out_prizes_batch(&ledger, now)).0, 0);
+
+        let now = now + PRIZE_PAYOUT_RETRY_DELAY_NS;
+        let (paid_out, errors) = block_on(pay_out_prizes_batch(&ledger, now));
+        assert_eq!((paid_out, errors.len()), (1, 0));
+
+        STATE.with_borrow(|s| {
+            let winner = &s.winners.get(&ROUND_TIMESTAMP).unwrap().winners[0];
+
+            assert_eq!(
+                winner.payout.as_ref().unwrap().status,
+                PrizePayoutStatus::Paid {
+                    block_index: Nat::from(0u64)
+                }
+            );
+            assert!(!s.has_prize_payouts_left());
+        });
+        assert_eq!(
+            ledger.balance(&winner_account()),
+            Nat::from(PRIZE_E8S - ICP_FEE)
+        );
+    }
+
+    #[test]
+    fn unknown_prize_payout_stays_reserved_until_resolved() {
+        insert_prize_payout();
+
+        let prize_account = Account {
+            owner: furnace_id(),
+            subaccount: Some(FURNACE_ICP_PRIZE_DISTRIBUTION_SUBACCOUNT),
+        };
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+
+        // a definite failure makes the prize claimable manually
+        let mut now = ROUND_TIMESTAMP + 1;
+        let (paid_out, errors) = block_on(pay_out_prizes_batch(&ledger, now));
+        assert_eq!((paid_out, errors.len()), (1, 1));
+        STATE.with_borrow(|s| {
+            assert!(claim_req().validate_and_escape(s, winner(), now).is_ok());
+        });
+
+        ledger.set_balance(prize_account, PRIZE_E8S);
+
+        // the unknown outcomes are retried past the attempts limit, with the same created_at_time
+        for _ in 0..MAX_PRIZE_PAYOUT_ATTEMPTS {
+            now += PRIZE_PAYOUT_RETRY_DELAY_NS;
+            ledger
+                .rejects
+                .reject_next_call(RejectionCode::SysTransient, "");
+
+            let (paid_out, errors) = block_on(pay_out_prizes_batch(&ledger, now));
+            assert_eq!((paid_out, errors.len()), (1, 1));
+        }
+
+        STATE.with_borrow(|s| {
+            let payout = s.winners.get(&ROUND_TIMESTAMP).unwrap().winners[0]
+                .payout
+                .clone()
+                .unwrap();
+
+            assert_eq!(
+                payout.created_at_time,
+                Some(ROUND_TIMESTAMP + 1 + PRIZE_PAYOUT_RETRY_DELAY_NS)
+            );
+            assert!(matches!(payout.status, PrizePayoutStatus::Unknown { .. }));
+            assert!(s.has_prize_payouts_left());
+            assert!(claim_req().validate_and_escape(s, winner(), now).is_err());
+        });
+
+        // out of the deduplication window the outcome can't be found out, so it is resolved manually
+        now += PRIZE_PAYOUT_DEDUP_WINDOW_NS;
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::SysTransient, "");
+        assert_eq!(block_on(pay_out_prizes_batch(&ledger, now)).0, 0);
+
+        let resolve_req = || ResolvePrizePayoutRequest {
+            winning_entry_timestamp_ns: ROUND_TIMESTAMP,
+            winner_idx: 0,
+            block_index: None,
+        };
+
+        STATE.with_borrow_mut(|s| {
+            assert!(!s.has_prize_payouts_left());
+            assert!(claim_req().validate_and_escape(s, winner(), now).is_err());
+
+            assert!(s.resolve_prize_payout(resolve_req(), now).is_ok());
+            assert!(claim_req().validate_and_escape(s, winner(), now).is_ok());
+            assert!(s.resolve_prize_payout(resolve_req(), now).is_err());
+        });
+    }
+
+    #[test]
+    fn pledge_is_refunded_if_the_round_ends_during_the_transfer() {
+        const PLEDGE_E8S: u64 = 1_0000_0000;
+
+        let token_can_id = ENV_VARS.burn_token_canister_id;
+        let caller_subaccount = Account {
+            owner: furnace_id(),
+            subaccount: Some(Subaccount::from(winner()).0),
+        };
+
+        let round = STATE.with_borrow_mut(|s| {
+            s.add_supported_token(TokenX {
+                can_id: token_can_id,
+                fee: Nat::from(ICP_FEE),
+                decimals: 8,
+            });
+
+            let mut info = s.get_furnace_info();
+            let round = info.current_round;
+            info.current_round += 1;
+            s.set_furnace_info(info);
+
+            round
+        });
+
+        let req = PledgeRequest {
+            pid: winner(),
+            token_can_id,
+            qty: Nat::from(PLEDGE_E8S),
+            downvote: false,
+        };
+
+        STATE.with_borrow_mut(|s| {
+            assert!(s.pledge(req, round, E8s::one(), Nat::from(0u64)).is_err());
+            assert!(s.cur_round_positions.get(&winner()).is_none());
+        });
+
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+        ledger.set_balance(
+            Account {
+                owner: furnace_id(),
+                subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+            },
+            PLEDGE_E8S,
+        );
+
+        assert!(block_on(refund_pledged_tokens(
+            &ledger,
+            token_can_id,
+            winner(),
+            furnace_id(),
+            Nat::from(PLEDGE_E8S),
+        ))
+        .is_ok());
+        assert_eq!(
+            ledger.balance(&caller_subaccount),
+            Nat::from(PLEDGE_E8S - ICP_FEE)
+        );
+    }
+
+    #[test]
+    fn pledged_tokens_are_redistributed_even_if_a_split_fails() {
+        let token_x = TokenX {
+            can_id: Principal::from_slice(&[3]),
+            fee: Nat::from(ICP_FEE),
+            decimals: 8,
+        };
+        let dispenser_id = Principal::from_slice(&[4]);
+
+        let redistribution = Account {
+            owner: furnace_id(),
+            subaccount: Some(FURNACE_REDISTRIBUTION_SUBACCOUNT),
+        };
+        let dispenser_account = Account {
+            owner: dispenser_id,
+            subaccount: Some(Subaccount::from(furnace_id()).0),
+        };
+        let dev = Account {
+            owner: furnace_id(),
+            subaccount: Some(FURNACE_DEV_FEE_SUBACCOUNT),
+        };
+
+        let ledger = FakeICRC1Ledger::new(furnace_id(), ICP_FEE);
+        ledger.set_balance(redistribution, 100_0000_0000);
+        let dispenser = FakeDispenser::default();
+
+        let redistribute = || {
+            block_on(redistribute_collected_token(
+                &ledger,
+                &dispenser,
+                dispenser_id,
+                token_x.clone(),
+                furnace_id(),
+                ROUND_TIMESTAMP,
+            ))
+        };
+        let burned = || {
+            STATE.with_borrow(|s| {
+                s.total_burned_tokens
+                    .get(&token_x.can_id)
+                    .map(|it| Nat(it.val))
+            })
+        };
+
+        // nothing is moved, if the balance is unknown
+        ledger
+            .rejects
+            .reject_next_call(RejectionCode::SysTransient, "");
+        assert!(redistribute().is_err());
+        assert_eq!(ledger.blocks(), 0);
+        assert_eq!(dispenser.created(), 0);
+
+        // the tokens stay on the dispenser and the other splits are still made
+        dispenser
+            .rejects
+            .reject_next_call(RejectionCode::CanisterError, "The dispenser is stopped");
+        assert_eq!(redistribute().unwrap_err().len(), 1);
+
+        assert_eq!(
+            ledger.balance(&dispenser_account),
+            Nat::from(50_0000_0000u64 - ICP_FEE)
+        );
+        assert_eq!(dispenser.created(), 0);
+        assert_eq!(burned(), Some(Nat::from(47_5000_0000u64)));
+        assert_eq!(ledger.balance(&dev), Nat::from(2_5000_0000u64 - ICP_FEE));
+        assert_eq!(ledger.balance(&redistribution), Nat::from(0u64));
+
+        ledger.set_balance(redistribution, 100_0000_0000);
+        assert!(redistribute().is_ok());
+
+        assert_eq!(dispenser.created(), 1);
+        assert_eq!(burned(), Some(Nat::from(95_0000_0000u64)));
+        assert_eq!(ledger.balance(&redistribution), Nat::from(0u64));
+    }
+    #[test]
+    fn raffle_round_is_verified_from_the_recorded_positions() {
+        let position = |i: u8| Principal::from_slice(&[10, i]);
+        let last_round = RAFFLE_ROUND_PROOFS_TO_KEEP;
+
+        STATE.with_borrow_mut(|s| {
+            s.init(furnace_id(), vec![7; 32], ROUND_TIMESTAMP);
+
+            // the proofs of the previous rounds, the oldest one with a recorded position
+            for round in 0..last_round {
+                s.raffle_round_proofs
+                    .insert(round, RaffleRoundProof::new(&[round as u8], round));
+            }
+            s.raffle_round_positions
+                .insert((0, position(0)), E8s::one().to_dynamic());
+
+            let mut info = s.get_furnace_info();
+            info.current_round = last_round;
+            info.cur_round_pledged_usd = E8s::from(15_0000_0000u64);
+            s.set_furnace_info(info);
+
+            for i in 1..=5u8 {
+                s.cur_round_positions
+                    .insert(position(i), E8s::from(i as u64 * 1_0000_0000).to_dynamic());
+            }
+
+            s.prepare_raffle(E8s::from(PRIZE_E8S));
+            while s.find_winners_batch(300) {}
+
+            // the stale position is removed first, then the positions of the round are copied
+            let mut batches = 1;
+            while s.record_raffle_positions_batch(2) {
+                batches += 1;
+            }
+            assert_eq!(batches, 4);
+            assert!(!s.raffle_round_positions.contains_key(&(0, position(0))));
+
+            s.complete_raffle(ROUND_TIMESTAMP + 1);
+
+            assert_eq!(s.raffle_round_proofs.len(), RAFFLE_ROUND_PROOFS_TO_KEEP);
+            assert!(s
+                .raffle_round_proofs
+                .get(&last_round)
+                .unwrap()
+                .positions
+                .is_empty());
+
+            let verification = s
+                .verify_raffle_round(VerifyRaffleRoundRequest { round: last_round })
+                .result
+                .unwrap();
+
+            assert!(verification.is_valid);
+            assert_eq!(verification.recomputed_winners.len(), 1);
+
+            assert!(s
+                .verify_raffle_round(VerifyRaffleRoundRequest { round: 0 })
+                .result
+                .is_err());
+        });
+    }
+}
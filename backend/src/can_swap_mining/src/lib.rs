@@ -2,13 +2,18 @@ use candid::Principal;
 use ic_cdk::{
     api::{
         call::{msg_cycles_accept128, msg_cycles_available128},
-        canister_balance128, time,
+        canister_balance128, is_controller, time,
     },
-    export_candid, init, post_upgrade, query, update,
+    caller, export_candid, init, post_upgrade, query, update,
+};
+use shared::{
+    burner::types::TCycles,
+    metrics::{serve_metrics, HttpRequest, HttpResponse},
+    timers::{self, GetTimersStatusResponse, RestartTimerRequest, RestartTimerResponse},
 };
-use shared::burner::types::TCycles;
 use utils::{
-    fetch_last_swapped_volume, generate_random_f64, get_block_winner, set_mining_interval,
+    fetch_last_swapped_volume, generate_random_f64, get_block_winner, restart_job,
+    set_mining_interval,
 };
 
 pub mod utils;
@@ -45,6 +50,29 @@ async fn emulate() -> (Vec<(Principal, f64)>, f64, f64, Principal) {
     (volumes, total, random_num, winner)
 }
 
+#[query]
+fn get_timers_status() -> GetTimersStatusResponse {
+    timers::get_timers_status(time())
+}
+
+// there is no state in this canister, so only the common metrics are served
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    serve_metrics(req, |_| {})
+}
+
+// there are no roles in this canister, so it is managed by its controllers
+#[update]
+fn restart_timer(req: RestartTimerRequest) -> RestartTimerResponse {
+    if !is_controller(&caller()) {
+        panic!("Access denied");
+    }
+
+    RestartTimerResponse {
+        result: restart_job(&req.job),
+    }
+}
+
 #[init]
 fn init_hook() {
     set_mining_interval();
@@ -8,10 +8,13 @@ use ic_cdk::{
     api::{management_canister::main::raw_rand, time},
     print, spawn,
 };
-use ic_cdk_timers::set_timer_interval;
 use ic_e8s::c::E8s;
 use shared::{
-    burner::{client::BurnerClient, types::TimestampNs},
+    burner::{
+        client::{BurnerApi, BurnerClient},
+        types::TimestampNs,
+    },
+    timers::{note_job_finished, prepare_job_restart, schedule_job_interval},
     trading::types::PriceInfo,
     ENV_VARS, ONE_DAY_NS, ONE_HOUR_NS,
 };
@@ -19,8 +22,23 @@ use shared::{
 pub const BURN_PER_HOUR_E8S: u64 = 30_0000_0000;
 pub const ROUNDS_PER_HOUR: u64 = 2; // once every 30 minutes
 
+pub const JOB_MINING_ROUND: &str = "run_mining_round";
+
+// only stuck jobs can be restarted, see `prepare_job_restart`
+pub fn restart_job(job: &str) -> Result<(), String> {
+    if job != JOB_MINING_ROUND {
+        return Err(format!("Unknown job {}", job));
+    }
+
+    prepare_job_restart(job, time())?;
+    set_mining_interval();
+
+    Ok(())
+}
+
 pub fn set_mining_interval() {
-    set_timer_interval(
+    schedule_job_interval(
+        JOB_MINING_ROUND,
         Duration::from_nanos(ONE_HOUR_NS / ROUNDS_PER_HOUR),
         spawn_mining_round,
     );
@@ -34,6 +52,7 @@ pub async fn run_mining_round() {
     let (volumes, total) = fetch_last_swapped_volume(1000, time()).await;
     if total == 0f64 {
         print("No transaction fitting the criteria found, skipping");
+        note_job_finished(JOB_MINING_ROUND);
         return;
     }
 
@@ -42,6 +61,7 @@ pub async fn run_mining_round() {
     let winner = get_block_winner(&volumes, total, random_num);
 
     mint_burn(winner, Nat::from(BURN_PER_HOUR_E8S / ROUNDS_PER_HOUR)).await;
+    note_job_finished(JOB_MINING_ROUND);
 }
 
 pub async fn generate_random_f64() -> f64 {
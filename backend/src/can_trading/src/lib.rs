@@ -1,4 +1,4 @@
-use std::{cell::RefCell, collections::BTreeMap};
+use std::collections::BTreeMap;
 
 use candid::{Nat, Principal};
 use ic_cdk::{
@@ -12,33 +12,51 @@ use ic_e8s::c::E8s;
 use ic_ledger_types::Subaccount;
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 use shared::{
-    burner::{client::BurnerClient, types::TCycles},
-    icrc1::ICRC1CanisterClient,
+    access::{
+        GetAccessLogRequest, GetAccessLogResponse, GrantRoleRequest, GrantRoleResponse,
+        RevokeRoleRequest, RevokeRoleResponse, Role,
+    },
+    burner::{
+        client::{BurnerApi, BurnerClient},
+        types::TCycles,
+    },
+    feature_flags::{FeatureFlag, SetFeatureFlagRequest, SetFeatureFlagResponse},
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    metrics::{ecs_to_f64, serve_metrics, HttpRequest, HttpResponse},
+    timers::{self, GetTimersStatusResponse, RestartTimerRequest, RestartTimerResponse},
     trading::{
         api::{GetPriceHistoryRequest, OrderRequest},
-        types::{BalancesInfo, Candle, Order, PriceInfo, TraderStats},
+        types::{
+            BalancesInfo, Candle, Order, PriceInfo, TraderStats, FEATURE_DEPOSIT, FEATURE_ORDER,
+            FEATURE_REGISTER, FEATURE_WITHDRAW, TRADING_FEATURES,
+        },
     },
     ENV_VARS,
 };
-use utils::{set_fetch_total_supply_timer, set_produce_new_price_timer, STATE};
+use utils::{
+    assert_caller_has_role, assert_feature_enabled, restart_job, set_fetch_total_supply_timer,
+    set_produce_new_price_timer, STATE,
+};
 
 mod utils;
 
 #[update]
 fn order(req: OrderRequest) -> Order {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_ORDER);
 
     let user_pid = caller();
     let now = time();
 
     STATE.with_borrow_mut(|s| {
+        let dev_pid = s.get_price_info().dev_pid.expect("The dev is not set");
+
         s.order(
             user_pid,
             req.sell,
             req.short,
             req.qty,
             req.expected_price,
-            DEV.with_borrow(|s| *s),
+            dev_pid,
             now,
         )
     })
@@ -46,7 +64,7 @@ fn order(req: OrderRequest) -> Order {
 
 #[update]
 async fn deposit(qty: E8s) {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_DEPOSIT);
 
     let user_pid = caller();
     let user_subaccount = subaccount_of(user_pid);
@@ -75,7 +93,7 @@ async fn deposit(qty: E8s) {
 
 #[update]
 async fn withdraw() -> Result<(), String> {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_WITHDRAW);
 
     let user_pid = caller();
     let user_qty = STATE.with_borrow_mut(|s| s.withdraw(user_pid));
@@ -131,7 +149,7 @@ async fn withdraw_from_user_subaccount(token_can_id: Principal, qty: E8s) {
 
 #[update]
 fn register(pid: Principal, inviter: Option<Principal>) {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_REGISTER);
 
     let c = caller();
     if c != ENV_VARS.trading_invites_canister_id {
@@ -202,14 +220,103 @@ fn get_cycles_balance() -> TCycles {
         .to_const()
 }
 
-thread_local! {
-    static DEV: RefCell<Principal> = RefCell::new(Principal::management_canister());
+#[query]
+fn get_timers_status() -> GetTimersStatusResponse {
+    timers::get_timers_status(time())
+}
+
+#[update]
+fn restart_timer(req: RestartTimerRequest) -> RestartTimerResponse {
+    assert_caller_has_role(Role::Operator);
+
+    RestartTimerResponse {
+        result: restart_job(&req.job),
+    }
+}
+
+#[update]
+fn set_feature_flag(req: SetFeatureFlagRequest) -> SetFeatureFlagResponse {
+    assert_caller_has_role(Role::Operator);
+
+    let result = STATE.with_borrow_mut(|s| s.feature_flags.set_feature_flag(req, caller(), time()));
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_feature_flags() -> Vec<(String, FeatureFlag)> {
+    STATE.with_borrow(|s| s.feature_flags.get_feature_flags(time()))
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    serve_metrics(req, |m| {
+        STATE.with_borrow(|s| {
+            let price_info = s.get_price_info();
+
+            m.gauge(
+                "trading_balances_count",
+                "The number of accounts in the balances map",
+                s.balances.len() as f64,
+            );
+            m.gauge(
+                "trading_stats_count",
+                "The number of accounts which have traded at least once",
+                s.stats.len() as f64,
+            );
+            m.gauge(
+                "trading_cur_step",
+                "The index of the current price step",
+                price_info.cur_step as f64,
+            );
+            m.gauge(
+                "trading_cur_long_price",
+                "The current price of the long token",
+                price_info.cur_long_price,
+            );
+            m.gauge(
+                "trading_cur_short_price",
+                "The current price of the short token",
+                price_info.cur_short_price,
+            );
+            m.gauge(
+                "trading_total_supply",
+                "The total supply of the underlying token",
+                ecs_to_f64(&price_info.total_supply),
+            );
+        })
+    })
+}
+
+#[update]
+fn grant_role(req: GrantRoleRequest) -> GrantRoleResponse {
+    let result =
+        STATE.with_borrow_mut(|s| s.access.grant_role(caller(), req.pid, req.role, time()));
+
+    GrantRoleResponse { result }
+}
+
+#[update]
+fn revoke_role(req: RevokeRoleRequest) -> RevokeRoleResponse {
+    let result = STATE.with_borrow_mut(|s| s.access.revoke_role(caller(), req.pid, time()));
+
+    RevokeRoleResponse { result }
+}
+
+#[query]
+fn get_roles() -> Vec<(Principal, Role)> {
+    STATE.with_borrow(|s| s.access.get_roles())
+}
+
+#[query]
+fn get_access_log(req: GetAccessLogRequest) -> GetAccessLogResponse {
+    assert_caller_has_role(Role::Auditor);
+
+    STATE.with_borrow(|s| s.access.get_access_log(req))
 }
 
 #[init]
 fn init_hook() {
-    DEV.with_borrow_mut(|d| *d = caller());
-
     set_produce_new_price_timer();
     set_fetch_total_supply_timer();
 
@@ -217,6 +324,8 @@ fn init_hook() {
         let now = time();
         let mut info = s.get_price_info();
 
+        s.access.bootstrap(caller(), now);
+        s.feature_flags.init_disabled(TRADING_FEATURES, now);
         s.register(caller(), None);
 
         info.cur_1d_long_candle.open_ts = now;
@@ -231,17 +340,26 @@ fn init_hook() {
         info.cur_4h_short_candle.open_ts = now;
         info.cur_4h_short_candle.close_ts = now;
 
+        info.dev_pid = Some(caller());
+
         s.set_price_info(info);
     });
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
-    DEV.with_borrow_mut(|d| *d = caller());
-
     STATE.with_borrow_mut(|s| {
-        if !s.balances.contains_key(&caller()) {
-            s.register(caller(), None);
+        // the dev wasn't persisted before, so the first upgrade after the roles were introduced
+        // makes the upgrader both the dev and the owner
+        let mut info = s.get_price_info();
+        let dev_pid = *info.dev_pid.get_or_insert(caller());
+        s.set_price_info(info);
+
+        s.access.bootstrap(dev_pid, time());
+        s.feature_flags.init_disabled(TRADING_FEATURES, time());
+
+        if !s.balances.contains_key(&dev_pid) {
+            s.register(dev_pid, None);
         }
     });
 }
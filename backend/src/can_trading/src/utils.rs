@@ -2,16 +2,18 @@ use std::{cell::RefCell, time::Duration};
 
 use ic_cdk::{
     api::{management_canister::main::raw_rand, time},
-    spawn,
+    caller, spawn,
 };
-use ic_cdk_timers::set_timer_interval;
 use ic_e8s::c::E8s;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
     Cell, DefaultMemoryImpl, StableBTreeMap, StableVec,
 };
 use shared::{
-    icrc1::ICRC1CanisterClient,
+    access::{AccessControl, Role},
+    feature_flags::FeatureFlags,
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
+    timers::{note_job_failed, note_job_finished, prepare_job_restart, schedule_job_interval},
     trading::{
         state::TradingState,
         types::{OrderHistory, PriceInfo, PRICE_UPDATE_DELAY_NS},
@@ -37,40 +39,93 @@ thread_local! {
 
             order_history: Cell::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(7))), OrderHistory::default()).expect("Unable to create order history cell"),
             fees_received: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(8))),),
+
+            access: AccessControl {
+                roles: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(10))),),
+                log: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(11))),),
+            },
+            feature_flags: FeatureFlags {
+                flags: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(9))),),
+            },
         }
     );
 }
 
+pub fn assert_caller_has_role(role: Role) {
+    STATE
+        .with_borrow(|s| s.access.assert_role(&caller(), role))
+        .expect("Access denied");
+}
+
+pub fn assert_feature_enabled(feature: &str) {
+    let result = STATE.with_borrow(|s| s.feature_flags.assert_enabled(feature, time()));
+
+    if let Err(reason) = result {
+        panic!("{}", reason);
+    }
+}
+
+pub const JOB_PRODUCE_NEW_PRICE: &str = "produce_new_price";
+pub const JOB_FETCH_TOTAL_SUPPLY: &str = "fetch_total_supply";
+
+// only stuck jobs can be restarted, see `prepare_job_restart`
+pub fn restart_job(job: &str) -> Result<(), String> {
+    let restart: fn() = match job {
+        JOB_PRODUCE_NEW_PRICE => set_produce_new_price_timer,
+        JOB_FETCH_TOTAL_SUPPLY => set_fetch_total_supply_timer,
+        _ => return Err(format!("Unknown job {}", job)),
+    };
+
+    prepare_job_restart(job, time())?;
+    restart();
+
+    Ok(())
+}
+
 pub fn set_produce_new_price_timer() {
-    set_timer_interval(Duration::from_nanos(PRICE_UPDATE_DELAY_NS), || {
-        spawn(produce_new_price())
-    });
+    schedule_job_interval(
+        JOB_PRODUCE_NEW_PRICE,
+        Duration::from_nanos(PRICE_UPDATE_DELAY_NS),
+        || spawn(produce_new_price()),
+    );
 }
 
 async fn produce_new_price() {
-    let (rand,) = raw_rand()
-        .await
-        .expect("Unable to produce a new random number");
+    let (rand,) = match raw_rand().await {
+        Ok(it) => it,
+        Err((c, m)) => {
+            note_job_failed(JOB_PRODUCE_NEW_PRICE, format!("{:?}: {}", c, m));
+            return;
+        }
+    };
 
     STATE.with_borrow_mut(|s| s.increment_prices(rand, time()));
+    note_job_finished(JOB_PRODUCE_NEW_PRICE);
 }
 
 pub fn set_fetch_total_supply_timer() {
-    set_timer_interval(Duration::from_nanos(ONE_DAY_NS), || {
-        spawn(fetch_burn_total_supply());
-    });
+    schedule_job_interval(
+        JOB_FETCH_TOTAL_SUPPLY,
+        Duration::from_nanos(ONE_DAY_NS),
+        || spawn(fetch_burn_total_supply()),
+    );
 }
 
 async fn fetch_burn_total_supply() {
     let burn_token = ICRC1CanisterClient::new(ENV_VARS.burn_token_canister_id);
-    let (total_supply,) = burn_token
-        .icrc1_total_supply()
-        .await
-        .expect("Unable to fetch $BURN total supply");
+    let (total_supply,) = match burn_token.icrc1_total_supply().await {
+        Ok(it) => it,
+        Err((c, m)) => {
+            note_job_failed(JOB_FETCH_TOTAL_SUPPLY, format!("{:?}: {}", c, m));
+            return;
+        }
+    };
 
     STATE.with_borrow_mut(|s| {
         let mut info = s.get_price_info();
         info.total_supply = E8s::new(total_supply.0);
         s.set_price_info(info);
     });
+
+    note_job_finished(JOB_FETCH_TOTAL_SUPPLY);
 }
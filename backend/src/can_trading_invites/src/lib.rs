@@ -6,6 +6,7 @@ use ic_cdk::{
         call::{msg_cycles_accept128, msg_cycles_available128},
         canister_balance128,
         management_canister::main::raw_rand,
+        time,
     },
     caller, export_candid, id, init, post_upgrade, query, update,
 };
@@ -13,13 +14,21 @@ use ic_cdk::{
 use ic_ledger_types::Subaccount;
 use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
 use shared::{
+    access::{
+        GetAccessLogRequest, GetAccessLogResponse, GrantRoleRequest, GrantRoleResponse,
+        RevokeRoleRequest, RevokeRoleResponse, Role,
+    },
     burner::types::TCycles,
-    icrc1::ICRC1CanisterClient,
+    feature_flags::{FeatureFlag, SetFeatureFlagRequest, SetFeatureFlagResponse},
+    icrc1::{ICRC1Api, ICRC1CanisterClient},
     trading::client::TradingClient,
-    trading_invites::types::{Invite, MemberInfo},
+    trading_invites::types::{
+        Invite, MemberInfo, FEATURE_REGISTER_WITH_BRIBE, FEATURE_REGISTER_WITH_INVITE,
+        FEATURE_UPDATE_MY_INVITE, FEATURE_WITHDRAW_FROM_USER_SUBACCOUNT, TRADING_INVITES_FEATURES,
+    },
     ENV_VARS,
 };
-use utils::STATE;
+use utils::{assert_feature_enabled, STATE};
 
 mod utils;
 
@@ -35,13 +44,17 @@ fn get_invite_owner(invite: Invite) -> Option<Principal> {
 
 #[update]
 async fn update_my_invite() -> Invite {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_UPDATE_MY_INVITE);
 
     let pid = caller();
-    let is_member = STATE.with_borrow(|s| s.members.get(&pid)).is_some();
-    let is_dev = pid == DEV.with_borrow(|s| *s);
+    let (is_member, is_operator) = STATE.with_borrow(|s| {
+        (
+            s.members.get(&pid).is_some(),
+            s.access.has_role(&pid, Role::Operator),
+        )
+    });
 
-    if !is_member && !is_dev {
+    if !is_member && !is_operator {
         panic!("Access denied");
     }
 
@@ -66,7 +79,7 @@ async fn update_my_invite() -> Invite {
 
 #[update]
 async fn register_with_invite(invite: Invite) {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_REGISTER_WITH_INVITE);
 
     let pid = caller();
     let is_member = STATE.with_borrow(|s| s.members.get(&pid)).is_some();
@@ -93,7 +106,7 @@ async fn register_with_invite(invite: Invite) {
 
 #[update]
 async fn register_with_bribe() {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_REGISTER_WITH_BRIBE);
 
     let user_pid = caller();
     let user_subaccount = subaccount_of(user_pid);
@@ -139,7 +152,7 @@ async fn register_with_bribe() {
 
 #[update]
 async fn withdraw_from_user_subaccount(token_can_id: Principal) {
-    panic!("Temporarily unavailable");
+    assert_feature_enabled(FEATURE_WITHDRAW_FROM_USER_SUBACCOUNT);
 
     let user_pid = caller();
     let user_subaccount = subaccount_of(user_pid);
@@ -174,6 +187,51 @@ async fn withdraw_from_user_subaccount(token_can_id: Principal) {
         .expect("Unable to transfer");
 }
 
+#[update]
+fn set_feature_flag(req: SetFeatureFlagRequest) -> SetFeatureFlagResponse {
+    STATE
+        .with_borrow(|s| s.access.assert_role(&caller(), Role::Operator))
+        .expect("Access denied");
+
+    let result = STATE.with_borrow_mut(|s| s.feature_flags.set_feature_flag(req, caller(), time()));
+
+    SetFeatureFlagResponse { result }
+}
+
+#[query]
+fn get_feature_flags() -> Vec<(String, FeatureFlag)> {
+    STATE.with_borrow(|s| s.feature_flags.get_feature_flags(time()))
+}
+
+#[update]
+fn grant_role(req: GrantRoleRequest) -> GrantRoleResponse {
+    let result =
+        STATE.with_borrow_mut(|s| s.access.grant_role(caller(), req.pid, req.role, time()));
+
+    GrantRoleResponse { result }
+}
+
+#[update]
+fn revoke_role(req: RevokeRoleRequest) -> RevokeRoleResponse {
+    let result = STATE.with_borrow_mut(|s| s.access.revoke_role(caller(), req.pid, time()));
+
+    RevokeRoleResponse { result }
+}
+
+#[query]
+fn get_roles() -> Vec<(Principal, Role)> {
+    STATE.with_borrow(|s| s.access.get_roles())
+}
+
+#[query]
+fn get_access_log(req: GetAccessLogRequest) -> GetAccessLogResponse {
+    STATE
+        .with_borrow(|s| s.access.assert_role(&caller(), Role::Auditor))
+        .expect("Access denied");
+
+    STATE.with_borrow(|s| s.access.get_access_log(req))
+}
+
 #[query]
 fn subaccount_of(pid: Principal) -> Subaccount {
     Subaccount::from(pid)
@@ -198,17 +256,29 @@ fn get_cycles_balance() -> TCycles {
 }
 
 thread_local! {
+    // only receives the bribes, access is controlled by the roles
     static DEV: RefCell<Principal> = RefCell::new(Principal::management_canister());
 }
 
 #[init]
 fn init_hook() {
     DEV.with_borrow_mut(|s| *s = caller());
+    STATE.with_borrow_mut(|s| {
+        s.access.bootstrap(caller(), time());
+        s.feature_flags
+            .init_disabled(TRADING_INVITES_FEATURES, time());
+    });
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
     DEV.with_borrow_mut(|s| *s = caller());
+    // the first upgrade after the roles were introduced makes the upgrader the owner
+    STATE.with_borrow_mut(|s| {
+        s.access.bootstrap(caller(), time());
+        s.feature_flags
+            .init_disabled(TRADING_INVITES_FEATURES, time());
+    });
 }
 
 export_candid!();
@@ -1,10 +1,12 @@
 use std::cell::RefCell;
 
+use ic_cdk::api::time;
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager}, DefaultMemoryImpl, StableBTreeMap,
+    memory_manager::{MemoryId, MemoryManager},
+    DefaultMemoryImpl, StableBTreeMap,
 };
 use shared::{
-    trading_invites::state::TradingInvitesState,
+    access::AccessControl, feature_flags::FeatureFlags, trading_invites::state::TradingInvitesState,
 };
 
 thread_local! {
@@ -15,6 +17,22 @@ thread_local! {
         TradingInvitesState {
             members: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(0))),),
             invites: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(1))),),
+
+            access: AccessControl {
+                roles: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(2))),),
+                log: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(3))),),
+            },
+            feature_flags: FeatureFlags {
+                flags: StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(MemoryId::new(4))),),
+            },
         }
     );
 }
+
+pub fn assert_feature_enabled(feature: &str) {
+    let result = STATE.with_borrow(|s| s.feature_flags.assert_enabled(feature, time()));
+
+    if let Err(reason) = result {
+        panic!("{}", reason);
+    }
+}
@@ -0,0 +1,221 @@
+use candid::{decode_one, encode_one, CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+
+use crate::burner::types::TimestampNs;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Roles are ordered - each role is also allowed to do everything the roles below it can do.
+///
+/// `Owner` manages roles and funds, `Operator` runs the day-to-day maintenance (pausing, toggling
+/// features, starting rounds), `Auditor` can only read the privileged data (like the audit log).
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Auditor,
+    Operator,
+    Owner,
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AccessLogAction {
+    // the first owner, set on init or migrated from the previous admin model
+    Bootstrapped { pid: Principal },
+    Granted { pid: Principal, role: Role },
+    Revoked { pid: Principal, role: Role },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccessLogEntry {
+    pub timestamp: TimestampNs,
+    pub caller: Principal,
+    pub action: AccessLogAction,
+}
+
+impl Storable for AccessLogEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub struct AccessControl {
+    pub roles: StableBTreeMap<Principal, Role, Memory>,
+    pub log: StableBTreeMap<u64, AccessLogEntry, Memory>,
+}
+
+impl AccessControl {
+    /// Makes the provided principal the owner, but only if there are no roles yet.
+    /// Safe to call on each upgrade - this is how canisters migrate from the old "whoever
+    /// upgraded is the dev" model.
+    pub fn bootstrap(&mut self, owner: Principal, now: TimestampNs) {
+        if !self.roles.is_empty() {
+            return;
+        }
+
+        self.roles.insert(owner, Role::Owner);
+        self.log_action(owner, AccessLogAction::Bootstrapped { pid: owner }, now);
+    }
+
+    pub fn get_role(&self, pid: &Principal) -> Option<Role> {
+        self.roles.get(pid)
+    }
+
+    pub fn has_role(&self, pid: &Principal, role: Role) -> bool {
+        self.get_role(pid).is_some_and(|it| it >= role)
+    }
+
+    pub fn assert_role(&self, pid: &Principal, role: Role) -> Result<(), String> {
+        if self.has_role(pid, role) {
+            Ok(())
+        } else {
+            Err(String::from("Access denied"))
+        }
+    }
+
+    pub fn grant_role(
+        &mut self,
+        caller: Principal,
+        pid: Principal,
+        role: Role,
+        now: TimestampNs,
+    ) -> Result<(), String> {
+        self.assert_role(&caller, Role::Owner)?;
+
+        if pid == Principal::anonymous() {
+            return Err(String::from(
+                "Unable to grant a role to the anonymous principal",
+            ));
+        }
+
+        if let Some(prev_role) = self.get_role(&pid) {
+            if prev_role == role {
+                return Err(String::from("The principal already has this role"));
+            }
+
+            if prev_role == Role::Owner && self.count_owners() == 1 {
+                return Err(String::from("Unable to demote the last owner"));
+            }
+        }
+
+        self.roles.insert(pid, role);
+        self.log_action(caller, AccessLogAction::Granted { pid, role }, now);
+
+        Ok(())
+    }
+
+    pub fn revoke_role(
+        &mut self,
+        caller: Principal,
+        pid: Principal,
+        now: TimestampNs,
+    ) -> Result<(), String> {
+        self.assert_role(&caller, Role::Owner)?;
+
+        let role = self
+            .get_role(&pid)
+            .ok_or(String::from("The principal has no role"))?;
+
+        if role == Role::Owner && self.count_owners() == 1 {
+            return Err(String::from("Unable to revoke the last owner"));
+        }
+
+        self.roles.remove(&pid);
+        self.log_action(caller, AccessLogAction::Revoked { pid, role }, now);
+
+        Ok(())
+    }
+
+    pub fn get_roles(&self) -> Vec<(Principal, Role)> {
+        self.roles.iter().collect()
+    }
+
+    pub fn get_access_log(&self, req: GetAccessLogRequest) -> GetAccessLogResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.log.range(&start_from..);
+            i.next();
+            i
+        } else {
+            self.log.iter()
+        };
+
+        GetAccessLogResponse {
+            entries: iter.take(req.take as usize).collect(),
+        }
+    }
+
+    fn count_owners(&self) -> usize {
+        self.roles
+            .iter()
+            .filter(|(_, role)| *role == Role::Owner)
+            .count()
+    }
+
+    fn log_action(&mut self, caller: Principal, action: AccessLogAction, now: TimestampNs) {
+        let id = self
+            .log
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        self.log.insert(
+            id,
+            AccessLogEntry {
+                timestamp: now,
+                caller,
+                action,
+            },
+        );
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GrantRoleRequest {
+    pub pid: Principal,
+    pub role: Role,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GrantRoleResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RevokeRoleRequest {
+    pub pid: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RevokeRoleResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccessLogRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccessLogResponse {
+    pub entries: Vec<(u64, AccessLogEntry)>,
+}
@@ -1,10 +1,16 @@
 use candid::{CandidType, Nat, Principal};
 use ic_e8s::c::E8s;
 use ic_ledger_types::AccountIdentifier;
+use icrc_ledger_types::icrc1::{account::Account, transfer::Memo};
 
 use serde::Deserialize;
 
-use super::types::{TCycles, TimestampNs};
+use super::types::{
+    AccountEvent, AccountTransferProposal, AccountTransferRecord, EmissionSchedule,
+    KamikazeRoundVerification, KamikazeTier, LotteryRoundVerification, RedistributionDestination,
+    RoundSummary, SharesSnapshot, SharesSnapshotEntry, SpikeEvent, TCycles, TimestampNs,
+};
+use crate::outbox::OutboxTransfer;
 
 #[derive(CandidType, Deserialize)]
 pub struct GetBurnersRequest {
@@ -12,7 +18,7 @@ pub struct GetBurnersRequest {
     pub take: u32,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct BurnerInfo {
     pub pid: Principal,
     pub share: TCycles,
@@ -32,11 +38,12 @@ pub struct GetKamikazesRequest {
     pub take: u32,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct KamikazeInfo {
     pub pid: Principal,
     pub share: TCycles,
     pub created_at: TimestampNs,
+    pub tier: KamikazeTier,
     pub rounds_won: u64,
 }
 
@@ -68,6 +75,7 @@ pub struct GetTotalsResponse {
 
     pub your_kamikaze_share_tcycles: TCycles,
     pub your_kamikaze_position_created_at: Option<u64>,
+    pub your_kamikaze_position_tier: Option<KamikazeTier>,
     pub your_share_tcycles: TCycles,
     pub your_unclaimed_reward_e8s: E8s,
     pub your_decide_id_verification_status: bool,
@@ -93,7 +101,10 @@ pub struct RefundLostTokensResponse {
 
 #[derive(CandidType, Deserialize)]
 pub struct ClaimRewardRequest {
-    pub to: Principal,
+    pub to: Account,
+    // the whole unclaimed reward is claimed, if not set
+    pub qty: Option<E8s>,
+    pub memo: Option<Memo>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -104,10 +115,22 @@ pub struct ClaimRewardResponse {
 #[derive(CandidType, Deserialize)]
 pub struct StakeRequest {
     pub qty_e8s_u64: u64,
+    // used as a deduplication key - retrying with the same value is a no-op
+    pub created_at_time: TimestampNs,
+    // only used by kamikaze stakes, defaults to one day
+    pub kamikaze_tier: Option<KamikazeTier>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StakeReceipt {
+    pub block_idx: Nat,
+    pub shares_minted: TCycles,
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct StakeResponse {}
+pub struct StakeResponse {
+    pub result: Result<StakeReceipt, String>,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct WithdrawRequest {
@@ -126,7 +149,9 @@ pub struct VerifyDecideIdRequest {
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct VerifyDecideIdResponse {}
+pub struct VerifyDecideIdResponse {
+    pub result: Result<(), String>,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct MigrateMsqAccountRequest {
@@ -135,3 +160,216 @@ pub struct MigrateMsqAccountRequest {
 
 #[derive(CandidType, Deserialize)]
 pub struct MigrateMsqAccountResponse {}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountHistoryRequest {
+    pub pid: Principal,
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountHistoryResponse {
+    pub entries: Vec<(u64, AccountEvent)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetRoundHistoryRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetRoundHistoryResponse {
+    pub entries: Vec<RoundSummary>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetEmissionScheduleRequest {
+    pub schedule: EmissionSchedule,
+    pub activate_at_round: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetEmissionScheduleResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetEmissionProjectionRequest {
+    pub rounds: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetEmissionProjectionResponse {
+    pub schedule: EmissionSchedule,
+    pub next_schedule: Option<(u64, EmissionSchedule)>,
+    pub entries: Vec<(u64, E8s)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyKamikazeRoundRequest {
+    pub round: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyKamikazeRoundResponse {
+    pub result: Result<KamikazeRoundVerification, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyLotteryRoundRequest {
+    pub round: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyLotteryRoundResponse {
+    pub result: Result<LotteryRoundVerification, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetRedistributionsRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RedistributionSplitInfo {
+    pub destination: RedistributionDestination,
+    pub qty_e8s: u64,
+    pub transfer: Option<OutboxTransfer>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RedistributionInfo {
+    pub id: u64,
+    pub timestamp: TimestampNs,
+    pub balance_e8s: u64,
+    pub reserved_e8s: u64,
+    pub splits: Vec<RedistributionSplitInfo>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetRedistributionsResponse {
+    pub entries: Vec<RedistributionInfo>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSpikesRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSpikesResponse {
+    pub entries: Vec<(u64, SpikeEvent)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProposeAccountTransferRequest {
+    pub to: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProposeAccountTransferResponse {
+    pub result: Result<AccountTransferProposal, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AcceptAccountTransferRequest {
+    pub from: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AcceptAccountTransferResponse {
+    pub result: Result<AccountTransferRecord, String>,
+}
+
+// can be called by both sides of the transfer
+#[derive(CandidType, Deserialize)]
+pub struct CancelAccountTransferRequest {
+    pub from: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CancelAccountTransferResponse {
+    pub result: Result<(), String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransferProposalRequest {
+    pub from: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransferProposalResponse {
+    pub proposal: Option<AccountTransferProposal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransfersRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAccountTransfersResponse {
+    pub entries: Vec<(u64, AccountTransferRecord)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RequestSharesSnapshotResponse {
+    // none, if the snapshot is postponed until the current round completes;
+    // the snapshot is only listed once it is built, before the next round starts
+    pub snapshot_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetSharesSnapshotIntervalRequest {
+    pub interval_rounds: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSharesSnapshotsRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSharesSnapshotsResponse {
+    pub entries: Vec<(u64, SharesSnapshot)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSharesSnapshotChunkRequest {
+    pub snapshot_id: u64,
+    pub chunk_idx: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SharesSnapshotChunk {
+    pub entries: Vec<SharesSnapshotEntry>,
+    pub chunk_hash: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetSharesSnapshotChunkResponse {
+    pub result: Result<SharesSnapshotChunk, String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CertifiedBurnerTotals {
+    pub total_share_supply: TCycles,
+    pub total_tcycles_burned: TCycles,
+    pub total_burn_token_minted: E8s,
+    pub current_pos_round: u64,
+    // (snapshot id, merkle root) of each kept shares snapshot
+    pub shares_snapshot_roots: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetTotalsCertifiedResponse {
+    pub totals: CertifiedBurnerTotals,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
+}
@@ -3,28 +3,36 @@ use ic_cdk::{api::call::CallResult, call};
 use ic_e8s::c::E8s;
 
 use super::api::{
-    GetBurnersRequest, GetBurnersResponse, GetKamikazesRequest, GetKamikazesResponse,
+    GetBurnersRequest, GetBurnersResponse, GetKamikazesRequest, GetKamikazesResponse, StakeRequest,
+    StakeResponse,
 };
 
+pub trait BurnerApi {
+    async fn stake(&self, req: StakeRequest) -> CallResult<(StakeResponse,)>;
+
+    async fn get_burners(&self, req: GetBurnersRequest) -> CallResult<(GetBurnersResponse,)>;
+
+    async fn get_kamikazes(&self, req: GetKamikazesRequest) -> CallResult<(GetKamikazesResponse,)>;
+
+    async fn mint(&self, pid: Principal, qty: E8s) -> CallResult<()>;
+}
+
 pub struct BurnerClient(pub Principal);
 
-impl BurnerClient {
-    pub async fn stake(&self, qty_e8s_u64: u64) -> CallResult<()> {
-        call(self.0, "stake", (qty_e8s_u64,)).await
+impl BurnerApi for BurnerClient {
+    async fn stake(&self, req: StakeRequest) -> CallResult<(StakeResponse,)> {
+        call(self.0, "stake", (req,)).await
     }
 
-    pub async fn get_burners(&self, req: GetBurnersRequest) -> CallResult<(GetBurnersResponse,)> {
+    async fn get_burners(&self, req: GetBurnersRequest) -> CallResult<(GetBurnersResponse,)> {
         call(self.0, "get_burners", (req,)).await
     }
 
-    pub async fn get_kamikazes(
-        &self,
-        req: GetKamikazesRequest,
-    ) -> CallResult<(GetKamikazesResponse,)> {
+    async fn get_kamikazes(&self, req: GetKamikazesRequest) -> CallResult<(GetKamikazesResponse,)> {
         call(self.0, "get_kamikazes", (req,)).await
     }
 
-    pub async fn mint(&self, pid: Principal, qty: E8s) -> CallResult<()> {
+    async fn mint(&self, pid: Principal, qty: E8s) -> CallResult<()> {
         call(self.0, "mint", (pid, qty)).await
     }
 }
@@ -1,29 +1,85 @@
-use candid::{decode_one, encode_one, Principal};
+use candid::{decode_one, encode_one, Nat, Principal};
 
 use ic_e8s::c::{E8s, ECs};
 use ic_stable_structures::{storable::Bound, Cell, StableBTreeMap, Storable};
+use icrc_ledger_types::icrc1::account::Account;
 
 use super::{
     api::{
-        BurnerInfo, GetBurnersRequest, GetBurnersResponse, GetKamikazesRequest,
-        GetKamikazesResponse, GetTotalsResponse, KamikazeInfo,
+        BurnerInfo, CertifiedBurnerTotals, GetAccountHistoryRequest, GetAccountHistoryResponse,
+        GetAccountTransferProposalRequest, GetAccountTransferProposalResponse,
+        GetAccountTransfersRequest, GetAccountTransfersResponse, GetBurnersRequest,
+        GetBurnersResponse, GetEmissionProjectionRequest, GetEmissionProjectionResponse,
+        GetKamikazesRequest, GetKamikazesResponse, GetRedistributionsRequest,
+        GetRedistributionsResponse, GetRoundHistoryRequest, GetRoundHistoryResponse,
+        GetSharesSnapshotChunkRequest, GetSharesSnapshotChunkResponse, GetSharesSnapshotsRequest,
+        GetSharesSnapshotsResponse, GetSpikesRequest, GetSpikesResponse, GetTotalsResponse,
+        KamikazeInfo, RedistributionInfo, RedistributionSplitInfo, SharesSnapshotChunk,
+        StakeReceipt, StakeRequest, VerifyKamikazeRoundRequest, VerifyKamikazeRoundResponse,
+        VerifyLotteryRoundRequest, VerifyLotteryRoundResponse,
     },
     types::{
-        BurnerStateInfo, Memory, TCycles, TimestampNs, KAMIKAZE_POOL_POSITION_LIFESPAN_NS,
-        TCYCLE_POS_ROUND_BASE_FEE,
+        AccountEvent, AccountEventKind, AccountTransferOutcome, AccountTransferProposal,
+        AccountTransferRecord, BurnerStateInfo, KamikazePosition, KamikazeRoundProof, KamikazeTier,
+        LotteryRoundProof, Memory, RedistributionDestination, RedistributionRecord,
+        RedistributionSplit, RoundSummary, SharesSnapshot, SharesSnapshotEntry,
+        SharesSnapshotProgress, SpikeEvent, SpikeStatus, StakeKind, StakeRecord, TCycles,
+        TimestampNs, ACCOUNT_TRANSFER_WINDOW_NS, BURNER_DEV_FEE_SUBACCOUNT,
+        BURNER_REDISTRIBUTION_SUBACCOUNT, BURNER_SPIKE_SUBACCOUNT, KAMIKAZE_ROUND_PROOFS_TO_KEEP,
+        LOTTERY_ROUND_PROOFS_TO_KEEP, MAX_ACCOUNT_HISTORY_ENTRIES_PER_REQUEST,
+        MAX_EMISSION_PROJECTION_ROUNDS, MAX_ROUND_HISTORY_ENTRIES_PER_REQUEST,
+        REDISTRIBUTIONS_TO_KEEP, REDISTRIBUTION_DEV_SHARE_E8S, REDISTRIBUTION_FURNACE_SHARE_E8S,
+        REDISTRIBUTION_MIN_BALANCE_E8S, REDISTRIBUTION_SPIKE_SHARE_E8S, SHARES_SNAPSHOTS_TO_KEEP,
+        SHARES_SNAPSHOT_CHUNK_SIZE, STAKE_DEDUP_WINDOW_NS, STAKE_PERMITTED_DRIFT_NS,
+        TCYCLE_POS_ROUND_MIN_FEE,
     },
 };
+use crate::{
+    access::AccessControl,
+    decideid::verify_decide_id_proof,
+    feature_flags::FeatureFlags,
+    merkle::merkle_root,
+    outbox::{Outbox, OutboxTransferRequest},
+    ICP_FEE, ONE_DAY_NS,
+};
 
 pub struct BurnerState {
     pub shares: StableBTreeMap<Principal, (TCycles, E8s), Memory>,
 
-    pub kamikaze_shares: StableBTreeMap<Principal, (TCycles, u64), Memory>,
+    pub kamikaze_shares: StableBTreeMap<Principal, KamikazePosition, Memory>,
     pub kamikaze_rounds_won: StableBTreeMap<Principal, u64, Memory>,
 
     pub verified_via_decide_id: StableBTreeMap<Principal, (), Memory>,
+    // the principals whose verification has moved to another account with an account transfer
+    pub decide_id_transferred: StableBTreeMap<Principal, (), Memory>,
+    // legacy, only read once to migrate into lottery_participants
     pub eligible_for_lottery: StableBTreeMap<Principal, (), Memory>,
+    pub lottery_participants: LotteryParticipants,
     pub lottery_rounds_won: StableBTreeMap<Principal, u64, Memory>,
 
+    pub stakes: StableBTreeMap<(Principal, TimestampNs), StakeRecord, Memory>,
+    // append-only, every event except the pos rounds
+    pub account_history: StableBTreeMap<(Principal, u64), AccountEvent, Memory>,
+    // the pos rounds happen every couple of minutes, so they are summed up per (account, day)
+    pub account_daily_pos_rounds: StableBTreeMap<(Principal, u64), AccountEvent, Memory>,
+    pub round_history: StableBTreeMap<u64, RoundSummary, Memory>,
+    pub kamikaze_round_proofs: StableBTreeMap<u64, KamikazeRoundProof, Memory>,
+    pub lottery_round_proofs: StableBTreeMap<u64, LotteryRoundProof, Memory>,
+
+    pub outbox: Outbox,
+    pub access: AccessControl,
+    pub feature_flags: FeatureFlags,
+    pub redistributions: StableBTreeMap<u64, RedistributionRecord, Memory>,
+    pub spikes: StableBTreeMap<u64, SpikeEvent, Memory>,
+
+    pub account_transfer_proposals: StableBTreeMap<Principal, AccountTransferProposal, Memory>,
+    pub account_transfers: StableBTreeMap<u64, AccountTransferRecord, Memory>,
+
+    pub shares_snapshots: StableBTreeMap<u64, SharesSnapshot, Memory>,
+    pub shares_snapshot_entries: StableBTreeMap<(u64, u64), SharesSnapshotEntry, Memory>,
+    // the accounts changed while a snapshot is built, as they were when it began
+    pub shares_snapshot_originals: StableBTreeMap<Principal, SharesSnapshotEntry, Memory>,
+
     pub info: Cell<BurnerStateInfo, Memory>,
 }
 
@@ -32,6 +88,8 @@ impl BurnerState {
         let mut info = self.get_info();
 
         info.init(seed);
+        self.commit_kamikaze_round_seed(info.current_pos_round, &info.seed);
+        self.commit_lottery_round_seed(info.current_pos_round, &info.seed);
 
         self.set_info(info);
     }
@@ -40,6 +98,7 @@ impl BurnerState {
         &mut self,
         caller: &Principal,
         to: Principal,
+        now: TimestampNs,
     ) -> Result<(), String> {
         let mut info = self.get_info();
 
@@ -47,6 +106,9 @@ impl BurnerState {
             return Err(String::from("Access denied"));
         }
 
+        self.preserve_for_shares_snapshot(*caller);
+        self.preserve_for_shares_snapshot(to);
+
         if let Some((share_1, reward_1)) = self.shares.remove(caller) {
             let (share_2, reward_2) = if let Some((s1, r1)) = self.shares.get(&to) {
                 (s1, r1)
@@ -54,12 +116,33 @@ impl BurnerState {
                 (TCycles::zero(), E8s::zero())
             };
 
+            self.note_account_event(
+                *caller,
+                now,
+                AccountEventKind::MigratedTo {
+                    to,
+                    share: share_1.clone(),
+                    reward: reward_1.clone(),
+                },
+            );
+            self.note_account_event(
+                to,
+                now,
+                AccountEventKind::MigratedFrom {
+                    from: *caller,
+                    share: share_1.clone(),
+                    reward: reward_1.clone(),
+                },
+            );
+
             let share = share_1 + share_2;
             let reward = reward_1 + reward_2;
 
-            if &share > &BurnerStateInfo::get_current_fee() {
+            self.lottery_participants.remove(caller);
+
+            if share > self.get_info().get_current_fee() {
                 if self.verified_via_decide_id.contains_key(&to) {
-                    self.eligible_for_lottery.insert(to, ());
+                    self.lottery_participants.insert(to);
                 }
             }
 
@@ -75,22 +158,736 @@ impl BurnerState {
         }
     }
 
-    pub fn mint_kamikaze_share(&mut self, qty: TCycles, to: Principal, now: TimestampNs) {
-        // add new share to the account
-        let cur_opt = self.kamikaze_shares.get(&to);
+    /// Proposes to move all the shares of the caller to `to`, who has to accept it before the
+    /// proposal expires. A new proposal of the same caller replaces the previous one.
+    pub fn propose_account_transfer(
+        &mut self,
+        caller: Principal,
+        to: Principal,
+        now: TimestampNs,
+    ) -> Result<AccountTransferProposal, String> {
+        if to == caller {
+            return Err(String::from("Unable to transfer the account to itself"));
+        }
+
+        if to == Principal::anonymous() {
+            return Err(String::from(
+                "Unable to transfer the account to the anonymous principal",
+            ));
+        }
+
+        if !self.shares.contains_key(&caller) && !self.kamikaze_shares.contains_key(&caller) {
+            return Err(String::from("No entry found"));
+        }
+
+        // a new proposal replaces the previous one
+        if let Some(prev) = self.account_transfer_proposals.get(&caller) {
+            self.finish_account_transfer(
+                prev,
+                now,
+                AccountTransferOutcome::Cancelled { by: caller },
+            );
+        }
+
+        let proposal = AccountTransferProposal {
+            from: caller,
+            to,
+            proposed_at: now,
+            expires_at: now + ACCOUNT_TRANSFER_WINDOW_NS,
+        };
+
+        self.account_transfer_proposals
+            .insert(caller, proposal.clone());
+
+        Ok(proposal)
+    }
+
+    pub fn accept_account_transfer(
+        &mut self,
+        caller: Principal,
+        from: Principal,
+        now: TimestampNs,
+    ) -> Result<AccountTransferRecord, String> {
+        let proposal = self
+            .account_transfer_proposals
+            .get(&from)
+            .filter(|it| it.to == caller)
+            .ok_or(String::from("No transfer proposal found"))?;
+
+        if proposal.expires_at < now {
+            self.finish_account_transfer(proposal, now, AccountTransferOutcome::Expired);
+
+            return Err(String::from("The transfer proposal has expired"));
+        }
+
+        self.expire_stakes(from, now);
+
+        // otherwise the shares would be minted to the old account, after it is transferred
+        let has_stake_in_flight = self
+            .stakes
+            .range((from, 0)..=(from, u64::MAX))
+            .any(|(_, stake)| stake.block_idx.is_none());
+
+        if has_stake_in_flight {
+            return Err(String::from(
+                "The account has a stake in progress, try again later",
+            ));
+        }
+
+        let kamikaze_position = self.kamikaze_shares.get(&from);
+
+        if kamikaze_position.is_some() && self.kamikaze_shares.contains_key(&caller) {
+            return Err(String::from(
+                "Both accounts have a kamikaze position, wait for one of them to expire",
+            ));
+        }
+
+        self.preserve_for_shares_snapshot(from);
+        self.preserve_for_shares_snapshot(caller);
+
+        // pool share and unclaimed reward are merged with the ones of the target account
+        let (share, unclaimed_reward) = self.shares.remove(&from).unwrap_or_default();
+
+        if share > TCycles::zero() || unclaimed_reward > E8s::zero() {
+            let (to_share, to_unclaimed_reward) = self.shares.get(&caller).unwrap_or_default();
+
+            self.shares.insert(
+                caller,
+                (to_share + &share, to_unclaimed_reward + &unclaimed_reward),
+            );
+        }
+
+        if let Some(position) = &kamikaze_position {
+            self.kamikaze_shares.remove(&from);
+            self.kamikaze_shares.insert(caller, position.clone());
+        }
+
+        let kamikaze_rounds_won = self.kamikaze_rounds_won.remove(&from).unwrap_or_default();
+        if kamikaze_rounds_won > 0 {
+            let prev = self.kamikaze_rounds_won.get(&caller).unwrap_or_default();
+            self.kamikaze_rounds_won
+                .insert(caller, prev + kamikaze_rounds_won);
+        }
+
+        let lottery_rounds_won = self.lottery_rounds_won.remove(&from).unwrap_or_default();
+        if lottery_rounds_won > 0 {
+            let prev = self.lottery_rounds_won.get(&caller).unwrap_or_default();
+            self.lottery_rounds_won
+                .insert(caller, prev + lottery_rounds_won);
+        }
+
+        // the proof of personhood belongs to the same person, so it follows the account
+        // and can't be used again by the old account
+        let decide_id_verified = self.verified_via_decide_id.remove(&from).is_some();
+        if decide_id_verified {
+            self.verified_via_decide_id.insert(caller, ());
+            self.decide_id_transferred.insert(from, ());
+        }
+
+        self.lottery_participants.remove(&from);
+
+        if let Some((to_share, _)) = self.shares.get(&caller) {
+            if to_share >= self.get_info().get_current_fee()
+                && self.verified_via_decide_id.contains_key(&caller)
+            {
+                self.lottery_participants.insert(caller);
+            }
+        }
+
+        let record = self.finish_account_transfer(
+            proposal,
+            now,
+            AccountTransferOutcome::Completed {
+                share,
+                unclaimed_reward,
+                kamikaze_position,
+                kamikaze_rounds_won,
+                lottery_rounds_won,
+                decide_id_verified,
+            },
+        );
+
+        Ok(record)
+    }
+
+    pub fn cancel_account_transfer(
+        &mut self,
+        caller: Principal,
+        from: Principal,
+        now: TimestampNs,
+    ) -> Result<(), String> {
+        let proposal = self
+            .account_transfer_proposals
+            .get(&from)
+            .filter(|it| it.from == caller || it.to == caller)
+            .ok_or(String::from("No transfer proposal found"))?;
+
+        self.finish_account_transfer(
+            proposal,
+            now,
+            AccountTransferOutcome::Cancelled { by: caller },
+        );
+
+        Ok(())
+    }
+
+    fn finish_account_transfer(
+        &mut self,
+        proposal: AccountTransferProposal,
+        now: TimestampNs,
+        outcome: AccountTransferOutcome,
+    ) -> AccountTransferRecord {
+        self.account_transfer_proposals.remove(&proposal.from);
+
+        let id = self
+            .account_transfers
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        if let AccountTransferOutcome::Completed { .. } = &outcome {
+            self.note_account_event(
+                proposal.from,
+                now,
+                AccountEventKind::AccountTransferredTo {
+                    to: proposal.to,
+                    transfer_id: id,
+                },
+            );
+            self.note_account_event(
+                proposal.to,
+                now,
+                AccountEventKind::AccountTransferredFrom {
+                    from: proposal.from,
+                    transfer_id: id,
+                },
+            );
+        }
+
+        let record = AccountTransferRecord {
+            from: proposal.from,
+            to: proposal.to,
+            proposed_at: proposal.proposed_at,
+            finished_at: now,
+            outcome,
+        };
+
+        self.account_transfers.insert(id, record.clone());
+
+        record
+    }
+
+    // the snapshot is only started between rounds, otherwise it is postponed until the current round completes
+    pub fn request_shares_snapshot(&mut self, now: TimestampNs) -> Option<u64> {
         let mut info = self.get_info();
 
-        let (share, created_at) = if let Some((mut cur_share, created_at)) = cur_opt {
-            cur_share += &qty;
+        if info.is_round_in_progress() || info.shares_snapshot_in_progress.is_some() {
+            info.shares_snapshot_requested = Some(true);
+            self.set_info(info);
+
+            return None;
+        }
+
+        let last_completed_round = info.current_pos_round.saturating_sub(1);
+        self.set_info(info);
+
+        Some(self.begin_shares_snapshot(last_completed_round, now))
+    }
+
+    /// Reserves the id of a new snapshot. The snapshot itself is built by `shares_snapshot_batch`,
+    /// which should be called until it returns false, before the next round starts.
+    pub fn begin_shares_snapshot(&mut self, round: u64, now: TimestampNs) -> u64 {
+        let mut info = self.get_info();
+
+        let id = self
+            .shares_snapshots
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        info.shares_snapshot_in_progress = Some(SharesSnapshotProgress {
+            id,
+            round,
+            created_at: now,
+            last_pid: None,
+            total_entries: 0,
+            total_share: TCycles::zero(),
+            total_kamikaze_share: TCycles::zero(),
+            chunk_hashes: Vec::new(),
+        });
+
+        self.set_info(info);
+
+        id
+    }
+
+    // Copy-on-write for the snapshot in progress - should be called before the share of `pid` changes.
+    // The accounts the snapshot has already copied are not preserved again.
+    fn preserve_for_shares_snapshot(&mut self, pid: Principal) {
+        let Some(progress) = self.get_info().shares_snapshot_in_progress else {
+            return;
+        };
+
+        if progress.last_pid.is_some_and(|it| pid <= it)
+            || self.shares_snapshot_originals.contains_key(&pid)
+        {
+            return;
+        }
+
+        let entry = SharesSnapshotEntry {
+            pid,
+            share: self
+                .shares
+                .get(&pid)
+                .map(|(share, _)| share)
+                .unwrap_or_default(),
+            kamikaze_share: self
+                .kamikaze_shares
+                .get(&pid)
+                .map(|position| position.share)
+                .unwrap_or_default(),
+        };
+
+        self.shares_snapshot_originals.insert(pid, entry);
+    }
+
+    /// Copies up to `batch_size` accounts into the snapshot in progress, or, when there is none,
+    /// prunes up to `batch_size` entries of the snapshots which are no longer kept.
+    /// Returns true, if there is more work left.
+    pub fn shares_snapshot_batch(&mut self, batch_size: u64) -> bool {
+        let mut info = self.get_info();
+
+        let Some(mut progress) = info.shares_snapshot_in_progress.take() else {
+            return self.prune_shares_snapshot_entries(batch_size);
+        };
+
+        let start = match progress.last_pid {
+            Some(pid) => std::ops::Bound::Excluded(pid),
+            None => std::ops::Bound::Unbounded,
+        };
+
+        // both maps are ordered by principal, so they are merged in a single pass
+        let mut pool = self
+            .shares
+            .range((start, std::ops::Bound::Unbounded))
+            .peekable();
+        let mut kamikaze = self
+            .kamikaze_shares
+            .range((start, std::ops::Bound::Unbounded))
+            .peekable();
+        // an account changed since the snapshot began is taken as it was back then,
+        // including the removed ones, which are only left here
+        let mut originals = self
+            .shares_snapshot_originals
+            .range((start, std::ops::Bound::Unbounded))
+            .peekable();
+
+        let mut entries = Vec::new();
+        let mut originals_used = Vec::new();
+        let mut completed = false;
+
+        for _ in 0..batch_size {
+            let next_pool_pid = pool.peek().map(|(pid, _)| *pid);
+            let next_kamikaze_pid = kamikaze.peek().map(|(pid, _)| *pid);
+            let next_original_pid = originals.peek().map(|(pid, _)| *pid);
+
+            let Some(pid) = [next_pool_pid, next_kamikaze_pid, next_original_pid]
+                .into_iter()
+                .flatten()
+                .min()
+            else {
+                completed = true;
+                break;
+            };
+
+            progress.last_pid = Some(pid);
+
+            let mut share = if next_pool_pid == Some(pid) {
+                pool.next().map(|(_, (share, _))| share).unwrap()
+            } else {
+                TCycles::zero()
+            };
+
+            let mut kamikaze_share = if next_kamikaze_pid == Some(pid) {
+                kamikaze.next().map(|(_, position)| position.share).unwrap()
+            } else {
+                TCycles::zero()
+            };
+
+            if next_original_pid == Some(pid) {
+                let (_, original) = originals.next().unwrap();
 
-            (cur_share, created_at)
+                share = original.share;
+                kamikaze_share = original.kamikaze_share;
+                originals_used.push(pid);
+            }
+
+            // accounts with only an unclaimed reward left don't own any share
+            if share == TCycles::zero() && kamikaze_share == TCycles::zero() {
+                continue;
+            }
+
+            entries.push(SharesSnapshotEntry {
+                pid,
+                share,
+                kamikaze_share,
+            });
+        }
+
+        if !completed
+            && pool.peek().is_none()
+            && kamikaze.peek().is_none()
+            && originals.peek().is_none()
+        {
+            completed = true;
+        }
+
+        drop(pool);
+        drop(kamikaze);
+        drop(originals);
+
+        // the accounts behind the cursor are never looked at again by this snapshot
+        for pid in originals_used {
+            self.shares_snapshot_originals.remove(&pid);
+        }
+
+        for entry in entries {
+            progress.total_share += &entry.share;
+            progress.total_kamikaze_share += &entry.kamikaze_share;
+
+            self.shares_snapshot_entries
+                .insert((progress.id, progress.total_entries), entry);
+            progress.total_entries += 1;
+        }
+
+        // the hashes are only calculated for the complete chunks, so a chunk split between two batches
+        // is read back from the stable memory
+        loop {
+            let from = progress.chunk_hashes.len() as u64 * SHARES_SNAPSHOT_CHUNK_SIZE;
+            let to = (from + SHARES_SNAPSHOT_CHUNK_SIZE).min(progress.total_entries);
+
+            let is_chunk_complete = to >= from + SHARES_SNAPSHOT_CHUNK_SIZE;
+            let is_last_chunk = completed && to > from;
+
+            if !is_chunk_complete && !is_last_chunk {
+                break;
+            }
+
+            let leaves: Vec<_> = self
+                .shares_snapshot_entries
+                .range((progress.id, from)..(progress.id, to))
+                .map(|(_, entry)| entry.leaf_hash())
+                .collect();
+
+            progress.chunk_hashes.push(merkle_root(&leaves));
+        }
+
+        if !completed {
+            info.shares_snapshot_in_progress = Some(progress);
+            self.set_info(info);
+
+            return true;
+        }
+
+        self.set_info(info);
+
+        let snapshot = SharesSnapshot {
+            round: progress.round,
+            created_at: progress.created_at,
+            total_entries: progress.total_entries,
+            total_share: progress.total_share,
+            total_kamikaze_share: progress.total_kamikaze_share,
+            chunk_size: SHARES_SNAPSHOT_CHUNK_SIZE,
+            merkle_root: merkle_root(&progress.chunk_hashes),
+            chunk_hashes: progress.chunk_hashes,
+        };
+
+        self.shares_snapshots.insert(progress.id, snapshot);
+
+        // only the headers are removed here, the entries are pruned by the following batches
+        while self.shares_snapshots.len() > SHARES_SNAPSHOTS_TO_KEEP {
+            let (oldest, _) = self.shares_snapshots.first_key_value().unwrap();
+            self.shares_snapshots.remove(&oldest);
+        }
+
+        self.has_stale_shares_snapshot_entries()
+    }
+
+    // the ids only grow, so the stale entries are always the first ones
+    fn has_stale_shares_snapshot_entries(&self) -> bool {
+        let Some(((id, _), _)) = self.shares_snapshot_entries.first_key_value() else {
+            return false;
+        };
+
+        let in_progress_id = self.get_info().shares_snapshot_in_progress.map(|it| it.id);
+
+        !self.shares_snapshots.contains_key(&id) && in_progress_id != Some(id)
+    }
+
+    fn prune_shares_snapshot_entries(&mut self, batch_size: u64) -> bool {
+        for _ in 0..batch_size {
+            if !self.has_stale_shares_snapshot_entries() {
+                return false;
+            }
+
+            let (key, _) = self.shares_snapshot_entries.first_key_value().unwrap();
+            self.shares_snapshot_entries.remove(&key);
+        }
+
+        self.has_stale_shares_snapshot_entries()
+    }
+
+    // the roots of the snapshots which are kept, so the proofs of each of them can be checked against the certificate
+    pub fn get_shares_snapshot_roots(&self) -> Vec<(u64, Vec<u8>)> {
+        self.shares_snapshots
+            .iter()
+            .map(|(id, snapshot)| (id, snapshot.merkle_root))
+            .collect()
+    }
+
+    pub fn get_shares_snapshots(
+        &self,
+        req: GetSharesSnapshotsRequest,
+    ) -> GetSharesSnapshotsResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.shares_snapshots.range(&start_from..);
+            i.next();
+            i
+        } else {
+            self.shares_snapshots.iter()
+        };
+
+        let entries = iter.take(req.take as usize).collect();
+
+        GetSharesSnapshotsResponse { entries }
+    }
+
+    pub fn get_shares_snapshot_chunk(
+        &self,
+        req: GetSharesSnapshotChunkRequest,
+    ) -> GetSharesSnapshotChunkResponse {
+        let result = self
+            .shares_snapshots
+            .get(&req.snapshot_id)
+            .ok_or(String::from("Snapshot not found"))
+            .and_then(|snapshot| {
+                let chunk_hash = snapshot
+                    .chunk_hashes
+                    .get(req.chunk_idx as usize)
+                    .cloned()
+                    .ok_or(String::from("Chunk not found"))?;
+
+                let from = req.chunk_idx * snapshot.chunk_size;
+                let to = (from + snapshot.chunk_size).min(snapshot.total_entries);
+
+                let entries = self
+                    .shares_snapshot_entries
+                    .range((req.snapshot_id, from)..(req.snapshot_id, to))
+                    .map(|(_, entry)| entry)
+                    .collect();
+
+                Ok(SharesSnapshotChunk {
+                    entries,
+                    chunk_hash,
+                })
+            });
+
+        GetSharesSnapshotChunkResponse { result }
+    }
+
+    pub fn get_account_transfer_proposal(
+        &self,
+        req: GetAccountTransferProposalRequest,
+    ) -> GetAccountTransferProposalResponse {
+        GetAccountTransferProposalResponse {
+            proposal: self.account_transfer_proposals.get(&req.from),
+        }
+    }
+
+    pub fn get_account_transfers(
+        &self,
+        req: GetAccountTransfersRequest,
+    ) -> GetAccountTransfersResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.account_transfers.range(&start_from..);
+            i.next();
+            i
         } else {
-            (qty.clone(), now)
+            self.account_transfers.iter()
         };
 
-        self.kamikaze_shares.insert(to, (share, created_at));
+        let entries = iter.take(req.take as usize).collect();
+
+        GetAccountTransfersResponse { entries }
+    }
+
+    /// Returns Ok(Some(receipt)) if this exact stake was already processed, Ok(None) if the caller
+    /// should proceed with the ledger transfer. The dedup key is locked until completed or reverted.
+    pub fn begin_stake(
+        &mut self,
+        caller: Principal,
+        req: &StakeRequest,
+        kind: StakeKind,
+        now: TimestampNs,
+    ) -> Result<Option<StakeReceipt>, String> {
+        if req.created_at_time + STAKE_DEDUP_WINDOW_NS < now {
+            return Err(String::from("The stake is too old"));
+        }
+
+        if req.created_at_time > now + STAKE_PERMITTED_DRIFT_NS {
+            return Err(String::from("The stake is created in future"));
+        }
+
+        self.expire_stakes(caller, now);
+
+        if let StakeKind::Kamikaze(tier) = kind {
+            if let Some(position) = self.kamikaze_shares.get(&caller) {
+                if position.tier != tier {
+                    return Err(format!(
+                        "You already have a kamikaze position of tier {:?}",
+                        position.tier
+                    ));
+                }
+            }
+        }
+
+        let key = (caller, req.created_at_time);
+
+        if let Some(record) = self.stakes.get(&key) {
+            if record.kind != kind || record.qty_e8s_u64 != req.qty_e8s_u64 {
+                return Err(String::from(
+                    "This created_at_time is already used by another stake",
+                ));
+            }
+
+            return match record.block_idx {
+                Some(block_idx) => Ok(Some(StakeReceipt {
+                    block_idx,
+                    shares_minted: record.shares_minted,
+                })),
+                None => Err(String::from("The stake is already being processed")),
+            };
+        }
+
+        self.stakes.insert(
+            key,
+            StakeRecord {
+                kind,
+                qty_e8s_u64: req.qty_e8s_u64,
+                block_idx: None,
+                shares_minted: TCycles::zero(),
+            },
+        );
+
+        Ok(None)
+    }
+
+    // the ledger won't deduplicate these anymore, so there is no need to keep them around. A stake,
+    // which is still in flight after the window, is stuck (its callback has trapped) - its retries
+    // are rejected as too old, so it is dropped instead of blocking the account forever
+    fn expire_stakes(&mut self, pid: Principal, now: TimestampNs) {
+        let expired: Vec<_> = self
+            .stakes
+            .range((pid, 0)..(pid, now.saturating_sub(STAKE_DEDUP_WINDOW_NS)))
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in expired {
+            self.stakes.remove(&key);
+        }
+    }
+
+    pub fn complete_stake(
+        &mut self,
+        caller: Principal,
+        created_at_time: TimestampNs,
+        block_idx: Nat,
+        now: TimestampNs,
+    ) -> StakeReceipt {
+        let key = (caller, created_at_time);
+        let mut record = self.stakes.get(&key).expect("The stake should be locked");
+
+        if let Some(block_idx) = record.block_idx {
+            return StakeReceipt {
+                block_idx,
+                shares_minted: record.shares_minted,
+            };
+        }
+
+        let staked_icps_e12s = E8s::from(record.qty_e8s_u64)
+            .to_dynamic()
+            .to_decimals(12)
+            .to_const::<12>();
+
+        let cycles_rate = self.get_info().get_icp_to_cycles_exchange_rate();
+        let shares_minted = staked_icps_e12s * cycles_rate;
+
+        let event = match record.kind {
+            StakeKind::Common => {
+                self.mint_share(shares_minted.clone(), caller);
+
+                AccountEventKind::Stake {
+                    qty_e8s_u64: record.qty_e8s_u64,
+                    shares_minted: shares_minted.clone(),
+                }
+            }
+            StakeKind::Kamikaze(tier) => {
+                self.mint_kamikaze_share(shares_minted.clone(), caller, tier, now);
+
+                AccountEventKind::KamikazeStake {
+                    qty_e8s_u64: record.qty_e8s_u64,
+                    shares_minted: shares_minted.clone(),
+                }
+            }
+        };
+
+        self.note_account_event(caller, now, event);
+
+        record.block_idx = Some(block_idx.clone());
+        record.shares_minted = shares_minted.clone();
+
+        self.stakes.insert(key, record);
+
+        StakeReceipt {
+            block_idx,
+            shares_minted,
+        }
+    }
+
+    pub fn revert_stake(&mut self, caller: Principal, created_at_time: TimestampNs) {
+        let key = (caller, created_at_time);
+
+        if let Some(record) = self.stakes.get(&key) {
+            if record.block_idx.is_none() {
+                self.stakes.remove(&key);
+            }
+        }
+    }
+
+    pub fn mint_kamikaze_share(
+        &mut self,
+        qty: TCycles,
+        to: Principal,
+        tier: KamikazeTier,
+        now: TimestampNs,
+    ) {
+        // add new share to the account, keeping the tier and the lifespan of an existing position
+        let cur_opt = self.kamikaze_shares.get(&to);
+        let mut info = self.get_info();
+
+        let mut position = cur_opt.unwrap_or(KamikazePosition {
+            share: TCycles::zero(),
+            created_at: now,
+            tier,
+            weight: Some(TCycles::zero()),
+        });
+
+        let weight = position.add_share(&qty);
+        self.preserve_for_shares_snapshot(to);
+        self.kamikaze_shares.insert(to, position);
 
         // adjust total share supply
+        info.kamikaze_pool_total_weight = Some(info.get_kamikaze_pool_total_weight() + weight);
         info.kamikaze_pool_total_shares =
             Some(info.kamikaze_pool_total_shares.unwrap_or_default() + qty);
 
@@ -112,12 +909,13 @@ impl BurnerState {
         };
 
         // allow the pool member to participate in the lottery
-        if &share >= &BurnerStateInfo::get_current_fee() {
+        if share >= info.get_current_fee() {
             if self.verified_via_decide_id.contains_key(&to) {
-                self.eligible_for_lottery.insert(to, ());
+                self.lottery_participants.insert(to);
             }
         }
 
+        self.preserve_for_shares_snapshot(to);
         self.shares.insert(to, (share, unclaimed_reward));
 
         // adjust total share supply
@@ -125,39 +923,79 @@ impl BurnerState {
         self.set_info(info);
     }
 
-    pub fn claim_reward(&mut self, caller: Principal) -> Option<E8s> {
-        let fee = TCycles::from(TCYCLE_POS_ROUND_BASE_FEE);
+    // claims the whole unclaimed reward, if the quantity is not specified
+    pub fn claim_reward(
+        &mut self,
+        caller: Principal,
+        qty: Option<E8s>,
+        now: TimestampNs,
+    ) -> Result<E8s, String> {
+        let (share, unclaimed_reward) = self
+            .shares
+            .get(&caller)
+            .ok_or(String::from("No unclaimed reward found!"))?;
 
-        if let Some((share, unclaimed_reward)) = self.shares.get(&caller) {
-            if share < fee {
-                let mut info = self.get_info();
-                info.total_shares_supply -= &share;
-                self.set_info(info);
+        let qty = qty.unwrap_or(unclaimed_reward.clone());
 
-                self.shares.remove(&caller);
-            } else {
-                self.shares.insert(caller, (share, E8s::zero()));
-            }
+        if qty == E8s::zero() {
+            return Err(String::from("No unclaimed reward found!"));
+        }
 
-            if unclaimed_reward > E8s::zero() {
-                let mut info = self.get_info();
-                info.total_burn_token_minted += &unclaimed_reward;
-                self.set_info(info);
+        if qty > unclaimed_reward {
+            return Err(format!(
+                "Insufficient unclaimed reward (requested {}, available {})",
+                qty, unclaimed_reward
+            ));
+        }
 
-                Some(unclaimed_reward)
-            } else {
-                None
-            }
+        let remaining_reward = unclaimed_reward - &qty;
+
+        // the fee is dynamic, so only remove positions that can't ever participate again
+        let min_fee = TCycles::from(TCYCLE_POS_ROUND_MIN_FEE);
+
+        if share < min_fee && remaining_reward == E8s::zero() {
+            let mut info = self.get_info();
+            info.total_shares_supply -= &share;
+            self.set_info(info);
+
+            self.preserve_for_shares_snapshot(caller);
+            self.shares.remove(&caller);
+            self.lottery_participants.remove(&caller);
         } else {
-            None
+            self.shares.insert(caller, (share, remaining_reward));
         }
+
+        let mut info = self.get_info();
+        info.total_burn_token_minted += &qty;
+        self.set_info(info);
+
+        self.note_account_event(
+            caller,
+            now,
+            AccountEventKind::RewardClaimed { qty: qty.clone() },
+        );
+
+        Ok(qty)
     }
 
-    pub fn revert_claim_reward(&mut self, caller: Principal, unclaimed_reward: E8s) {
+    pub fn revert_claim_reward(
+        &mut self,
+        caller: Principal,
+        unclaimed_reward: E8s,
+        now: TimestampNs,
+    ) {
         let mut info = self.get_info();
         info.total_burn_token_minted -= &unclaimed_reward;
         self.set_info(info);
 
+        self.note_account_event(
+            caller,
+            now,
+            AccountEventKind::RewardClaimReverted {
+                qty: unclaimed_reward.clone(),
+            },
+        );
+
         if let Some((share, reward)) = self.shares.get(&caller) {
             self.shares
                 .insert(caller, (share, reward + unclaimed_reward));
@@ -168,41 +1006,69 @@ impl BurnerState {
     }
 
     // returns true if any winner was determined
-    /*     pub fn lottery_round(&mut self) -> bool {
-        if self.eligible_for_lottery.is_empty() {
+    pub fn lottery_round(&mut self, now: TimestampNs) -> bool {
+        if self.lottery_participants.is_empty() {
             return false;
         }
 
-        let info = self.get_info();
+        let mut info = self.get_info();
+
+        let round = info.current_pos_round;
+        let mut proof = match self.lottery_round_proofs.get(&round) {
+            // the winner of this round is already selected, so its published commitment is never replaced
+            Some(proof) if proof.winner.is_some() => return true,
+            Some(proof) => proof,
+            // the seed of this round was never published, so nobody can win it
+            None => return false,
+        };
 
         let cur_reward = &info.current_burn_token_reward / E8s::two(); // only distribute half the block via the lottery
 
-        let winner_idx = info.current_winning_idx(self.eligible_for_lottery.len());
+        let participants = self.lottery_participants.len();
+        let winner_idx = LotteryRoundProof::winning_idx(&proof.commitment.seed, participants);
 
-        // we don't split lottery in batches in hope that "skip" will scale well even on high numbers of participants
-        let (winner, _) = self
-            .eligible_for_lottery
-            .iter()
-            .skip(winner_idx as usize)
-            .next()
+        let winner = self
+            .lottery_participants
+            .get(winner_idx)
             .expect("The lottery winner should be found");
 
+        proof.participants = participants;
+        proof.winner_idx = Some(winner_idx);
+        proof.winner = Some(winner);
+        self.lottery_round_proofs.insert(round, proof);
+
         let (share, unclaimed_reward) = self
             .shares
             .get(&winner)
             .expect("The lottery winner should have a share!");
 
         self.shares
-            .insert(winner, (share, unclaimed_reward + cur_reward));
+            .insert(winner, (share, unclaimed_reward + &cur_reward));
 
         let rounds_won = self.lottery_rounds_won.get(&winner).unwrap_or_default();
         self.lottery_rounds_won.insert(winner, rounds_won + 1);
 
+        let summary = info.current_round_summary_mut(now);
+        summary.lottery_winner = Some(winner);
+        summary.reward_emitted += &cur_reward;
+
+        self.note_account_event(
+            winner,
+            now,
+            AccountEventKind::LotteryRoundWon {
+                round,
+                reward_accrued: cur_reward,
+            },
+        );
+
+        self.set_info(info);
+
         true
-    } */
+    }
 
     // returns Some(true) if should be rescheduled, returns Some(false) if round completed, returns None if nobody is in the pool
-    pub fn kamikaze_round_batch(&mut self, batch_size: u64) -> Option<bool> {
+    // or the seed of the round was never published
+    pub fn kamikaze_round_batch(&mut self, batch_size: u64, now: TimestampNs) -> Option<bool> {
         // only run the protocol if someone is minting
         if self.kamikaze_shares.len() == 0 {
             return None;
@@ -210,6 +1076,20 @@ impl BurnerState {
 
         let mut info = self.get_info();
 
+        let round = info.current_pos_round;
+        let mut proof = match self.kamikaze_round_proofs.get(&round) {
+            // the winner of this round is already selected, so its published commitment is never replaced
+            Some(proof) if proof.winner.is_some() && info.kamikaze_pool_random_number.is_none() => {
+                return Some(false);
+            }
+            Some(proof) => proof,
+            // the seed of this round was never published, so nobody can win it;
+            // the seed of the next round is published when this one closes
+            None => return None,
+        };
+
+        info.current_round_summary_mut(now).kamikaze_pool_ran = true;
+
         let mut iter = if let Some(id) = info.next_kamikaze_id {
             let mut i = self.kamikaze_shares.range(&id..);
             i.next();
@@ -219,21 +1099,19 @@ impl BurnerState {
             self.kamikaze_shares.iter()
         };
 
+        let total_weight = info.get_kamikaze_pool_total_weight();
+
         let random_number = if let Some(random_number) = info.kamikaze_pool_random_number.clone() {
             random_number
         } else {
-            let n = info.generate_random_number();
+            let n = KamikazeRoundProof::random_number(&proof.commitment.seed);
             info.kamikaze_pool_random_number = Some(n.clone());
+            proof.total_weight = total_weight.clone();
 
             n
         };
 
-        let mut counter = info.kamikaze_pool_counter.unwrap_or_default();
-
-        let total_shares = info
-            .kamikaze_pool_total_shares
-            .clone()
-            .expect("Total shares should be present");
+        let mut counter = info.kamikaze_pool_counter.clone().unwrap_or_default();
 
         let mut i: u64 = 0;
 
@@ -247,22 +1125,39 @@ impl BurnerState {
                 continue;
             }
 
-            let (pid, (share, _)) = entry.unwrap();
+            let (pid, position) = entry.unwrap();
+            let weight = position.weight();
 
-            counter += share / &total_shares;
+            counter += &weight / &total_weight;
             info.next_kamikaze_id = Some(pid);
+            proof.walk.push((pid, weight));
 
             if counter >= random_number {
+                proof.winner = Some(pid);
+
                 let cur_reward = &info.current_burn_token_reward / E8s::two(); // only distribute half the block via the kamikaze pool
 
                 let (common_pool_shares, unclaimed_reward) =
                     self.shares.get(&pid).unwrap_or_default();
                 self.shares
-                    .insert(pid, (common_pool_shares, unclaimed_reward + cur_reward));
+                    .insert(pid, (common_pool_shares, unclaimed_reward + &cur_reward));
 
                 let prev_rounds_won = self.kamikaze_rounds_won.get(&pid).unwrap_or_default();
                 self.kamikaze_rounds_won.insert(pid, prev_rounds_won + 1);
 
+                let summary = info.current_round_summary_mut(now);
+                summary.kamikaze_winner = Some(pid);
+                summary.reward_emitted += &cur_reward;
+
+                self.note_account_event(
+                    pid,
+                    now,
+                    AccountEventKind::KamikazeRoundWon {
+                        round,
+                        reward_accrued: cur_reward,
+                    },
+                );
+
                 break false;
             }
 
@@ -273,6 +1168,8 @@ impl BurnerState {
             }
         };
 
+        self.kamikaze_round_proofs.insert(round, proof);
+
         if should_reschedule {
             info.kamikaze_pool_counter = Some(counter);
         } else {
@@ -305,7 +1202,9 @@ impl BurnerState {
 
         let mut kamikaze_total_supply = info
             .kamikaze_pool_total_shares
+            .clone()
             .expect("Total supply should be set");
+        let mut kamikaze_total_weight = info.get_kamikaze_pool_total_weight();
 
         let mut positions_to_remove = Vec::new();
         let mut i = 0;
@@ -316,14 +1215,21 @@ impl BurnerState {
                 break false;
             }
 
-            let (pid, (shares, created_at)) = entry.unwrap();
+            let (pid, position) = entry.unwrap();
 
             info.next_kamikaze_id = Some(pid);
 
-            if now - created_at >= KAMIKAZE_POOL_POSITION_LIFESPAN_NS {
+            if position.is_expired(now) {
                 self.kamikaze_rounds_won.remove(&pid);
 
-                kamikaze_total_supply -= shares;
+                // the weight of a position created before it was tracked can differ slightly from its part of the total
+                let weight = position.weight();
+                kamikaze_total_weight = if kamikaze_total_weight > weight {
+                    kamikaze_total_weight - weight
+                } else {
+                    TCycles::zero()
+                };
+                kamikaze_total_supply -= position.share;
                 positions_to_remove.push(pid);
             }
 
@@ -338,6 +1244,7 @@ impl BurnerState {
         }
 
         info.kamikaze_pool_total_shares = Some(kamikaze_total_supply);
+        info.kamikaze_pool_total_weight = Some(kamikaze_total_weight);
 
         if !should_reschedule {
             info.next_kamikaze_id = None;
@@ -349,7 +1256,12 @@ impl BurnerState {
     }
 
     // return true if the round has completed
-    pub fn pos_round_batch(&mut self, split_reward_in_half: bool, batch_size: u64) -> bool {
+    pub fn pos_round_batch(
+        &mut self,
+        split_reward_in_half: bool,
+        batch_size: u64,
+        now: TimestampNs,
+    ) -> bool {
         // only run the protocol if someone is minting
         if self.shares.len() == 0 {
             return true;
@@ -366,7 +1278,7 @@ impl BurnerState {
             self.shares.iter()
         };
 
-        let fee = BurnerStateInfo::get_current_fee();
+        let fee = info.get_current_fee();
 
         let mut cur_reward = info
             .current_burn_token_reward
@@ -379,30 +1291,49 @@ impl BurnerState {
             cur_reward /= ECs::<12>::two(); // only distribute half the block via the pool shares
         }
 
+        // open the summary before the round number gets bumped
+        let round = info.current_round_summary_mut(now).round;
         let mut total_shares_burned = TCycles::zero();
+        let mut total_reward_emitted = E8s::zero();
         let mut accounts_to_update = Vec::with_capacity(batch_size as usize);
+        let mut accounts_to_leave_lottery = Vec::new();
         let mut i: u64 = 0;
         let mut completed = false;
 
         loop {
             if let Some((account, (share, unclaimed_reward))) = iter.next() {
-                if share < fee {
+                if share == TCycles::zero() {
+                    accounts_to_leave_lottery.push(account);
                     continue;
                 }
 
+                // the fee could have grown since this account became eligible - then it burns the rest
+                // of its share, otherwise the share would stay in the total supply without earning anything
+                let shares_burned = if share < fee {
+                    share.clone()
+                } else {
+                    fee.clone()
+                };
+
                 let new_reward = (&cur_reward * &share / &info.total_shares_supply)
                     .to_dynamic()
                     .to_decimals(8)
                     .to_const();
-                let new_share = share - &fee;
+                let new_share = share - &shares_burned;
 
                 if new_share < fee {
-                    self.eligible_for_lottery.remove(&account);
+                    accounts_to_leave_lottery.push(account);
                 }
 
-                accounts_to_update.push((account, (new_share, unclaimed_reward + new_reward)));
+                total_reward_emitted += &new_reward;
+                total_shares_burned += &shares_burned;
+                accounts_to_update.push((
+                    account,
+                    (new_share, unclaimed_reward + &new_reward),
+                    new_reward,
+                    shares_burned,
+                ));
                 info.next_burner_id = Some(account);
-                total_shares_burned += &fee;
 
                 i += 1;
 
@@ -422,18 +1353,57 @@ impl BurnerState {
             info.complete_round();
         }
 
-        for (account, entry) in accounts_to_update {
+        let summary = info.current_round_summary_mut(now);
+        summary.participants += accounts_to_update.len() as u64;
+        summary.tcycles_burned += &total_shares_burned;
+        summary.share_fee = fee.clone();
+        summary.reward_emitted += total_reward_emitted;
+
+        if completed {
+            if let Some(mut summary) = info.current_round_summary.take() {
+                summary.ended_at = now;
+                self.round_history.insert(summary.round, summary);
+            }
+
+            // the winners of this round are known by now, so its seed can be revealed
+            self.reveal_kamikaze_round_seed(round);
+            self.reveal_lottery_round_seed(round);
+            self.commit_kamikaze_round_seed(info.current_pos_round, &info.seed);
+            self.commit_lottery_round_seed(info.current_pos_round, &info.seed);
+        }
+
+        for account in accounts_to_leave_lottery {
+            self.lottery_participants.remove(&account);
+        }
+
+        for (account, entry, reward_accrued, shares_burned) in accounts_to_update {
             self.shares.insert(account, entry);
+            self.note_account_event(
+                account,
+                now,
+                AccountEventKind::PosRound {
+                    round,
+                    last_round: None,
+                    shares_burned,
+                    reward_accrued,
+                },
+            );
         }
 
         info.total_shares_supply -= total_shares_burned;
 
+        let take_shares_snapshot = completed && info.should_take_shares_snapshot(round);
+
         self.set_info(info);
 
+        if take_shares_snapshot {
+            self.begin_shares_snapshot(round, now);
+        }
+
         completed
     }
 
-    /*     pub fn verify_decide_id(
+    pub fn verify_decide_id(
         &mut self,
         jwt: &str,
         caller: Principal,
@@ -443,18 +1413,38 @@ impl BurnerState {
             return Err(String::from("Already verified"));
         }
 
+        if self.decide_id_transferred.contains_key(&caller) {
+            return Err(String::from(
+                "The verification of this account was transferred to another account",
+            ));
+        }
+
         verify_decide_id_proof(jwt, caller, now as u128)?;
 
         self.verified_via_decide_id.insert(caller, ());
 
         if let Some((share, _)) = self.shares.get(&caller) {
-            if share >= BurnerStateInfo::get_current_fee() {
-                self.eligible_for_lottery.insert(caller, ());
+            if share >= self.get_info().get_current_fee() {
+                self.lottery_participants.insert(caller);
             }
         }
 
         Ok(())
-    } */
+    }
+
+    // before the lottery participants were indexed, they were stored in a plain set
+    pub fn migrate_lottery_participants(&mut self) {
+        let legacy: Vec<_> = self
+            .eligible_for_lottery
+            .iter()
+            .map(|(pid, _)| pid)
+            .collect();
+
+        for pid in legacy {
+            self.lottery_participants.insert(pid);
+            self.eligible_for_lottery.remove(&pid);
+        }
+    }
 
     pub fn get_info(&self) -> BurnerStateInfo {
         self.info.get().clone()
@@ -473,7 +1463,7 @@ impl BurnerState {
             self.shares.iter()
         };
 
-        let fee = BurnerStateInfo::get_current_fee();
+        let fee = self.get_info().get_current_fee();
         let mut entries = Vec::new();
         let mut i = 0;
 
@@ -483,7 +1473,7 @@ impl BurnerState {
                     continue;
                 }
 
-                let is_lottery_participant = self.eligible_for_lottery.contains_key(&account);
+                let is_lottery_participant = self.lottery_participants.contains(&account);
                 let rounds_won = self.lottery_rounds_won.get(&account).unwrap_or_default();
 
                 let entry = BurnerInfo {
@@ -526,14 +1516,15 @@ impl BurnerState {
                 break;
             }
 
-            let (pid, (share, created_at)) = entry.unwrap();
+            let (pid, position) = entry.unwrap();
 
             let rounds_won = self.kamikaze_rounds_won.get(&pid).unwrap_or_default();
 
             let entry = KamikazeInfo {
                 pid,
-                share,
-                created_at,
+                share: position.share,
+                created_at: position.created_at,
+                tier: position.tier,
                 rounds_won,
             };
 
@@ -548,25 +1539,513 @@ impl BurnerState {
         GetKamikazesResponse { entries }
     }
 
+    pub fn get_account_history(&self, req: GetAccountHistoryRequest) -> GetAccountHistoryResponse {
+        GetAccountHistoryResponse {
+            entries: Self::get_account_events(&self.account_history, req),
+        }
+    }
+
+    // the keys of the entries are days since the epoch
+    pub fn get_account_daily_pos_rounds(
+        &self,
+        req: GetAccountHistoryRequest,
+    ) -> GetAccountHistoryResponse {
+        GetAccountHistoryResponse {
+            entries: Self::get_account_events(&self.account_daily_pos_rounds, req),
+        }
+    }
+
+    fn get_account_events(
+        events: &StableBTreeMap<(Principal, u64), AccountEvent, Memory>,
+        req: GetAccountHistoryRequest,
+    ) -> Vec<(u64, AccountEvent)> {
+        let mut iter = if let Some(start_from) = req.start {
+            let mut i = events.range(&(req.pid, start_from)..);
+            i.next();
+            i
+        } else {
+            events.range(&(req.pid, 0)..)
+        };
+
+        let take = req.take.min(MAX_ACCOUNT_HISTORY_ENTRIES_PER_REQUEST) as usize;
+        let mut entries = Vec::new();
+
+        while entries.len() < take {
+            match iter.next() {
+                Some(((pid, key), event)) if pid == req.pid => entries.push((key, event)),
+                _ => break,
+            }
+        }
+
+        entries
+    }
+
+    pub fn get_round_history(&self, req: GetRoundHistoryRequest) -> GetRoundHistoryResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.round_history.range(&start_from..);
+            i.next();
+            i
+        } else {
+            self.round_history.iter()
+        };
+
+        let take = req.take.min(MAX_ROUND_HISTORY_ENTRIES_PER_REQUEST);
+
+        let entries = iter
+            .take(take as usize)
+            .map(|(_, summary)| summary)
+            .collect();
+
+        GetRoundHistoryResponse { entries }
+    }
+
+    pub fn get_emission_projection(
+        &self,
+        req: GetEmissionProjectionRequest,
+    ) -> GetEmissionProjectionResponse {
+        let info = self.get_info();
+        let rounds = req.rounds.min(MAX_EMISSION_PROJECTION_ROUNDS);
+
+        GetEmissionProjectionResponse {
+            schedule: info.get_emission_schedule(),
+            next_schedule: info.next_emission_schedule.clone(),
+            entries: info.project_emissions(rounds),
+        }
+    }
+
+    /// Splits the ICP collected on the redistribution subaccount between the furnace, the spike and the devs.
+    /// The transfers are only enqueued here - the outbox sends them and retries, if something goes wrong.
+    /// Returns none, if there is not enough ICP to redistribute yet.
+    pub fn redistribute_icps(
+        &mut self,
+        balance_e8s: u64,
+        icp_ledger: Principal,
+        this_canister_id: Principal,
+        furnace_canister_id: Principal,
+        now: TimestampNs,
+    ) -> Option<u64> {
+        let redistribution_subaccount = Some(BURNER_REDISTRIBUTION_SUBACCOUNT);
+
+        // pending transfers of the previous redistributions are still on the balance
+        let reserved_e8s: u64 = self
+            .outbox
+            .get_pending_debit(icp_ledger, redistribution_subaccount)
+            .0
+            .try_into()
+            .unwrap_or(u64::MAX);
+        let available_e8s = balance_e8s.saturating_sub(reserved_e8s);
+
+        if available_e8s <= REDISTRIBUTION_MIN_BALANCE_E8S {
+            return None;
+        }
+
+        let one_e8s = 1_0000_0000u128;
+        let share_of =
+            |share_e8s: u64| (available_e8s as u128 * share_e8s as u128 / one_e8s) as u64;
+
+        let destinations = [
+            (
+                RedistributionDestination::Furnace,
+                REDISTRIBUTION_FURNACE_SHARE_E8S,
+                Account {
+                    owner: furnace_canister_id,
+                    subaccount: None,
+                },
+            ),
+            (
+                RedistributionDestination::Spike,
+                REDISTRIBUTION_SPIKE_SHARE_E8S,
+                Account {
+                    owner: this_canister_id,
+                    subaccount: Some(BURNER_SPIKE_SUBACCOUNT),
+                },
+            ),
+            (
+                RedistributionDestination::Dev,
+                REDISTRIBUTION_DEV_SHARE_E8S,
+                Account {
+                    owner: this_canister_id,
+                    subaccount: Some(BURNER_DEV_FEE_SUBACCOUNT),
+                },
+            ),
+        ];
+
+        let splits = destinations
+            .into_iter()
+            .map(|(destination, share_e8s, to)| {
+                let qty_e8s = share_of(share_e8s);
+
+                // the fee is paid out of the split, so a split smaller than the fee stays on the balance
+                let outbox_transfer_id = if qty_e8s > ICP_FEE {
+                    Some(self.outbox.enqueue(
+                        OutboxTransferRequest {
+                            ledger: icp_ledger,
+                            from_subaccount: redistribution_subaccount,
+                            to,
+                            amount: Nat::from(qty_e8s - ICP_FEE),
+                            fee: Some(Nat::from(ICP_FEE)),
+                        },
+                        now,
+                    ))
+                } else {
+                    None
+                };
+
+                RedistributionSplit {
+                    destination,
+                    qty_e8s,
+                    outbox_transfer_id,
+                }
+            })
+            .collect();
+
+        let id = self
+            .redistributions
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        self.redistributions.insert(
+            id,
+            RedistributionRecord {
+                timestamp: now,
+                balance_e8s,
+                reserved_e8s,
+                splits,
+            },
+        );
+
+        while self.redistributions.len() > REDISTRIBUTIONS_TO_KEEP {
+            let (oldest, _) = self.redistributions.first_key_value().unwrap();
+            self.redistributions.remove(&oldest);
+        }
+
+        Some(id)
+    }
+
+    pub fn get_redistributions(
+        &self,
+        req: GetRedistributionsRequest,
+    ) -> GetRedistributionsResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.redistributions.range(&start_from..);
+            i.next();
+            i
+        } else {
+            self.redistributions.iter()
+        };
+
+        let entries = iter
+            .take(req.take as usize)
+            .map(|(id, record)| RedistributionInfo {
+                id,
+                timestamp: record.timestamp,
+                balance_e8s: record.balance_e8s,
+                reserved_e8s: record.reserved_e8s,
+                splits: record
+                    .splits
+                    .into_iter()
+                    .map(|split| RedistributionSplitInfo {
+                        destination: split.destination,
+                        qty_e8s: split.qty_e8s,
+                        transfer: split.outbox_transfer_id.and_then(|it| self.outbox.get(it)),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        GetRedistributionsResponse { entries }
+    }
+
+    /// Returns the spike to continue with: either the one that is still in progress, or a new one,
+    /// if enough ICP is collected on the spike subaccount to reach the current target.
+    pub fn begin_spike(&mut self, balance_e8s: u64, now: TimestampNs) -> Option<(u64, SpikeEvent)> {
+        if let Some(in_progress) = self.get_spike_in_progress() {
+            return Some(in_progress);
+        }
+
+        let mut info = self.get_info();
+        let target_e8s = info.get_icp_burn_spike_target();
+
+        if balance_e8s < target_e8s || balance_e8s <= ICP_FEE {
+            // the target is lowered, if it wasn't reached for too long
+            info.update_icp_burn_spike_target(false, now);
+            self.set_info(info);
+
+            return None;
+        }
+
+        let id = self
+            .spikes
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        let spike = SpikeEvent {
+            started_at: now,
+            target_e8s,
+            qty_e8s: balance_e8s,
+            status: SpikeStatus::Transferring,
+            attempts: 0,
+            last_error: None,
+            finished_at: None,
+            next_target_e8s: None,
+        };
+
+        self.spikes.insert(id, spike.clone());
+
+        Some((id, spike))
+    }
+
+    pub fn get_spike_in_progress(&self) -> Option<(u64, SpikeEvent)> {
+        self.spikes
+            .last_key_value()
+            .filter(|(_, spike)| spike.is_in_progress())
+    }
+
+    pub fn note_spike_attempt(&mut self, id: u64) {
+        if let Some(mut spike) = self.spikes.get(&id) {
+            spike.attempts += 1;
+            self.spikes.insert(id, spike);
+        }
+    }
+
+    pub fn note_spike_transferred(&mut self, id: u64, block_index: u64) {
+        if let Some(mut spike) = self.spikes.get(&id) {
+            spike.status = SpikeStatus::Notifying { block_index };
+            spike.last_error = None;
+            self.spikes.insert(id, spike);
+        }
+    }
+
+    pub fn note_spike_error(&mut self, id: u64, error: String) {
+        if let Some(mut spike) = self.spikes.get(&id) {
+            spike.last_error = Some(error);
+            self.spikes.insert(id, spike);
+        }
+    }
+
+    pub fn complete_spike(&mut self, id: u64, cycles_minted: Nat, now: TimestampNs) {
+        let Some(mut spike) = self.spikes.get(&id) else {
+            return;
+        };
+        let SpikeStatus::Notifying { block_index } = spike.status else {
+            return;
+        };
+
+        let mut info = self.get_info();
+        info.update_icp_burn_spike_target(true, now);
+
+        spike.status = SpikeStatus::Completed {
+            block_index,
+            cycles_minted,
+        };
+        spike.last_error = None;
+        spike.finished_at = Some(now);
+        spike.next_target_e8s = Some(info.get_icp_burn_spike_target());
+
+        self.spikes.insert(id, spike);
+        self.set_info(info);
+    }
+
+    // the target stays the same, so the next spike is attempted as soon as the ICP is back on the balance
+    pub fn fail_spike(&mut self, id: u64, reason: String, now: TimestampNs) {
+        if let Some(mut spike) = self.spikes.get(&id) {
+            spike.status = SpikeStatus::Failed { reason };
+            spike.finished_at = Some(now);
+            self.spikes.insert(id, spike);
+        }
+    }
+
+    pub fn get_spikes(&self, req: GetSpikesRequest) -> GetSpikesResponse {
+        let iter = if let Some(start_from) = req.start {
+            let mut i = self.spikes.range(&start_from..);
+            i.next();
+            i
+        } else {
+            self.spikes.iter()
+        };
+
+        let entries = iter.take(req.take as usize).collect();
+
+        GetSpikesResponse { entries }
+    }
+
+    // approximated by the number of accounts that paid the fee during the last round
+    pub fn get_active_burners_count(&self) -> u64 {
+        self.round_history
+            .last_key_value()
+            .map(|(_, summary)| summary.participants)
+            .unwrap_or(self.shares.len())
+    }
+
+    pub fn verify_kamikaze_round(
+        &self,
+        req: VerifyKamikazeRoundRequest,
+    ) -> VerifyKamikazeRoundResponse {
+        let result = self
+            .kamikaze_round_proofs
+            .get(&req.round)
+            .map(|proof| proof.verify(req.round))
+            .ok_or(String::from("No data for this round"));
+
+        VerifyKamikazeRoundResponse { result }
+    }
+
+    pub fn verify_lottery_round(
+        &self,
+        req: VerifyLotteryRoundRequest,
+    ) -> VerifyLotteryRoundResponse {
+        let result = self
+            .lottery_round_proofs
+            .get(&req.round)
+            .map(|proof| proof.verify(req.round))
+            .ok_or(String::from("No data for this round"));
+
+        VerifyLotteryRoundResponse { result }
+    }
+
+    // the rounds before the commitments were introduced have none, so the current one is published on upgrade
+    pub fn migrate_round_seeds(&mut self) {
+        let info = self.get_info();
+        self.commit_kamikaze_round_seed(info.current_pos_round, &info.seed);
+        self.commit_lottery_round_seed(info.current_pos_round, &info.seed);
+    }
+
+    // publishes the seed hash of the round before anyone can win it
+    fn commit_kamikaze_round_seed(&mut self, round: u64, secret: &[u8]) {
+        if !self.kamikaze_round_proofs.contains_key(&round) {
+            self.kamikaze_round_proofs
+                .insert(round, KamikazeRoundProof::new(secret, round));
+        }
+
+        while self.kamikaze_round_proofs.len() > KAMIKAZE_ROUND_PROOFS_TO_KEEP {
+            let (oldest, _) = self.kamikaze_round_proofs.first_key_value().unwrap();
+            self.kamikaze_round_proofs.remove(&oldest);
+        }
+    }
+
+    fn reveal_kamikaze_round_seed(&mut self, round: u64) {
+        if let Some(mut proof) = self.kamikaze_round_proofs.get(&round) {
+            proof.commitment.reveal();
+            self.kamikaze_round_proofs.insert(round, proof);
+        }
+    }
+
+    fn commit_lottery_round_seed(&mut self, round: u64, secret: &[u8]) {
+        if !self.lottery_round_proofs.contains_key(&round) {
+            self.lottery_round_proofs
+                .insert(round, LotteryRoundProof::new(secret, round));
+        }
+
+        while self.lottery_round_proofs.len() > LOTTERY_ROUND_PROOFS_TO_KEEP {
+            let (oldest, _) = self.lottery_round_proofs.first_key_value().unwrap();
+            self.lottery_round_proofs.remove(&oldest);
+        }
+    }
+
+    fn reveal_lottery_round_seed(&mut self, round: u64) {
+        if let Some(mut proof) = self.lottery_round_proofs.get(&round) {
+            proof.commitment.reveal();
+            self.lottery_round_proofs.insert(round, proof);
+        }
+    }
+
+    fn note_account_event(
+        &mut self,
+        pid: Principal,
+        timestamp: TimestampNs,
+        kind: AccountEventKind,
+    ) {
+        if let AccountEventKind::PosRound {
+            round,
+            shares_burned,
+            reward_accrued,
+            ..
+        } = kind
+        {
+            let key = (pid, timestamp / ONE_DAY_NS);
+
+            let event = match self.account_daily_pos_rounds.get(&key) {
+                Some(AccountEvent {
+                    timestamp: first_timestamp,
+                    kind:
+                        AccountEventKind::PosRound {
+                            round: first_round,
+                            shares_burned: prev_shares_burned,
+                            reward_accrued: prev_reward_accrued,
+                            ..
+                        },
+                }) => AccountEvent {
+                    timestamp: first_timestamp,
+                    kind: AccountEventKind::PosRound {
+                        round: first_round,
+                        last_round: Some(round),
+                        shares_burned: prev_shares_burned + shares_burned,
+                        reward_accrued: prev_reward_accrued + reward_accrued,
+                    },
+                },
+                _ => AccountEvent {
+                    timestamp,
+                    kind: AccountEventKind::PosRound {
+                        round,
+                        last_round: Some(round),
+                        shares_burned,
+                        reward_accrued,
+                    },
+                },
+            };
+
+            self.account_daily_pos_rounds.insert(key, event);
+
+            return;
+        }
+
+        // the map is ordered by (pid, seq), so the last entry below (pid, MAX) is this account's latest one
+        let seq = match self
+            .account_history
+            .iter_upper_bound(&(pid, u64::MAX))
+            .next()
+        {
+            Some(((last_pid, last_seq), _)) if last_pid == pid => last_seq + 1,
+            _ => 0,
+        };
+
+        self.account_history
+            .insert((pid, seq), AccountEvent { timestamp, kind });
+    }
+
     pub fn get_total_verified_accounts(&self) -> u32 {
         self.verified_via_decide_id.len() as u32
     }
 
+    pub fn get_certified_totals(&self) -> CertifiedBurnerTotals {
+        let info = self.get_info();
+
+        CertifiedBurnerTotals {
+            total_share_supply: info.total_shares_supply,
+            total_tcycles_burned: info.total_tcycles_burned,
+            total_burn_token_minted: info.total_burn_token_minted,
+            current_pos_round: info.current_pos_round,
+            shares_snapshot_roots: self.get_shares_snapshot_roots(),
+        }
+    }
+
     pub fn get_totals(&self, caller: &Principal) -> GetTotalsResponse {
         let info = self.get_info();
-        let fee = BurnerStateInfo::get_current_fee();
+        let fee = info.get_current_fee();
         let is_lottery_enabled = info.is_lottery_enabled();
 
         let (share, unclaimed_reward) = self.shares.get(caller).unwrap_or_default();
         let verified_via_decide_id = self.verified_via_decide_id.contains_key(caller);
-        let eligible_for_lottery = self.eligible_for_lottery.contains_key(caller);
+        let eligible_for_lottery = self.lottery_participants.contains(caller);
         let icp_to_cycles_exchange_rate = info.get_icp_to_cycles_exchange_rate();
 
-        let (kamikaze_share, kamikaze_created_at) = self
+        let (kamikaze_share, kamikaze_created_at, kamikaze_tier) = self
             .kamikaze_shares
             .get(caller)
-            .map(|(share, created_at)| (share, Some(created_at)))
-            .unwrap_or((TCycles::zero(), None));
+            .map(|it| (it.share, Some(it.created_at), Some(it.tier)))
+            .unwrap_or((TCycles::zero(), None, None));
         let is_kamikaze_pool_enabled = info.is_kamikaze_pool_enabled();
 
         let spike_target = E8s::from(info.get_icp_burn_spike_target());
@@ -584,7 +2063,7 @@ impl BurnerState {
 
             total_burners: self.shares.len() + self.kamikaze_shares.len(),
             total_verified_accounts: self.verified_via_decide_id.len(),
-            total_lottery_participants: self.eligible_for_lottery.len(),
+            total_lottery_participants: self.lottery_participants.len(),
 
             icp_to_cycles_exchange_rate,
             total_kamikaze_pool_supply: info.kamikaze_pool_total_shares.unwrap_or_default(),
@@ -593,6 +2072,7 @@ impl BurnerState {
             your_share_tcycles: share,
             your_kamikaze_share_tcycles: kamikaze_share,
             your_kamikaze_position_created_at: kamikaze_created_at,
+            your_kamikaze_position_tier: kamikaze_tier,
             your_unclaimed_reward_e8s: unclaimed_reward,
             your_decide_id_verification_status: verified_via_decide_id,
             your_lottery_eligibility_status: eligible_for_lottery,
@@ -602,6 +2082,60 @@ impl BurnerState {
     }
 }
 
+/// The accounts eligible for the lottery, densely indexed from 0 to len - 1,
+/// so the winner can be picked by its index in O(log n).
+/// Removal moves the last participant into the freed index.
+pub struct LotteryParticipants {
+    pub index_of: StableBTreeMap<Principal, u64, Memory>,
+    pub by_index: StableBTreeMap<u64, Principal, Memory>,
+}
+
+impl LotteryParticipants {
+    pub fn insert(&mut self, pid: Principal) {
+        if self.index_of.contains_key(&pid) {
+            return;
+        }
+
+        let idx = self.by_index.len();
+
+        self.index_of.insert(pid, idx);
+        self.by_index.insert(idx, pid);
+    }
+
+    pub fn remove(&mut self, pid: &Principal) {
+        let Some(idx) = self.index_of.remove(pid) else {
+            return;
+        };
+
+        let last_idx = self.by_index.len() - 1;
+        let last_pid = self
+            .by_index
+            .remove(&last_idx)
+            .expect("The last participant should exist");
+
+        if idx != last_idx {
+            self.by_index.insert(idx, last_pid);
+            self.index_of.insert(last_pid, idx);
+        }
+    }
+
+    pub fn get(&self, idx: u64) -> Option<Principal> {
+        self.by_index.get(&idx)
+    }
+
+    pub fn contains(&self, pid: &Principal) -> bool {
+        self.index_of.contains_key(pid)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.by_index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_index.is_empty()
+    }
+}
+
 impl Storable for BurnerStateInfo {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         std::borrow::Cow::Owned(encode_one(self).unwrap())
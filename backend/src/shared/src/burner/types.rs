@@ -1,27 +1,61 @@
 use std::{cmp::max, collections::BTreeSet};
 
-use candid::{CandidType, Principal};
+use candid::{decode_one, encode_one, CandidType, Nat, Principal};
 use ic_e8s::c::{E8s, ECs};
-use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, Storable,
+};
 use num_bigint::BigUint;
 use serde::Deserialize;
 use sha2::Digest;
 
-use crate::{cmc::XdrData, ONE_DAY_NS, ONE_HOUR_NS, ONE_MINUTE_NS, ONE_WEEK_NS};
+use crate::{
+    cmc::XdrData,
+    merkle::merkle_leaf_hash,
+    randomness::{random_u128, verify_seed, SeedCommitment},
+    ONE_DAY_NS, ONE_HOUR_NS, ONE_MINUTE_NS, ONE_WEEK_NS,
+};
 
 pub type TCycles = ECs<12>;
 pub type TimestampNs = u64;
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 pub const TCYCLE_POS_ROUND_BASE_FEE: u64 = 100_000_000_000_u64;
+pub const TCYCLE_POS_ROUND_MIN_FEE: u64 = 10_000_000_000_u64;
+pub const TCYCLE_POS_ROUND_MAX_FEE: u64 = 1_000_000_000_000_u64;
+// the base fee is defined for this rate and scales linearly with it
+pub const REFERENCE_ICP_TO_CYCLES_EXCHANGE_RATE: u64 = 8_0000_0000_0000u64; // 8T per ICP
+
+// an average position should last for about a week
+pub const SHARE_FEE_TARGET_POSITION_LIFESPAN_ROUNDS: u64 = 5040;
 
 pub const POS_ROUND_START_REWARD_E8S: u64 = 1024_0000_0000_u64;
 pub const POS_ROUND_END_REWARD_E8S: u64 = 1_0000_0000_u64;
 pub const POS_ROUND_DELAY_NS: u64 = ONE_MINUTE_NS * 2;
 pub const POS_ROUNDS_PER_HALVING: u64 = 5040;
 pub const POS_ACCOUNTS_PER_BATCH: u64 = 300;
+pub const MAX_EMISSION_PROJECTION_ROUNDS: u32 = POS_ROUNDS_PER_HALVING as u32 * 2;
+pub const MAX_ROUND_HISTORY_ENTRIES_PER_REQUEST: u32 = 500;
+
+pub const FEATURE_STAKE: &str = "stake";
+pub const FEATURE_STAKE_KAMIKAZE: &str = "stake_kamikaze";
+// the features which are disabled, until explicitly enabled
+pub const BURNER_FEATURES: &[(&str, &str)] = &[
+    (
+        FEATURE_STAKE,
+        "The mining is temporarily stopped. Please, come back later",
+    ),
+    (
+        FEATURE_STAKE_KAMIKAZE,
+        "The mining is temporarily stopped. Please, come back later",
+    ),
+];
 
 pub const UPDATE_SEED_DOMAIN: &[u8] = b"msq-burn-update-seed";
+pub const KAMIKAZE_ROUND_SEED_DOMAIN: &[u8] = b"msq-burn-kamikaze-round";
+pub const KAMIKAZE_ROUND_PROOFS_TO_KEEP: u64 = 720; // about a day of rounds
+pub const LOTTERY_ROUND_SEED_DOMAIN: &[u8] = b"msq-burn-lottery-round";
+pub const LOTTERY_ROUND_PROOFS_TO_KEEP: u64 = 720;
 
 pub const BURNER_REDISTRIBUTION_SUBACCOUNT: [u8; 32] = [0u8; 32];
 pub const BURNER_SPIKE_SUBACCOUNT: [u8; 32] = [
@@ -34,14 +68,33 @@ pub const BURNER_DEV_FEE_SUBACCOUNT: [u8; 32] = [
 pub const REDISTRIBUTION_SPIKE_SHARE_E8S: u64 = 4750_0000; // 47.5%
 pub const REDISTRIBUTION_FURNACE_SHARE_E8S: u64 = 5000_0000; // 50%
 pub const REDISTRIBUTION_DEV_SHARE_E8S: u64 = 0250_0000; // 2.5%
+pub const REDISTRIBUTION_MIN_BALANCE_E8S: u64 = 1_0000_0000; // 1 ICP
+pub const REDISTRIBUTIONS_TO_KEEP: u64 = 2000;
 
-pub const KAMIKAZE_POOL_POSITION_LIFESPAN_NS: TimestampNs = ONE_DAY_NS;
 pub const ICPSWAP_PRICE_UPDATE_INTERVAL_NS: u64 = ONE_MINUTE_NS * 10;
 pub const ICP_REDISTRIBUTION_INTERVAL_NS: u64 = ONE_HOUR_NS * 3;
 pub const SPIKING_INTERVAL_NS: u64 = ONE_HOUR_NS * 6;
+// while a spike is in progress, it is retried much more often
+pub const SPIKE_RETRY_INTERVAL_NS: u64 = ONE_MINUTE_NS * 10;
+// the transfer has to be retried within the ledger's deduplication window
+pub const SPIKE_MAX_TRANSFER_ATTEMPTS: u32 = 12;
 pub const SPIKE_RECORD_DOWNGRADE_TIMEOUT_NS: TimestampNs = ONE_WEEK_NS * 2;
 pub const DEFAULT_SPIKE_TARGET_E8S: u64 = 20_000_0000_0000u64; // 20k ICP
 
+// the ICP ledger only deduplicates transactions within this window, so do we
+pub const STAKE_DEDUP_WINDOW_NS: u64 = ONE_DAY_NS;
+// how long the target account has to accept an account transfer
+pub const ACCOUNT_TRANSFER_WINDOW_NS: u64 = ONE_DAY_NS;
+
+pub const SHARES_SNAPSHOT_CHUNK_SIZE: u64 = 1000;
+pub const SHARES_SNAPSHOTS_TO_KEEP: u64 = 10;
+// the accounts copied (or the old entries pruned) in a single message
+pub const SHARES_SNAPSHOT_ENTRIES_PER_BATCH: u64 = 2000;
+
+pub const STAKE_PERMITTED_DRIFT_NS: u64 = ONE_MINUTE_NS;
+
+pub const MAX_ACCOUNT_HISTORY_ENTRIES_PER_REQUEST: u32 = 500;
+
 #[derive(CandidType, Deserialize, Clone, Default, Debug)]
 pub struct BurnerStateInfo {
     pub total_shares_supply: TCycles,
@@ -56,6 +109,7 @@ pub struct BurnerStateInfo {
     pub lottery_enabled: Option<bool>,
 
     pub kamikaze_pool_total_shares: Option<TCycles>,
+    pub kamikaze_pool_total_weight: Option<TCycles>,
     pub next_kamikaze_id: Option<Principal>,
     pub kamikaze_pool_random_number: Option<TCycles>,
     pub kamikaze_pool_counter: Option<TCycles>,
@@ -66,6 +120,20 @@ pub struct BurnerStateInfo {
     pub icp_to_cycles_exchange_rate: Option<TCycles>,
     pub icp_burn_spike_target: Option<u64>,
     pub prev_icp_burn_spike_timestamp_ns: Option<TimestampNs>,
+
+    pub current_round_summary: Option<RoundSummary>,
+
+    pub current_share_fee: Option<TCycles>,
+
+    // none means the original halving schedule, started at round 0
+    pub emission_schedule: Option<EmissionSchedule>,
+    pub emission_schedule_start_round: Option<u64>,
+    pub next_emission_schedule: Option<(u64, EmissionSchedule)>,
+
+    pub shares_snapshot_requested: Option<bool>,
+    // none means the snapshots are only taken on request
+    pub shares_snapshot_interval_rounds: Option<u64>,
+    pub shares_snapshot_in_progress: Option<SharesSnapshotProgress>,
 }
 
 impl BurnerStateInfo {
@@ -106,7 +174,7 @@ impl BurnerStateInfo {
         self.icp_to_cycles_exchange_rate
             .clone()
             // shouldn't ever be the case, since we're fetching the rate each 10 minutes, but defaults to 8T per ICP
-            .unwrap_or(TCycles::from(8_0000_0000_0000u64))
+            .unwrap_or(TCycles::from(REFERENCE_ICP_TO_CYCLES_EXCHANGE_RATE))
     }
 
     pub fn update_icp_to_cycles_exchange_rate(&mut self, new_rate: XdrData) {
@@ -116,6 +184,14 @@ impl BurnerStateInfo {
         self.icp_to_cycles_exchange_rate = Some(rate_tcycles);
     }
 
+    pub fn get_kamikaze_pool_total_weight(&self) -> TCycles {
+        // before the tiers were introduced, all positions had the weight of 1
+        self.kamikaze_pool_total_weight
+            .clone()
+            .or(self.kamikaze_pool_total_shares.clone())
+            .unwrap_or_default()
+    }
+
     pub fn is_kamikaze_pool_enabled(&self) -> bool {
         self.kamikaze_pool_enabled.unwrap_or_default()
     }
@@ -144,39 +220,98 @@ impl BurnerStateInfo {
         self.current_pos_round += 1;
         self.next_burner_id = None;
         self.update_seed();
+        self.advance_emission_schedule();
+    }
 
-        // each 5040 blocks we half the reward, until it reaches 0.0014 BURN per block
-        if (self.current_pos_round % POS_ROUNDS_PER_HALVING) == 0 {
-            let end_reward = E8s::from(POS_ROUND_END_REWARD_E8S);
+    pub fn get_emission_schedule(&self) -> EmissionSchedule {
+        self.emission_schedule.clone().unwrap_or_default()
+    }
 
-            if self.current_burn_token_reward > end_reward {
-                self.current_burn_token_reward.val /= BigUint::from(2u64);
+    pub fn schedule_emission_schedule(
+        &mut self,
+        schedule: EmissionSchedule,
+        activate_at_round: u64,
+    ) -> Result<(), String> {
+        if activate_at_round <= self.current_pos_round {
+            return Err(format!(
+                "The schedule can only be activated at a future round (current round is {})",
+                self.current_pos_round
+            ));
+        }
 
-                if self.current_burn_token_reward < end_reward {
-                    self.current_burn_token_reward = end_reward;
-                }
+        schedule.validate()?;
+        self.next_emission_schedule = Some((activate_at_round, schedule));
+
+        Ok(())
+    }
+
+    // should be called right after the round counter is incremented
+    pub fn advance_emission_schedule(&mut self) {
+        if let Some((activate_at_round, _)) = &self.next_emission_schedule {
+            if *activate_at_round <= self.current_pos_round {
+                let (_, schedule) = self.next_emission_schedule.take().unwrap();
+
+                self.current_burn_token_reward = schedule.start_reward();
+                self.emission_schedule = Some(schedule);
+                self.emission_schedule_start_round = Some(self.current_pos_round);
+
+                return;
             }
         }
+
+        let rounds_since_start =
+            self.current_pos_round - self.emission_schedule_start_round.unwrap_or_default();
+
+        self.current_burn_token_reward = self
+            .get_emission_schedule()
+            .next_reward(rounds_since_start, &self.current_burn_token_reward);
     }
 
-    pub fn generate_random_number(&mut self) -> TCycles {
-        self.update_seed();
+    pub fn project_emissions(&self, rounds: u32) -> Vec<(u64, E8s)> {
+        let mut info = self.clone();
+        let mut entries = Vec::with_capacity(rounds as usize);
 
-        let mut v_buf = [0u8; 16];
-        v_buf.copy_from_slice(&self.seed[0..16]);
-        let v = u128::from_le_bytes(v_buf);
+        for _ in 0..rounds {
+            entries.push((
+                info.current_pos_round,
+                info.current_burn_token_reward.clone(),
+            ));
 
-        let base = TCycles::from(u128::MAX);
-        let value = TCycles::from(v);
+            info.current_pos_round += 1;
+            info.advance_emission_schedule();
+        }
 
-        value / base
+        entries
     }
 
-    pub fn current_winning_idx(&self, total_options: u64) -> u64 {
-        let mut rng_buf = [0u8; 8];
-        rng_buf.copy_from_slice(&self.seed[0..8]);
+    // the summary is opened by whichever batch of the round runs first and is closed once the round completes
+    pub fn current_round_summary_mut(&mut self, now: TimestampNs) -> &mut RoundSummary {
+        let round = self.current_pos_round;
+
+        self.current_round_summary
+            .get_or_insert_with(|| RoundSummary {
+                round,
+                started_at: now,
+                ..Default::default()
+            })
+    }
 
-        u64::from_le_bytes(rng_buf) % total_options
+    pub fn is_round_in_progress(&self) -> bool {
+        self.current_round_summary.is_some()
+            || self.next_burner_id.is_some()
+            || self.next_kamikaze_id.is_some()
+    }
+
+    // resets the request, if there was one
+    pub fn should_take_shares_snapshot(&mut self, completed_round: u64) -> bool {
+        let requested = self.shares_snapshot_requested.take().unwrap_or_default();
+        let scheduled = self
+            .shares_snapshot_interval_rounds
+            .filter(|it| *it > 0)
+            .map(|it| (completed_round + 1).is_multiple_of(it))
+            .unwrap_or_default();
+
+        requested || scheduled
     }
 
     pub fn update_seed(&mut self) {
@@ -208,7 +343,765 @@ impl BurnerStateInfo {
         }
     }
 
-    pub fn get_current_fee() -> TCycles {
-        TCycles::from(TCYCLE_POS_ROUND_BASE_FEE)
+    pub fn get_current_fee(&self) -> TCycles {
+        self.current_share_fee
+            .clone()
+            .unwrap_or(TCycles::from(TCYCLE_POS_ROUND_BASE_FEE))
+    }
+
+    // should only be called between rounds
+    pub fn set_current_fee(&mut self, fee: TCycles) {
+        let min_fee = TCycles::from(TCYCLE_POS_ROUND_MIN_FEE);
+        let max_fee = TCycles::from(TCYCLE_POS_ROUND_MAX_FEE);
+
+        self.current_share_fee = Some(fee.clamp(min_fee, max_fee));
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum EmissionSchedule {
+    // the reward is halved each `rounds_per_halving` rounds, until it reaches `end_reward`
+    Halving {
+        start_reward: E8s,
+        end_reward: E8s,
+        rounds_per_halving: u64,
+    },
+    // the reward is decreased by `decay_per_round` each round, until it reaches `end_reward`
+    LinearDecay {
+        start_reward: E8s,
+        end_reward: E8s,
+        decay_per_round: E8s,
+    },
+    // a list of (rounds since the schedule start, reward) pairs, the first one should start at 0
+    Piecewise {
+        steps: Vec<(u64, E8s)>,
+    },
+}
+
+impl Default for EmissionSchedule {
+    fn default() -> Self {
+        Self::Halving {
+            start_reward: E8s::from(POS_ROUND_START_REWARD_E8S),
+            end_reward: E8s::from(POS_ROUND_END_REWARD_E8S),
+            rounds_per_halving: POS_ROUNDS_PER_HALVING,
+        }
+    }
+}
+
+impl EmissionSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Halving {
+                start_reward,
+                end_reward,
+                rounds_per_halving,
+            } => {
+                if *rounds_per_halving == 0 {
+                    return Err(String::from("Rounds per halving should be greater than 0"));
+                }
+
+                if start_reward < end_reward {
+                    return Err(String::from(
+                        "Start reward should not be less than end reward",
+                    ));
+                }
+            }
+            Self::LinearDecay {
+                start_reward,
+                end_reward,
+                ..
+            } => {
+                if start_reward < end_reward {
+                    return Err(String::from(
+                        "Start reward should not be less than end reward",
+                    ));
+                }
+            }
+            Self::Piecewise { steps } => {
+                if steps.first().map(|(round, _)| *round) != Some(0) {
+                    return Err(String::from("The first step should start at round 0"));
+                }
+
+                if steps.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    return Err(String::from(
+                        "Steps should be sorted by round without duplicates",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn start_reward(&self) -> E8s {
+        match self {
+            Self::Halving { start_reward, .. } => start_reward.clone(),
+            Self::LinearDecay { start_reward, .. } => start_reward.clone(),
+            Self::Piecewise { steps } => steps
+                .first()
+                .map(|(_, reward)| reward.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn next_reward(&self, rounds_since_start: u64, cur_reward: &E8s) -> E8s {
+        match self {
+            Self::Halving {
+                end_reward,
+                rounds_per_halving,
+                ..
+            } => {
+                if !rounds_since_start.is_multiple_of(*rounds_per_halving)
+                    || cur_reward <= end_reward
+                {
+                    return cur_reward.clone();
+                }
+
+                let mut reward = cur_reward.clone();
+                reward.val /= BigUint::from(2u64);
+
+                if &reward < end_reward {
+                    end_reward.clone()
+                } else {
+                    reward
+                }
+            }
+            Self::LinearDecay {
+                end_reward,
+                decay_per_round,
+                ..
+            } => {
+                if cur_reward <= end_reward {
+                    return cur_reward.clone();
+                }
+
+                if cur_reward - end_reward > *decay_per_round {
+                    cur_reward - decay_per_round
+                } else {
+                    end_reward.clone()
+                }
+            }
+            Self::Piecewise { steps } => steps
+                .iter()
+                .rev()
+                .find(|(round, _)| *round <= rounds_since_start)
+                .map(|(_, reward)| reward.clone())
+                .unwrap_or(cur_reward.clone()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KamikazeTier {
+    #[default]
+    OneDay,
+    ThreeDays,
+    SevenDays,
+}
+
+impl KamikazeTier {
+    pub fn lifespan_ns(&self) -> TimestampNs {
+        match self {
+            Self::OneDay => ONE_DAY_NS,
+            Self::ThreeDays => ONE_DAY_NS * 3,
+            Self::SevenDays => ONE_WEEK_NS,
+        }
     }
+
+    // longer positions take part in more rounds, so their per-round weight is lower,
+    // but still slightly above the proportional one, to reward the commitment
+    pub fn weight_multiplier(&self) -> TCycles {
+        match self {
+            Self::OneDay => TCycles::one(),
+            Self::ThreeDays => TCycles::f0_4(),
+            Self::SevenDays => TCycles::f0_2(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KamikazePosition {
+    pub share: TCycles,
+    pub created_at: TimestampNs,
+    pub tier: KamikazeTier,
+    // the sum of the weights added to the pool total by each top-up, see `add_share`
+    pub weight: Option<TCycles>,
+}
+
+impl KamikazePosition {
+    pub fn weight(&self) -> TCycles {
+        self.weight
+            .clone()
+            .unwrap_or(&self.share * self.tier.weight_multiplier())
+    }
+
+    // returns the weight that is added to the pool total, the same value is subtracted once the position expires
+    pub fn add_share(&mut self, qty: &TCycles) -> TCycles {
+        let weight = qty * self.tier.weight_multiplier();
+
+        self.weight = Some(self.weight() + &weight);
+        self.share += qty;
+
+        weight
+    }
+
+    pub fn is_expired(&self, now: TimestampNs) -> bool {
+        now - self.created_at >= self.tier.lifespan_ns()
+    }
+}
+
+impl Storable for KamikazePosition {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        if let Ok(position) = decode_one(&bytes) {
+            return position;
+        }
+
+        // positions created before the tiers were introduced are stored as (share, created_at) tuples
+        let (share, created_at) = <(TCycles, TimestampNs)>::from_bytes(bytes);
+
+        Self {
+            share,
+            created_at,
+            tier: KamikazeTier::OneDay,
+            weight: None,
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StakeKind {
+    Common,
+    Kamikaze(KamikazeTier),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StakeRecord {
+    pub kind: StakeKind,
+    pub qty_e8s_u64: u64,
+    // none, while the ledger call is in flight
+    pub block_idx: Option<Nat>,
+    pub shares_minted: TCycles,
+}
+
+impl Storable for StakeRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AccountEventKind {
+    Stake {
+        qty_e8s_u64: u64,
+        shares_minted: TCycles,
+    },
+    KamikazeStake {
+        qty_e8s_u64: u64,
+        shares_minted: TCycles,
+    },
+    // only in the daily aggregate, the rounds of the same day are summed up from `round` to `last_round`
+    PosRound {
+        round: u64,
+        last_round: Option<u64>,
+        shares_burned: TCycles,
+        reward_accrued: E8s,
+    },
+    KamikazeRoundWon {
+        round: u64,
+        reward_accrued: E8s,
+    },
+    LotteryRoundWon {
+        round: u64,
+        reward_accrued: E8s,
+    },
+    RewardClaimed {
+        qty: E8s,
+    },
+    RewardClaimReverted {
+        qty: E8s,
+    },
+    MigratedTo {
+        to: Principal,
+        share: TCycles,
+        reward: E8s,
+    },
+    MigratedFrom {
+        from: Principal,
+        share: TCycles,
+        reward: E8s,
+    },
+    AccountTransferredTo {
+        to: Principal,
+        transfer_id: u64,
+    },
+    AccountTransferredFrom {
+        from: Principal,
+        transfer_id: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountEvent {
+    pub timestamp: TimestampNs,
+    pub kind: AccountEventKind,
+}
+
+impl Storable for AccountEvent {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct RoundSummary {
+    pub round: u64,
+    pub started_at: TimestampNs,
+    pub ended_at: TimestampNs,
+    pub reward_emitted: E8s,
+    pub tcycles_burned: TCycles,
+    pub share_fee: TCycles,
+    pub participants: u64,
+    pub kamikaze_pool_ran: bool,
+    pub kamikaze_winner: Option<Principal>,
+    pub lottery_winner: Option<Principal>,
+}
+
+impl Storable for RoundSummary {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharesSnapshotEntry {
+    pub pid: Principal,
+    pub share: TCycles,
+    pub kamikaze_share: TCycles,
+}
+
+impl SharesSnapshotEntry {
+    /// The Merkle leaf of the entry: each field is prefixed with its length as a single byte,
+    /// the principal goes as raw bytes, the shares - as big-endian integers of TCycles base units.
+    pub fn leaf_hash(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for field in [
+            self.pid.as_slice().to_vec(),
+            self.share.val.to_bytes_be(),
+            self.kamikaze_share.val.to_bytes_be(),
+        ] {
+            buf.push(field.len() as u8);
+            buf.extend(field);
+        }
+
+        merkle_leaf_hash(&buf)
+    }
+}
+
+impl Storable for SharesSnapshotEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A frozen copy of the pool and kamikaze shares, taken at a round boundary.
+///
+/// Entries are ordered by principal and split into chunks of `chunk_size`. Each chunk hash is the
+/// Merkle root of its entries' leaf hashes, and `merkle_root` is the Merkle root of the chunk hashes.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharesSnapshot {
+    // the last round, that was completed before the snapshot was taken
+    pub round: u64,
+    pub created_at: TimestampNs,
+    pub total_entries: u64,
+    pub total_share: TCycles,
+    pub total_kamikaze_share: TCycles,
+    pub chunk_size: u64,
+    pub chunk_hashes: Vec<Vec<u8>>,
+    pub merkle_root: Vec<u8>,
+}
+
+/// A snapshot which is being built batch by batch. It only becomes a `SharesSnapshot` once every
+/// account is copied - until then its entries are not served.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SharesSnapshotProgress {
+    pub id: u64,
+    pub round: u64,
+    pub created_at: TimestampNs,
+    // the last account copied so far
+    pub last_pid: Option<Principal>,
+    pub total_entries: u64,
+    pub total_share: TCycles,
+    pub total_kamikaze_share: TCycles,
+    // only the complete chunks are hashed, the last one - when the snapshot is complete
+    pub chunk_hashes: Vec<Vec<u8>>,
+}
+
+impl Storable for SharesSnapshot {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountTransferProposal {
+    pub from: Principal,
+    pub to: Principal,
+    pub proposed_at: TimestampNs,
+    pub expires_at: TimestampNs,
+}
+
+impl Storable for AccountTransferProposal {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AccountTransferOutcome {
+    Completed {
+        share: TCycles,
+        unclaimed_reward: E8s,
+        kamikaze_position: Option<KamikazePosition>,
+        kamikaze_rounds_won: u64,
+        lottery_rounds_won: u64,
+        decide_id_verified: bool,
+    },
+    Cancelled {
+        by: Principal,
+    },
+    Expired,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountTransferRecord {
+    pub from: Principal,
+    pub to: Principal,
+    pub proposed_at: TimestampNs,
+    pub finished_at: TimestampNs,
+    pub outcome: AccountTransferOutcome,
+}
+
+impl Storable for AccountTransferRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum SpikeStatus {
+    // sending the ICP to the CMC
+    Transferring,
+    // the ICP is sent, waiting for the CMC to mint the cycles
+    Notifying {
+        block_index: u64,
+    },
+    Completed {
+        block_index: u64,
+        cycles_minted: Nat,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SpikeEvent {
+    pub started_at: TimestampNs,
+    pub target_e8s: u64,
+    // includes the transfer fee
+    pub qty_e8s: u64,
+    pub status: SpikeStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub finished_at: Option<TimestampNs>,
+    pub next_target_e8s: Option<u64>,
+}
+
+impl SpikeEvent {
+    pub fn is_in_progress(&self) -> bool {
+        matches!(
+            self.status,
+            SpikeStatus::Transferring | SpikeStatus::Notifying { .. }
+        )
+    }
+}
+
+impl Storable for SpikeEvent {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedistributionDestination {
+    Furnace,
+    Spike,
+    Dev,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RedistributionSplit {
+    pub destination: RedistributionDestination,
+    // includes the transfer fee
+    pub qty_e8s: u64,
+    // none, if the split was too small to cover the transfer fee
+    pub outbox_transfer_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RedistributionRecord {
+    pub timestamp: TimestampNs,
+    pub balance_e8s: u64,
+    // the part of the balance, that was still reserved by pending transfers of previous redistributions
+    pub reserved_e8s: u64,
+    pub splits: Vec<RedistributionSplit>,
+}
+
+impl Storable for RedistributionRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// everything needed to replay the winner selection of a kamikaze round
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KamikazeRoundProof {
+    pub commitment: SeedCommitment,
+    pub total_weight: TCycles,
+    // positions in the order they were visited, until the winner was found
+    pub walk: Vec<(Principal, TCycles)>,
+    pub winner: Option<Principal>,
+}
+
+impl KamikazeRoundProof {
+    pub fn new(secret: &[u8], round: u64) -> Self {
+        Self {
+            commitment: SeedCommitment::new(secret, KAMIKAZE_ROUND_SEED_DOMAIN, round),
+            total_weight: TCycles::zero(),
+            walk: Vec::new(),
+            winner: None,
+        }
+    }
+
+    pub fn random_number(seed: &[u8]) -> TCycles {
+        let base = TCycles::from(u128::MAX);
+        let value = TCycles::from(random_u128(seed));
+
+        value / base
+    }
+
+    pub fn verify(&self, round: u64) -> KamikazeRoundVerification {
+        let seed = self.commitment.revealed_seed();
+
+        let mut result = KamikazeRoundVerification {
+            round,
+            seed_hash: self.commitment.seed_hash.clone(),
+            seed: seed.clone(),
+            seed_matches_hash: false,
+            random_number: None,
+            total_weight: self.total_weight.clone(),
+            walk: Vec::new(),
+            winner: self.winner,
+            recomputed_winner: None,
+            is_valid: false,
+        };
+
+        let Some(seed) = seed else {
+            return result;
+        };
+
+        let random_number = Self::random_number(&seed);
+        let mut counter = TCycles::zero();
+
+        for (pid, weight) in &self.walk {
+            counter += weight / &self.total_weight;
+            result.walk.push((*pid, weight.clone(), counter.clone()));
+
+            if result.recomputed_winner.is_none() && counter >= random_number {
+                result.recomputed_winner = Some(*pid);
+            }
+        }
+
+        result.seed_matches_hash = verify_seed(&seed, &self.commitment.seed_hash);
+        result.random_number = Some(random_number);
+        result.is_valid = result.seed_matches_hash
+            && result.recomputed_winner == self.winner
+            && self.walk.last().map(|(pid, _)| *pid) == self.winner;
+
+        result
+    }
+}
+
+impl Storable for KamikazeRoundProof {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KamikazeRoundVerification {
+    pub round: u64,
+    pub seed_hash: Vec<u8>,
+    // only revealed after the winner is selected
+    pub seed: Option<Vec<u8>>,
+    pub seed_matches_hash: bool,
+    pub random_number: Option<TCycles>,
+    pub total_weight: TCycles,
+    // (pid, weight, cumulative normalized weight)
+    pub walk: Vec<(Principal, TCycles, TCycles)>,
+    pub winner: Option<Principal>,
+    pub recomputed_winner: Option<Principal>,
+    pub is_valid: bool,
+}
+
+// everything needed to replay the winner selection of a lottery round
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LotteryRoundProof {
+    pub commitment: SeedCommitment,
+    pub participants: u64,
+    pub winner_idx: Option<u64>,
+    pub winner: Option<Principal>,
+}
+
+impl LotteryRoundProof {
+    pub fn new(secret: &[u8], round: u64) -> Self {
+        Self {
+            commitment: SeedCommitment::new(secret, LOTTERY_ROUND_SEED_DOMAIN, round),
+            participants: 0,
+            winner_idx: None,
+            winner: None,
+        }
+    }
+
+    pub fn winning_idx(seed: &[u8], participants: u64) -> u64 {
+        let mut rng_buf = [0u8; 8];
+        rng_buf.copy_from_slice(&seed[0..8]);
+
+        u64::from_le_bytes(rng_buf) % participants
+    }
+
+    pub fn verify(&self, round: u64) -> LotteryRoundVerification {
+        let seed = self.commitment.revealed_seed();
+
+        let mut result = LotteryRoundVerification {
+            round,
+            seed_hash: self.commitment.seed_hash.clone(),
+            seed: seed.clone(),
+            seed_matches_hash: false,
+            participants: self.participants,
+            winner_idx: self.winner_idx,
+            recomputed_winner_idx: None,
+            winner: self.winner,
+            is_valid: false,
+        };
+
+        let Some(seed) = seed else {
+            return result;
+        };
+
+        if self.participants > 0 {
+            result.recomputed_winner_idx = Some(Self::winning_idx(&seed, self.participants));
+        }
+
+        result.seed_matches_hash = verify_seed(&seed, &self.commitment.seed_hash);
+        result.is_valid = result.seed_matches_hash
+            && self.winner.is_some()
+            && result.recomputed_winner_idx == self.winner_idx;
+
+        result
+    }
+}
+
+impl Storable for LotteryRoundProof {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LotteryRoundVerification {
+    pub round: u64,
+    pub seed_hash: Vec<u8>,
+    // only revealed after the winner is selected
+    pub seed: Option<Vec<u8>>,
+    pub seed_matches_hash: bool,
+    // the number of participants the winner was selected among
+    pub participants: u64,
+    pub winner_idx: Option<u64>,
+    pub recomputed_winner_idx: Option<u64>,
+    pub winner: Option<Principal>,
+    pub is_valid: bool,
 }
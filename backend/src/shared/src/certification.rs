@@ -0,0 +1,52 @@
+use candid::{encode_one, CandidType};
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certification::{labeled, labeled_hash, AsHashTree, HashTree, RbTree};
+use serde::Serialize;
+
+pub const CERTIFIED_TOTALS_LABEL: &[u8] = b"totals";
+
+/// The global counters of a canister, certified with `set_certified_data`.
+///
+/// The certified data is the root hash of `labeled("totals", tree)`, where the tree maps
+/// the counter names to their candid-encoded values. Since there are only a few counters,
+/// the witness is the whole tree, so a client can check every value against the certificate at once.
+///
+/// Lives on the heap and should be rebuilt after each upgrade.
+#[derive(Default)]
+pub struct CertifiedTotals {
+    tree: RbTree<Vec<u8>, Vec<u8>>,
+}
+
+impl CertifiedTotals {
+    pub fn insert<T: CandidType>(&mut self, key: &str, value: &T) {
+        self.tree.insert(
+            key.as_bytes().to_vec(),
+            encode_one(value).expect("Unable to encode"),
+        );
+    }
+
+    pub fn certify(&self) {
+        set_certified_data(&labeled_hash(
+            CERTIFIED_TOTALS_LABEL,
+            &self.tree.root_hash(),
+        ));
+    }
+
+    pub fn witness(&self) -> Vec<u8> {
+        let tree: HashTree = labeled(CERTIFIED_TOTALS_LABEL, self.tree.as_hash_tree());
+
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer
+            .self_describe()
+            .expect("Unable to serialize the witness");
+        tree.serialize(&mut serializer)
+            .expect("Unable to serialize the witness");
+
+        serializer.into_inner()
+    }
+}
+
+// the certificate is only available in non-replicated query calls
+pub fn get_data_certificate() -> Vec<u8> {
+    data_certificate().expect("The certificate is only available in query calls")
+}
@@ -8,7 +8,7 @@ pub struct NotifyTopUpRequest {
     pub canister_id: Principal,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum NotifyTopUpError {
     Refunded {
         block_index: Option<u64>,
@@ -36,19 +36,26 @@ pub struct XdrData {
     pub timestamp_seconds: u64,
 }
 
+pub trait CMCApi {
+    async fn notify_top_up(
+        &self,
+        req: NotifyTopUpRequest,
+    ) -> CallResult<(Result<Nat, NotifyTopUpError>,)>;
+
+    async fn get_icp_xdr_conversion_rate(&self) -> CallResult<(GetIcpXdrConversionRateResponse,)>;
+}
+
 pub struct CMCClient(pub Principal);
 
-impl CMCClient {
-    pub async fn notify_top_up(
+impl CMCApi for CMCClient {
+    async fn notify_top_up(
         &self,
         req: NotifyTopUpRequest,
     ) -> CallResult<(Result<Nat, NotifyTopUpError>,)> {
         call(self.0, "notify_top_up", (req,)).await
     }
 
-    pub async fn get_icp_xdr_conversion_rate(
-        &self,
-    ) -> CallResult<(GetIcpXdrConversionRateResponse,)> {
+    async fn get_icp_xdr_conversion_rate(&self) -> CallResult<(GetIcpXdrConversionRateResponse,)> {
         call(self.0, "get_icp_xdr_conversion_rate", ()).await
     }
 }
@@ -1,19 +1,28 @@
 use candid::Principal;
+#[cfg(feature = "decide-id")]
 use ic_canister_sig_creation::extract_raw_root_pk_from_der;
+#[cfg(feature = "decide-id")]
 use ic_verifiable_credentials::{
     issuer_api::CredentialSpec, validate_ii_presentation_and_claims, VcFlowSigners,
 };
 
+#[cfg(feature = "decide-id")]
 use crate::ENV_VARS;
 
+#[cfg(feature = "decide-id")]
 const ISSUER_CANISTER_ID: &str = "qgxyr-pyaaa-aaaah-qdcwq-cai";
+#[cfg(feature = "decide-id")]
 const ISSUER_ORIGIN: &str = "https://id.decideai.xyz/";
+#[cfg(feature = "decide-id")]
 const CRED_TYPE: &str = "ProofOfUniqueness";
 
 /**
- * This function uses the ic-verifiable-credentials library (https://github.com/dfinity/verifiable-credentials-sdk/tree/main/rust-packages/ic-verifiable-credentials)
- * cloned to the local directory. See top-level Cargo.toml for more details.
+ * This function uses the ic-verifiable-credentials library (https://github.com/dfinity/verifiable-credentials-sdk/tree/main/rust-packages/ic-verifiable-credentials).
+ *
+ * The library pulls getrandom in, which doesn't build for wasm32 on its own. The verification never
+ * needs randomness, so a failing source of it is registered below.
  */
+#[cfg(feature = "decide-id")]
 pub fn verify_decide_id_proof(jwt: &str, caller: Principal, now: u128) -> Result<(), String> {
     let signers = VcFlowSigners {
         ii_canister_id: ENV_VARS.ii_canister_id,
@@ -27,10 +36,33 @@ pub fn verify_decide_id_proof(jwt: &str, caller: Principal, now: u128) -> Result
         arguments: None,
     };
 
-    let pk =
-        extract_raw_root_pk_from_der(&ENV_VARS.ic_root_key_der).expect("Unable to extract the PK");
+    let pk = extract_raw_root_pk_from_der(&ENV_VARS.ic_root_key_der)
+        .map_err(|e| format!("Unable to extract the root PK: {}", e))?;
 
-    validate_ii_presentation_and_claims(&jwt, caller, &signers, &spec, &pk, now)
-        .map_err(|e| format!("{:?}", e))
-        .map(|_| ())
+    validate_ii_presentation_and_claims(
+        jwt,
+        caller,
+        ENV_VARS.frontend_origin.clone(),
+        &signers,
+        &spec,
+        &pk,
+        now,
+    )
+    .map_err(|e| format!("{:?}", e))
+    .map(|_| ())
+}
+
+#[cfg(all(feature = "decide-id", target_arch = "wasm32"))]
+fn no_randomness(_buf: &mut [u8]) -> Result<(), getrandom::Error> {
+    Err(getrandom::Error::UNSUPPORTED)
+}
+
+#[cfg(all(feature = "decide-id", target_arch = "wasm32"))]
+getrandom::register_custom_getrandom!(no_randomness);
+
+#[cfg(not(feature = "decide-id"))]
+pub fn verify_decide_id_proof(_jwt: &str, _caller: Principal, _now: u128) -> Result<(), String> {
+    Err(String::from(
+        "Decide ID verification is not available in this build",
+    ))
 }
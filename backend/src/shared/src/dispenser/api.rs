@@ -9,8 +9,8 @@ use crate::{utils::escape_script_tag, Guard, ENV_VARS};
 use super::{
     state::DispenserState,
     types::{
-        Distribution, DistributionId, DistributionScheme, DistributionStartCondition,
-        DistributionStatus,
+        CertifiedDispenserInfo, Distribution, DistributionId, DistributionScheme,
+        DistributionStartCondition, DistributionStatus,
     },
 };
 
@@ -256,3 +256,10 @@ pub struct WithdrawUserTokensResponse {
 pub struct InitArgs {
     pub token_can_id: Principal,
 }
+
+#[derive(CandidType, Deserialize)]
+pub struct GetInfoCertifiedResponse {
+    pub info: CertifiedDispenserInfo,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
+}
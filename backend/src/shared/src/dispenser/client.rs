@@ -2,6 +2,8 @@ use candid::{Nat, Principal};
 use ic_cdk::{api::call::CallResult, call};
 use icrc_ledger_types::icrc1::account::Account;
 
+use crate::feature_flags::{SetFeatureFlagRequest, SetFeatureFlagResponse};
+
 use super::{
     api::{
         CreateDistributionRequest, CreateDistributionResponse, FurnaceTriggerDistributionRequest,
@@ -10,39 +12,68 @@ use super::{
     types::{Distribution, DistributionId},
 };
 
+pub trait DispenserApi {
+    async fn withdraw_dev_fee(&self, to: Account, qty: Nat) -> CallResult<()>;
+
+    async fn furnace_trigger_distribution(
+        &self,
+        req: FurnaceTriggerDistributionRequest,
+    ) -> CallResult<(FurnaceTriggerDistributionResponse,)>;
+
+    async fn get_distribution(&self, id: DistributionId) -> CallResult<(Option<Distribution>,)>;
+
+    async fn create_distribution(
+        &self,
+        req: CreateDistributionRequest,
+    ) -> CallResult<(CreateDistributionResponse,)>;
+
+    async fn stop(&self) -> CallResult<()>;
+
+    async fn resume(&self) -> CallResult<()>;
+
+    async fn set_feature_flag(
+        &self,
+        req: SetFeatureFlagRequest,
+    ) -> CallResult<(SetFeatureFlagResponse,)>;
+}
+
 pub struct DispenserClient(pub Principal);
 
-impl DispenserClient {
-    pub async fn withdraw_dev_fee(&self, to: Account, qty: Nat) -> CallResult<()> {
+impl DispenserApi for DispenserClient {
+    async fn withdraw_dev_fee(&self, to: Account, qty: Nat) -> CallResult<()> {
         call(self.0, "withdraw_dev_fee", (to, qty)).await
     }
 
-    pub async fn furnace_trigger_distribution(
+    async fn furnace_trigger_distribution(
         &self,
         req: FurnaceTriggerDistributionRequest,
     ) -> CallResult<(FurnaceTriggerDistributionResponse,)> {
         call(self.0, "furnace_trigger_distribution", (req,)).await
     }
 
-    pub async fn get_distribution(
-        &self,
-        id: DistributionId,
-    ) -> CallResult<(Option<Distribution>,)> {
+    async fn get_distribution(&self, id: DistributionId) -> CallResult<(Option<Distribution>,)> {
         call(self.0, "get_distribution", (id,)).await
     }
 
-    pub async fn create_distribution(
+    async fn create_distribution(
         &self,
         req: CreateDistributionRequest,
     ) -> CallResult<(CreateDistributionResponse,)> {
         call(self.0, "create_distribution", (req,)).await
     }
 
-    pub async fn stop(&self) -> CallResult<()> {
+    async fn stop(&self) -> CallResult<()> {
         call(self.0, "stop", ()).await
     }
 
-    pub async fn resume(&self) -> CallResult<()> {
+    async fn resume(&self) -> CallResult<()> {
         call(self.0, "resume", ()).await
     }
+
+    async fn set_feature_flag(
+        &self,
+        req: SetFeatureFlagRequest,
+    ) -> CallResult<(SetFeatureFlagResponse,)> {
+        call(self.0, "set_feature_flag", (req,)).await
+    }
 }
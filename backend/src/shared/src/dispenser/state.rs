@@ -5,7 +5,10 @@ use ic_e8s::d::EDs;
 use ic_stable_structures::{Cell, StableBTreeMap};
 use num_bigint::BigUint;
 
-use crate::burner::types::{Memory, TCycles, TimestampNs};
+use crate::{
+    burner::types::{Memory, TCycles, TimestampNs},
+    feature_flags::FeatureFlags,
+};
 
 use super::{
     api::{
@@ -33,6 +36,8 @@ pub struct DispenserState {
 
     pub dispenser_info: Cell<DispenserInfo, Memory>,
     pub current_distribution_info: Cell<CurrentDistributionInfo, Memory>,
+
+    pub feature_flags: FeatureFlags,
 }
 
 impl DispenserState {
@@ -26,6 +26,13 @@ pub const DISPENSER_DEV_FEE_SUBACCOUNT: [u8; 32] = [
 pub const DISPENSER_ICP_FEE_E8S: u64 = 1_0000_0000;
 pub const DISPENSER_ICP_FEE_TRANSFORM_DELAY_NS: u64 = ONE_DAY_NS;
 
+pub const FEATURE_CREATE_DISTRIBUTION: &str = "create_distribution";
+// the features which are disabled, until explicitly enabled
+pub const DISPENSER_FEATURES: &[(&str, &str)] = &[(
+    FEATURE_CREATE_DISTRIBUTION,
+    "The dispensing is temporarily stopped. Please, come back later",
+)];
+
 #[derive(CandidType, Deserialize, Clone, Default)]
 pub struct DispenserInfo {
     pub initted: bool,
@@ -48,6 +55,12 @@ pub struct DispenserInfo {
     pub total_kamikaze_pool_members_weight: TCycles,
 }
 
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct CertifiedDispenserInfo {
+    pub total_distributed: Nat,
+    pub cur_tick: u64,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct DispenserInfoPub {
     pub initted: bool,
@@ -64,6 +77,13 @@ pub struct DispenserInfoPub {
 }
 
 impl DispenserInfo {
+    pub fn to_certified(&self) -> CertifiedDispenserInfo {
+        CertifiedDispenserInfo {
+            total_distributed: self.total_distributed.clone(),
+            cur_tick: self.cur_tick,
+        }
+    }
+
     pub fn to_pub(&self) -> DispenserInfoPub {
         DispenserInfoPub {
             initted: self.initted,
@@ -0,0 +1,509 @@
+//! In-memory implementations of the client traits, so the async flows of the canisters can be
+//! exercised with plain `cargo test`. Only available in tests and with the `fakes` feature.
+//!
+//! Each fake can be told to reject its next calls, which is how a real call fails when the callee
+//! traps, is stopped or is out of cycles.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
+};
+
+use candid::{encode_args, Nat, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use icrc_ledger_types::{
+    icrc1::{
+        account::Account,
+        transfer::{BlockIndex, Memo, TransferArg, TransferError},
+    },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
+
+use crate::{
+    cmc::{CMCApi, GetIcpXdrConversionRateResponse, NotifyTopUpError, NotifyTopUpRequest, XdrData},
+    dispenser::{
+        api::{
+            CreateDistributionRequest, CreateDistributionResponse,
+            FurnaceTriggerDistributionRequest, FurnaceTriggerDistributionResponse,
+        },
+        client::DispenserApi,
+        types::{Distribution, DistributionId},
+    },
+    feature_flags::{SetFeatureFlagRequest, SetFeatureFlagResponse},
+    icrc1::{ICRC1Api, StandardRecord, ICRC2_STANDARD},
+};
+
+#[derive(Default)]
+pub struct FakeRejects {
+    queue: RefCell<VecDeque<(RejectionCode, String)>>,
+}
+
+impl FakeRejects {
+    pub fn reject_next_call(&self, code: RejectionCode, message: &str) {
+        self.queue
+            .borrow_mut()
+            .push_back((code, message.to_string()));
+    }
+
+    fn check(&self) -> CallResult<()> {
+        match self.queue.borrow_mut().pop_front() {
+            Some(reject) => Err(reject),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An ICRC-1/ICRC-2 ledger with balances, allowances, fees and deduplication.
+///
+/// Transfers from the minting account mint tokens and transfers to it burn them, both without a fee.
+/// Transactions with `created_at_time` set are deduplicated forever, since there is no clock here.
+pub struct FakeICRC1Ledger {
+    // the canister which uses this client, it is the spender in `icrc2_transfer_from`
+    pub caller: Principal,
+    pub fee: Nat,
+    pub decimals: u8,
+    pub minting_account: Account,
+    // when false, the ledger only advertises ICRC-1, but still serves `icrc2_transfer_from`
+    pub advertises_icrc2: Cell<bool>,
+    pub rejects: FakeRejects,
+
+    balances: RefCell<BTreeMap<Account, Nat>>,
+    allowances: RefCell<BTreeMap<(Account, Account), Nat>>,
+    dedup: RefCell<BTreeMap<Vec<u8>, BlockIndex>>,
+    next_block_idx: Cell<u64>,
+    lost_responses: Cell<u32>,
+}
+
+impl FakeICRC1Ledger {
+    pub fn new(caller: Principal, fee: u64) -> Self {
+        Self {
+            caller,
+            fee: Nat::from(fee),
+            decimals: 8,
+            minting_account: Account {
+                owner: Principal::management_canister(),
+                subaccount: None,
+            },
+            advertises_icrc2: Cell::new(true),
+            rejects: FakeRejects::default(),
+            balances: RefCell::default(),
+            allowances: RefCell::default(),
+            dedup: RefCell::default(),
+            next_block_idx: Cell::new(0),
+            lost_responses: Cell::new(0),
+        }
+    }
+
+    pub fn set_balance(&self, account: Account, qty: u64) {
+        self.balances.borrow_mut().insert(account, Nat::from(qty));
+    }
+
+    pub fn balance(&self, account: &Account) -> Nat {
+        self.balances
+            .borrow()
+            .get(account)
+            .cloned()
+            .unwrap_or(Nat::from(0u64))
+    }
+
+    pub fn approve(&self, from: Account, spender: Account, qty: u64) {
+        self.allowances
+            .borrow_mut()
+            .insert((from, spender), Nat::from(qty));
+    }
+
+    pub fn allowance(&self, from: &Account, spender: &Account) -> Nat {
+        self.allowances
+            .borrow()
+            .get(&(*from, *spender))
+            .cloned()
+            .unwrap_or(Nat::from(0u64))
+    }
+
+    // the number of the executed transactions
+    pub fn blocks(&self) -> u64 {
+        self.next_block_idx.get()
+    }
+
+    /// The next transaction is executed, but its caller gets a reject instead of the response
+    pub fn lose_next_response(&self) {
+        self.lost_responses.set(self.lost_responses.get() + 1);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn execute(
+        &self,
+        from: Account,
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Memo>,
+        created_at_time: Option<u64>,
+        spender: Option<Account>,
+    ) -> CallResult<Result<BlockIndex, TransferFromError>> {
+        self.rejects.check()?;
+
+        let dedup_key = created_at_time.map(|_| {
+            encode_args((
+                from,
+                to,
+                amount.clone(),
+                fee.clone(),
+                memo,
+                created_at_time,
+                spender,
+            ))
+            .expect("Unable to encode")
+        });
+
+        if let Some(duplicate_of) = dedup_key
+            .as_ref()
+            .and_then(|key| self.dedup.borrow().get(key).cloned())
+        {
+            return self.respond(Err(TransferFromError::Duplicate { duplicate_of }));
+        }
+
+        let is_mint = from == self.minting_account;
+        let is_burn = to == self.minting_account;
+
+        let expected_fee = if is_mint || is_burn {
+            Nat::from(0u64)
+        } else {
+            self.fee.clone()
+        };
+
+        if fee.is_some_and(|it| it != expected_fee) {
+            return self.respond(Err(TransferFromError::BadFee { expected_fee }));
+        }
+
+        if is_burn && amount < self.fee {
+            return self.respond(Err(TransferFromError::BadBurn {
+                min_burn_amount: self.fee.clone(),
+            }));
+        }
+
+        let debit = amount.clone() + expected_fee;
+
+        if !is_mint {
+            // the allowance is checked first, like the real ledgers do
+            let allowance_opt = spender.map(|spender| (spender, self.allowance(&from, &spender)));
+            if let Some((_, allowance)) = &allowance_opt {
+                if allowance < &debit {
+                    return self.respond(Err(TransferFromError::InsufficientAllowance {
+                        allowance: allowance.clone(),
+                    }));
+                }
+            }
+
+            let balance = self.balance(&from);
+            if balance < debit {
+                return self.respond(Err(TransferFromError::InsufficientFunds { balance }));
+            }
+
+            if let Some((spender, allowance)) = allowance_opt {
+                self.allowances
+                    .borrow_mut()
+                    .insert((from, spender), allowance - debit.clone());
+            }
+
+            self.balances.borrow_mut().insert(from, balance - debit);
+        }
+
+        if !is_burn {
+            let balance = self.balance(&to);
+            self.balances.borrow_mut().insert(to, balance + amount);
+        }
+
+        let block_idx = Nat::from(self.next_block_idx.get());
+        self.next_block_idx.set(self.next_block_idx.get() + 1);
+
+        if let Some(key) = dedup_key {
+            self.dedup.borrow_mut().insert(key, block_idx.clone());
+        }
+
+        self.respond(Ok(block_idx))
+    }
+
+    fn respond<T>(&self, result: T) -> CallResult<T> {
+        if self.lost_responses.get() > 0 {
+            self.lost_responses.set(self.lost_responses.get() - 1);
+
+            return Err((
+                RejectionCode::SysTransient,
+                String::from("The response was lost"),
+            ));
+        }
+
+        Ok(result)
+    }
+}
+
+fn to_transfer_error(e: TransferFromError) -> TransferError {
+    match e {
+        TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+        TransferFromError::BadBurn { min_burn_amount } => {
+            TransferError::BadBurn { min_burn_amount }
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            TransferError::InsufficientFunds { balance }
+        }
+        TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+        TransferFromError::TooOld => TransferError::TooOld,
+        TransferFromError::CreatedInFuture { ledger_time } => {
+            TransferError::CreatedInFuture { ledger_time }
+        }
+        TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+        TransferFromError::GenericError {
+            error_code,
+            message,
+        } => TransferError::GenericError {
+            error_code,
+            message,
+        },
+        TransferFromError::InsufficientAllowance { .. } => {
+            unreachable!("There is no allowance check in icrc1_transfer")
+        }
+    }
+}
+
+impl ICRC1Api for FakeICRC1Ledger {
+    async fn icrc1_balance_of(&self, arg: Account) -> CallResult<(Nat,)> {
+        self.rejects.check()?;
+
+        Ok((self.balance(&arg),))
+    }
+
+    async fn icrc1_minting_account(&self) -> CallResult<(Option<Account>,)> {
+        self.rejects.check()?;
+
+        Ok((Some(self.minting_account),))
+    }
+
+    async fn icrc1_transfer(
+        &self,
+        arg: TransferArg,
+    ) -> CallResult<(Result<BlockIndex, TransferError>,)> {
+        let from = Account {
+            owner: self.caller,
+            subaccount: arg.from_subaccount,
+        };
+
+        self.execute(
+            from,
+            arg.to,
+            arg.amount,
+            arg.fee,
+            arg.memo,
+            arg.created_at_time,
+            None,
+        )
+        .map(|it| (it.map_err(to_transfer_error),))
+    }
+
+    async fn icrc2_transfer_from(
+        &self,
+        arg: TransferFromArgs,
+    ) -> CallResult<(Result<BlockIndex, TransferFromError>,)> {
+        let spender = Account {
+            owner: self.caller,
+            subaccount: arg.spender_subaccount,
+        };
+
+        self.execute(
+            arg.from,
+            arg.to,
+            arg.amount,
+            arg.fee,
+            arg.memo,
+            arg.created_at_time,
+            Some(spender),
+        )
+        .map(|it| (it,))
+    }
+
+    async fn icrc1_decimals(&self) -> CallResult<(u8,)> {
+        self.rejects.check()?;
+
+        Ok((self.decimals,))
+    }
+
+    async fn icrc1_fee(&self) -> CallResult<(Nat,)> {
+        self.rejects.check()?;
+
+        Ok((self.fee.clone(),))
+    }
+
+    async fn icrc1_total_supply(&self) -> CallResult<(Nat,)> {
+        self.rejects.check()?;
+
+        let total = self
+            .balances
+            .borrow()
+            .values()
+            .fold(Nat::from(0u64), |acc, it| acc + it.clone());
+
+        Ok((total,))
+    }
+
+    async fn icrc1_supported_standards(&self) -> CallResult<(Vec<StandardRecord>,)> {
+        self.rejects.check()?;
+
+        let mut standards = vec![StandardRecord {
+            name: String::from("ICRC-1"),
+            url: String::from("https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1"),
+        }];
+
+        if self.advertises_icrc2.get() {
+            standards.push(StandardRecord {
+                name: String::from(ICRC2_STANDARD),
+                url: String::from("https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2"),
+            });
+        }
+
+        Ok((standards,))
+    }
+}
+
+/// Notifications are idempotent, like in the real CMC - each block has a single outcome, set with
+/// `set_notify_result`. Notifying about an unknown block returns `InvalidTransaction`.
+#[derive(Default)]
+pub struct FakeCMC {
+    pub xdr_permyriad_per_icp: Cell<u64>,
+    pub rejects: FakeRejects,
+
+    notify_results: RefCell<BTreeMap<u64, Result<Nat, NotifyTopUpError>>>,
+    notified: RefCell<Vec<u64>>,
+}
+
+impl FakeCMC {
+    pub fn set_notify_result(&self, block_index: u64, result: Result<Nat, NotifyTopUpError>) {
+        self.notify_results.borrow_mut().insert(block_index, result);
+    }
+
+    // the block indices of all the notifications, including the repeated ones
+    pub fn notified(&self) -> Vec<u64> {
+        self.notified.borrow().clone()
+    }
+}
+
+impl CMCApi for FakeCMC {
+    async fn notify_top_up(
+        &self,
+        req: NotifyTopUpRequest,
+    ) -> CallResult<(Result<Nat, NotifyTopUpError>,)> {
+        self.rejects.check()?;
+        self.notified.borrow_mut().push(req.block_index);
+
+        let result = self
+            .notify_results
+            .borrow()
+            .get(&req.block_index)
+            .cloned()
+            .unwrap_or(Err(NotifyTopUpError::InvalidTransaction(String::from(
+                "Unknown block",
+            ))));
+
+        Ok((result,))
+    }
+
+    async fn get_icp_xdr_conversion_rate(&self) -> CallResult<(GetIcpXdrConversionRateResponse,)> {
+        self.rejects.check()?;
+
+        Ok((GetIcpXdrConversionRateResponse {
+            certificate: Vec::new(),
+            hash_tree: Vec::new(),
+            data: XdrData {
+                xdr_permyriad_per_icp: self.xdr_permyriad_per_icp.get(),
+                timestamp_seconds: 0,
+            },
+        },))
+    }
+}
+
+/// Keeps the created distributions and records the triggers and the dev fee withdrawals
+#[derive(Default)]
+pub struct FakeDispenser {
+    pub distributions: RefCell<BTreeMap<DistributionId, Distribution>>,
+    pub is_stopped: Cell<bool>,
+    pub rejects: FakeRejects,
+
+    created: RefCell<Vec<CreateDistributionRequest>>,
+    triggered: RefCell<Vec<DistributionId>>,
+    dev_fee_withdrawals: RefCell<Vec<(Account, Nat)>>,
+}
+
+impl FakeDispenser {
+    pub fn created(&self) -> usize {
+        self.created.borrow().len()
+    }
+
+    pub fn triggered(&self) -> Vec<DistributionId> {
+        self.triggered.borrow().clone()
+    }
+
+    pub fn dev_fee_withdrawals(&self) -> Vec<(Account, Nat)> {
+        self.dev_fee_withdrawals.borrow().clone()
+    }
+}
+
+impl DispenserApi for FakeDispenser {
+    async fn withdraw_dev_fee(&self, to: Account, qty: Nat) -> CallResult<()> {
+        self.rejects.check()?;
+        self.dev_fee_withdrawals.borrow_mut().push((to, qty));
+
+        Ok(())
+    }
+
+    async fn furnace_trigger_distribution(
+        &self,
+        req: FurnaceTriggerDistributionRequest,
+    ) -> CallResult<(FurnaceTriggerDistributionResponse,)> {
+        self.rejects.check()?;
+        self.triggered.borrow_mut().push(req.distribution_id);
+
+        Ok((FurnaceTriggerDistributionResponse {},))
+    }
+
+    async fn get_distribution(&self, id: DistributionId) -> CallResult<(Option<Distribution>,)> {
+        self.rejects.check()?;
+
+        Ok((self.distributions.borrow().get(&id).cloned(),))
+    }
+
+    async fn create_distribution(
+        &self,
+        req: CreateDistributionRequest,
+    ) -> CallResult<(CreateDistributionResponse,)> {
+        self.rejects.check()?;
+
+        let mut created = self.created.borrow_mut();
+        created.push(req);
+
+        Ok((CreateDistributionResponse {
+            distribution_id: (created.len() - 1) as DistributionId,
+        },))
+    }
+
+    async fn stop(&self) -> CallResult<()> {
+        self.rejects.check()?;
+        self.is_stopped.set(true);
+
+        Ok(())
+    }
+
+    async fn resume(&self) -> CallResult<()> {
+        self.rejects.check()?;
+        self.is_stopped.set(false);
+
+        Ok(())
+    }
+
+    async fn set_feature_flag(
+        &self,
+        _req: SetFeatureFlagRequest,
+    ) -> CallResult<(SetFeatureFlagResponse,)> {
+        self.rejects.check()?;
+
+        Ok((SetFeatureFlagResponse { result: Ok(()) },))
+    }
+}
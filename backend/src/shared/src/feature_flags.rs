@@ -0,0 +1,149 @@
+use candid::{decode_one, encode_one, CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+
+use crate::burner::types::TimestampNs;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeatureFlag {
+    pub enabled: bool,
+    // shown to the users, when they try to use a disabled feature
+    pub reason: Option<String>,
+    // a disabled feature is enabled back automatically at this moment
+    pub reenable_at: Option<TimestampNs>,
+
+    pub updated_at: TimestampNs,
+    pub updated_by: Option<Principal>,
+}
+
+impl FeatureFlag {
+    pub fn is_enabled(&self, now: TimestampNs) -> bool {
+        self.enabled || self.reenable_at.is_some_and(|it| it <= now)
+    }
+}
+
+impl Storable for FeatureFlag {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Runtime switches for the user-facing endpoints.
+///
+/// Each canister declares the features it has together with their default state. Unknown
+/// features can't be toggled and are always enabled.
+pub struct FeatureFlags {
+    pub flags: StableBTreeMap<String, FeatureFlag, Memory>,
+}
+
+impl FeatureFlags {
+    /// Adds the features which are not yet in the map as disabled with the provided reason.
+    /// Safe to call on each upgrade - the features which are already there keep their state.
+    pub fn init_disabled(&mut self, features: &[(&str, &str)], now: TimestampNs) {
+        for (feature, reason) in features {
+            let key = feature.to_string();
+
+            if self.flags.contains_key(&key) {
+                continue;
+            }
+
+            self.flags.insert(
+                key,
+                FeatureFlag {
+                    enabled: false,
+                    reason: Some(reason.to_string()),
+                    reenable_at: None,
+                    updated_at: now,
+                    updated_by: None,
+                },
+            );
+        }
+    }
+
+    pub fn is_enabled(&self, feature: &str, now: TimestampNs) -> bool {
+        self.flags
+            .get(&feature.to_string())
+            .map(|it| it.is_enabled(now))
+            .unwrap_or(true)
+    }
+
+    pub fn assert_enabled(&self, feature: &str, now: TimestampNs) -> Result<(), String> {
+        match self.flags.get(&feature.to_string()) {
+            Some(flag) if !flag.is_enabled(now) => Err(flag
+                .reason
+                .unwrap_or(String::from("This feature is temporarily disabled"))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_feature_flag(
+        &mut self,
+        req: SetFeatureFlagRequest,
+        caller: Principal,
+        now: TimestampNs,
+    ) -> Result<(), String> {
+        if !self.flags.contains_key(&req.feature) {
+            return Err(format!("Unknown feature {}", req.feature));
+        }
+
+        if req.enabled && req.reenable_at.is_some() {
+            return Err(String::from(
+                "Only a disabled feature can be scheduled to re-enable",
+            ));
+        }
+
+        if req.reenable_at.is_some_and(|it| it <= now) {
+            return Err(String::from("The re-enable time should be in the future"));
+        }
+
+        let flag = FeatureFlag {
+            enabled: req.enabled,
+            reason: if req.enabled { None } else { req.reason },
+            reenable_at: req.reenable_at,
+            updated_at: now,
+            updated_by: Some(caller),
+        };
+
+        self.flags.insert(req.feature, flag);
+
+        Ok(())
+    }
+
+    // the scheduled re-enables are resolved here, so the clients don't have to check the time
+    pub fn get_feature_flags(&self, now: TimestampNs) -> Vec<(String, FeatureFlag)> {
+        self.flags
+            .iter()
+            .map(|(feature, mut flag)| {
+                if !flag.enabled && flag.is_enabled(now) {
+                    flag.enabled = true;
+                    flag.reason = None;
+                }
+
+                (feature, flag)
+            })
+            .collect()
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeatureFlagRequest {
+    pub feature: String,
+    pub enabled: bool,
+    pub reason: Option<String>,
+    pub reenable_at: Option<TimestampNs>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeatureFlagResponse {
+    pub result: Result<(), String>,
+}
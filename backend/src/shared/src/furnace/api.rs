@@ -4,13 +4,14 @@ use ic_e8s::c::E8s;
 use icrc_ledger_types::icrc1::account::Account;
 use serde::Deserialize;
 
-use crate::{burner::types::TimestampNs, Guard};
+use crate::{access::Role, burner::types::TimestampNs, Guard};
 
 use super::{
     state::FurnaceState,
     types::{
-        DistributionTrigger, DistributionTriggerKind, FurnaceWinnerHistoryEntry, TokenX,
-        TokenXVote, MIN_ALLOWED_USD_POSITION_QTY_E8S,
+        CertifiedFurnaceInfo, DistributionTrigger, DistributionTriggerKind,
+        FurnaceWinnerHistoryEntry, RaffleRoundVerification, TokenX, TokenXVote,
+        MIN_ALLOWED_USD_POSITION_QTY_E8S,
     },
 };
 
@@ -41,14 +42,15 @@ impl Guard<FurnaceState> for PledgeRequest {
             return Err(String::from("Unable to pledge right now, try again later"));
         }
 
-        let decimals_opt = info.get_decimals(&self.token_can_id);
-
-        if decimals_opt.is_none() {
+        if info.get_decimals(&self.token_can_id).is_none() {
             return Err(String::from("Pledging this token is not allowed right now"));
         }
 
-        let usd_value =
-            state.get_usd_value(&self.token_can_id, self.qty.clone(), decimals_opt.unwrap());
+        let usd_value = state
+            .get_pledge_usd_value(&self.token_can_id, self.qty.clone())
+            .ok_or(String::from(
+                "The price of this token is not known yet, try again later",
+            ))?;
 
         let min_usd_value = E8s::from(MIN_ALLOWED_USD_POSITION_QTY_E8S);
         if usd_value < min_usd_value {
@@ -62,6 +64,8 @@ impl Guard<FurnaceState> for PledgeRequest {
 #[derive(CandidType, Deserialize)]
 pub struct PledgeResponse {
     pub pledge_value_usd: E8s,
+    // the block of the transfer the tokens were pledged with
+    pub block_index: Nat,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -216,10 +220,7 @@ impl Guard<FurnaceState> for AddSupportedTokenRequest {
     ) -> Result<(), String> {
         self.validate(&()).map_err(|e| e.to_string())?;
 
-        let info = state.get_furnace_info();
-        if !info.is_dev(&caller) {
-            return Err(String::from("Access denied"));
-        }
+        state.access.assert_role(&caller, Role::Owner)?;
 
         Ok(())
     }
@@ -243,10 +244,7 @@ impl Guard<FurnaceState> for RemoveSupportedTokenRequest {
     ) -> Result<(), String> {
         self.validate(&()).map_err(|e| e.to_string())?;
 
-        let info = state.get_furnace_info();
-        if !info.is_dev(&caller) {
-            return Err(String::from("Access denied"));
-        }
+        state.access.assert_role(&caller, Role::Owner)?;
 
         Ok(())
     }
@@ -298,7 +296,7 @@ impl Guard<FurnaceState> for GetCurRoundPositionsRequest {
     }
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Position {
     pub pid: Principal,
     pub usd: E8s,
@@ -406,3 +404,33 @@ pub struct GetDistributionTriggersRequest {
 pub struct GetDistributionTriggersResponse {
     pub triggers: Vec<DistributionTrigger>,
 }
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyRaffleRoundRequest {
+    pub round: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct VerifyRaffleRoundResponse {
+    pub result: Result<RaffleRoundVerification, String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetFurnaceInfoCertifiedResponse {
+    pub info: CertifiedFurnaceInfo,
+    pub certificate: Vec<u8>,
+    pub witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ResolvePrizePayoutRequest {
+    pub winning_entry_timestamp_ns: u64,
+    pub winner_idx: u32,
+    // the block of the lost transfer, if it was made; otherwise the prize becomes claimable again
+    pub block_index: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ResolvePrizePayoutResponse {
+    pub result: Result<(), String>,
+}
@@ -3,10 +3,17 @@ use ic_cdk::{api::call::CallResult, call};
 
 use super::api::{GetCurRoundPositionsRequest, GetCurRoundPositionsResponse};
 
+pub trait FurnaceApi {
+    async fn get_cur_round_positions(
+        &self,
+        req: GetCurRoundPositionsRequest,
+    ) -> CallResult<(GetCurRoundPositionsResponse,)>;
+}
+
 pub struct FurnaceClient(pub Principal);
 
-impl FurnaceClient {
-    pub async fn get_cur_round_positions(
+impl FurnaceApi for FurnaceClient {
+    async fn get_cur_round_positions(
         &self,
         req: GetCurRoundPositionsRequest,
     ) -> CallResult<(GetCurRoundPositionsResponse,)> {
@@ -3,25 +3,33 @@ use ic_e8s::{c::E8s, d::EDs};
 use ic_stable_structures::{Cell, StableBTreeMap};
 
 use crate::{
+    access::AccessControl,
     burner::types::{Memory, TimestampNs},
-    icpswap::{GetAllTokensResponse, ICPSwapTokenInfo},
+    feature_flags::FeatureFlags,
+    icpswap::{ICPSwapPoolPrice, ICPSwapTokenInfo},
+    price_oracle::{collect_price_quotes, TokenPrice},
     ENV_VARS,
 };
 
 use super::{
     api::{
-        ClaimRewardICPRequest, PledgeRequest, PledgeResponse, VoteTokenXRequest, VoteTokenXResponse,
+        ClaimRewardICPRequest, PledgeRequest, PledgeResponse, ResolvePrizePayoutRequest,
+        VerifyRaffleRoundRequest, VerifyRaffleRoundResponse, VoteTokenXRequest, VoteTokenXResponse,
     },
     types::{
-        DistributionTrigger, DistributionTriggerKind, FurnaceInfo, FurnaceWinner,
-        FurnaceWinnerHistoryEntry, RaffleRoundInfo, TokenX, TokenXVote,
+        ActiveTokenX, DistributionTrigger, DistributionTriggerKind, FurnaceInfo, FurnaceWinner,
+        FurnaceWinnerHistoryEntry, PrizePayout, PrizePayoutOutcome, PrizePayoutStatus,
+        PrizePayoutTask, RaffleRoundInfo, RaffleRoundProof, RaffleStage, TokenX, TokenXVote,
+        ACTIVE_TOKENS_X_COUNT, BURN_TOKEN_DISCOUNT_E8S, RAFFLE_ROUND_PROOFS_TO_KEEP,
     },
 };
 
 pub struct FurnaceState {
     pub furnace_info: Cell<FurnaceInfo, Memory>,
     pub supported_tokens: StableBTreeMap<Principal, TokenX, Memory>,
+    // the prices the pledges are valued at, see `update_token_prices`
     pub token_exchange_rates: StableBTreeMap<Principal, ICPSwapTokenInfo, Memory>,
+    pub token_prices: StableBTreeMap<Principal, TokenPrice, Memory>,
     pub winners: StableBTreeMap<TimestampNs, FurnaceWinnerHistoryEntry, Memory>,
 
     pub cur_round_burn_positions: StableBTreeMap<Principal, EDs, Memory>,
@@ -39,12 +47,20 @@ pub struct FurnaceState {
     pub distribution_triggers: StableBTreeMap<u64, DistributionTrigger, Memory>,
 
     pub total_pledged_tokens: StableBTreeMap<Principal, EDs, Memory>,
+
+    pub raffle_round_proofs: StableBTreeMap<u64, RaffleRoundProof, Memory>,
+    // the positions of each proven round, in the order the winner selection walks through them
+    pub raffle_round_positions: StableBTreeMap<(u64, Principal), EDs, Memory>,
+
+    pub access: AccessControl,
+    pub feature_flags: FeatureFlags,
 }
 
 impl FurnaceState {
     pub fn init(&mut self, dev_pid: Principal, seed: Vec<u8>, now: TimestampNs) {
         let mut furnace_info = self.get_furnace_info();
         furnace_info.init(dev_pid, seed, now);
+        self.commit_raffle_round_seed(furnace_info.current_round, &furnace_info.seed);
 
         self.set_furnace_info(furnace_info);
     }
@@ -54,6 +70,10 @@ impl FurnaceState {
         let winner = entry.winners.get_mut(req.winner_idx as usize).unwrap();
 
         winner.claimed = true;
+        if let Some(payout) = winner.payout.as_mut() {
+            payout.status = PrizePayoutStatus::ClaimInProgress;
+        }
+
         let prize = winner.prize_icp.clone();
 
         self.winners.insert(req.winning_entry_timestamp_ns, entry);
@@ -61,13 +81,169 @@ impl FurnaceState {
         prize
     }
 
-    pub fn revert_claim_reward(&mut self, req: ClaimRewardICPRequest) {
+    pub fn complete_claim_reward(&mut self, req: ClaimRewardICPRequest, block_index: Nat) {
+        let mut entry = self.winners.get(&req.winning_entry_timestamp_ns).unwrap();
+        let winner = entry.winners.get_mut(req.winner_idx as usize).unwrap();
+
+        if let Some(payout) = winner.payout.as_mut() {
+            payout.status = PrizePayoutStatus::Paid { block_index };
+        }
+
+        self.winners.insert(req.winning_entry_timestamp_ns, entry);
+    }
+
+    pub fn revert_claim_reward(&mut self, req: ClaimRewardICPRequest, error: String) {
         let mut entry = self.winners.get(&req.winning_entry_timestamp_ns).unwrap();
         let winner = entry.winners.get_mut(req.winner_idx as usize).unwrap();
 
         winner.claimed = false;
+        if let Some(payout) = winner.payout.as_mut() {
+            payout.status = PrizePayoutStatus::Failed { error };
+        }
+
+        self.winners.insert(req.winning_entry_timestamp_ns, entry);
+    }
+
+    /// Reserves the next batch of the prizes to pay out automatically, the same way `claim_reward` does
+    pub fn reserve_prize_payouts_batch(
+        &mut self,
+        batch_size: usize,
+        now: TimestampNs,
+    ) -> Vec<PrizePayoutTask> {
+        let mut batch = Vec::new();
+
+        let Some(cursor) = self.get_furnace_info_ref().prize_payout_cursor else {
+            return batch;
+        };
+
+        let entries: Vec<_> = self.winners.range(cursor..).collect();
+
+        for (timestamp, mut entry) in entries {
+            let mut is_changed = false;
+
+            for (idx, winner) in entry.winners.iter_mut().enumerate() {
+                if batch.len() >= batch_size || !winner.is_payout_due(now) {
+                    continue;
+                }
+
+                let payout = winner.payout.as_mut().unwrap();
+                payout.status = PrizePayoutStatus::InProgress;
+                payout.attempts += 1;
+                payout.last_attempt_at = Some(now);
+                let created_at_time = *payout.created_at_time.get_or_insert(now);
+
+                winner.claimed = true;
+                is_changed = true;
+
+                batch.push(PrizePayoutTask {
+                    winning_entry_timestamp_ns: timestamp,
+                    winner_idx: idx as u32,
+                    to: winner.pid,
+                    prize_icp: winner.prize_icp.clone(),
+                    created_at_time,
+                });
+            }
+
+            if is_changed {
+                self.winners.insert(timestamp, entry);
+            }
+        }
+
+        batch
+    }
+
+    // moves the cursor past the rounds, where every prize is either paid, has run out of attempts,
+    // or has an outcome which can't be found out anymore
+    pub fn update_prize_payout_cursor(&mut self, now: TimestampNs) {
+        let mut info = self.get_furnace_info();
+
+        let Some(cursor) = info.prize_payout_cursor else {
+            return;
+        };
+
+        info.prize_payout_cursor = self
+            .winners
+            .range(cursor..)
+            .find(|(_, entry)| entry.winners.iter().any(|it| !it.is_payout_final(now)))
+            .map(|(timestamp, _)| timestamp);
+
+        self.set_furnace_info(info);
+    }
+
+    pub fn complete_prize_payout(&mut self, task: &PrizePayoutTask, outcome: PrizePayoutOutcome) {
+        let mut entry = self.winners.get(&task.winning_entry_timestamp_ns).unwrap();
+        let winner = entry.winners.get_mut(task.winner_idx as usize).unwrap();
+
+        let status = match outcome {
+            PrizePayoutOutcome::Paid(block_index) => PrizePayoutStatus::Paid { block_index },
+            PrizePayoutOutcome::Rejected(error) => {
+                // the winner can claim the prize manually now
+                winner.claimed = false;
+                // nothing was transferred, so the next attempt doesn't have to be deduplicated with this one
+                winner.payout.as_mut().unwrap().created_at_time = None;
+
+                PrizePayoutStatus::Failed { error }
+            }
+            // the prize stays reserved, the transfer is retried with the same created_at_time
+            PrizePayoutOutcome::Unknown(error) => PrizePayoutStatus::Unknown { error },
+        };
+
+        winner.payout.as_mut().unwrap().status = status;
+
+        self.winners.insert(task.winning_entry_timestamp_ns, entry);
+    }
+
+    /// Resolves a payout, which outcome could not be found out within the ledger deduplication window
+    pub fn resolve_prize_payout(
+        &mut self,
+        req: ResolvePrizePayoutRequest,
+        now: TimestampNs,
+    ) -> Result<(), String> {
+        let mut entry = self
+            .winners
+            .get(&req.winning_entry_timestamp_ns)
+            .ok_or(String::from("Invalid timestamp"))?;
+
+        let winner = entry
+            .winners
+            .get_mut(req.winner_idx as usize)
+            .ok_or(String::from("Invalid winner idx"))?;
+
+        if !winner.is_payout_final(now) {
+            return Err(String::from("The payout is not final yet"));
+        }
+
+        let payout = winner
+            .payout
+            .as_mut()
+            .ok_or(String::from("No automatic payout"))?;
+
+        if !matches!(
+            payout.status,
+            PrizePayoutStatus::InProgress | PrizePayoutStatus::Unknown { .. }
+        ) {
+            return Err(String::from("The payout outcome is known"));
+        }
+
+        match req.block_index {
+            Some(block_index) => {
+                payout.status = PrizePayoutStatus::Paid { block_index };
+            }
+            None => {
+                payout.status = PrizePayoutStatus::Failed {
+                    error: String::from("Resolved as not paid"),
+                };
+                winner.claimed = false;
+            }
+        }
 
         self.winners.insert(req.winning_entry_timestamp_ns, entry);
+
+        Ok(())
+    }
+
+    pub fn has_prize_payouts_left(&self) -> bool {
+        self.get_furnace_info_ref().prize_payout_cursor.is_some()
     }
 
     pub fn note_burned_token(&mut self, token_can_id: Principal, qty: &EDs) {
@@ -167,107 +343,163 @@ impl FurnaceState {
     }
 
     // not batching this method, because the number of possible tokens is very limited
-    pub fn select_next_token_x(&mut self) -> Principal {
+    /// Elects the top voted tokens, which will be pledged during the next round.
+    /// BURN can always be pledged - if it was not elected, it is added at a discount.
+    pub fn select_next_token_x(&mut self) -> Vec<Principal> {
         let mut info = self.get_furnace_info();
 
-        // if no token was elected - fallback to BURN
-        if self.next_token_x_alternatives.is_empty() {
-            info.update_token_x(TokenX {
-                can_id: ENV_VARS.burn_token_canister_id,
-                fee: Nat::from(10_000u64),
-                decimals: 8,
-            });
-            self.set_furnace_info(info);
-
-            return ENV_VARS.burn_token_canister_id;
-        }
+        let mut alternatives: Vec<(Principal, E8s)> = self
+            .next_token_x_alternatives
+            .iter()
+            .map(|(can_id, votes)| (can_id, votes.to_const()))
+            .filter(|(_, votes)| votes > &E8s::zero())
+            .collect();
 
-        let random_num = info.generate_random_numbers(1).remove(0);
+        // the sort is stable, so the tokens with equal votes stay ordered by their canister ids
+        alternatives.sort_by(|(_, a), (_, b)| b.cmp(a));
+        alternatives.truncate(ACTIVE_TOKENS_X_COUNT);
 
-        let mut total_votes = E8s::zero();
-        let mut iter = self.next_token_x_alternatives.iter();
+        let mut tokens = Vec::new();
 
-        loop {
-            let entry = iter.next();
+        if let Some((_, top_votes)) = alternatives.first().cloned() {
+            for (can_id, votes) in alternatives {
+                let token_x = self
+                    .get_supported_token(&can_id)
+                    .expect("Only supported tokens can be elected");
 
-            if let Some((_, votes)) = entry {
-                total_votes += votes.to_const();
-            } else {
-                break;
+                tokens.push(ActiveTokenX::elected(token_x, votes / &top_votes));
             }
         }
 
-        let mut to = E8s::zero();
-        iter = self.next_token_x_alternatives.iter();
+        let elected = tokens.iter().map(|it| it.token.can_id).collect::<Vec<_>>();
 
-        let result = loop {
-            let entry = iter.next().map(|(p, e)| (p, e.to_const()));
-
-            if let Some((token_x_can_id, votes)) = entry {
-                to += votes / &total_votes;
+        // if no token was elected - fallback to BURN
+        if elected.is_empty() {
+            tokens.push(ActiveTokenX::burn(E8s::one()));
+        } else if !elected.contains(&ENV_VARS.burn_token_canister_id) {
+            tokens.push(ActiveTokenX::burn(E8s::from(BURN_TOKEN_DISCOUNT_E8S)));
+        }
 
-                if to >= random_num {
-                    break token_x_can_id;
-                }
-            } else {
-                panic!("Should find the winner");
-            }
-        };
+        info.set_next_tokens_x(tokens);
+        self.set_furnace_info(info);
 
-        let token_x = self
-            .get_supported_token(&result)
-            .expect("Only supported tokens can be elected");
+        if elected.is_empty() {
+            vec![ENV_VARS.burn_token_canister_id]
+        } else {
+            elected
+        }
+    }
 
-        info.update_token_x(token_x);
+    // ICP is tracked as well, since the prices of the pools are quoted in it
+    pub fn list_price_tracked_tokens(&self) -> Vec<Principal> {
+        let mut tokens: Vec<Principal> = self.supported_tokens.iter().map(|(it, _)| it).collect();
 
-        self.set_furnace_info(info);
+        if !tokens.contains(&ENV_VARS.icp_token_canister_id) {
+            tokens.push(ENV_VARS.icp_token_canister_id);
+        }
 
-        result
+        tokens
     }
 
-    pub fn complete_token_x_voting(&mut self, token_x: TokenX) {
-        let mut info = self.get_furnace_info();
-        info.cur_token_x = token_x;
+    /// Feeds the fetched quotes to the price oracle of each tracked token and stores the resulting TWAPs
+    /// as the exchange rates. A token without any valid quote keeps its last good price.
+    pub fn update_token_prices(
+        &mut self,
+        token_list: &[ICPSwapTokenInfo],
+        pool_prices: &[ICPSwapPoolPrice],
+        errors: &[String],
+        now: TimestampNs,
+    ) {
+        let tokens = self.list_price_tracked_tokens();
+        let icp_can_id = ENV_VARS.icp_token_canister_id;
+
+        let icp_price_usd = self
+            .token_prices
+            .get(&icp_can_id)
+            .filter(|it| it.is_known())
+            .map(|it| it.twap_usd);
+
+        let mut quotes = collect_price_quotes(
+            &tokens,
+            token_list,
+            pool_prices,
+            icp_can_id,
+            icp_price_usd.as_ref(),
+        );
+
+        for token_can_id in &tokens {
+            let mut price = self.token_prices.get(token_can_id).unwrap_or_default();
+            let token_quotes = quotes.remove(token_can_id).unwrap_or_default();
+
+            if token_quotes.is_empty() {
+                let error = if errors.is_empty() {
+                    String::from("No quotes for the token, the last good price is kept")
+                } else {
+                    errors.join("; ")
+                };
+
+                price.note_error(error, now);
+            } else {
+                price.update(token_quotes, now);
+            }
 
-        self.next_token_x_alternatives.clear_new();
-        self.next_token_x_votes.clear_new();
+            if price.is_known() {
+                self.token_exchange_rates.insert(
+                    *token_can_id,
+                    ICPSwapTokenInfo {
+                        can_id: *token_can_id,
+                        exchange_rate_usd: price.twap_usd.clone(),
+                        updated_at: Some(price.last_good_price_at),
+                    },
+                );
+            }
 
-        self.set_furnace_info(info);
-    }
+            self.token_prices.insert(*token_can_id, price);
+        }
 
-    pub fn update_token_exchange_rates(&mut self, icp_swap_response: GetAllTokensResponse) {
-        self.token_exchange_rates.clear_new();
+        // the rates of all the ICPSwap tokens used to be stored here
+        let untracked: Vec<Principal> = self
+            .token_exchange_rates
+            .iter()
+            .map(|(it, _)| it)
+            .filter(|it| !tokens.contains(it))
+            .collect();
 
-        for token in icp_swap_response {
-            self.token_exchange_rates.insert(token.can_id, token);
+        for token_can_id in untracked {
+            self.token_exchange_rates.remove(&token_can_id);
         }
     }
 
-    pub fn pledge(&mut self, req: PledgeRequest) -> PledgeResponse {
+    /// Called after the tokens are transferred. The round could have ended during the transfer, then
+    /// the pledge is not counted and the tokens should be refunded.
+    pub fn pledge(
+        &mut self,
+        req: PledgeRequest,
+        round: u64,
+        usd_value: E8s,
+        block_index: Nat,
+    ) -> Result<PledgeResponse, String> {
         let mut info = self.get_furnace_info();
 
-        let (usd_value, decimals) = if req.token_can_id == info.cur_token_x.can_id {
-            let usd_value = self.get_usd_value(
-                &req.token_can_id,
-                req.qty.clone(),
-                info.cur_token_x.decimals,
-            );
+        if info.current_round != round || info.is_looking_for_winners {
+            return Err(String::from(
+                "The round has ended while the tokens were transferred",
+            ));
+        }
 
-            (usd_value, info.cur_token_x.decimals)
-        } else {
-            let usd_value =
-                self.get_usd_value(&ENV_VARS.burn_token_canister_id, req.qty.clone(), 8)
-                    * E8s::from(9500_0000u64);
+        let decimals = info
+            .get_decimals(&req.token_can_id)
+            .ok_or(String::from("Only active tokens can be pledged"))?;
 
-            (usd_value, 8)
-        };
+        // the info is only saved in the end, so nothing is changed, if the token is not active
+        info.note_pledged_token(&req.token_can_id, &req.qty, &usd_value)?;
 
         let prev_pledged = self
             .total_pledged_tokens
             .get(&req.token_can_id)
             .unwrap_or_default()
             .to_decimals(decimals);
-        let new_total_pledged = prev_pledged + EDs::new(req.qty.0, decimals);
+        let new_total_pledged = prev_pledged + EDs::new(req.qty.0.clone(), decimals);
 
         self.total_pledged_tokens
             .insert(req.token_can_id, new_total_pledged);
@@ -310,22 +542,67 @@ impl FurnaceState {
 
         self.set_furnace_info(info);
 
-        PledgeResponse {
+        Ok(PledgeResponse {
             pledge_value_usd: usd_value,
+            block_index,
+        })
+    }
+
+    // the raffles started before the stages were persisted only have the is_looking_for_winners flag
+    pub fn get_raffle_stage(&self) -> Option<RaffleStage> {
+        let info = self.get_furnace_info_ref();
+
+        if info.raffle_stage.is_some() || !info.is_looking_for_winners {
+            return info.raffle_stage;
+        }
+
+        if self.raffle_round_info.get().is_some() {
+            Some(RaffleStage::FindingWinners)
+        } else {
+            Some(RaffleStage::MovingPrizeFund)
         }
     }
 
-    pub fn set_looking_for_winners(&mut self, value: bool) {
+    pub fn set_raffle_stage(&mut self, stage: RaffleStage) {
+        let mut furnace_info = self.get_furnace_info();
+        furnace_info.raffle_stage = Some(stage);
+        self.set_furnace_info(furnace_info);
+    }
+
+    // returns false, if the raffle is already in progress
+    pub fn start_raffle(&mut self, now: TimestampNs) -> bool {
+        if self.get_raffle_stage().is_some() {
+            return false;
+        }
+
         let mut furnace_info = self.get_furnace_info();
-        furnace_info.is_looking_for_winners = value;
+
+        furnace_info.start_round(now);
         self.set_furnace_info(furnace_info);
+
+        true
     }
 
     pub fn prepare_raffle(&mut self, cur_prize_fund_icp: E8s) {
         let mut furnace_info = self.get_furnace_info();
 
         let prize_distribution = furnace_info.calculate_prize_distribution(&cur_prize_fund_icp);
-        let random_numbers = furnace_info.generate_random_numbers(prize_distribution.len());
+
+        // should only be missing for the first round after the upgrade
+        self.commit_raffle_round_seed(furnace_info.current_round, &furnace_info.seed);
+
+        let mut proof = self
+            .raffle_round_proofs
+            .get(&furnace_info.current_round)
+            .unwrap();
+        let random_numbers = RaffleRoundInfo::random_numbers_from_seed(
+            &proof.commitment.seed,
+            prize_distribution.len(),
+        );
+
+        proof.prize_distribution = prize_distribution.clone();
+        self.raffle_round_proofs
+            .insert(furnace_info.current_round, proof);
 
         let raffle_round_info = RaffleRoundInfo {
             prize_fund_icp: cur_prize_fund_icp,
@@ -334,8 +611,11 @@ impl FurnaceState {
             winners: Vec::new(),
             winner_selection_cursor: None,
             from: E8s::zero(),
+            positions_recording_cursor: None,
         };
 
+        furnace_info.raffle_stage = Some(RaffleStage::FindingWinners);
+
         self.set_furnace_info(furnace_info);
         self.set_raffle_round_info(raffle_round_info);
     }
@@ -344,6 +624,16 @@ impl FurnaceState {
         let raffle_round_info = self.get_raffle_round_info();
         let mut furnace_info = self.get_furnace_info();
 
+        // the winners are known by now, so the seed can be revealed
+        if let Some(mut proof) = self.raffle_round_proofs.get(&furnace_info.current_round) {
+            proof.total_pledged_usd = furnace_info.cur_round_pledged_usd.clone();
+            proof.winners = raffle_round_info.winners.clone();
+            proof.commitment.reveal();
+
+            self.raffle_round_proofs
+                .insert(furnace_info.current_round, proof);
+        }
+
         let mut result = Vec::new();
         for (pid, prize_icp) in raffle_round_info.winners {
             let share = self
@@ -358,11 +648,16 @@ impl FurnaceState {
                 pid,
                 claimed: false,
                 share_normalized: share / &furnace_info.cur_round_pledged_usd,
+                payout: Some(PrizePayout::default()),
             };
 
             result.push(entry);
         }
 
+        if !result.is_empty() && furnace_info.prize_payout_cursor.is_none() {
+            furnace_info.prize_payout_cursor = Some(now);
+        }
+
         let winner_history_entry = FurnaceWinnerHistoryEntry {
             timestamp: now,
             token_can_id: furnace_info.cur_token_x.can_id,
@@ -370,6 +665,7 @@ impl FurnaceState {
             round: furnace_info.current_round,
             prize_fund_icp: raffle_round_info.prize_fund_icp.clone(),
             winners: result,
+            tokens_x: Some(furnace_info.get_active_tokens_x()),
         };
 
         self.winners.insert(now, winner_history_entry);
@@ -384,6 +680,8 @@ impl FurnaceState {
         furnace_info.complete_round(now);
         furnace_info.icp_won_total += raffle_round_info.prize_fund_icp;
 
+        self.commit_raffle_round_seed(furnace_info.current_round, &furnace_info.seed);
+
         self.set_furnace_info(furnace_info);
     }
 
@@ -440,10 +738,71 @@ impl FurnaceState {
         !is_over
     }
 
+    /// Copies the positions of the current round into `raffle_round_positions`, so the round can
+    /// be verified later, and removes the positions of the rounds whose proofs were pruned.
+    /// Returns true if should be rescheduled immediately.
+    pub fn record_raffle_positions_batch(&mut self, batch_size: usize) -> bool {
+        let oldest_proven_round = self
+            .raffle_round_proofs
+            .first_key_value()
+            .map(|(round, _)| round)
+            .unwrap_or_default();
+
+        let mut pruned = 0;
+        while pruned < batch_size {
+            let Some(((round, pid), _)) = self.raffle_round_positions.first_key_value() else {
+                break;
+            };
+
+            if round >= oldest_proven_round {
+                break;
+            }
+
+            self.raffle_round_positions.remove(&(round, pid));
+            pruned += 1;
+        }
+
+        if pruned == batch_size {
+            return true;
+        }
+
+        let mut raffle_round_info = self.get_raffle_round_info();
+        let round = self.get_furnace_info_ref().current_round;
+
+        let mut iter = if let Some(cursor) = raffle_round_info.positions_recording_cursor {
+            let mut i = self.cur_round_positions.range(cursor..);
+            i.next();
+
+            i
+        } else {
+            self.cur_round_positions.iter()
+        };
+
+        let mut i = pruned;
+        let should_reschedule = loop {
+            let Some((pid, votes)) = iter.next() else {
+                break false;
+            };
+
+            self.raffle_round_positions.insert((round, pid), votes);
+            raffle_round_info.positions_recording_cursor = Some(pid);
+
+            i += 1;
+            if i >= batch_size {
+                break true;
+            }
+        };
+
+        self.set_raffle_round_info(raffle_round_info);
+
+        should_reschedule
+    }
+
     pub fn process_triggers_batch(
         &mut self,
         batch_size: usize,
-        trigger_kind: DistributionTriggerKind,
+        // a trigger is executed, if any of these has happened
+        events: &[DistributionTriggerKind],
     ) -> (Option<Vec<DistributionTrigger>>, bool) {
         if self.distribution_triggers.is_empty() {
             return (None, false);
@@ -470,33 +829,11 @@ impl FurnaceState {
 
             info.distribution_trigger_cursor = Some(id);
 
-            match &trigger_kind {
-                DistributionTriggerKind::TokenXVotingWinner(expected_can_id) => {
-                    match trigger.kind {
-                        DistributionTriggerKind::TokenXVotingWinner(actual_can_id) => {
-                            if *expected_can_id == actual_can_id {
-                                ids_to_remove.push(id);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                DistributionTriggerKind::TokenTotalPledged {
-                    token_can_id: expected_token_can_id,
-                    threshold: current_pledged,
-                } => match trigger.kind {
-                    DistributionTriggerKind::TokenTotalPledged {
-                        token_can_id: actual_token_can_id,
-                        threshold,
-                    } => {
-                        if *expected_token_can_id == actual_token_can_id
-                            && current_pledged.clone() >= threshold
-                        {
-                            ids_to_remove.push(id);
-                        }
-                    }
-                    _ => {}
-                },
+            if events
+                .iter()
+                .any(|event| trigger.kind.is_triggered_by(event))
+            {
+                ids_to_remove.push(id);
             }
 
             i += 1;
@@ -509,6 +846,8 @@ impl FurnaceState {
             info.distribution_trigger_cursor = None;
         }
 
+        self.set_furnace_info(info);
+
         if ids_to_remove.is_empty() {
             return (None, should_reschedule);
         }
@@ -524,15 +863,58 @@ impl FurnaceState {
         )
     }
 
-    pub fn get_usd_value(&self, can_id: &Principal, qty: Nat, decimals: u8) -> E8s {
+    pub fn verify_raffle_round(&self, req: VerifyRaffleRoundRequest) -> VerifyRaffleRoundResponse {
+        let result = self
+            .raffle_round_proofs
+            .get(&req.round)
+            .map(|proof| {
+                if !proof.positions.is_empty() {
+                    return proof.verify(req.round, &proof.positions);
+                }
+
+                let positions: Vec<_> = self
+                    .raffle_round_positions
+                    .range((req.round, Principal::management_canister())..)
+                    .take_while(|((round, _), _)| *round == req.round)
+                    .map(|((_, pid), votes)| (pid, votes.to_const()))
+                    .collect();
+
+                proof.verify(req.round, &positions)
+            })
+            .ok_or(String::from("No data for this round"));
+
+        VerifyRaffleRoundResponse { result }
+    }
+
+    // publishes the seed hash of the round before anyone pledges into it
+    fn commit_raffle_round_seed(&mut self, round: u64, secret: &[u8]) {
+        if !self.raffle_round_proofs.contains_key(&round) {
+            self.raffle_round_proofs
+                .insert(round, RaffleRoundProof::new(secret, round));
+        }
+
+        // the positions of the pruned rounds are removed by `record_raffle_positions_batch`
+        while self.raffle_round_proofs.len() > RAFFLE_ROUND_PROOFS_TO_KEEP {
+            let (oldest, _) = self.raffle_round_proofs.first_key_value().unwrap();
+            self.raffle_round_proofs.remove(&oldest);
+        }
+    }
+
+    // none, if the price of the token is not known yet
+    // the value the tokens are pledged at, None if the token is not active or its price is not known yet
+    pub fn get_pledge_usd_value(&self, token_can_id: &Principal, qty: Nat) -> Option<E8s> {
+        let info = self.get_furnace_info_ref();
+        let decimals = info.get_decimals(token_can_id)?;
+        let usd_value = self.get_usd_value(token_can_id, qty, decimals)?;
+
+        Some(info.burn_token_discount(token_can_id, usd_value))
+    }
+
+    pub fn get_usd_value(&self, can_id: &Principal, qty: Nat, decimals: u8) -> Option<E8s> {
         let qty_e8s = EDs::new(qty.0, decimals).to_decimals(8).to_const();
-        let exchange_rate = self
-            .token_exchange_rates
-            .get(can_id)
-            .expect("The token is not supported")
-            .exchange_rate_usd;
+        let exchange_rate = self.token_exchange_rates.get(can_id)?.exchange_rate_usd;
 
-        qty_e8s * exchange_rate
+        Some(qty_e8s * exchange_rate)
     }
 
     pub fn get_furnace_info(&self) -> FurnaceInfo {
@@ -1,18 +1,60 @@
 use std::u32;
 
 use candid::{decode_one, encode_one, CandidType, Nat, Principal};
-use ic_e8s::{c::E8s};
+use ic_e8s::c::E8s;
 use ic_stable_structures::{storable::Bound, Storable};
 use serde::Deserialize;
 use sha2::Digest;
 
-use crate::{burner::types::TimestampNs, dispenser::types::DistributionId, ENV_VARS, ONE_WEEK_NS};
+use crate::{
+    burner::types::TimestampNs,
+    dispenser::types::DistributionId,
+    randomness::{random_u32s, verify_seed, SeedCommitment},
+    utils::duration_until_next_sunday_15_00,
+    ENV_VARS, ONE_DAY_NS, ONE_MINUTE_NS, ONE_WEEK_NS,
+};
 
 pub const DEFAULT_ROUND_DELAY_NS: u64 = ONE_WEEK_NS;
 pub const UPDATE_FURNACE_SEED_DOMAIN: &[u8] = b"msq-burn-furnace-update-seed";
+pub const RAFFLE_ROUND_SEED_DOMAIN: &[u8] = b"msq-burn-furnace-raffle-round";
 pub const GEN_FURNACE_POSITION_ID_DOMAIN: &[u8] = b"msq-burn-furnace-position-id";
 pub const DEFAULT_WINNER_ICP_THRESHOLD: u64 = 1_000_0000_0000; // 1k ICP ~ $10k
 pub const MIN_ALLOWED_USD_POSITION_QTY_E8S: u64 = 10_0000; // 0.1 cent
+pub const ACTIVE_TOKENS_X_COUNT: usize = 3;
+pub const PRIZE_PAYOUTS_BATCH_SIZE: usize = 20;
+pub const PRIZE_PAYOUT_RETRY_DELAY_NS: u64 = ONE_MINUTE_NS * 10;
+// only the attempts which have definitely failed are counted
+pub const MAX_PRIZE_PAYOUT_ATTEMPTS: u32 = 5;
+// the ledger deduplicates the transfers for 24 hours, after that the outcome of a lost attempt can't be found out
+pub const PRIZE_PAYOUT_DEDUP_WINDOW_NS: u64 = ONE_DAY_NS;
+// ~2 years of weekly rounds
+pub const RAFFLE_ROUND_PROOFS_TO_KEEP: u64 = 104;
+pub const RAFFLE_ROUND_POSITIONS_BATCH_SIZE: usize = 500;
+pub const BURN_TOKEN_DISCOUNT_E8S: u64 = 9500_0000; // BURN is pledged at 95% of its price, unless elected
+
+pub const FEATURE_PLEDGE: &str = "pledge";
+pub const FEATURE_VOTE_TOKEN_X: &str = "vote_token_x";
+pub const FEATURE_DEPLOY_DISPENSER: &str = "deploy_dispenser";
+pub const FEATURE_CREATE_DISTRIBUTION_TRIGGER: &str = "create_distribution_trigger";
+// the features which are disabled, until explicitly enabled
+pub const FURNACE_FEATURES: &[(&str, &str)] = &[
+    (
+        FEATURE_PLEDGE,
+        "The Bonfire is temporarily stopped. Please, come back later",
+    ),
+    (
+        FEATURE_VOTE_TOKEN_X,
+        "The Bonfire is temporarily stopped. Please, come back later",
+    ),
+    (
+        FEATURE_DEPLOY_DISPENSER,
+        "The Airdrops are temporarily stopped. Please, come back later",
+    ),
+    (
+        FEATURE_CREATE_DISTRIBUTION_TRIGGER,
+        "The Airdrops are temporarily stopped. Please, come back later",
+    ),
+];
 
 pub const FURNACE_REDISTRIBUTION_SUBACCOUNT: [u8; 32] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
@@ -47,7 +89,85 @@ pub struct FurnaceInfo {
     pub is_looking_for_winners: bool,
     pub is_on_maintenance: bool,
 
+    // legacy, only used to migrate to the access roles
     pub dev_pid: Option<Principal>,
+
+    // none until the first raffle is scheduled after an upgrade
+    pub next_round_at: Option<TimestampNs>,
+    // none, when no raffle is in progress
+    pub raffle_stage: Option<RaffleStage>,
+    // the deadlines, which have passed while the canister was stopped, see `start_round`
+    pub missed_rounds: Option<u64>,
+
+    // none until the first round with several tokens, see `get_active_tokens_x`
+    pub active_tokens_x: Option<Vec<ActiveTokenX>>,
+    // elected during the raffle, become active once the round is complete
+    pub next_tokens_x: Option<Vec<ActiveTokenX>>,
+
+    // the oldest round with the prizes left to pay out, none if all are paid
+    pub prize_payout_cursor: Option<TimestampNs>,
+}
+
+/// A token, which can be pledged during the current round.
+///
+/// The USD value of a pledge is multiplied by both `usd_weight` and `discount`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ActiveTokenX {
+    pub token: TokenX,
+    // the votes the token has got, relative to the top voted token
+    pub usd_weight: E8s,
+    pub discount: E8s,
+    pub cur_round_pledged: Nat,
+    pub cur_round_pledged_usd: E8s,
+    // false for BURN, when it is only added as a fallback
+    pub elected: bool,
+}
+
+impl ActiveTokenX {
+    pub fn new(token: TokenX, usd_weight: E8s, discount: E8s) -> Self {
+        Self {
+            token,
+            usd_weight,
+            discount,
+            cur_round_pledged: Nat::from(0u64),
+            cur_round_pledged_usd: E8s::zero(),
+            elected: false,
+        }
+    }
+
+    pub fn elected(token: TokenX, usd_weight: E8s) -> Self {
+        Self {
+            elected: true,
+            ..Self::new(token, usd_weight, E8s::one())
+        }
+    }
+
+    pub fn burn(discount: E8s) -> Self {
+        Self::new(TokenX::default(), E8s::one(), discount)
+    }
+}
+
+/// The steps of a raffle, in the order they are made.
+///
+/// The stage is persisted, so a raffle interrupted by an upgrade or a stop continues from the step
+/// it has reached, instead of starting over and moving the prize fund twice.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RaffleStage {
+    MovingPrizeFund,
+    FindingWinners,
+    RecordingPositions,
+    ElectingTokenX,
+    TriggeringTokenXDistributions,
+    Completing,
+}
+
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct CertifiedFurnaceInfo {
+    pub current_round: u64,
+    pub icp_won_total: E8s,
+    pub total_pledged_usd: E8s,
+    pub cur_round_pledged_usd: E8s,
+    pub cur_round_pledged_burn_usd: E8s,
 }
 
 #[derive(CandidType, Deserialize, Default, Clone)]
@@ -67,6 +187,13 @@ pub struct FurnaceInfoPub {
     pub is_looking_for_winners: bool,
     pub is_on_maintenance: bool,
     pub dev_pid: Option<Principal>,
+
+    pub next_round_at: Option<TimestampNs>,
+    pub raffle_stage: Option<RaffleStage>,
+    pub missed_rounds: Option<u64>,
+
+    pub active_tokens_x: Option<Vec<ActiveTokenX>>,
+    pub next_tokens_x: Option<Vec<ActiveTokenX>>,
 }
 
 impl FurnaceInfo {
@@ -102,11 +229,24 @@ impl FurnaceInfo {
             is_looking_for_winners: self.is_looking_for_winners,
             is_on_maintenance: self.is_on_maintenance,
             dev_pid: self.dev_pid,
+
+            next_round_at: self.next_round_at,
+            raffle_stage: self.raffle_stage,
+            missed_rounds: self.missed_rounds,
+
+            active_tokens_x: Some(self.get_active_tokens_x()),
+            next_tokens_x: self.next_tokens_x.clone(),
         }
     }
 
-    pub fn is_dev(&self, pid: &Principal) -> bool {
-        &self.dev_pid.unwrap() == pid
+    pub fn to_certified(&self) -> CertifiedFurnaceInfo {
+        CertifiedFurnaceInfo {
+            current_round: self.current_round,
+            icp_won_total: self.icp_won_total.clone(),
+            total_pledged_usd: self.total_pledged_usd.clone(),
+            cur_round_pledged_usd: self.cur_round_pledged_usd.clone(),
+            cur_round_pledged_burn_usd: self.cur_round_pledged_burn_usd.clone(),
+        }
     }
 
     pub fn note_pledged_usd(&mut self, qty: &E8s) {
@@ -118,8 +258,75 @@ impl FurnaceInfo {
         self.cur_round_pledged_burn_usd += qty;
     }
 
-    pub fn update_token_x(&mut self, token_x: TokenX) {
-        self.cur_token_x = token_x;
+    pub fn note_pledged_token(
+        &mut self,
+        can_id: &Principal,
+        qty: &Nat,
+        qty_usd: &E8s,
+    ) -> Result<(), String> {
+        let mut tokens = self.get_active_tokens_x();
+
+        let token = tokens
+            .iter_mut()
+            .find(|it| &it.token.can_id == can_id)
+            .ok_or(String::from("Only active tokens can be pledged"))?;
+
+        token.cur_round_pledged += qty.clone();
+        token.cur_round_pledged_usd += qty_usd;
+
+        self.active_tokens_x = Some(tokens);
+
+        Ok(())
+    }
+
+    pub fn set_next_tokens_x(&mut self, tokens: Vec<ActiveTokenX>) {
+        self.next_tokens_x = Some(tokens);
+    }
+
+    // the tokens which were elected during the raffle, or the current ones, if none were
+    pub fn get_next_tokens_x(&self) -> Vec<ActiveTokenX> {
+        self.next_tokens_x
+            .clone()
+            .unwrap_or_else(|| self.get_active_tokens_x())
+    }
+
+    // only the tokens which have won the voting, not the BURN fallback
+    pub fn get_elected_next_tokens_x(&self) -> Vec<Principal> {
+        self.next_tokens_x
+            .iter()
+            .flatten()
+            .filter(|it| it.elected)
+            .map(|it| it.token.can_id)
+            .collect()
+    }
+
+    // BURN can always be pledged, so it is the last one in the list, unless it is elected
+    pub fn get_active_tokens_x(&self) -> Vec<ActiveTokenX> {
+        if let Some(tokens) = &self.active_tokens_x {
+            return tokens.clone();
+        }
+
+        if self.cur_token_x.can_id == ENV_VARS.burn_token_canister_id {
+            let mut burn = ActiveTokenX::burn(E8s::one());
+            burn.cur_round_pledged_usd = self.cur_round_pledged_burn_usd.clone();
+
+            return vec![burn];
+        }
+
+        let mut token_x = ActiveTokenX::new(self.cur_token_x.clone(), E8s::one(), E8s::one());
+        token_x.cur_round_pledged_usd =
+            &self.cur_round_pledged_usd - &self.cur_round_pledged_burn_usd;
+
+        let mut burn = ActiveTokenX::burn(E8s::from(BURN_TOKEN_DISCOUNT_E8S));
+        burn.cur_round_pledged_usd = self.cur_round_pledged_burn_usd.clone();
+
+        vec![token_x, burn]
+    }
+
+    pub fn get_active_token_x(&self, can_id: &Principal) -> Option<ActiveTokenX> {
+        self.get_active_tokens_x()
+            .into_iter()
+            .find(|it| &it.token.can_id == can_id)
     }
 
     pub fn complete_round(&mut self, now: TimestampNs) {
@@ -128,30 +335,73 @@ impl FurnaceInfo {
         self.cur_round_pledged_usd = E8s::zero();
         self.cur_round_pledged_burn_usd = E8s::zero();
 
+        let mut tokens = self.get_next_tokens_x();
+        for token in tokens.iter_mut() {
+            token.cur_round_pledged = Nat::from(0u64);
+            token.cur_round_pledged_usd = E8s::zero();
+        }
+
+        self.cur_token_x = tokens[0].token.clone();
+        self.active_tokens_x = Some(tokens);
+        self.next_tokens_x = None;
+
+        self.raffle_stage = None;
+        self.is_looking_for_winners = false;
+        self.schedule_next_round(now);
+
         self.update_seed();
     }
 
+    /// Starts the raffle, once its deadline has come.
+    ///
+    /// A raffle, which is late because the canister was stopped or upgrading, starts as soon as the
+    /// canister is back. The rounds, whose deadlines have passed completely during that time, are not
+    /// run one after another - all their pledges take part in this single round, and only the number
+    /// of such rounds is recorded.
+    pub fn start_round(&mut self, now: TimestampNs) {
+        let late_by_ns = now.saturating_sub(self.get_next_round_at());
+        let missed = late_by_ns / self.round_delay.max(1);
+
+        if missed > 0 {
+            self.missed_rounds = Some(self.missed_rounds.unwrap_or_default() + missed);
+        }
+
+        self.raffle_stage = Some(RaffleStage::MovingPrizeFund);
+        self.is_looking_for_winners = true;
+    }
+
+    // the raffles happen each Sunday at 15:00 UTC
+    pub fn schedule_next_round(&mut self, now: TimestampNs) {
+        let delay = duration_until_next_sunday_15_00(now);
+
+        self.next_round_at = Some(now + delay.as_nanos() as u64);
+    }
+
+    // the first upgrade after the deadline was persisted keeps the schedule the timer used to have
+    pub fn ensure_next_round_scheduled(&mut self, now: TimestampNs) {
+        if self.next_round_at.is_none() {
+            self.schedule_next_round(now);
+        }
+    }
+
+    pub fn get_next_round_at(&self) -> TimestampNs {
+        self.next_round_at
+            .unwrap_or(self.prev_round_timestamp + self.round_delay)
+    }
+
     // TODO: make burn into voting power
 
-    // returns decimal point position if true
+    // returns decimal point position if the token can be pledged right now
     pub fn get_decimals(&self, can_id: &Principal) -> Option<u8> {
-        if can_id == &ENV_VARS.burn_token_canister_id {
-            Some(8)
-        } else if can_id == &self.cur_token_x.can_id {
-            Some(self.cur_token_x.decimals)
-        } else {
-            None
-        }
+        self.get_active_token_x(can_id).map(|it| it.token.decimals)
     }
 
     pub fn burn_token_discount(&self, can_id: &Principal, qty_usd: E8s) -> E8s {
-        if can_id == &self.cur_token_x.can_id {
-            qty_usd
-        } else if can_id == &ENV_VARS.burn_token_canister_id {
-            qty_usd * E8s::from(9500_0000u64)
-        } else {
-            unreachable!();
-        }
+        let token = self
+            .get_active_token_x(can_id)
+            .expect("Only active tokens can be pledged");
+
+        qty_usd * token.usd_weight * token.discount
     }
 
     pub fn note_won_icps(&mut self, won: &E8s) {
@@ -254,14 +504,9 @@ impl FurnaceInfo {
         self.seed = hasher.finalize().to_vec();
     }
 
+    // zero, if the deadline has already passed
     pub fn get_timer_delay_before_next_round(&self, now: TimestampNs) -> u64 {
-        let next_round_timestamp = self.prev_round_timestamp + self.round_delay;
-
-        if now > next_round_timestamp {
-            next_round_timestamp - now
-        } else {
-            0
-        }
+        self.get_next_round_at().saturating_sub(now)
     }
 
     pub fn start_looking_for_winners(&mut self) {
@@ -301,6 +546,8 @@ pub struct FurnaceWinnerHistoryEntry {
     pub round: u64,
     pub prize_fund_icp: E8s,
     pub winners: Vec<FurnaceWinner>,
+    // all the tokens which could be pledged during the round, none for the older rounds
+    pub tokens_x: Option<Vec<ActiveTokenX>>,
 }
 
 impl Storable for FurnaceWinnerHistoryEntry {
@@ -321,6 +568,97 @@ pub struct FurnaceWinner {
     pub prize_icp: E8s,
     pub claimed: bool,
     pub share_normalized: E8s,
+    // none for the rounds before the automatic payouts, which can only be claimed
+    pub payout: Option<PrizePayout>,
+}
+
+impl FurnaceWinner {
+    // the automatic payout pass is done with this winner
+    pub fn is_payout_final(&self, now: TimestampNs) -> bool {
+        match &self.payout {
+            None => true,
+            Some(payout) => match payout.status {
+                PrizePayoutStatus::Paid { .. } => true,
+                // a manual claim can still be reverted
+                PrizePayoutStatus::Pending | PrizePayoutStatus::ClaimInProgress => false,
+                // retried with the same created_at_time, until the ledger tells what has happened;
+                // past the deduplication window the prize stays reserved until it is resolved manually
+                PrizePayoutStatus::InProgress | PrizePayoutStatus::Unknown { .. } => payout
+                    .created_at_time
+                    .is_some_and(|it| it + PRIZE_PAYOUT_DEDUP_WINDOW_NS <= now),
+                // the winner can claim the prize manually
+                PrizePayoutStatus::Failed { .. } => payout.attempts >= MAX_PRIZE_PAYOUT_ATTEMPTS,
+            },
+        }
+    }
+
+    pub fn is_payout_due(&self, now: TimestampNs) -> bool {
+        if self.is_payout_final(now) {
+            return false;
+        }
+
+        let Some(payout) = &self.payout else {
+            return false;
+        };
+
+        let is_retry_due = payout
+            .last_attempt_at
+            .is_some_and(|it| it + PRIZE_PAYOUT_RETRY_DELAY_NS <= now);
+
+        match payout.status {
+            PrizePayoutStatus::Pending => !self.claimed,
+            PrizePayoutStatus::Failed { .. } => !self.claimed && is_retry_due,
+            // an attempt, which has never finished, is retried as well - the ledger deduplicates it
+            PrizePayoutStatus::InProgress | PrizePayoutStatus::Unknown { .. } => is_retry_due,
+            PrizePayoutStatus::ClaimInProgress | PrizePayoutStatus::Paid { .. } => false,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PrizePayout {
+    pub status: PrizePayoutStatus,
+    pub attempts: u32,
+    pub last_attempt_at: Option<TimestampNs>,
+    // the same for all the attempts, so the ledger deduplicates them
+    pub created_at_time: Option<TimestampNs>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug, PartialEq)]
+pub enum PrizePayoutStatus {
+    #[default]
+    Pending,
+    InProgress,
+    // the winner is claiming the prize manually
+    ClaimInProgress,
+    Paid {
+        block_index: Nat,
+    },
+    // the transfer was not made, the prize can be claimed manually
+    Failed {
+        error: String,
+    },
+    // the transfer could have been made
+    Unknown {
+        error: String,
+    },
+}
+
+/// How a payout transfer has ended
+pub enum PrizePayoutOutcome {
+    Paid(Nat),
+    // the transfer was not made
+    Rejected(String),
+    // the transfer could have been made, so the prize stays reserved until a retry tells for sure
+    Unknown(String),
+}
+
+pub struct PrizePayoutTask {
+    pub winning_entry_timestamp_ns: TimestampNs,
+    pub winner_idx: u32,
+    pub to: Principal,
+    pub prize_icp: E8s,
+    pub created_at_time: TimestampNs,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -360,6 +698,7 @@ pub struct RaffleRoundInfo {
     pub winners: Vec<(Principal, E8s)>,
     pub winner_selection_cursor: Option<Principal>,
     pub from: E8s,
+    pub positions_recording_cursor: Option<Principal>,
 }
 
 impl RaffleRoundInfo {
@@ -393,6 +732,16 @@ impl RaffleRoundInfo {
     pub fn round_is_over(&self) -> bool {
         self.random_numbers.is_empty()
     }
+
+    // generates COUNT random E8s from 0 to 1
+    pub fn random_numbers_from_seed(seed: &[u8], count: usize) -> Vec<E8s> {
+        let base = E8s::from(u32::MAX as u64);
+
+        random_u32s(seed, count)
+            .into_iter()
+            .map(|num| E8s::from(num as u64) / &base)
+            .collect()
+    }
 }
 
 impl Storable for RaffleRoundInfo {
@@ -440,6 +789,29 @@ pub enum DistributionTriggerKind {
     },
 }
 
+impl DistributionTriggerKind {
+    // `self` is the condition of a trigger, `event` is what has happened
+    pub fn is_triggered_by(&self, event: &DistributionTriggerKind) -> bool {
+        match (self, event) {
+            (
+                DistributionTriggerKind::TokenXVotingWinner(can_id),
+                DistributionTriggerKind::TokenXVotingWinner(elected_can_id),
+            ) => can_id == elected_can_id,
+            (
+                DistributionTriggerKind::TokenTotalPledged {
+                    token_can_id,
+                    threshold,
+                },
+                DistributionTriggerKind::TokenTotalPledged {
+                    token_can_id: pledged_token_can_id,
+                    threshold: total_pledged,
+                },
+            ) => token_can_id == pledged_token_can_id && total_pledged >= threshold,
+            _ => false,
+        }
+    }
+}
+
 impl Storable for DistributionTrigger {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
@@ -451,3 +823,204 @@ impl Storable for DistributionTrigger {
 
     const BOUND: Bound = Bound::Unbounded;
 }
+
+// everything needed to replay the winner selection of a raffle round
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RaffleRoundProof {
+    pub commitment: SeedCommitment,
+    pub prize_distribution: Vec<E8s>,
+    pub total_pledged_usd: E8s,
+    // only filled for the rounds completed before the positions were moved to `FurnaceState::raffle_round_positions`
+    pub positions: Vec<(Principal, E8s)>,
+    pub winners: Vec<(Principal, E8s)>,
+}
+
+impl RaffleRoundProof {
+    pub fn new(secret: &[u8], round: u64) -> Self {
+        Self {
+            commitment: SeedCommitment::new(secret, RAFFLE_ROUND_SEED_DOMAIN, round),
+            prize_distribution: Vec::new(),
+            total_pledged_usd: E8s::zero(),
+            positions: Vec::new(),
+            winners: Vec::new(),
+        }
+    }
+
+    // replays the same walk as FurnaceState::find_winners_batch does, but in a single go
+    // `positions` are the positions of the round in the order they are walked through
+    pub fn verify(&self, round: u64, positions: &[(Principal, E8s)]) -> RaffleRoundVerification {
+        let seed = self.commitment.revealed_seed();
+
+        let mut result = RaffleRoundVerification {
+            round,
+            seed_hash: self.commitment.seed_hash.clone(),
+            seed: seed.clone(),
+            seed_matches_hash: false,
+            random_numbers: Vec::new(),
+            total_pledged_usd: self.total_pledged_usd.clone(),
+            walk: Vec::new(),
+            winners: self.winners.clone(),
+            recomputed_winners: Vec::new(),
+            is_valid: false,
+        };
+
+        let Some(seed) = seed else {
+            return result;
+        };
+
+        let random_numbers =
+            RaffleRoundInfo::random_numbers_from_seed(&seed, self.prize_distribution.len());
+
+        let mut raffle_round_info = RaffleRoundInfo {
+            prize_distribution: self.prize_distribution.clone(),
+            random_numbers: random_numbers.clone(),
+            ..Default::default()
+        };
+
+        let mut to = E8s::zero();
+
+        // the counter continues over the next pass, so two passes are always enough to find every winner
+        'walk: for _ in 0..2 {
+            for (position_id, votes) in positions {
+                to += votes / &self.total_pledged_usd;
+                result.walk.push((*position_id, votes.clone(), to.clone()));
+
+                raffle_round_info.match_winner(&to, *position_id);
+
+                if raffle_round_info.round_is_over() {
+                    break 'walk;
+                }
+            }
+        }
+
+        result.seed_matches_hash = verify_seed(&seed, &self.commitment.seed_hash);
+        result.random_numbers = random_numbers;
+        result.recomputed_winners = raffle_round_info.winners;
+        result.is_valid = result.seed_matches_hash && result.recomputed_winners == self.winners;
+
+        result
+    }
+}
+
+impl Storable for RaffleRoundProof {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RaffleRoundVerification {
+    pub round: u64,
+    pub seed_hash: Vec<u8>,
+    // only revealed after the winners are selected
+    pub seed: Option<Vec<u8>>,
+    pub seed_matches_hash: bool,
+    pub random_numbers: Vec<E8s>,
+    pub total_pledged_usd: E8s,
+    // (position id, pledged usd, cumulative normalized weight)
+    pub walk: Vec<(Principal, E8s, E8s)>,
+    pub winners: Vec<(Principal, E8s)>,
+    pub recomputed_winners: Vec<(Principal, E8s)>,
+    pub is_valid: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use ic_e8s::c::E8s;
+
+    use crate::{ENV_VARS, ONE_DAY_NS};
+
+    use super::{
+        ActiveTokenX, FurnaceInfo, RaffleStage, TokenX, BURN_TOKEN_DISCOUNT_E8S,
+        DEFAULT_ROUND_DELAY_NS,
+    };
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn info(next_round_at: u64) -> FurnaceInfo {
+        FurnaceInfo {
+            round_delay: DEFAULT_ROUND_DELAY_NS,
+            next_round_at: Some(next_round_at),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delay_before_next_round_never_underflows() {
+        assert_eq!(
+            info(NOW + ONE_DAY_NS).get_timer_delay_before_next_round(NOW),
+            ONE_DAY_NS
+        );
+        assert_eq!(
+            info(NOW - ONE_DAY_NS).get_timer_delay_before_next_round(NOW),
+            0
+        );
+    }
+
+    #[test]
+    fn overdue_round_starts_once_and_counts_the_missed_deadlines() {
+        let mut info = info(NOW);
+        info.start_round(NOW + DEFAULT_ROUND_DELAY_NS * 2 + ONE_DAY_NS);
+
+        assert_eq!(info.missed_rounds, Some(2));
+        assert_eq!(info.raffle_stage, Some(RaffleStage::MovingPrizeFund));
+
+        let completed_at = NOW + DEFAULT_ROUND_DELAY_NS * 3;
+        info.complete_round(completed_at);
+
+        assert_eq!(info.raffle_stage, None);
+        assert!(info.get_next_round_at() > completed_at);
+        assert!(info.get_next_round_at() <= completed_at + DEFAULT_ROUND_DELAY_NS);
+    }
+
+    #[test]
+    fn elected_tokens_become_active_after_the_round() {
+        let token_x = TokenX {
+            can_id: Principal::management_canister(),
+            fee: Nat::from(0u64),
+            decimals: 6,
+        };
+
+        let mut info = info(NOW);
+        info.set_next_tokens_x(vec![
+            ActiveTokenX::elected(token_x.clone(), E8s::from(5000_0000u64)),
+            ActiveTokenX::burn(E8s::from(BURN_TOKEN_DISCOUNT_E8S)),
+        ]);
+
+        // the BURN fallback has not won the voting, so its distributions are not triggered
+        assert_eq!(info.get_elected_next_tokens_x(), vec![token_x.can_id]);
+
+        let burn_can_id = ENV_VARS.burn_token_canister_id;
+        assert_eq!(info.get_decimals(&token_x.can_id), None);
+        assert_eq!(info.get_decimals(&burn_can_id), Some(8));
+
+        info.complete_round(NOW);
+
+        assert_eq!(info.cur_token_x.can_id, token_x.can_id);
+        assert_eq!(info.get_decimals(&token_x.can_id), Some(6));
+        assert_eq!(
+            info.burn_token_discount(&token_x.can_id, E8s::one()),
+            E8s::from(5000_0000u64)
+        );
+        assert_eq!(
+            info.burn_token_discount(&burn_can_id, E8s::one()),
+            E8s::from(BURN_TOKEN_DISCOUNT_E8S)
+        );
+
+        info.note_pledged_token(&token_x.can_id, &Nat::from(100u64), &E8s::one())
+            .unwrap();
+        info.complete_round(NOW + DEFAULT_ROUND_DELAY_NS);
+
+        // the same tokens stay active, if nothing was elected
+        let tokens = info.get_active_tokens_x();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].cur_round_pledged, Nat::from(0u64));
+    }
+}
@@ -1,12 +1,18 @@
+use std::collections::BTreeMap;
+
 use candid::{decode_one, encode_one, CandidType, Int, Nat, Principal};
-use ic_cdk::{api::call::CallResult, call};
+use ic_cdk::{
+    api::{call::CallResult, time},
+    call,
+};
 use ic_e8s::c::E8s;
 use ic_stable_structures::{storable::Bound, Storable};
 use serde::Deserialize;
 
-use crate::{utils::f64_to_e8s, ENV_VARS};
+use crate::{burner::types::TimestampNs, icpswap_base_index, utils::f64_to_e8s, ENV_VARS};
 
 pub const ICPSWAP_CAN_ID: &str = "ggzvv-5qaaa-aaaag-qck7a-cai";
+pub const ICPSWAP_BASE_INDEX_CAN_ID: &str = "g54jq-hiaaa-aaaag-qck5q-cai";
 
 pub type GetAllTokensResponse = Vec<ICPSwapTokenInfo>;
 
@@ -31,6 +37,8 @@ pub struct ICPSwapTokenEntry {
 pub struct ICPSwapTokenInfo {
     pub can_id: Principal,
     pub exchange_rate_usd: E8s,
+    // when the source has last updated the price, none if it is unknown
+    pub updated_at: Option<TimestampNs>,
 }
 
 impl Storable for ICPSwapTokenInfo {
@@ -45,6 +53,27 @@ impl Storable for ICPSwapTokenInfo {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ICPSwapPoolPrice {
+    pub pool: Principal,
+    pub token0: Principal,
+    pub token1: Principal,
+    // the price of one token0 in token1
+    pub last_price: f64,
+    pub timestamp: TimestampNs,
+}
+
+pub trait ICPSwapApi {
+    async fn get_all_tokens(&self) -> CallResult<GetAllTokensResponse>;
+
+    /// The last prices of the pools, which pair any of `tokens` with `quote_token_can_id`
+    async fn get_pool_prices(
+        &self,
+        tokens: &[Principal],
+        quote_token_can_id: Principal,
+    ) -> CallResult<Vec<ICPSwapPoolPrice>>;
+}
+
 pub struct ICPSwapClient {
     pub can_id: Principal,
     pub mock: bool,
@@ -57,31 +86,139 @@ impl ICPSwapClient {
             mock,
         }
     }
+}
 
-    pub async fn get_all_tokens(&self) -> CallResult<GetAllTokensResponse> {
+impl ICPSwapApi for ICPSwapClient {
+    async fn get_all_tokens(&self) -> CallResult<GetAllTokensResponse> {
         if self.mock {
             Ok(vec![
                 ICPSwapTokenInfo {
                     can_id: ENV_VARS.burn_token_canister_id,
                     exchange_rate_usd: E8s::from(0_0556_0000u64),
+                    updated_at: Some(time()),
                 },
                 ICPSwapTokenInfo {
                     can_id: ENV_VARS.icp_token_canister_id,
                     exchange_rate_usd: E8s::from(8_0300_0000u64),
+                    updated_at: Some(time()),
                 },
             ])
         } else {
-            call::<(), (Vec<ICPSwapTokenEntry>,)>(self.can_id, "getAllTokens", ())
-                .await
-                .map(|(tokens,)| {
-                    tokens
-                        .into_iter()
-                        .map(|token| ICPSwapTokenInfo {
-                            can_id: Principal::from_text(token.address).unwrap(),
-                            exchange_rate_usd: f64_to_e8s(token.priceUSD),
-                        })
-                        .collect()
+            let (tokens,) =
+                call::<(), (Vec<ICPSwapTokenEntry>,)>(self.can_id, "getAllTokens", ()).await?;
+
+            // the token list doesn't tell when its prices were updated, so the last trade in the
+            // pools of a token is used instead - without it the prices can't be checked for staleness
+            let updated_at = fetch_tokens_last_price_times().await.unwrap_or_default();
+
+            Ok(tokens
+                .into_iter()
+                .map(|token| ICPSwapTokenInfo {
+                    updated_at: updated_at.get(&token.address).copied(),
+                    can_id: Principal::from_text(token.address).unwrap(),
+                    exchange_rate_usd: f64_to_e8s(token.priceUSD),
                 })
+                .collect())
+        }
+    }
+
+    async fn get_pool_prices(
+        &self,
+        tokens: &[Principal],
+        quote_token_can_id: Principal,
+    ) -> CallResult<Vec<ICPSwapPoolPrice>> {
+        if self.mock {
+            return Ok(Vec::new());
+        }
+
+        let index =
+            icpswap_base_index::Service(Principal::from_text(ICPSWAP_BASE_INDEX_CAN_ID).unwrap());
+
+        let (pools,) = index.get_all_pools().await?;
+        let (last_price_times,) = index.get_pool_last_price_time().await?;
+
+        let last_price_times: BTreeMap<String, Int> = last_price_times.into_iter().collect();
+        let mut result = Vec::new();
+
+        for pool in pools {
+            let (Ok(pool_id), Ok(token0), Ok(token1)) = (
+                Principal::from_text(&pool.pool),
+                Principal::from_text(&pool.token0Id),
+                Principal::from_text(&pool.token1Id),
+            ) else {
+                continue;
+            };
+
+            let is_quoted = (token0 == quote_token_can_id && tokens.contains(&token1))
+                || (token1 == quote_token_can_id && tokens.contains(&token0));
+
+            if !is_quoted {
+                continue;
+            }
+
+            // a price without a timestamp can't be checked for staleness
+            let Some(timestamp) = last_price_times
+                .get(&pool.pool)
+                .and_then(icpswap_time_to_ns)
+            else {
+                continue;
+            };
+
+            // one failing pool should not fail the others
+            if let Ok((last_price,)) = index.get_pool_last_price(pool_id).await {
+                result.push(ICPSwapPoolPrice {
+                    pool: pool_id,
+                    token0,
+                    token1,
+                    last_price,
+                    timestamp,
+                });
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+// the latest last price time of the pools of each token
+async fn fetch_tokens_last_price_times() -> CallResult<BTreeMap<String, TimestampNs>> {
+    let index =
+        icpswap_base_index::Service(Principal::from_text(ICPSWAP_BASE_INDEX_CAN_ID).unwrap());
+
+    let (pools,) = index.get_all_pools().await?;
+    let (last_price_times,) = index.get_pool_last_price_time().await?;
+
+    let last_price_times: BTreeMap<String, Int> = last_price_times.into_iter().collect();
+    let mut result: BTreeMap<String, TimestampNs> = BTreeMap::new();
+
+    for pool in pools {
+        let Some(timestamp) = last_price_times
+            .get(&pool.pool)
+            .and_then(icpswap_time_to_ns)
+        else {
+            continue;
+        };
+
+        for token in [pool.token0Id, pool.token1Id] {
+            let entry = result.entry(token).or_default();
+            *entry = (*entry).max(timestamp);
         }
     }
+
+    Ok(result)
+}
+
+// ICPSwap doesn't document the unit of its timestamps, so it is guessed by the magnitude
+fn icpswap_time_to_ns(time: &Int) -> Option<TimestampNs> {
+    let time: u64 = time.0.to_biguint()?.try_into().ok()?;
+
+    let time_ns = if time < 10_000_000_000 {
+        time.checked_mul(1_000_000_000)?
+    } else if time < 10_000_000_000_000 {
+        time.checked_mul(1_000_000)?
+    } else {
+        time
+    };
+
+    Some(time_ns)
 }
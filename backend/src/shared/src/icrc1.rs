@@ -1,4 +1,4 @@
-use candid::{Nat, Principal};
+use candid::{CandidType, Nat, Principal};
 use ic_cdk::{
     api::call::{CallResult, RejectionCode},
     call,
@@ -11,39 +11,48 @@ use icrc_ledger_types::{
     },
     icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
+use serde::Deserialize;
 
-pub struct ICRC1CanisterClient {
-    pub canister_id: Principal,
-}
+pub const ICRC2_STANDARD: &str = "ICRC-2";
 
-impl ICRC1CanisterClient {
-    pub fn new(canister_id: Principal) -> Self {
-        Self { canister_id }
-    }
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
 
-    pub async fn icrc1_balance_of(&self, arg: Account) -> CallResult<(Nat,)> {
-        call(self.canister_id, "icrc1_balance_of", (arg,)).await
-    }
+/// The ledger calls the canisters make. Implemented by `ICRC1CanisterClient` for the real ledgers
+/// and by `fakes::FakeICRC1Ledger` for the tests.
+pub trait ICRC1Api {
+    async fn icrc1_balance_of(&self, arg: Account) -> CallResult<(Nat,)>;
 
-    pub async fn icrc1_minting_account(&self) -> CallResult<(Option<Account>,)> {
-        call(self.canister_id, "icrc1_minting_account", ()).await
-    }
+    async fn icrc1_minting_account(&self) -> CallResult<(Option<Account>,)>;
 
-    pub async fn icrc1_transfer(
+    async fn icrc1_transfer(
         &self,
         arg: TransferArg,
-    ) -> CallResult<(Result<BlockIndex, TransferError>,)> {
-        call(self.canister_id, "icrc1_transfer", (arg,)).await
-    }
+    ) -> CallResult<(Result<BlockIndex, TransferError>,)>;
 
-    pub async fn icrc2_transfer_from(
+    async fn icrc2_transfer_from(
         &self,
         arg: TransferFromArgs,
-    ) -> CallResult<(Result<BlockIndex, TransferFromError>,)> {
-        call(self.canister_id, "icrc2_transfer_from", (arg,)).await
+    ) -> CallResult<(Result<BlockIndex, TransferFromError>,)>;
+
+    async fn icrc1_decimals(&self) -> CallResult<(u8,)>;
+
+    async fn icrc1_fee(&self) -> CallResult<(Nat,)>;
+
+    async fn icrc1_total_supply(&self) -> CallResult<(Nat,)>;
+
+    async fn icrc1_supported_standards(&self) -> CallResult<(Vec<StandardRecord>,)>;
+
+    async fn supports_icrc2(&self) -> CallResult<bool> {
+        let (standards,) = self.icrc1_supported_standards().await?;
+
+        Ok(standards.iter().any(|it| it.name == ICRC2_STANDARD))
     }
 
-    pub async fn icrc1_furnace_burn(
+    async fn icrc1_furnace_burn(
         &self,
         from_subaccount: Option<[u8; 32]>,
         amount: NumTokens,
@@ -64,16 +73,54 @@ impl ICRC1CanisterClient {
 
         self.icrc1_transfer(arg).await
     }
+}
+
+pub struct ICRC1CanisterClient {
+    pub canister_id: Principal,
+}
+
+impl ICRC1CanisterClient {
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+}
+
+impl ICRC1Api for ICRC1CanisterClient {
+    async fn icrc1_balance_of(&self, arg: Account) -> CallResult<(Nat,)> {
+        call(self.canister_id, "icrc1_balance_of", (arg,)).await
+    }
 
-    pub async fn icrc1_decimals(&self) -> CallResult<(u8,)> {
+    async fn icrc1_minting_account(&self) -> CallResult<(Option<Account>,)> {
+        call(self.canister_id, "icrc1_minting_account", ()).await
+    }
+
+    async fn icrc1_transfer(
+        &self,
+        arg: TransferArg,
+    ) -> CallResult<(Result<BlockIndex, TransferError>,)> {
+        call(self.canister_id, "icrc1_transfer", (arg,)).await
+    }
+
+    async fn icrc2_transfer_from(
+        &self,
+        arg: TransferFromArgs,
+    ) -> CallResult<(Result<BlockIndex, TransferFromError>,)> {
+        call(self.canister_id, "icrc2_transfer_from", (arg,)).await
+    }
+
+    async fn icrc1_decimals(&self) -> CallResult<(u8,)> {
         call(self.canister_id, "icrc1_decimals", ()).await
     }
 
-    pub async fn icrc1_fee(&self) -> CallResult<(Nat,)> {
+    async fn icrc1_fee(&self) -> CallResult<(Nat,)> {
         call(self.canister_id, "icrc1_fee", ()).await
     }
 
-    pub async fn icrc1_total_supply(&self) -> CallResult<(Nat,)> {
+    async fn icrc1_total_supply(&self) -> CallResult<(Nat,)> {
         call(self.canister_id, "icrc1_total_supply", ()).await
     }
+
+    async fn icrc1_supported_standards(&self) -> CallResult<(Vec<StandardRecord>,)> {
+        call(self.canister_id, "icrc1_supported_standards", ()).await
+    }
 }
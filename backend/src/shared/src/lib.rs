@@ -1,3 +1,6 @@
+// canisters are single-threaded, so the futures of the client traits don't have to be `Send`
+#![allow(async_fn_in_trait)]
+
 use burner::types::TimestampNs;
 use candid::{CandidType, Principal};
 use env::{
@@ -8,15 +11,27 @@ use env::{
 use lazy_static::lazy_static;
 use serde::Deserialize;
 
+pub mod access;
 pub mod burner;
+pub mod certification;
 pub mod cmc;
+pub mod decideid;
 pub mod dispenser;
 mod env;
+#[cfg(any(test, feature = "fakes"))]
+pub mod fakes;
+pub mod feature_flags;
 pub mod furnace;
 pub mod icpswap;
 pub mod icpswap_base_index;
 pub mod icpswap_base_storage;
 pub mod icrc1;
+pub mod merkle;
+pub mod metrics;
+pub mod outbox;
+pub mod price_oracle;
+pub mod randomness;
+pub mod timers;
 pub mod trading;
 pub mod trading_invites;
 pub mod utils;
@@ -60,6 +75,8 @@ pub struct EnvVarsState {
     pub swap_mining_canister_id: Principal,
     pub ii_canister_id: Principal,
     pub ii_origin: String,
+    // the origin the users sign in to II from, their principals are derived from it
+    pub frontend_origin: String,
     pub ic_root_key_der: Vec<u8>,
     pub icp_token_canister_id: Principal,
     pub cycles_minting_canister_id: Principal,
@@ -74,6 +91,12 @@ impl EnvVarsState {
             String::from(CAN_IC_HOST).replace("http://", &format!("http://{}.", CAN_II_CANISTER_ID))
         };
 
+        let frontend_origin = if CAN_MODE == "ic" {
+            String::from("https://burn.msq.tech")
+        } else {
+            String::from("http://localhost:8000")
+        };
+
         Self {
             burner_canister_id: Principal::from_text(CAN_BURNER_CANISTER_ID).unwrap(),
             burn_token_canister_id: Principal::from_text(CAN_BURN_TOKEN_CANISTER_ID).unwrap(),
@@ -85,6 +108,7 @@ impl EnvVarsState {
             ii_canister_id: Principal::from_text(CAN_II_CANISTER_ID).unwrap(),
 
             ii_origin,
+            frontend_origin,
 
             ic_root_key_der: CAN_ROOT_KEY
                 .trim_start_matches("[")
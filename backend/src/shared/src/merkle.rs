@@ -0,0 +1,48 @@
+use sha2::Digest;
+
+pub const MERKLE_LEAF_DOMAIN: &[u8] = b"msq-burn-merkle-leaf";
+pub const MERKLE_NODE_DOMAIN: &[u8] = b"msq-burn-merkle-node";
+pub const MERKLE_EMPTY_DOMAIN: &[u8] = b"msq-burn-merkle-empty";
+
+pub fn merkle_leaf_hash(data: &[u8]) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::default();
+    hasher.update(MERKLE_LEAF_DOMAIN);
+    hasher.update(data);
+
+    hasher.finalize().to_vec()
+}
+
+pub fn merkle_node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::default();
+    hasher.update(MERKLE_NODE_DOMAIN);
+    hasher.update(left);
+    hasher.update(right);
+
+    hasher.finalize().to_vec()
+}
+
+/// A plain binary Merkle tree over already hashed leaves.
+/// A node without a pair is moved to the next level as is, an empty tree has a fixed root.
+pub fn merkle_root(hashes: &[Vec<u8>]) -> Vec<u8> {
+    if hashes.is_empty() {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(MERKLE_EMPTY_DOMAIN);
+
+        return hasher.finalize().to_vec();
+    }
+
+    let mut level = hashes.to_vec();
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merkle_node_hash(left, right),
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+
+    level.pop().unwrap()
+}
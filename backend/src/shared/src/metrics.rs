@@ -0,0 +1,194 @@
+use std::fmt::Write;
+
+use candid::{CandidType, Nat};
+use ic_cdk::api::{canister_balance128, stable::stable64_size, time};
+use ic_e8s::c::ECs;
+use num_bigint::BigUint;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+use crate::timers::get_timers_status;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+const WASM_PAGE_SIZE_BYTES: f64 = 65536.0;
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+/// Builds a Prometheus text exposition (format version 0.0.4).
+///
+/// Only gauges are used, since every value is read from the state at the moment of the request.
+#[derive(Default)]
+pub struct MetricsEncoder {
+    buf: String,
+}
+
+impl MetricsEncoder {
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help);
+        writeln!(self.buf, "{} {}", name, value).unwrap();
+    }
+
+    /// Writes a gauge with one sample per label value, e.g. `name{job="tick"} 1`
+    pub fn labeled_gauge<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: impl IntoIterator<Item = (&'a str, f64)>,
+    ) {
+        self.header(name, help);
+
+        for (label_value, value) in samples {
+            writeln!(
+                self.buf,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape_label_value(label_value),
+                value
+            )
+            .unwrap();
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf.into_bytes()
+    }
+
+    fn header(&mut self, name: &str, help: &str) {
+        writeln!(self.buf, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.buf, "# TYPE {} gauge", name).unwrap();
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// precision loss is fine here - the metrics are only used for dashboards and alerts
+pub fn biguint_to_f64(value: &BigUint, decimals: u32) -> f64 {
+    let int = value.to_string().parse::<f64>().unwrap_or(f64::NAN);
+
+    int / 10f64.powi(decimals as i32)
+}
+
+pub fn ecs_to_f64<const D: usize>(value: &ECs<D>) -> f64 {
+    biguint_to_f64(&value.val, D as u32)
+}
+
+pub fn nat_to_f64(value: &Nat, decimals: u32) -> f64 {
+    biguint_to_f64(&value.0, decimals)
+}
+
+/// The metrics every canister has: cycles, memory and the health of the jobs in the timers registry
+pub fn encode_common_metrics(m: &mut MetricsEncoder) {
+    m.gauge(
+        "canister_cycles_balance",
+        "The cycles balance of the canister",
+        canister_balance128() as f64,
+    );
+    m.gauge(
+        "canister_stable_memory_bytes",
+        "The size of the stable memory of the canister in bytes",
+        stable64_size() as f64 * WASM_PAGE_SIZE_BYTES,
+    );
+    m.gauge(
+        "canister_heap_memory_bytes",
+        "The size of the heap memory of the canister in bytes",
+        heap_memory_pages() as f64 * WASM_PAGE_SIZE_BYTES,
+    );
+
+    encode_timers_metrics(m);
+}
+
+fn encode_timers_metrics(m: &mut MetricsEncoder) {
+    let timers = get_timers_status(time()).timers;
+
+    m.labeled_gauge(
+        "timer_running",
+        "1 if the job has started, but not yet finished",
+        "job",
+        timers
+            .iter()
+            .map(|it| (it.job.as_str(), it.is_running as u8 as f64)),
+    );
+    m.labeled_gauge(
+        "timer_stuck",
+        "1 if the job is late to start or to finish and can be restarted",
+        "job",
+        timers
+            .iter()
+            .map(|it| (it.job.as_str(), it.is_stuck as u8 as f64)),
+    );
+    m.labeled_gauge(
+        "timer_consecutive_failures",
+        "The number of failed runs of the job since the last successful one",
+        "job",
+        timers
+            .iter()
+            .map(|it| (it.job.as_str(), it.status.consecutive_failures as f64)),
+    );
+    m.labeled_gauge(
+        "timer_last_finished_at_seconds",
+        "The timestamp of the last finished run of the job, 0 if it has never finished",
+        "job",
+        timers.iter().map(|it| {
+            let finished_at = it.status.last_finished_at.unwrap_or_default();
+
+            (it.job.as_str(), (finished_at / 1_000_000_000) as f64)
+        }),
+    );
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_pages() -> usize {
+    core::arch::wasm32::memory_size(0)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_pages() -> usize {
+    0
+}
+
+/// Serves `/metrics` with the common metrics plus the ones added by `f`, answers 404 to everything else
+pub fn serve_metrics(req: HttpRequest, f: impl FnOnce(&mut MetricsEncoder)) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+
+    if path != METRICS_PATH {
+        return HttpResponse {
+            status_code: 404,
+            headers: vec![],
+            body: ByteBuf::from("Not found"),
+        };
+    }
+
+    let mut m = MetricsEncoder::default();
+    encode_common_metrics(&mut m);
+    f(&mut m);
+
+    HttpResponse {
+        status_code: 200,
+        headers: vec![(
+            String::from("Content-Type"),
+            String::from("text/plain; version=0.0.4"),
+        )],
+        body: ByteBuf::from(m.finish()),
+    }
+}
@@ -0,0 +1,423 @@
+use candid::{decode_one, encode_one, CandidType, Nat, Principal};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use icrc_ledger_types::icrc1::{
+    account::{Account, Subaccount},
+    transfer::{Memo, TransferArg, TransferError},
+};
+use serde::Deserialize;
+
+use crate::{burner::types::TimestampNs, icrc1::ICRC1Api, ONE_HOUR_NS, ONE_MINUTE_NS};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const OUTBOX_MAX_ATTEMPTS: u32 = 8;
+pub const OUTBOX_BASE_BACKOFF_NS: u64 = ONE_MINUTE_NS;
+// ledgers only deduplicate transfers within 24 hours, so all the retries have to fit into this window
+pub const OUTBOX_MAX_BACKOFF_NS: u64 = ONE_HOUR_NS;
+pub const OUTBOX_PROCESSING_INTERVAL_NS: u64 = ONE_MINUTE_NS;
+pub const OUTBOX_TRANSFERS_PER_BATCH: usize = 10;
+// the sent and failed transfers are only kept for the history
+pub const OUTBOX_TRANSFERS_TO_KEEP: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum OutboxTransferStatus {
+    Pending,
+    Sent { block_idx: Nat },
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OutboxTransferRequest {
+    pub ledger: Principal,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+}
+
+/// A ledger transfer that is stored before it is sent.
+///
+/// `created_at_time` and `memo` are fixed once, when the transfer is enqueued, so every retry
+/// is an exact copy of the first attempt and gets deduplicated by the ledger, if that attempt
+/// actually went through.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OutboxTransfer {
+    pub ledger: Principal,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Memo,
+    pub created_at_time: TimestampNs,
+
+    pub attempts: u32,
+    pub last_attempt_at: Option<TimestampNs>,
+    pub next_attempt_at: TimestampNs,
+    pub last_error: Option<String>,
+    pub status: OutboxTransferStatus,
+}
+
+impl OutboxTransfer {
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, OutboxTransferStatus::Pending)
+    }
+
+    pub fn to_transfer_arg(&self) -> TransferArg {
+        TransferArg {
+            from_subaccount: self.from_subaccount,
+            to: self.to,
+            amount: self.amount.clone(),
+            fee: self.fee.clone(),
+            memo: Some(self.memo.clone()),
+            created_at_time: Some(self.created_at_time),
+        }
+    }
+
+    // how much this transfer is going to take from the source account, once sent
+    pub fn total_debit(&self) -> Nat {
+        self.amount.clone() + self.fee.clone().unwrap_or_default()
+    }
+}
+
+impl Storable for OutboxTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Debug)]
+pub enum OutboxSendError {
+    Retryable(String),
+    Fatal(String),
+}
+
+pub struct Outbox {
+    pub transfers: StableBTreeMap<u64, OutboxTransfer, Memory>,
+    pub pending: StableBTreeMap<u64, (), Memory>,
+}
+
+impl Outbox {
+    pub fn enqueue(&mut self, req: OutboxTransferRequest, now: TimestampNs) -> u64 {
+        let id = self
+            .transfers
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+
+        let transfer = OutboxTransfer {
+            ledger: req.ledger,
+            from_subaccount: req.from_subaccount,
+            to: req.to,
+            amount: req.amount,
+            fee: req.fee,
+            memo: Memo::from(id),
+            created_at_time: now,
+
+            attempts: 0,
+            last_attempt_at: None,
+            next_attempt_at: now,
+            last_error: None,
+            status: OutboxTransferStatus::Pending,
+        };
+
+        self.transfers.insert(id, transfer);
+        self.pending.insert(id, ());
+
+        self.prune(id);
+
+        id
+    }
+
+    // removes the oldest final transfers, the last one is kept, since the next id is derived from it
+    fn prune(&mut self, last_id: u64) {
+        let excess = self
+            .transfers
+            .len()
+            .saturating_sub(OUTBOX_TRANSFERS_TO_KEEP);
+
+        if excess == 0 {
+            return;
+        }
+
+        let to_remove: Vec<_> = self
+            .transfers
+            .range(..last_id)
+            .map(|(id, _)| id)
+            .filter(|id| !self.pending.contains_key(id))
+            .take(excess as usize)
+            .collect();
+
+        for id in to_remove {
+            self.transfers.remove(&id);
+        }
+    }
+
+    /// Returns the transfers which are due to be sent and schedules their next attempt right away,
+    /// so an overlapping run won't pick them up again while they are in flight.
+    pub fn take_due(&mut self, now: TimestampNs, limit: usize) -> Vec<(u64, OutboxTransfer)> {
+        let due: Vec<_> = self
+            .pending
+            .iter()
+            .filter_map(|(id, _)| self.transfers.get(&id).map(|it| (id, it)))
+            .filter(|(_, it)| it.next_attempt_at <= now)
+            .take(limit)
+            .collect();
+
+        due.into_iter()
+            .map(|(id, mut transfer)| {
+                transfer.attempts += 1;
+                transfer.last_attempt_at = Some(now);
+                transfer.next_attempt_at = now + Self::backoff_ns(transfer.attempts);
+
+                self.transfers.insert(id, transfer.clone());
+
+                (id, transfer)
+            })
+            .collect()
+    }
+
+    pub fn complete(
+        &mut self,
+        id: u64,
+        result: Result<Nat, OutboxSendError>,
+    ) -> Option<OutboxTransfer> {
+        let mut transfer = self.transfers.get(&id)?;
+
+        if !transfer.is_pending() {
+            return Some(transfer);
+        }
+
+        match result {
+            Ok(block_idx) => {
+                transfer.status = OutboxTransferStatus::Sent { block_idx };
+                transfer.last_error = None;
+            }
+            Err(OutboxSendError::Fatal(reason)) => {
+                transfer.status = OutboxTransferStatus::Failed { reason };
+            }
+            Err(OutboxSendError::Retryable(reason)) => {
+                if transfer.attempts >= OUTBOX_MAX_ATTEMPTS {
+                    transfer.status = OutboxTransferStatus::Failed { reason };
+                } else {
+                    transfer.last_error = Some(reason);
+                }
+            }
+        }
+
+        if !transfer.is_pending() {
+            self.pending.remove(&id);
+        }
+
+        self.transfers.insert(id, transfer.clone());
+
+        Some(transfer)
+    }
+
+    /// The sum of all the pending transfers (including fees) from the provided account.
+    /// This amount is still on the account's balance, but it is already spoken for.
+    pub fn get_pending_debit(&self, ledger: Principal, from_subaccount: Option<Subaccount>) -> Nat {
+        let from = from_subaccount.unwrap_or_default();
+
+        self.pending
+            .iter()
+            .filter_map(|(id, _)| self.transfers.get(&id))
+            .filter(|it| it.ledger == ledger && it.from_subaccount.unwrap_or_default() == from)
+            .fold(Nat::from(0u64), |acc, it| acc + it.total_debit())
+    }
+
+    pub fn get(&self, id: u64) -> Option<OutboxTransfer> {
+        self.transfers.get(&id)
+    }
+
+    pub fn get_transfers(&self, req: GetOutboxTransfersRequest) -> GetOutboxTransfersResponse {
+        let entries = if req.pending_only {
+            let iter = if let Some(start_from) = req.start {
+                let mut i = self.pending.range(&start_from..);
+                i.next();
+                i
+            } else {
+                self.pending.iter()
+            };
+
+            iter.take(req.take as usize)
+                .filter_map(|(id, _)| self.transfers.get(&id).map(|it| (id, it)))
+                .collect()
+        } else {
+            let iter = if let Some(start_from) = req.start {
+                let mut i = self.transfers.range(&start_from..);
+                i.next();
+                i
+            } else {
+                self.transfers.iter()
+            };
+
+            iter.take(req.take as usize).collect()
+        };
+
+        GetOutboxTransfersResponse { entries }
+    }
+
+    fn backoff_ns(attempts: u32) -> u64 {
+        let exp = attempts.saturating_sub(1).min(16);
+
+        (OUTBOX_BASE_BACKOFF_NS << exp).min(OUTBOX_MAX_BACKOFF_NS)
+    }
+}
+
+// the ledger should be the one the transfer is made on, see `OutboxTransfer::ledger`
+pub async fn send_outbox_transfer(
+    ledger: &impl ICRC1Api,
+    transfer: &OutboxTransfer,
+) -> Result<Nat, OutboxSendError> {
+    match ledger.icrc1_transfer(transfer.to_transfer_arg()).await {
+        Ok((Ok(block_idx),)) => Ok(block_idx),
+        // a previous attempt went through, but its response was lost
+        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+        Ok((Err(e @ TransferError::TooOld),))
+        | Ok((Err(e @ TransferError::BadFee { .. }),))
+        | Ok((Err(e @ TransferError::BadBurn { .. }),)) => {
+            Err(OutboxSendError::Fatal(e.to_string()))
+        }
+        Ok((Err(e),)) => Err(OutboxSendError::Retryable(e.to_string())),
+        Err((c, m)) => Err(OutboxSendError::Retryable(format!("{:?}: {}", c, m))),
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetOutboxTransfersRequest {
+    pub start: Option<u64>,
+    pub take: u32,
+    pub pending_only: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetOutboxTransfersResponse {
+    pub entries: Vec<(u64, OutboxTransfer)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use futures::executor::block_on;
+    use ic_stable_structures::{
+        memory_manager::{MemoryId, MemoryManager},
+        DefaultMemoryImpl, StableBTreeMap,
+    };
+    use icrc_ledger_types::icrc1::account::Account;
+
+    use crate::{fakes::FakeICRC1Ledger, ONE_HOUR_NS};
+
+    use super::{
+        send_outbox_transfer, Outbox, OutboxSendError, OutboxTransferRequest, OutboxTransferStatus,
+        OUTBOX_TRANSFERS_TO_KEEP,
+    };
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+    const FEE: u64 = 10_000;
+
+    fn canister_id() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn outbox() -> Outbox {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+
+        Outbox {
+            transfers: StableBTreeMap::init(memory_manager.get(MemoryId::new(0))),
+            pending: StableBTreeMap::init(memory_manager.get(MemoryId::new(1))),
+        }
+    }
+
+    fn enqueue(outbox: &mut Outbox, fee: u64) -> u64 {
+        outbox.enqueue(
+            OutboxTransferRequest {
+                ledger: canister_id(),
+                from_subaccount: None,
+                to: Account {
+                    owner: Principal::from_slice(&[2]),
+                    subaccount: None,
+                },
+                amount: Nat::from(1_0000_0000u64),
+                fee: Some(Nat::from(fee)),
+            },
+            NOW,
+        )
+    }
+
+    #[test]
+    fn transfer_retried_after_a_lost_response_is_sent_once() {
+        let mut outbox = outbox();
+        let id = enqueue(&mut outbox, FEE);
+
+        let ledger = FakeICRC1Ledger::new(canister_id(), FEE);
+        ledger.set_balance(
+            Account {
+                owner: canister_id(),
+                subaccount: None,
+            },
+            10_0000_0000,
+        );
+        ledger.lose_next_response();
+
+        let (_, transfer) = outbox.take_due(NOW, 10).remove(0);
+        let result = block_on(send_outbox_transfer(&ledger, &transfer));
+        assert!(matches!(result, Err(OutboxSendError::Retryable(_))));
+        assert!(outbox.complete(id, result).unwrap().is_pending());
+
+        // the retry is an exact copy of the first attempt, so the ledger deduplicates it
+        let (_, transfer) = outbox.take_due(NOW + ONE_HOUR_NS, 10).remove(0);
+        let result = block_on(send_outbox_transfer(&ledger, &transfer));
+        let transfer = outbox.complete(id, result).unwrap();
+
+        assert!(matches!(
+            transfer.status,
+            OutboxTransferStatus::Sent { block_idx } if block_idx == 0u64
+        ));
+        assert_eq!(ledger.blocks(), 1);
+    }
+
+    #[test]
+    fn transfer_with_a_wrong_fee_fails_without_retries() {
+        let mut outbox = outbox();
+        let id = enqueue(&mut outbox, FEE * 2);
+
+        let ledger = FakeICRC1Ledger::new(canister_id(), FEE);
+
+        let (_, transfer) = outbox.take_due(NOW, 10).remove(0);
+        let result = block_on(send_outbox_transfer(&ledger, &transfer));
+        let transfer = outbox.complete(id, result).unwrap();
+
+        assert!(matches!(
+            transfer.status,
+            OutboxTransferStatus::Failed { .. }
+        ));
+        assert!(outbox.take_due(NOW + ONE_HOUR_NS, 10).is_empty());
+    }
+
+    #[test]
+    fn only_the_final_transfers_are_pruned() {
+        let mut outbox = outbox();
+        let pending_id = enqueue(&mut outbox, FEE);
+
+        for _ in 0..OUTBOX_TRANSFERS_TO_KEEP {
+            let id = enqueue(&mut outbox, FEE);
+            outbox.complete(id, Err(OutboxSendError::Fatal(String::new())));
+        }
+
+        assert_eq!(outbox.transfers.len(), OUTBOX_TRANSFERS_TO_KEEP);
+        assert!(outbox.get(pending_id).is_some());
+        assert!(outbox.get(pending_id + 1).is_none());
+
+        // the ids are never reused
+        let id = enqueue(&mut outbox, FEE);
+        assert_eq!(id, OUTBOX_TRANSFERS_TO_KEEP + 1);
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+
+use candid::{decode_one, encode_one, CandidType, Principal};
+use ic_e8s::c::E8s;
+use ic_stable_structures::{storable::Bound, Storable};
+use num_bigint::BigUint;
+use serde::Deserialize;
+
+use crate::{
+    burner::types::TimestampNs,
+    icpswap::{ICPSwapPoolPrice, ICPSwapTokenInfo},
+    utils::f64_to_e8s,
+    ONE_HOUR_NS,
+};
+
+// the price used to value the pledges is averaged over this period
+pub const PRICE_TWAP_WINDOW_NS: u64 = ONE_HOUR_NS * 6;
+// the quotes older than this are ignored
+pub const PRICE_MAX_QUOTE_AGE_NS: u64 = ONE_HOUR_NS;
+// the quotes which differ from the reference price by more than 20% are ignored
+pub const PRICE_MAX_DEVIATION_E8S: u64 = 2000_0000;
+// if nothing was accepted for this long, the market has likely moved for real - the reference price
+// is taken from the fresh quotes again
+pub const PRICE_REANCHOR_AFTER_NS: u64 = ONE_HOUR_NS * 6;
+const MAX_PRICE_SAMPLES: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PriceSource {
+    // the `priceUSD` of the token in the ICPSwap token list
+    ICPSwapTokenList,
+    // the last price in an ICPSwap pool, converted into USD with the price of the other token
+    ICPSwapPool {
+        pool: Principal,
+        quote_token_can_id: Principal,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PriceQuote {
+    pub source: PriceSource,
+    pub price_usd: E8s,
+    pub timestamp: TimestampNs,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RejectedPriceQuote {
+    pub quote: PriceQuote,
+    pub reason: String,
+}
+
+/// The price of a token together with the quotes it was made of, so anyone can check where it came from.
+///
+/// Each fetch adds the median of the accepted quotes as a sample. The pledges are valued at the TWAP of
+/// these samples. When every quote of a fetch is rejected, or the fetch fails, the price stays as it was.
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct TokenPrice {
+    pub twap_usd: E8s,
+    pub last_good_price_usd: E8s,
+    pub last_good_price_at: TimestampNs,
+    pub samples: Vec<(TimestampNs, E8s)>,
+
+    // the quotes of the last fetch
+    pub accepted_quotes: Vec<PriceQuote>,
+    pub rejected_quotes: Vec<RejectedPriceQuote>,
+    pub last_updated_at: TimestampNs,
+    pub last_error: Option<String>,
+}
+
+impl TokenPrice {
+    pub fn is_known(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Returns false, if all the quotes were rejected
+    pub fn update(&mut self, quotes: Vec<PriceQuote>, now: TimestampNs) -> bool {
+        self.last_updated_at = now;
+        self.accepted_quotes.clear();
+        self.rejected_quotes.clear();
+
+        let mut fresh = Vec::new();
+
+        for quote in quotes {
+            if quote.timestamp + PRICE_MAX_QUOTE_AGE_NS < now {
+                self.reject(quote, "The quote is stale");
+            } else if quote.price_usd == E8s::zero() {
+                self.reject(quote, "The price is zero");
+            } else {
+                fresh.push(quote);
+            }
+        }
+
+        let reference =
+            if self.is_known() && now < self.last_good_price_at + PRICE_REANCHOR_AFTER_NS {
+                Some(self.twap_usd.clone())
+            } else {
+                median(fresh.iter().map(|it| it.price_usd.clone()).collect())
+            };
+
+        if let Some(reference) = reference {
+            let max_deviation = E8s::from(PRICE_MAX_DEVIATION_E8S);
+
+            for quote in fresh {
+                if deviation(&quote.price_usd, &reference) > max_deviation {
+                    self.reject(
+                        quote,
+                        "The price deviates from the reference price too much",
+                    );
+                } else {
+                    self.accepted_quotes.push(quote);
+                }
+            }
+        }
+
+        let price_opt = median(
+            self.accepted_quotes
+                .iter()
+                .map(|it| it.price_usd.clone())
+                .collect(),
+        );
+
+        let Some(price) = price_opt else {
+            self.last_error = Some(String::from("No valid quotes, the last good price is kept"));
+
+            return false;
+        };
+
+        self.last_good_price_usd = price.clone();
+        self.last_good_price_at = now;
+        self.last_error = None;
+
+        self.samples.push((now, price));
+        self.samples
+            .retain(|(timestamp, _)| timestamp + PRICE_TWAP_WINDOW_NS >= now);
+
+        if self.samples.len() > MAX_PRICE_SAMPLES {
+            self.samples.drain(..self.samples.len() - MAX_PRICE_SAMPLES);
+        }
+
+        self.twap_usd = self.calculate_twap();
+
+        true
+    }
+
+    // for the fetches which have failed completely
+    pub fn note_error(&mut self, error: String, now: TimestampNs) {
+        self.last_updated_at = now;
+        self.accepted_quotes.clear();
+        self.rejected_quotes.clear();
+        self.last_error = Some(error);
+    }
+
+    fn reject(&mut self, quote: PriceQuote, reason: &str) {
+        self.rejected_quotes.push(RejectedPriceQuote {
+            quote,
+            reason: String::from(reason),
+        });
+    }
+
+    // each sample's price is held over the period since the previous sample
+    fn calculate_twap(&self) -> E8s {
+        let mut weighted = BigUint::from(0u64);
+        let mut total_ns = 0u64;
+
+        for pair in self.samples.windows(2) {
+            let period_ns = pair[1].0 - pair[0].0;
+
+            weighted += &pair[1].1.val * period_ns;
+            total_ns += period_ns;
+        }
+
+        if total_ns == 0 {
+            return self
+                .samples
+                .last()
+                .map(|(_, price)| price.clone())
+                .unwrap_or_default();
+        }
+
+        E8s::new(weighted / total_ns)
+    }
+}
+
+impl Storable for TokenPrice {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Makes the quotes for the tracked tokens out of the ICPSwap token list and the prices of the pools,
+/// whose other token is the quote token. The pools are skipped, until the quote token has a price.
+pub fn collect_price_quotes(
+    tokens: &[Principal],
+    token_list: &[ICPSwapTokenInfo],
+    pool_prices: &[ICPSwapPoolPrice],
+    quote_token_can_id: Principal,
+    quote_token_price_usd: Option<&E8s>,
+) -> BTreeMap<Principal, Vec<PriceQuote>> {
+    let mut result: BTreeMap<Principal, Vec<PriceQuote>> = BTreeMap::new();
+
+    for info in token_list {
+        if !tokens.contains(&info.can_id) {
+            continue;
+        }
+
+        // a price without a timestamp can't be checked for staleness
+        let Some(timestamp) = info.updated_at else {
+            continue;
+        };
+
+        result.entry(info.can_id).or_default().push(PriceQuote {
+            source: PriceSource::ICPSwapTokenList,
+            price_usd: info.exchange_rate_usd.clone(),
+            timestamp,
+        });
+    }
+
+    let Some(quote_token_price_usd) = quote_token_price_usd else {
+        return result;
+    };
+
+    for pool_price in pool_prices {
+        // the pool's last price is the price of one token0 in token1
+        let (token_can_id, price_in_quote_token) = if pool_price.token1 == quote_token_can_id {
+            (pool_price.token0, pool_price.last_price)
+        } else if pool_price.token0 == quote_token_can_id {
+            (pool_price.token1, 1.0 / pool_price.last_price)
+        } else {
+            continue;
+        };
+
+        if !tokens.contains(&token_can_id)
+            || !price_in_quote_token.is_finite()
+            || price_in_quote_token <= 0.0
+        {
+            continue;
+        }
+
+        result.entry(token_can_id).or_default().push(PriceQuote {
+            source: PriceSource::ICPSwapPool {
+                pool: pool_price.pool,
+                quote_token_can_id,
+            },
+            price_usd: f64_to_e8s(price_in_quote_token) * quote_token_price_usd,
+            timestamp: pool_price.timestamp,
+        });
+    }
+
+    result
+}
+
+// the lower one of the two middle values, if there is an even number of them
+fn median(mut prices: Vec<E8s>) -> Option<E8s> {
+    if prices.is_empty() {
+        return None;
+    }
+
+    prices.sort();
+
+    Some(prices.swap_remove((prices.len() - 1) / 2))
+}
+
+fn deviation(price: &E8s, reference: &E8s) -> E8s {
+    let diff = if price > reference {
+        price - reference
+    } else {
+        reference - price
+    };
+
+    diff / reference
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_e8s::c::E8s;
+
+    use crate::{icpswap::ICPSwapTokenInfo, ONE_MINUTE_NS};
+
+    use super::{
+        collect_price_quotes, PriceQuote, PriceSource, TokenPrice, PRICE_MAX_QUOTE_AGE_NS,
+        PRICE_REANCHOR_AFTER_NS,
+    };
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn quote(price_e8s: u64, timestamp: u64) -> PriceQuote {
+        PriceQuote {
+            source: PriceSource::ICPSwapTokenList,
+            price_usd: E8s::from(price_e8s),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn stale_and_deviating_quotes_are_rejected() {
+        let mut price = TokenPrice::default();
+
+        assert!(price.update(vec![quote(1_0000_0000, NOW)], NOW));
+
+        let now = NOW + ONE_MINUTE_NS * 10;
+        assert!(!price.update(
+            vec![
+                quote(1_0500_0000, now - PRICE_MAX_QUOTE_AGE_NS - 1),
+                quote(2_0000_0000, now),
+            ],
+            now,
+        ));
+
+        assert_eq!(price.rejected_quotes.len(), 2);
+        assert_eq!(price.twap_usd, E8s::from(1_0000_0000u64));
+        assert!(price.last_error.is_some());
+
+        // the market has moved for real
+        let now = NOW + PRICE_REANCHOR_AFTER_NS;
+        assert!(price.update(vec![quote(2_0000_0000, now)], now));
+        assert_eq!(price.last_good_price_usd, E8s::from(2_0000_0000u64));
+    }
+
+    #[test]
+    fn token_list_quotes_keep_the_time_of_their_source() {
+        let stale = Principal::from_slice(&[1]);
+        let unknown = Principal::from_slice(&[2]);
+
+        let token_list = [
+            ICPSwapTokenInfo {
+                can_id: stale,
+                exchange_rate_usd: E8s::from(1_0000_0000u64),
+                updated_at: Some(NOW - PRICE_MAX_QUOTE_AGE_NS - 1),
+            },
+            ICPSwapTokenInfo {
+                can_id: unknown,
+                exchange_rate_usd: E8s::from(1_0000_0000u64),
+                updated_at: None,
+            },
+        ];
+
+        let mut quotes = collect_price_quotes(&[stale, unknown], &token_list, &[], unknown, None);
+
+        assert!(!quotes.contains_key(&unknown));
+
+        let mut price = TokenPrice::default();
+        assert!(!price.update(quotes.remove(&stale).unwrap(), NOW));
+
+        assert_eq!(price.rejected_quotes.len(), 1);
+        assert_eq!(price.rejected_quotes[0].reason, "The quote is stale");
+        assert!(!price.is_known());
+    }
+
+    #[test]
+    fn twap_is_weighted_by_time() {
+        let mut price = TokenPrice::default();
+
+        price.update(vec![quote(1_0000_0000, NOW)], NOW);
+        price.update(
+            vec![quote(1_1000_0000, NOW + ONE_MINUTE_NS * 30)],
+            NOW + ONE_MINUTE_NS * 30,
+        );
+        price.update(
+            vec![quote(1_0500_0000, NOW + ONE_MINUTE_NS * 40)],
+            NOW + ONE_MINUTE_NS * 40,
+        );
+
+        // (1.1 * 30 + 1.05 * 10) / 40
+        assert_eq!(price.twap_usd, E8s::from(1_0875_0000u64));
+    }
+}
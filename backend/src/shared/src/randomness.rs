@@ -0,0 +1,108 @@
+use candid::{decode_one, encode_one, CandidType};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Deserialize;
+use sha2::Digest;
+
+pub const ROUND_SEED_DOMAIN: &[u8] = b"msq-burn-round-seed";
+pub const SEED_COMMITMENT_DOMAIN: &[u8] = b"msq-burn-seed-commitment";
+pub const RANDOM_NUMBERS_DOMAIN: &[u8] = b"msq-burn-random-numbers";
+
+/// A commit-reveal record for a single round.
+///
+/// The hash of the round seed is published before the participants of the round are known.
+/// The seed itself is only revealed after the winners are selected, so anyone can check
+/// that it matches the commitment and recompute the random numbers from it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SeedCommitment {
+    pub seed_hash: Vec<u8>,
+    pub seed: Vec<u8>,
+    pub revealed: bool,
+}
+
+impl SeedCommitment {
+    // each round seed is derived from the private canister seed, so revealing it tells nothing about other rounds
+    pub fn new(secret: &[u8], domain: &[u8], round: u64) -> Self {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(ROUND_SEED_DOMAIN);
+        hasher.update(domain);
+        hasher.update(secret);
+        hasher.update(round.to_le_bytes());
+
+        let seed = hasher.finalize().to_vec();
+
+        Self {
+            seed_hash: hash_seed(&seed),
+            seed,
+            revealed: false,
+        }
+    }
+
+    pub fn reveal(&mut self) {
+        self.revealed = true;
+    }
+
+    pub fn revealed_seed(&self) -> Option<Vec<u8>> {
+        if self.revealed {
+            Some(self.seed.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Storable for SeedCommitment {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(encode_one(self).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_one(&bytes).expect("Unable to decode")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn hash_seed(seed: &[u8]) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::default();
+    hasher.update(SEED_COMMITMENT_DOMAIN);
+    hasher.update(seed);
+
+    hasher.finalize().to_vec()
+}
+
+pub fn verify_seed(seed: &[u8], seed_hash: &[u8]) -> bool {
+    hash_seed(seed) == seed_hash
+}
+
+/// Expands the seed into a stream of random bytes: sha256(domain | seed | block_idx) for each 32 bytes
+pub fn random_bytes(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut result = Vec::with_capacity(len + 32);
+    let mut block_idx = 0u64;
+
+    while result.len() < len {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(RANDOM_NUMBERS_DOMAIN);
+        hasher.update(seed);
+        hasher.update(block_idx.to_le_bytes());
+
+        result.extend(hasher.finalize());
+        block_idx += 1;
+    }
+
+    result.truncate(len);
+
+    result
+}
+
+pub fn random_u32s(seed: &[u8], count: usize) -> Vec<u32> {
+    random_bytes(seed, count * 4)
+        .chunks_exact(4)
+        .map(|it| u32::from_le_bytes(it.try_into().unwrap()))
+        .collect()
+}
+
+pub fn random_u128(seed: &[u8]) -> u128 {
+    let bytes = random_bytes(seed, 16);
+
+    u128::from_le_bytes(bytes.try_into().unwrap())
+}
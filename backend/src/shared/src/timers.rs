@@ -0,0 +1,205 @@
+use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+use candid::CandidType;
+use ic_cdk::api::time;
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
+use serde::Deserialize;
+
+use crate::{burner::types::TimestampNs, ONE_MINUTE_NS};
+
+// a job which is late to start or to finish for this long is considered stuck
+pub const TIMER_STUCK_GRACE_PERIOD_NS: u64 = ONE_MINUTE_NS * 30;
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct TimerStatus {
+    pub last_started_at: Option<TimestampNs>,
+    pub last_finished_at: Option<TimestampNs>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<TimestampNs>,
+    pub next_run_at: Option<TimestampNs>,
+    pub consecutive_failures: u32,
+}
+
+impl TimerStatus {
+    // a run that has trapped after an await looks exactly like this - it has started, but never finished
+    pub fn is_running(&self) -> bool {
+        match (self.last_started_at, self.last_finished_at) {
+            (Some(started), Some(finished)) => started > finished,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_stuck(&self, now: TimestampNs) -> bool {
+        if self.is_running() {
+            // a job which keeps retrying is still alive
+            let last_sign_of_life = self.last_started_at.max(self.last_error_at);
+
+            return last_sign_of_life.is_some_and(|it| it + TIMER_STUCK_GRACE_PERIOD_NS < now);
+        }
+
+        // a run that has trapped before any await is rolled back completely, so it never starts
+        self.next_run_at
+            .is_some_and(|it| it + TIMER_STUCK_GRACE_PERIOD_NS < now)
+    }
+}
+
+#[derive(Default)]
+struct TimerEntry {
+    status: TimerStatus,
+    timer_id: Option<TimerId>,
+}
+
+thread_local! {
+    // timers don't survive upgrades, so neither does their status
+    static TIMERS_REGISTRY: RefCell<BTreeMap<String, TimerEntry>> = RefCell::default();
+}
+
+fn with_entry<R>(job: &str, f: impl FnOnce(&mut TimerEntry) -> R) -> R {
+    TIMERS_REGISTRY.with_borrow_mut(|r| f(r.entry(job.to_string()).or_default()))
+}
+
+/// Schedules the next run of a job, like `set_timer` does, but also tracks it in the registry.
+/// The job itself should report the end of its run with `note_job_finished` or `note_job_failed`.
+pub fn schedule_job(job: &'static str, delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+    let timer_id = set_timer(delay, move || {
+        note_job_started(job);
+        func();
+    });
+
+    with_entry(job, |entry| {
+        entry.status.next_run_at = Some(time() + delay.as_nanos() as u64);
+        entry.timer_id = Some(timer_id);
+    });
+
+    timer_id
+}
+
+/// The same as `schedule_job`, but for `set_timer_interval`
+pub fn schedule_job_interval(
+    job: &'static str,
+    interval: Duration,
+    mut func: impl FnMut() + 'static,
+) -> TimerId {
+    let interval_ns = interval.as_nanos() as u64;
+
+    let timer_id = set_timer_interval(interval, move || {
+        note_job_started(job);
+        with_entry(job, |entry| {
+            entry.status.next_run_at = Some(time() + interval_ns);
+        });
+
+        func();
+    });
+
+    with_entry(job, |entry| {
+        entry.status.next_run_at = Some(time() + interval_ns);
+        entry.timer_id = Some(timer_id);
+    });
+
+    timer_id
+}
+
+pub fn note_job_started(job: &str) {
+    let now = time();
+
+    with_entry(job, |entry| {
+        if entry.status.is_running() {
+            entry.status.consecutive_failures += 1;
+            entry.status.last_error = Some(String::from("The previous run did not finish"));
+            entry.status.last_error_at = Some(now);
+        }
+
+        entry.status.last_started_at = Some(now);
+        entry.status.next_run_at = None;
+    });
+}
+
+pub fn note_job_finished(job: &str) {
+    with_entry(job, |entry| {
+        entry.status.last_finished_at = Some(time());
+        entry.status.consecutive_failures = 0;
+    });
+}
+
+pub fn note_job_failed(job: &str, error: String) {
+    let now = time();
+
+    with_entry(job, |entry| {
+        entry.status.last_finished_at = Some(now);
+        entry.status.last_error = Some(error);
+        entry.status.last_error_at = Some(now);
+        entry.status.consecutive_failures += 1;
+    });
+}
+
+// for the errors a running job recovers from by itself
+pub fn note_job_error(job: &str, error: String) {
+    let now = time();
+
+    with_entry(job, |entry| {
+        entry.status.last_error = Some(error);
+        entry.status.last_error_at = Some(now);
+        entry.status.consecutive_failures += 1;
+    });
+}
+
+/// Checks if the job can be restarted and cancels its pending timer, if there is one.
+/// Only stuck jobs can be restarted, otherwise there is a risk of running two copies of the same job.
+pub fn prepare_job_restart(job: &str, now: TimestampNs) -> Result<(), String> {
+    TIMERS_REGISTRY.with_borrow_mut(|r| {
+        let entry = r
+            .get_mut(job)
+            .ok_or(String::from("The job has never been scheduled"))?;
+
+        if !entry.status.is_stuck(now) {
+            return Err(String::from("The job is not stuck"));
+        }
+
+        if let Some(timer_id) = entry.timer_id.take() {
+            clear_timer(timer_id);
+        }
+
+        entry.status.next_run_at = None;
+
+        Ok(())
+    })
+}
+
+pub fn get_timers_status(now: TimestampNs) -> GetTimersStatusResponse {
+    let timers = TIMERS_REGISTRY.with_borrow(|r| {
+        r.iter()
+            .map(|(job, entry)| JobTimerStatus {
+                job: job.clone(),
+                is_running: entry.status.is_running(),
+                is_stuck: entry.status.is_stuck(now),
+                status: entry.status.clone(),
+            })
+            .collect()
+    });
+
+    GetTimersStatusResponse { timers }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct JobTimerStatus {
+    pub job: String,
+    pub is_running: bool,
+    pub is_stuck: bool,
+    pub status: TimerStatus,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetTimersStatusResponse {
+    pub timers: Vec<JobTimerStatus>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RestartTimerRequest {
+    pub job: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RestartTimerResponse {
+    pub result: Result<(), String>,
+}
@@ -2,7 +2,11 @@ use candid::Principal;
 use ic_e8s::c::E8s;
 use ic_stable_structures::{Cell, StableBTreeMap, StableVec};
 
-use crate::burner::types::{Memory, TimestampNs};
+use crate::{
+    access::AccessControl,
+    burner::types::{Memory, TimestampNs},
+    feature_flags::FeatureFlags,
+};
 
 use super::{
     api::{CandleKind, GetPriceHistoryRequest},
@@ -26,6 +30,9 @@ pub struct TradingState {
     pub order_history: Cell<OrderHistory, Memory>,
 
     pub fees_received: StableBTreeMap<Principal, u64, Memory>,
+
+    pub access: AccessControl,
+    pub feature_flags: FeatureFlags,
 }
 
 impl TradingState {
@@ -20,6 +20,18 @@ pub const MAX_PRICE: f64 = 100.0;
 pub const TREND_SIGN_CHANGE_PROBABILITY: f64 = 0.008;
 pub const TREND_SIGN_CHANGE_PROBABILITY_FACTOR: f64 = 0.002;
 pub const START_PRICE: f64 = 1.0;
+
+pub const FEATURE_ORDER: &str = "order";
+pub const FEATURE_DEPOSIT: &str = "deposit";
+pub const FEATURE_WITHDRAW: &str = "withdraw";
+pub const FEATURE_REGISTER: &str = "register";
+// the features which are disabled, until explicitly enabled
+pub const TRADING_FEATURES: &[(&str, &str)] = &[
+    (FEATURE_ORDER, "Temporarily unavailable"),
+    (FEATURE_DEPOSIT, "Temporarily unavailable"),
+    (FEATURE_WITHDRAW, "Temporarily unavailable"),
+    (FEATURE_REGISTER, "Temporarily unavailable"),
+];
 pub const TREND_MODIFIER: f64 = 0.0000011;
 pub const DEFAULT_TREND: f64 = 0.0001;
 
@@ -282,6 +294,9 @@ pub struct PriceInfo {
     pub cur_4h_short_candle: Candle,
     pub cur_1d_long_candle: Candle,
     pub cur_1d_short_candle: Candle,
+
+    // receives the liquidity providers' cut of the fees
+    pub dev_pid: Option<Principal>,
 }
 
 impl PriceInfo {
@@ -305,6 +320,7 @@ impl PriceInfo {
             cur_4h_short_candle: Candle::open(START_PRICE, now),
             cur_1d_long_candle: Candle::open(START_PRICE, now),
             cur_1d_short_candle: Candle::open(START_PRICE, now),
+            dev_pid: None,
         }
     }
 
@@ -1,11 +1,14 @@
 use candid::Principal;
 use ic_stable_structures::StableBTreeMap;
 
-use crate::burner::types::Memory;
+use crate::{access::AccessControl, burner::types::Memory, feature_flags::FeatureFlags};
 
 use super::types::{Invite, MemberInfo};
 
 pub struct TradingInvitesState {
     pub members: StableBTreeMap<Principal, MemberInfo, Memory>,
     pub invites: StableBTreeMap<Invite, Principal, Memory>,
+
+    pub access: AccessControl,
+    pub feature_flags: FeatureFlags,
 }
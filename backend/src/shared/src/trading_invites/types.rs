@@ -6,6 +6,21 @@ pub type Invite = [u8; 32];
 
 pub const BRIBE_SIZE_E8S: u64 = 1000_0000_0000;
 
+pub const FEATURE_UPDATE_MY_INVITE: &str = "update_my_invite";
+pub const FEATURE_REGISTER_WITH_INVITE: &str = "register_with_invite";
+pub const FEATURE_REGISTER_WITH_BRIBE: &str = "register_with_bribe";
+pub const FEATURE_WITHDRAW_FROM_USER_SUBACCOUNT: &str = "withdraw_from_user_subaccount";
+// the features which are disabled, until explicitly enabled
+pub const TRADING_INVITES_FEATURES: &[(&str, &str)] = &[
+    (FEATURE_UPDATE_MY_INVITE, "Temporarily unavailable"),
+    (FEATURE_REGISTER_WITH_INVITE, "Temporarily unavailable"),
+    (FEATURE_REGISTER_WITH_BRIBE, "Temporarily unavailable"),
+    (
+        FEATURE_WITHDRAW_FROM_USER_SUBACCOUNT,
+        "Temporarily unavailable",
+    ),
+];
+
 #[derive(CandidType, Deserialize, Debug, Default)]
 pub struct MemberInfo {
     pub cur_invite: Option<Invite>,
@@ -1,6 +1,5 @@
 use std::time::Duration;
 
-
 use ic_e8s::c::E8s;
 
 use crate::{ONE_DAY_NS, ONE_HOUR_NS, ONE_WEEK_NS};
@@ -37,7 +36,8 @@ fn next_sunday_n_utc(current_timestamp_nanos: u64, n: u64) -> Duration {
 
     // Calculate the time difference in seconds
     let mut duration_until_target = days_until_sunday * ONE_DAY_NS + target_nanoseconds_of_day;
-    if current_nanoseconds_of_day > target_nanoseconds_of_day {
+    // it is Sunday already, but the hour has passed
+    if days_until_sunday == 0 && current_nanoseconds_of_day > target_nanoseconds_of_day {
         duration_until_target += ONE_WEEK_NS;
     }
     duration_until_target -= current_nanoseconds_of_day;
@@ -13,6 +13,8 @@ import {
 } from "@dfinity/verifiable-credentials/request-verifiable-presentation";
 import { useWallet } from "./wallet";
 import { AccountIdentifier } from "@dfinity/ledger-icp";
+import { IcrcLedgerCanister } from "@dfinity/ledger-icrc";
+import { KamikazeTier } from "@/declarations/burner/burner.did";
 
 export interface ITotals {
   totalSharesSupply: EDs;
@@ -64,7 +66,7 @@ export interface IBurnerStoreContext {
   fetchTotals: () => Promise<void>;
 
   canPledgePool: () => boolean;
-  pledgePool: (isKamikaze: boolean, qty: bigint) => Promise<void>;
+  pledgePool: (isKamikaze: boolean, qty: bigint, kamikazeTier?: KamikazeTier) => Promise<void>;
 
   poolMembers: () => IPoolMember[];
   fetchPoolMembers: () => Promise<void>;
@@ -108,7 +110,7 @@ export function BurnerStore(props: IChildren) {
     deauthorize,
   } = useAuth();
   const { fetchSubaccountOf, balanceOf, fetchBalanceOf } = useTokens();
-  const { pidBalance, fetchPidBalance, fetchPoolBalance, moveIcpToPoolAccount } = useWallet();
+  const { pidBalance, fetchPidBalance } = useWallet();
 
   const [totals, setTotals] = createStore<IBurnerStoreContext["totals"]>();
   const [poolMembers, setPoolMembers] = createSignal<IPoolMember[]>([]);
@@ -333,26 +335,42 @@ export function BurnerStore(props: IChildren) {
     return true;
   };
 
-  const pledgePool: IBurnerStoreContext["pledgePool"] = async (isKamikaze: boolean, qty: bigint) => {
+  const pledgePool: IBurnerStoreContext["pledgePool"] = async (
+    isKamikaze: boolean,
+    qty: bigint,
+    kamikazeTier?: KamikazeTier
+  ) => {
     err(ErrorCode.UNKNOWN, "Temporarily unavailable");
     assertAuthorized();
 
     disable();
 
     try {
-      await moveIcpToPoolAccount(qty);
+      // one fee is paid for the approval, another one for the transfer made by the burner
+      const icp = IcrcLedgerCanister.create({ canisterId: DEFAULT_TOKENS.icp, agent: agent()! });
+      await icp.approve({
+        spender: { owner: Principal.from(import.meta.env.VITE_BURNER_CANISTER_ID), subaccount: [] },
+        amount: qty - 10_000n,
+      });
 
       const burner = newBurnerActor(agent()!);
 
-      if (isKamikaze) {
-        await burner.stake_kamikaze({ qty_e8s_u64: qty - 10_000n });
-      } else {
-        await burner.stake({ qty_e8s_u64: qty - 10_000n });
+      // the same request can be safely retried, the burner deduplicates it by created_at_time
+      const req = {
+        qty_e8s_u64: qty - 20_000n,
+        created_at_time: BigInt(Date.now()) * 1_000_000n,
+        kamikaze_tier: (isKamikaze && kamikazeTier ? [kamikazeTier] : []) as [] | [KamikazeTier],
+      };
+
+      const response = isKamikaze ? await burner.stake_kamikaze(req) : await burner.stake(req);
+
+      if ("Err" in response.result) {
+        throw new Error(response.result.Err);
       }
-    } finally {
-      await Promise.all([fetchTotals(), fetchPidBalance(DEFAULT_TOKENS.icp), fetchPoolBalance(DEFAULT_TOKENS.icp)]);
 
       logInfo(`Successfully pledged ${E8s.new(qty).toString()} ICP`);
+    } finally {
+      await Promise.all([fetchTotals(), fetchPidBalance(DEFAULT_TOKENS.icp)]);
 
       enable();
     }
@@ -466,7 +484,11 @@ export function BurnerStore(props: IChildren) {
       });
 
       const burner = newBurnerActor(agent()!);
-      await burner.verify_decide_id({ jwt });
+      const response = await burner.verify_decide_id({ jwt });
+
+      if ("Err" in response.result) {
+        throw new Error(response.result.Err);
+      }
 
       fetchTotals();
     } finally {
@@ -251,7 +251,7 @@ export function WalletStore(props: IChildren) {
 
     try {
       const pool = newBurnerActor(agent()!);
-      const response = await pool.claim_reward({ to: pid()! });
+      const response = await pool.claim_reward({ to: { owner: pid()!, subaccount: [] }, qty: [], memo: [] });
 
       if ("Err" in response.result) {
         throw new Error(response.result.Err);